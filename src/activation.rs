@@ -0,0 +1,55 @@
+/*Copyright (c) 2022 Diego da Silva Lima. All rights reserved.
+
+This work is licensed under the terms of the MIT license.  
+For a copy, see <https://opensource.org/licenses/MIT>.*/
+
+use gtk4::glib;
+use gtk4::gio;
+use gtk4::prelude::*;
+use std::path::PathBuf;
+use super::{MultiArchiverAction, SingleArchiverAction};
+
+/*
+Glue between the open signal of a gio::Application and the archivers. The open signal is
+emitted when the application is activated with files (e.g. from the command line, the
+"Open With" menu of the file manager, or a search provider activating a result), including
+when it is activated through D-Bus while another instance is already running. The application
+must be built with gio::ApplicationFlags::HANDLES_OPEN, and is still responsible for presenting
+its window (the activate signal is not emitted when the open signal is).
+*/
+
+// Forwards every opened file to the MultiArchiver as an OpenRequest.
+pub fn connect_multi_manager_with_application(send : &glib::Sender<MultiArchiverAction>, app : &gio::Application) {
+    let send = send.clone();
+    app.connect_open(move |_app, files, _hint| {
+        for path in local_paths(files) {
+            send.send(MultiArchiverAction::OpenRequest(path))
+                .unwrap_or_else(super::log_err);
+        }
+    });
+}
+
+// Forwards the first opened file to the SingleArchiver as an OpenRequest (the
+// others are ignored, since the archiver holds a single document).
+pub fn connect_single_manager_with_application(send : &glib::Sender<SingleArchiverAction>, app : &gio::Application) {
+    let send = send.clone();
+    app.connect_open(move |_app, files, _hint| {
+        if let Some(path) = local_paths(files).into_iter().next() {
+            send.send(SingleArchiverAction::OpenRequest(path))
+                .unwrap_or_else(super::log_err);
+        }
+    });
+}
+
+// Files without a local path (e.g. remote URIs) are skipped.
+fn local_paths(files : &[gio::File]) -> Vec<PathBuf> {
+    files.iter()
+        .filter_map(|f| {
+            let path = f.path();
+            if path.is_none() {
+                eprintln!("Ignoring file without a local path: {}", f.uri());
+            }
+            path
+        })
+        .collect()
+}
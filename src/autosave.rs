@@ -0,0 +1,218 @@
+/*Copyright (c) 2022 Diego da Silva Lima. All rights reserved.
+
+This work is licensed under the terms of the MIT license.  
+For a copy, see <https://opensource.org/licenses/MIT>.*/
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{SystemTime, Duration};
+use gtk4::glib;
+use serde::{Serialize, Deserialize};
+use super::get_datadir;
+use super::io::{path_hash, write_replacing};
+
+/*
+Periodic autosave shared by both archivers. At every interval, the archiver pulls the
+content of the files with unsaved changes through on_buffer_read_request and either saves
+them at their own path (going through the regular save, so the file becomes saved) or
+writes them to a recovery file in a separate directory (e.g. $datadir/recovery), leaving
+the file itself untouched. Untitled files are only autosaved to the recovery directory,
+since they have no path yet.
+
+Recovery files are swap files named after a hash of the source, which record the source
+they were written for. They are removed when the source is saved or closed, so that the
+recovery files found when the directory is set at the next startup are the unsaved changes
+left by a crash (see MultiArchiver::pending_recoveries).
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchiverAutosave {
+
+    interval : Duration,
+
+    recovery_dir : Option<PathBuf>
+
+}
+
+/// Describes a single autosave, passed to on_autosaved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AutosaveRecord {
+
+    // Path of the autosaved file (or the untitled name).
+    pub source : PathBuf,
+
+    // Path the content was written to (the source path itself, or the recovery file).
+    pub target : PathBuf,
+
+    // Whether the content was written to a recovery file.
+    pub recovery : bool,
+
+    pub dt : SystemTime
+
+}
+
+/// A recovery file left by a previous session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingRecovery {
+
+    // Path of the file that had unsaved changes (or its untitled name).
+    pub source : PathBuf,
+
+    // Path of the recovery file.
+    pub swap : PathBuf,
+
+    // When the recovery file was written.
+    pub dt : SystemTime
+
+}
+
+#[derive(Serialize, Deserialize)]
+struct SwapFile {
+    source : PathBuf,
+    dt : SystemTime,
+    content : String
+}
+
+impl ArchiverAutosave {
+
+    /// Autosaves the files with unsaved changes at their own path.
+    pub fn new(interval : Duration) -> Self {
+        Self { interval, recovery_dir : None }
+    }
+
+    /// Writes the autosaved content to recovery files at the given directory instead.
+    pub fn with_recovery_dir<P : AsRef<Path>>(mut self, dir : P) -> Self {
+        self.recovery_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Writes the autosaved content to recovery files at $datadir/recovery, where
+    /// $datadir is the application data directory (see get_datadir). Files are
+    /// saved at their own path if the data directory could not be found.
+    pub fn with_datadir_recovery(mut self, app_id : &str) -> Self {
+        self.recovery_dir = get_datadir(app_id).map(|dir| dir.join("recovery") );
+        self
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    pub fn recovery_dir(&self) -> Option<&Path> {
+        self.recovery_dir.as_deref()
+    }
+
+    /// The recovery file for the file at the given path (or with the given untitled
+    /// name), if a recovery directory is set.
+    pub fn recovery_path<P : AsRef<Path>>(&self, source : P) -> Option<PathBuf> {
+        let dir = self.recovery_dir.as_ref()?;
+
+        // The hash does not change across Rust versions (see path_hash), so the recovery
+        // file of a source is still found (and discarded) after a toolchain upgrade.
+        Some(dir.join(format!("{:016x}.swp", path_hash(source.as_ref()))))
+    }
+
+    // Calls f after the interval elapses (the archiver then autosaves and schedules the next call).
+    pub(crate) fn schedule<F : FnOnce() + 'static>(&self, f : F) {
+        glib::timeout_add_local_once(self.interval, f);
+    }
+
+    // Removes the recovery file of the source, after the source was saved or closed.
+    pub(crate) fn discard(&self, source : &Path) {
+        if let Some(recovery) = self.recovery_path(source) {
+            thread::spawn(move || {
+                if recovery.exists() {
+                    if let Err(e) = fs::remove_file(&recovery) {
+                        eprintln!("Could not remove recovery file {}: {}", recovery.display(), e);
+                    }
+                }
+            });
+        }
+    }
+
+}
+
+// Writes the content to the recovery file of the source, passing the record (or the error
+// message) to done, which builds the action sent back to the archiver. The recovery file
+// replaces the previous one (see write_replacing).
+pub(crate) fn spawn_write_recovery<A, D>(
+    autosave : &ArchiverAutosave,
+    source : PathBuf,
+    content : String,
+    send : glib::Sender<A>,
+    done : D
+)
+where
+    A : Send + 'static,
+    D : FnOnce(Result<AutosaveRecord, String>) -> A + Send + 'static
+{
+    let Some(target) = autosave.recovery_path(&source) else {
+        return;
+    };
+    thread::spawn(move || {
+        let dt = SystemTime::now();
+        let swap = SwapFile { source, dt, content };
+        let res = serde_json::to_string(&swap).map_err(io::Error::other)
+            .and_then(|json| write_replacing(&target, |f| f.write_all(json.as_bytes()) ) )
+            .map(|_| AutosaveRecord {
+                source : swap.source,
+                target : target.clone(),
+                recovery : true,
+                dt
+            })
+            .map_err(|e| format!("Could not write recovery file {}: {}", target.display(), e) );
+        send.send(done(res)).unwrap_or_else(super::log_err);
+    });
+}
+
+// Lists the recovery files at the directory (a missing directory has none). Files that
+// are not valid recovery files are ignored.
+pub(crate) fn spawn_scan_recoveries<A, D>(dir : PathBuf, send : glib::Sender<A>, done : D)
+where
+    A : Send + 'static,
+    D : FnOnce(Vec<PendingRecovery>) -> A + Send + 'static
+{
+    thread::spawn(move || {
+        let mut recoveries = Vec::new();
+        if let Ok(entries) = fs::read_dir(&dir) {
+            for entry in entries.filter_map(|e| e.ok() ) {
+                let path = entry.path();
+                if path.extension().and_then(|ext| ext.to_str() ) != Some("swp") {
+                    continue;
+                }
+                let Ok(swap) = File::open(&path).map_err(|e| e.to_string() )
+                    .and_then(|f| serde_json::from_reader::<_, SwapFile>(f).map_err(|e| e.to_string() ) )
+                else {
+                    continue;
+                };
+                recoveries.push(PendingRecovery {
+                    source : swap.source,
+                    swap : path,
+                    dt : swap.dt
+                });
+            }
+        }
+        recoveries.sort_by_key(|r| r.dt );
+        send.send(done(recoveries)).unwrap_or_else(super::log_err);
+    });
+}
+
+// Reads the content of a recovery file, removing it (the content is written again
+// to a new recovery file by the next autosave, since the restored file has unsaved changes).
+pub(crate) fn take_recovery(swap : &Path) -> Result<String, String> {
+    let f = File::open(swap).map_err(|e| e.to_string() )?;
+    let swap_file : SwapFile = serde_json::from_reader(f).map_err(|e| e.to_string() )?;
+    if let Err(e) = fs::remove_file(swap) {
+        eprintln!("Could not remove recovery file {}: {}", swap.display(), e);
+    }
+    Ok(swap_file.content)
+}
+
+pub(crate) fn spawn_remove_recovery(swap : PathBuf) {
+    thread::spawn(move || {
+        if let Err(e) = fs::remove_file(&swap) {
+            eprintln!("Could not remove recovery file {}: {}", swap.display(), e);
+        }
+    });
+}
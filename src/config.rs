@@ -10,6 +10,7 @@ use std::cell::RefCell;
 use std::rc::Rc;
 use std::thread;
 use std::fs::File;
+use std::path::Path;
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct WindowState {
@@ -33,7 +34,7 @@ pub fn set_win_dims_on_close(win : &ApplicationWindow, state : &mut WindowState)
     state.height = win.allocation().height();
 }
 
-pub fn load_shared_serializable<T : DeserializeOwned>(path : &str) -> Option<Rc<RefCell<T>>> {
+pub fn load_shared_serializable<T : DeserializeOwned, P : AsRef<Path>>(path : P) -> Option<Rc<RefCell<T>>> {
     match File::open(path) {
         Ok(f) => {
             let state : Result<T, _> = serde_json::from_reader(f);
@@ -54,12 +55,12 @@ pub fn load_shared_serializable<T : DeserializeOwned>(path : &str) -> Option<Rc<
     }
 }
 
-pub fn save_shared_serializable<T : Serialize + Send + Clone + 'static>(
+pub fn save_shared_serializable<T : Serialize + Send + Clone + 'static, P : AsRef<Path>>(
     state : &Rc<RefCell<T>>,
-    path : &str
+    path : P
 ) -> thread::JoinHandle<bool> {
     let state = state.borrow().clone();
-    let path = path.to_string();
+    let path = path.as_ref().to_path_buf();
     thread::spawn(move|| {
         match File::create(&path) {
             Ok(f) => {
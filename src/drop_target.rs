@@ -0,0 +1,38 @@
+/*Copyright (c) 2022 Diego da Silva Lima. All rights reserved.
+
+This work is licensed under the terms of the MIT license.
+For a copy, see <https://opensource.org/licenses/MIT>.*/
+
+use gtk4::*;
+use gtk4::prelude::*;
+use super::{MultiArchiverImpl, MultiArchiverAction};
+
+/// Opens the files dropped at the widget (e.g. from a file manager). Each dropped file is
+/// sent as an OpenRequest, so it goes through the same checks as files opened from a dialog
+/// (allowed roots, size limits, etc.), and failures are reported with on_error. Files that
+/// have no local path are sent as an OpenUriRequest. The returned controller can be used to
+/// remove the target from the widget.
+pub fn connect_drop_target(archiver : &impl MultiArchiverImpl, widget : &Widget) -> DropTarget {
+    let target = DropTarget::new(gdk::FileList::static_type(), gdk::DragAction::COPY);
+    target.set_types(&[gdk::FileList::static_type(), gio::File::static_type()]);
+    let send = archiver.sender().clone();
+    target.connect_drop(move |_, value, _, _| {
+        let files = if let Ok(files) = value.get::<gdk::FileList>() {
+            files.files()
+        } else if let Ok(file) = value.get::<gio::File>() {
+            vec![file]
+        } else {
+            return false;
+        };
+        for file in &files {
+            let action = match file.path() {
+                Some(path) => MultiArchiverAction::OpenRequest(path),
+                None => MultiArchiverAction::OpenUriRequest(file.uri().to_string())
+            };
+            send.send(action).unwrap_or_else(super::log_err);
+        }
+        !files.is_empty()
+    });
+    widget.add_controller(target.clone());
+    target
+}
@@ -0,0 +1,178 @@
+/*Copyright (c) 2022 Diego da Silva Lima. All rights reserved.
+
+This work is licensed under the terms of the MIT license.  
+For a copy, see <https://opensource.org/licenses/MIT>.*/
+
+use std::fmt;
+use std::path::PathBuf;
+use super::io::InsufficientSpace;
+
+/// Errors reported by the archivers through their on_user_error callbacks (errors
+/// worth showing to the user) and on_internal_warning callbacks (inconsistent
+/// requests, usually a bug in the application, that are only worth logging). Matches
+/// outside of this crate need a catch-all arm (e.g. falling back to the Display message),
+/// since new errors are added with the features that report them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ArchiverError {
+
+    // A request that acts on the selected file arrived when no file
+    // was selected (e.g. a save accelerator pressed at the start page).
+    NoSelection,
+
+    NonAbsolutePath(PathBuf),
+
+    // Tried to save a file to a directory path.
+    IsDirectory(PathBuf),
+
+    // The URI given to OpenUriRequest has no local path (e.g. the location is not mounted).
+    NoLocalPath(String),
+
+    // The path is outside the roots set with SetAllowedRoots. Carries the roots.
+    OutsideRoots(Vec<PathBuf>),
+
+    // A relative path was requested, but no root is set (see SetAllowedRoots).
+    NoPrefix,
+
+    // Tried to save a file to a path that is already opened by another file.
+    AlreadyOpened(PathBuf),
+
+    // Tried to rename a file to a path that already exists.
+    AlreadyExists(PathBuf),
+
+    // Tried to save a read-only file at its own path (see OpenedFile::read_only).
+    ReadOnly(PathBuf),
+
+    // The file was closed before its save could be written (e.g. while a save validator
+    // was running). Carries the path the file would be saved to.
+    ClosedBeforeSave(PathBuf),
+
+    // The maximum number of opened files was reached.
+    FileLimit,
+
+    // The file is too large to be opened. Carries the file size and the
+    // size limit (in bytes).
+    TooLarge { size : u64, limit : u64 },
+
+    // The file is not a text file. Carries the detected MIME type.
+    NotText(String),
+
+    // The path is not a regular file (e.g. a FIFO, whose read would block until a writer
+    // closes it, or a device). Carries the path and the kind of file (see
+    // MultiArchiverImpl::set_special_file_filter).
+    SpecialFile(PathBuf, SpecialFileKind),
+
+    // The open was rejected by an open filter, or the save by a save validator.
+    // Carries the message returned by the filter or validator.
+    Rejected(String),
+
+    // The name given to an untitled file is empty, contains a path separator,
+    // does not have the archiver extension, or is used by another untitled file.
+    InvalidName(String),
+
+    // A save was not attempted because the filesystem does not have enough free space,
+    // which would leave a truncated file. Carries the size of the content and the free
+    // space (in bytes).
+    InsufficientSpace { required : u64, available : u64 },
+
+    // An error reported by the filesystem.
+    Io(String),
+
+    // The IO worker thread panicked. Carries the panic message.
+    WorkerPanic(String),
+
+    // An action referred to a file index (or file position) that does not exist.
+    // Reported as an internal warning.
+    InvalidIndex(usize),
+
+    // end_bulk was called without a matching begin_bulk. Reported as an internal warning.
+    UnmatchedEndBulk,
+
+    // A relative path was resolved against the given directory because no root
+    // was set (see SetInferRelative). Reported as an internal warning.
+    InferredPrefix(PathBuf),
+
+    // Any other internal failure. Reported as an internal warning.
+    Internal(String)
+
+}
+
+/// A kind of file that is not a regular file (see ArchiverError::SpecialFile).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpecialFileKind {
+
+    Fifo,
+
+    Socket,
+
+    CharDevice,
+
+    BlockDevice,
+
+    // Any other kind (e.g. the special files reported by the gio backend, which
+    // does not tell their kind).
+    Other
+
+}
+
+impl fmt::Display for SpecialFileKind {
+
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SpecialFileKind::Fifo => write!(f, "is a named pipe"),
+            SpecialFileKind::Socket => write!(f, "is a socket"),
+            SpecialFileKind::CharDevice => write!(f, "is a character device"),
+            SpecialFileKind::BlockDevice => write!(f, "is a block device"),
+            SpecialFileKind::Other => write!(f, "is not a regular file")
+        }
+    }
+
+}
+
+impl fmt::Display for ArchiverError {
+
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArchiverError::NoSelection => write!(f, "No file selected"),
+            ArchiverError::NonAbsolutePath(path) => write!(f, "Using non-absolute path: {}", path.display()),
+            ArchiverError::IsDirectory(path) => write!(f, "Tried to save file to directory path: {}", path.display()),
+            ArchiverError::NoLocalPath(uri) => write!(f, "Cannot open {}: location is not mounted", uri),
+            ArchiverError::OutsideRoots(roots) => {
+                let roots : Vec<_> = roots.iter().map(|root| root.display().to_string() ).collect();
+                write!(f, "Cannot use file outside {}", roots.join(", "))
+            },
+            ArchiverError::NoPrefix => write!(f, "No root directory set"),
+            ArchiverError::AlreadyOpened(path) => write!(f, "Cannot save file to a path that is already opened: {}", path.display()),
+            ArchiverError::AlreadyExists(path) => write!(f, "File already exists: {}", path.display()),
+            ArchiverError::ReadOnly(path) => write!(f, "Cannot save read-only file: {}", path.display()),
+            ArchiverError::ClosedBeforeSave(path) => write!(f, "File closed before it was saved: {}", path.display()),
+            ArchiverError::FileLimit => write!(f, "File list limit reached"),
+            ArchiverError::TooLarge { size, limit } => write!(f, "File size ({} bytes) extrapolates maximum size ({} bytes)", size, limit),
+            ArchiverError::NotText(mime) => write!(f, "Cannot open file of type {}", mime),
+            ArchiverError::SpecialFile(path, kind) => write!(f, "Cannot open {}: {}", path.display(), kind),
+            ArchiverError::Rejected(msg) => write!(f, "{}", msg),
+            ArchiverError::InvalidName(name) => write!(f, "Invalid file name: {}", name),
+            ArchiverError::InsufficientSpace { required, available } => write!(f, "{}", InsufficientSpace { required : *required, available : *available }),
+            ArchiverError::Io(msg) => write!(f, "{}", msg),
+            ArchiverError::WorkerPanic(msg) => write!(f, "File operation failed unexpectedly: {}", msg),
+            ArchiverError::InvalidIndex(ix) => write!(f, "Invalid file index: {}", ix),
+            ArchiverError::UnmatchedEndBulk => write!(f, "end_bulk called without begin_bulk"),
+            ArchiverError::InferredPrefix(dir) => write!(f, "Relative path resolved against {}", dir.display()),
+            ArchiverError::Internal(msg) => write!(f, "{}", msg)
+        }
+    }
+
+}
+
+impl std::error::Error for ArchiverError { }
+
+impl From<std::io::Error> for ArchiverError {
+
+    fn from(e : std::io::Error) -> Self {
+        match e.get_ref().and_then(|inner| inner.downcast_ref::<InsufficientSpace>() ) {
+            Some(space) => ArchiverError::InsufficientSpace { required : space.required, available : space.available },
+            None => ArchiverError::Io(format!("{}", e))
+        }
+    }
+
+}
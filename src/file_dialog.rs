@@ -0,0 +1,173 @@
+/*Copyright (c) 2022 Diego da Silva Lima. All rights reserved.
+
+This work is licensed under the terms of the MIT license.
+For a copy, see <https://opensource.org/licenses/MIT>.*/
+
+use std::path::PathBuf;
+use gtk4::*;
+use gtk4::prelude::*;
+use super::{MultiArchiverImpl, MultiArchiverAction, SingleArchiverImpl, SingleArchiverAction};
+
+/*
+Helpers around gtk::FileDialog (GTK 4.10), which replaces the deprecated gtk::FileChooserDialog
+used by OpenDialog and SaveDialog. The choose_ functions show a dialog and return the chosen
+paths, returning None (or nothing) when the dialog is dismissed. Other failures (e.g. a file
+chosen at a location without a local path) are logged and handled like a dismissal.
+*/
+
+/// Builds a modal dialog showing only the files matching the patterns (e.g. "*.sql").
+/// With no patterns, all files are shown.
+pub fn build_file_dialog(title : &str, patterns : &[&str]) -> FileDialog {
+    let builder = FileDialog::builder()
+        .title(title)
+        .modal(true);
+    if patterns.is_empty() {
+        return builder.build();
+    }
+    let filter = FileFilter::new();
+    for pattern in patterns {
+        filter.add_pattern(pattern);
+    }
+    let filters = gio::ListStore::new::<FileFilter>();
+    filters.append(&filter);
+    builder
+        .filters(&filters)
+        .default_filter(&filter)
+        .build()
+}
+
+pub async fn choose_open(dialog : &FileDialog, parent : Option<&impl IsA<Window>>) -> Option<PathBuf> {
+    chosen_path(dialog.open_future(parent).await)
+}
+
+/// The chosen files that have a local path.
+pub async fn choose_open_multiple(dialog : &FileDialog, parent : Option<&impl IsA<Window>>) -> Vec<PathBuf> {
+    let files = match dialog.open_multiple_future(parent).await {
+        Ok(files) => files,
+        Err(e) => {
+            log_dialog_error(&e);
+            return Vec::new();
+        }
+    };
+    (0..files.n_items())
+        .filter_map(|i| files.item(i) )
+        .filter_map(|item| item.downcast::<gio::File>().ok() )
+        .filter_map(|file| file.path() )
+        .collect()
+}
+
+/// Shows a save dialog suggesting the given name (e.g. the name passed to
+/// on_save_unknown_path).
+pub async fn choose_save(dialog : &FileDialog, parent : Option<&impl IsA<Window>>, name : Option<&str>) -> Option<PathBuf> {
+    dialog.set_initial_name(name);
+    chosen_path(dialog.save_future(parent).await)
+}
+
+pub async fn choose_folder(dialog : &FileDialog, parent : Option<&impl IsA<Window>>) -> Option<PathBuf> {
+    chosen_path(dialog.select_folder_future(parent).await)
+}
+
+fn chosen_path(res : Result<gio::File, glib::Error>) -> Option<PathBuf> {
+    match res {
+        Ok(file) => {
+            let path = file.path();
+            if path.is_none() {
+                eprintln!("Chosen file has no local path: {}", file.uri());
+            }
+            path
+        },
+        Err(e) => {
+            log_dialog_error(&e);
+            None
+        }
+    }
+}
+
+fn log_dialog_error(e : &glib::Error) {
+    if !e.matches(DialogError::Dismissed) && !e.matches(DialogError::Cancelled) {
+        eprintln!("File dialog failed: {}", e);
+    }
+}
+
+/// Shows the open dialog, opening each chosen file at the archiver (with OpenRequest).
+/// Meant to be called by the open action of the application.
+pub fn open_with_file_dialog<A : MultiArchiverImpl>(archiver : &A, dialog : &FileDialog, parent : &impl IsA<Window>) {
+    let send = archiver.sender().clone();
+    let dialog = dialog.clone();
+    let parent = parent.clone().upcast::<Window>();
+    glib::MainContext::default().spawn_local(async move {
+        for path in choose_open_multiple(&dialog, Some(&parent)).await {
+            send.send(MultiArchiverAction::OpenRequest(path))
+                .unwrap_or_else(super::log_err);
+        }
+    });
+}
+
+/// Shows the folder dialog, setting the chosen folder as the workspace root of the
+/// archiver (see MultiArchiverAction::SetWorkspaceRoot).
+pub fn select_workspace_with_file_dialog<A : MultiArchiverImpl>(archiver : &A, dialog : &FileDialog, parent : &impl IsA<Window>) {
+    let send = archiver.sender().clone();
+    let dialog = dialog.clone();
+    let parent = parent.clone().upcast::<Window>();
+    glib::MainContext::default().spawn_local(async move {
+        if let Some(path) = choose_folder(&dialog, Some(&parent)).await {
+            send.send(MultiArchiverAction::SetWorkspaceRoot(Some(path)))
+                .unwrap_or_else(super::log_err);
+        }
+    });
+}
+
+/// Shows the save dialog every time the archiver needs a path to save a file (see
+/// connect_save_unknown_path), saving the selected file at the chosen path.
+pub fn connect_multi_with_file_dialog<A : MultiArchiverImpl>(archiver : &A, save_dialog : &FileDialog, parent : &impl IsA<Window>) {
+    let send = archiver.sender().clone();
+    let dialog = save_dialog.clone();
+    let parent = parent.clone().upcast::<Window>();
+    archiver.connect_save_unknown_path(move |name| {
+        let (send, dialog, parent) = (send.clone(), dialog.clone(), parent.clone());
+        glib::MainContext::default().spawn_local(async move {
+            if let Some(path) = choose_save(&dialog, Some(&parent), Some(&name)).await {
+                send.send(MultiArchiverAction::SaveRequest { path : Some(path), overwrite : false })
+                    .unwrap_or_else(super::log_err);
+            }
+        });
+    });
+}
+
+/// Shows the open dialog when the archiver requests it (see connect_show_open), and the
+/// save dialog when it needs a path to save the document (see connect_save_unknown_path).
+pub fn connect_single_with_file_dialog<A : SingleArchiverImpl>(
+    archiver : &A,
+    open_dialog : &FileDialog,
+    save_dialog : &FileDialog,
+    parent : &impl IsA<Window>
+) {
+    let parent = parent.clone().upcast::<Window>();
+    archiver.connect_show_open({
+        let send = archiver.sender().clone();
+        let dialog = open_dialog.clone();
+        let parent = parent.clone();
+        move |_| {
+            let (send, dialog, parent) = (send.clone(), dialog.clone(), parent.clone());
+            glib::MainContext::default().spawn_local(async move {
+                if let Some(path) = choose_open(&dialog, Some(&parent)).await {
+                    send.send(SingleArchiverAction::OpenRequest(path))
+                        .unwrap_or_else(super::log_err);
+                }
+            });
+        }
+    });
+    archiver.connect_save_unknown_path({
+        let send = archiver.sender().clone();
+        let dialog = save_dialog.clone();
+        move |name| {
+            let (send, dialog, parent) = (send.clone(), dialog.clone(), parent.clone());
+            glib::MainContext::default().spawn_local(async move {
+                if let Some(path) = choose_save(&dialog, Some(&parent), Some(&name)).await {
+                    send.send(SingleArchiverAction::SaveRequest(Some(path)))
+                        .unwrap_or_else(super::log_err);
+                }
+            });
+        }
+    });
+}
@@ -0,0 +1,1100 @@
+/*Copyright (c) 2022 Diego da Silva Lima. All rights reserved.
+
+This work is licensed under the terms of the MIT license.  
+For a copy, see <https://opensource.org/licenses/MIT>.*/
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::panic::{self, AssertUnwindSafe};
+use std::any::Any;
+use std::borrow::Cow;
+use std::ops::Range;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU8, AtomicU64, Ordering};
+use std::task::{Poll, Waker};
+use gtk4::{gio, glib};
+use gtk4::prelude::*;
+use super::pool::{IoJob, submit_io};
+use std::time::{Duration, SystemTime};
+use std::collections::HashMap;
+use unicode_normalization::UnicodeNormalization;
+use encoding_rs::{Encoding, UTF_8, UTF_16LE, UTF_16BE, WINDOWS_1252};
+
+// Maximum number of links followed when resolving a symlink chain.
+const MAX_SYMLINK_DEPTH : usize = 32;
+
+// Number of bytes read from the start of a file to guess its content type.
+const SNIFF_LEN : usize = 4096;
+
+const UTF8_BOM : char = '\u{FEFF}';
+
+// Number of bytes read or written between progress reports. Operations on fewer
+// bytes are not reported.
+const PROGRESS_CHUNK : u64 = 1 << 20;
+
+// Attributes queried by the gio backend. The nanoseconds of the modification time are only
+// given by GLib 2.74 and later (the microseconds are used otherwise). They are needed so that
+// the modification time matches the one found by std::fs at the external change checks.
+const GIO_ATTRIBUTES : &str = "standard::type,standard::size,time::modified,time::modified-usec,time::modified-nsec,access::can-write";
+
+// Whether the user can write a file (see is_read_only).
+const WRITE_ATTRIBUTE : &str = "access::can-write";
+
+// Free bytes of a filesystem, checked before saves (see check_space).
+const FREE_ATTRIBUTE : &str = "filesystem::free";
+
+// Whether a filesystem is a network one (see is_offline_error).
+const REMOTE_ATTRIBUTE : &str = "filesystem::remote";
+
+// Called from the worker thread with the number of bytes done and the total number
+// of bytes of an operation.
+pub(crate) type Progress<'a> = &'a dyn Fn(u64, u64);
+
+// Reports the progress of a read every PROGRESS_CHUNK bytes.
+struct ProgressReader<'a, R> {
+    inner : R,
+    done : u64,
+    total : u64,
+    progress : Progress<'a>
+}
+
+impl<'a, R : Read> Read for ProgressReader<'a, R> {
+
+    fn read(&mut self, buf : &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        let before = self.done;
+        self.done += n as u64;
+        if self.total > PROGRESS_CHUNK && (self.done / PROGRESS_CHUNK > before / PROGRESS_CHUNK || (n > 0 && self.done >= self.total)) {
+            (self.progress)(self.done.min(self.total), self.total);
+        }
+        Ok(n)
+    }
+
+}
+
+// Writes the data in chunks, reporting the progress after each chunk.
+fn write_with_progress<W : Write>(w : &mut W, data : &[u8], progress : Progress) -> io::Result<()> {
+    let total = data.len() as u64;
+    if total <= PROGRESS_CHUNK {
+        return w.write_all(data);
+    }
+    let mut done = 0;
+    for chunk in data.chunks(PROGRESS_CHUNK as usize) {
+        w.write_all(chunk)?;
+        done += chunk.len() as u64;
+        progress(done, total);
+    }
+    Ok(())
+}
+
+/// What happens when the saved path is a symbolic link. Files opened through a link
+/// are kept at the path of its target, so this applies to the paths given to saves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SymlinkPolicy {
+
+    // Writes the content to the file the link points to, keeping the link.
+    #[default]
+    WriteThrough,
+
+    // Removes the link, and writes a regular file in its place.
+    Replace
+
+}
+
+/// How much the save worker waits for the content to reach the disk before
+/// reporting success.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+
+    // Leaves flushing to the operating system (fastest).
+    #[default]
+    None,
+
+    // Calls fsync on the saved file.
+    File,
+
+    // Calls fsync on the saved file and its parent directory, so that the
+    // directory entry of a newly-created file also survives a crash.
+    FileAndDir
+
+}
+
+/// Whether the save worker writes a UTF-8 byte order mark before the content.
+/// The mark is always stripped at open, so it never reaches the buffer. UTF-16
+/// files are always written with their mark, which is how they are recognized
+/// at open.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BomPolicy {
+
+    // Writes the mark only if the file had it when it was opened.
+    #[default]
+    Preserve,
+
+    // Never writes the mark to UTF-8 files.
+    Strip,
+
+    // Always writes the mark.
+    Always
+
+}
+
+/// What the save worker does with the newlines at the end of the content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NewlinePolicy {
+
+    // Saves the content as it is.
+    #[default]
+    Keep,
+
+    // Ends a non-empty file with exactly one newline.
+    EnsureSingle,
+
+    // Removes all trailing newlines.
+    Strip
+
+}
+
+/// Whether the content and the file name are normalized before saving (e.g. files
+/// edited on macOS often arrive in the decomposed form, NFD).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NormalizationPolicy {
+
+    // Saves the content and the file name as they are.
+    #[default]
+    Keep,
+
+    // Converts the content and the file name of new paths (at "Save as") to the
+    // composed form (NFC). The names of existing files are not changed.
+    Nfc
+
+}
+
+/// Where the archivers read and write the files they open and save.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IoBackend {
+
+    // std::fs calls at the IO pool threads.
+    #[default]
+    Threads,
+
+    // GIO calls (File::load_contents and File::replace_contents) at the main context, which also
+    // reach the locations std::fs cannot see, like GVfs mounts and the files exported by the flatpak
+    // document portal. Opens and saves are not reported through on_io_progress, and files over the
+    // maximum file size are rejected even if a streaming preview is set. Durability and
+    // SaveOptions::new_file_mode are left to GIO, which writes to a temporary file and renames it
+    // over the original. The content is still decoded and encoded at the IO pool. Peeks, range
+    // reads, renames and recovery also run there.
+    Gio
+
+}
+
+/// Whether the save worker copies the existing file before overwriting it.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum BackupPolicy {
+
+    #[default]
+    None,
+
+    // Copies the file to the same path followed by a tilde (e.g. document.tex~).
+    Tilde,
+
+    // Copies the file to the given directory (e.g. a backup directory under the datadir).
+    // The backup name is prefixed by a hash of the original path (see path_hash), so that
+    // files with the same name at different directories do not overwrite each other's backup.
+    Directory(PathBuf)
+
+}
+
+/// Options applied to saves by both archivers. The options are copied to the
+/// worker thread when the save is spawned, so changing them does not affect
+/// saves already in flight.
+#[derive(Debug, Clone, Default)]
+pub struct SaveOptions {
+
+    pub symlink : SymlinkPolicy,
+
+    pub durability : Durability,
+
+    pub bom : BomPolicy,
+
+    pub newline : NewlinePolicy,
+
+    pub backup : BackupPolicy,
+
+    pub normalization : NormalizationPolicy,
+
+    // If set, files created by a save get these permission bits (e.g. 0o600 for
+    // private notes) instead of the default permissions masked by the umask. Files
+    // that already exist keep their permissions. Ignored on non-unix platforms.
+    pub new_file_mode : Option<u32>,
+
+    // If set, the archiver reports the save as stalled (on_io_stalled) when
+    // the worker does not finish within this interval.
+    pub timeout : Option<Duration>
+
+}
+
+// How the text of a file is stored on disk, found when the file is read and
+// reproduced when it is written.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct TextFormat {
+
+    // Whether the file starts with a byte order mark.
+    pub(crate) bom : bool,
+
+    // Name of the encoding of files that are not UTF-8 (e.g. windows-1252 or UTF-16LE).
+    pub(crate) encoding : Option<String>
+
+}
+
+const TASK_RUNNING : u8 = 0;
+
+const TASK_CANCELLED : u8 = 1;
+
+const TASK_COMMITTED : u8 = 2;
+
+// Cancellation flag of an IO operation, shared between the main thread and its worker.
+// The worker commits before it has any effect (reporting the file it read, or writing
+// to disk), so an operation is either cancelled without effect or completes as usual.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CancelToken(Arc<AtomicU8>);
+
+impl CancelToken {
+
+    // Called by the main thread. Returns false if the worker committed already.
+    pub(crate) fn cancel(&self) -> bool {
+        self.0.compare_exchange(TASK_RUNNING, TASK_CANCELLED, Ordering::SeqCst, Ordering::SeqCst).is_ok()
+    }
+
+    // Called by the worker. Returns false if the operation was cancelled.
+    pub(crate) fn commit(&self) -> bool {
+        self.0.compare_exchange(TASK_RUNNING, TASK_COMMITTED, Ordering::SeqCst, Ordering::SeqCst).is_ok()
+    }
+
+}
+
+// Tracks the saves of an archiver. The IO pool runs each of them after the previous
+// operations of the same path, unless it is reported as stalled (e.g. a save to a
+// hung network mount), in which case it is detached so that the next operations on
+// the path are not queued behind it. The main thread never waits for a save.
+#[derive(Default)]
+pub(crate) struct IoTask {
+
+    // Saves not known to be finished, by generation. A save of another path started
+    // meanwhile does not keep a stalled one from being detached.
+    jobs : HashMap<u64, IoJob>,
+
+    generation : u64
+}
+
+impl IoTask {
+
+    // Holds the handle of a newly-submitted operation, returning the generation
+    // that identifies it.
+    pub(crate) fn start(&mut self, job : IoJob) -> u64 {
+        self.jobs.retain(|_, job| !job.is_finished() );
+        self.generation += 1;
+        self.jobs.insert(self.generation, job);
+        self.generation
+    }
+
+    // Called when the timeout of the operation with the given generation elapses.
+    // Returns true if the operation is still running, detaching it.
+    pub(crate) fn check_stalled(&mut self, generation : u64) -> bool {
+        match self.jobs.remove(&generation) {
+            Some(job) if !job.is_finished() => {
+                job.detach();
+                true
+            },
+            _ => false
+        }
+    }
+
+}
+
+/// Wraps the body of an IO worker. If the worker panics, the panic message is passed
+/// to on_panic (which should send an error action back to the archiver), so that the
+/// main thread is notified right away and the thread running it survives.
+pub(crate) fn worker<P, F>(on_panic : P, f : F) -> impl FnOnce() -> bool + Send + 'static
+where
+    P : FnOnce(String) + Send + 'static,
+    F : FnOnce() -> bool + Send + 'static
+{
+    move || {
+        match panic::catch_unwind(AssertUnwindSafe(f)) {
+            Ok(res) => res,
+            Err(payload) => {
+                on_panic(panic_message(payload));
+                false
+            }
+        }
+    }
+}
+
+/// Runs an IO worker at the IO pool, after the operations previously submitted for the
+/// same path (see worker).
+pub(crate) fn spawn_worker<P, F>(path : &Path, on_panic : P, f : F) -> IoJob
+where
+    P : FnOnce(String) + Send + 'static,
+    F : FnOnce() -> bool + Send + 'static
+{
+    submit_io(path, worker(on_panic, f))
+}
+
+fn panic_message(payload : Box<dyn Any + Send>) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        String::from("Unknown panic")
+    }
+}
+
+// Resolves the final target of a symlink chain. Relative link targets are
+// interpreted relative to the directory containing the link. Dangling links
+// resolve to the path they point to (which will be created at save).
+fn resolve_symlink(path : &Path) -> PathBuf {
+    let mut path = path.to_path_buf();
+    for _ in 0..MAX_SYMLINK_DEPTH {
+        match fs::read_link(&path) {
+            Ok(target) => {
+                path = if target.is_absolute() {
+                    target
+                } else {
+                    path.parent().map(|p| p.join(&target) ).unwrap_or(target)
+                };
+            },
+            Err(_) => break
+        }
+    }
+    path
+}
+
+fn is_symlink(path : &Path) -> bool {
+    fs::symlink_metadata(path).map(|m| m.file_type().is_symlink() ).unwrap_or(false)
+}
+
+/// Whether the path is handled as a gzip-compressed file, decompressed at open
+/// and recompressed at save. Always false if the gzip feature is disabled.
+pub fn is_gzip_path<P : AsRef<Path>>(path : P) -> bool {
+    cfg!(feature = "gzip") && path.as_ref().extension().is_some_and(|ext| ext == "gz" )
+}
+
+#[cfg(feature = "gzip")]
+fn gzip_decoder<'a, R : Read + 'a>(f : R) -> Box<dyn Read + 'a> {
+    Box::new(flate2::read::GzDecoder::new(f))
+}
+
+#[cfg(not(feature = "gzip"))]
+fn gzip_decoder<'a, R : Read + 'a>(f : R) -> Box<dyn Read + 'a> {
+    Box::new(f)
+}
+
+// Reads the file, decompressing it if it is a gzip file.
+fn reader<'a, R : Read + 'a>(f : R, path : &Path) -> Box<dyn Read + 'a> {
+    if is_gzip_path(path) {
+        gzip_decoder(f)
+    } else {
+        Box::new(f)
+    }
+}
+
+#[cfg(feature = "gzip")]
+fn compress(data : &[u8]) -> io::Result<Vec<u8>> {
+    let mut enc = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    enc.write_all(data)?;
+    enc.finish()
+}
+
+#[cfg(not(feature = "gzip"))]
+fn compress(data : &[u8]) -> io::Result<Vec<u8>> {
+    Ok(data.to_vec())
+}
+
+#[cfg(feature = "gzip")]
+fn write_gzip(f : File, content : &[u8], progress : Progress) -> io::Result<File> {
+    let mut enc = flate2::write::GzEncoder::new(f, flate2::Compression::default());
+    write_with_progress(&mut enc, content, progress)?;
+    enc.finish()
+}
+
+#[cfg(not(feature = "gzip"))]
+fn write_gzip(mut f : File, content : &[u8], progress : Progress) -> io::Result<File> {
+    write_with_progress(&mut f, content, progress)?;
+    Ok(f)
+}
+
+/// Reads the whole file into content, decompressing it if it is a gzip file and
+/// converting it to UTF-8. The encoding is given by the byte order mark (UTF-8 or
+/// UTF-16), if any. Otherwise, content that is not valid UTF-8 is read as windows-1252
+/// (a superset of Latin-1). The byte order mark is stripped from the content. Returns
+/// the format to write the content back with. The progress is given in bytes of the
+/// file as stored (i.e. compressed). Must be called from the worker thread.
+pub(crate) fn read_content(f : &mut File, path : &Path, content : &mut String, progress : Progress) -> io::Result<TextFormat> {
+    let total = f.metadata()?.len();
+    let mut data = Vec::new();
+    reader(ProgressReader { inner : f, done : 0, total, progress }, path).read_to_end(&mut data)?;
+    decode_content(&data, content)
+}
+
+// Converts the (decompressed) data of a file to UTF-8 (see read_content).
+fn decode_content(data : &[u8], content : &mut String) -> io::Result<TextFormat> {
+    let (encoding, bom_len) = match Encoding::for_bom(data) {
+        Some(found) => found,
+        None if std::str::from_utf8(data).is_ok() => (UTF_8, 0),
+        None => (WINDOWS_1252, 0)
+    };
+    let (decoded, had_errors) = encoding.decode_without_bom_handling(&data[bom_len..]);
+    if had_errors {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("File is not valid {}", encoding.name())));
+    }
+    content.push_str(&decoded);
+    Ok(TextFormat {
+        bom : bom_len > 0,
+        encoding : (encoding != UTF_8).then(|| encoding.name().to_string() )
+    })
+}
+
+/// Reads at most limit bytes from the start of the file (decompressing it if it is a
+/// gzip file), without the byte order mark. A multi-byte character cut by the limit
+/// is dropped. Must be called from the worker thread.
+pub(crate) fn read_prefix(path : &Path, limit : usize) -> io::Result<String> {
+    let mut f = File::open(path)?;
+    let mut data = Vec::with_capacity(limit.min(SNIFF_LEN));
+    reader(&mut f, path).take(limit as u64).read_to_end(&mut data)?;
+    let mut content = cut_utf8(data)?;
+    if content.starts_with(UTF8_BOM) {
+        content.drain(..UTF8_BOM.len_utf8());
+    }
+    Ok(content)
+}
+
+/// Reads the bytes of the file within the range (decompressing it if it is a gzip file).
+/// A multi-byte character that starts before the range or ends after it is dropped.
+/// Returns the range of the bytes actually read and their content. Must be called from
+/// the worker thread.
+pub(crate) fn read_range(path : &Path, range : Range<u64>) -> io::Result<(Range<u64>, String)> {
+    let mut f = File::open(path)?;
+    let len = range.end.saturating_sub(range.start);
+    let mut data = Vec::new();
+    if is_gzip_path(path) {
+        let mut r = reader(&mut f, path);
+        io::copy(&mut (&mut r).take(range.start), &mut io::sink())?;
+        r.take(len).read_to_end(&mut data)?;
+    } else {
+        f.seek(SeekFrom::Start(range.start))?;
+        f.take(len).read_to_end(&mut data)?;
+    }
+
+    // UTF-8 continuation bytes have the form 10xxxxxx.
+    let skip = data.iter().take(3).take_while(|b| (**b & 0xC0) == 0x80 ).count();
+    data.drain(..skip);
+    let content = cut_utf8(data)?;
+    let start = range.start + skip as u64;
+    Ok((start..start + content.len() as u64, content))
+}
+
+// Drops a multi-byte character cut at the end of the data.
+fn cut_utf8(data : Vec<u8>) -> io::Result<String> {
+    match String::from_utf8(data) {
+        Ok(content) => Ok(content),
+        Err(e) if e.utf8_error().error_len().is_none() => {
+            let valid = e.utf8_error().valid_up_to();
+            let mut data = e.into_bytes();
+            data.truncate(valid);
+            String::from_utf8(data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e) )
+        },
+        Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// Guesses the MIME type of the file from its name and its first bytes, returning
+/// the MIME type and whether the content can be treated as text. Only content that
+/// is not a text type and has NUL bytes is considered non-text, so that unknown
+/// text formats are still accepted. Must be called from the worker thread.
+pub(crate) fn sniff_mime(path : &Path) -> io::Result<(String, bool)> {
+    let mut f = File::open(path)?;
+    let mut data = Vec::with_capacity(SNIFF_LEN);
+    reader(&mut f, path).take(SNIFF_LEN as u64).read_to_end(&mut data)?;
+    Ok(guess_mime(path, &data))
+}
+
+// Guesses the MIME type from the first (decompressed) bytes of the file (see sniff_mime).
+fn guess_mime(path : &Path, data : &[u8]) -> (String, bool) {
+    let data = &data[..data.len().min(SNIFF_LEN)];
+    let name = if is_gzip_path(path) { path.with_extension("") } else { path.to_path_buf() };
+    let (content_type, _uncertain) = gio::content_type_guess(Some(name), data);
+    let mime = gio::content_type_get_mime_type(&content_type)
+        .map(|m| m.to_string() )
+        .unwrap_or(content_type.to_string());
+    let is_text = gio::content_type_is_a(&content_type, "text/plain") ||
+        !data.contains(&0) ||
+        Encoding::for_bom(data).is_some();
+    (mime, is_text)
+}
+
+/// Writes the content to the path, following the save options. format tells how the
+/// file was stored when it was opened (the content is converted back to its encoding).
+/// The progress is given in bytes of the encoded content (before compression). Must be
+/// called from the worker thread.
+// Returns the path of the backup, if one was created (see BackupPolicy).
+pub(crate) fn write_content(
+    path : &Path,
+    content : &str,
+    format : &TextFormat,
+    opts : &SaveOptions,
+    progress : Progress
+) -> io::Result<Option<PathBuf>> {
+    let data = encode_for_save(content, format, opts)?;
+    let compress = is_gzip_path(path);
+    let mut path = path.to_path_buf();
+    let mut replace_link = false;
+    if is_symlink(&path) {
+        match opts.symlink {
+            SymlinkPolicy::WriteThrough => {
+                path = resolve_symlink(&path);
+            },
+            SymlinkPolicy::Replace => {
+                replace_link = true;
+            }
+        }
+    }
+
+    // The file is not overwritten if the backup fails. The backup of a link that is
+    // replaced holds the content of its target.
+    let backup = create_backup(&path, &opts.backup)?;
+
+    // The size of compressed content is only known once it is written. The existing file
+    // is truncated before the write, so its space is available to the new content (unless
+    // it is the target of a link that is replaced, which is left untouched).
+    if !compress {
+        let existing = fs::metadata(&path).ok()
+            .filter(|m| m.is_file() && !replace_link )
+            .map(|m| m.len() )
+            .unwrap_or(0);
+        check_space(&path, data.len() as u64, existing)?;
+    }
+
+    // Only removed once the checks passed, so a failed save keeps the link.
+    if replace_link {
+        fs::remove_file(&path)?;
+    }
+    let mut f = create_file(&path, opts.new_file_mode)?;
+    if compress {
+        f = write_gzip(f, &data, progress)?;
+    } else {
+        write_with_progress(&mut f, &data, progress)?;
+    }
+    match opts.durability {
+        Durability::None => { },
+        Durability::File => {
+            f.sync_all()?;
+        },
+        Durability::FileAndDir => {
+            f.sync_all()?;
+            sync_parent_dir(&path)?;
+        }
+    }
+    Ok(backup)
+}
+
+// Applies the newline, normalization and byte order mark policies, and converts the
+// content back to the encoding of the file.
+fn encode_for_save<'a>(content : &'a str, format : &TextFormat, opts : &SaveOptions) -> io::Result<Cow<'a, [u8]>> {
+    let encoding = match format.encoding.as_deref() {
+        Some(label) => Encoding::for_label(label.as_bytes())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown encoding: {}", label)) )?,
+        None => UTF_8
+    };
+
+    // Only the Unicode encodings have a byte order mark. UTF-16 is only recognized
+    // by its mark at open (see read_content), so it always keeps it.
+    let with_bom = if encoding == UTF_16LE || encoding == UTF_16BE {
+        true
+    } else {
+        encoding == UTF_8 && match opts.bom {
+            BomPolicy::Preserve => format.bom,
+            BomPolicy::Strip => false,
+            BomPolicy::Always => true
+        }
+    };
+    let content = apply_newline_policy(content, opts.newline);
+    let content = if needs_normalization(&content, opts.normalization) {
+        Cow::Owned(content.nfc().collect())
+    } else {
+        content
+    };
+    let content = if with_bom && !content.starts_with(UTF8_BOM) {
+        Cow::Owned(format!("{}{}", UTF8_BOM, content))
+    } else {
+        content
+    };
+    match content {
+        Cow::Borrowed(content) => encode_content(content, encoding),
+        Cow::Owned(content) => Ok(Cow::Owned(encode_content(&content, encoding)?.into_owned()))
+    }
+}
+
+fn create_backup(path : &Path, policy : &BackupPolicy) -> io::Result<Option<PathBuf>> {
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let Some(backup_path) = backup_path(path, policy)? else {
+        return Ok(None);
+    };
+    fs::copy(path, &backup_path)?;
+    Ok(Some(backup_path))
+}
+
+// Path of the backup of the file, if the policy makes one. The backup directory is
+// created if it does not exist.
+fn backup_path(path : &Path, policy : &BackupPolicy) -> io::Result<Option<PathBuf>> {
+    let backup_path = match policy {
+        BackupPolicy::None => return Ok(None),
+        BackupPolicy::Tilde => {
+            let mut backup_path = path.as_os_str().to_owned();
+            backup_path.push("~");
+            PathBuf::from(backup_path)
+        },
+        BackupPolicy::Directory(dir) => {
+            let name = path.file_name()
+                .map(|n| n.to_string_lossy().to_string() )
+                .unwrap_or_default();
+            fs::create_dir_all(dir)?;
+            Path::new(dir).join(format!("{:016x}-{}~", path_hash(path), name))
+        }
+    };
+    Ok(Some(backup_path))
+}
+
+// 64-bit FNV-1a hash of the path. Unlike DefaultHasher, its output does not change
+// across Rust versions, so the backup of a file keeps its name after a toolchain upgrade.
+pub(crate) fn path_hash(path : &Path) -> u64 {
+    const OFFSET_BASIS : u64 = 0xcbf29ce484222325;
+    const PRIME : u64 = 0x100000001b3;
+    path.to_string_lossy().bytes().fold(OFFSET_BASIS, |hash, b| (hash ^ u64::from(b)).wrapping_mul(PRIME) )
+}
+
+/// Writes a file with write into a temporary file next to the path, which then replaces
+/// the file at the path, so that a crash during the write does not corrupt it. Each call
+/// uses a temporary file of its own (named after the process and the call), so writes of
+/// the same path by other threads or processes do not share it. The parent directory is
+/// created if it does not exist. Must be called from the worker thread.
+pub(crate) fn write_replacing<F>(path : &Path, write : F) -> io::Result<()>
+where
+    F : FnOnce(&mut File) -> io::Result<()>
+{
+    static NEXT_TMP : AtomicU64 = AtomicU64::new(0);
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(format!(".{}-{}.tmp", std::process::id(), NEXT_TMP.fetch_add(1, Ordering::Relaxed)));
+    let tmp_path = PathBuf::from(tmp_path);
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty() ) {
+        fs::create_dir_all(parent)?;
+    }
+    let res = File::create(&tmp_path)
+        .and_then(|mut f| write(&mut f) )
+        .and_then(|_| fs::rename(&tmp_path, path) );
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    res
+}
+
+// Characters the encoding cannot represent are an error (instead of being replaced), so
+// that the save does not lose content.
+fn encode_content<'a>(content : &'a str, encoding : &'static Encoding) -> io::Result<Cow<'a, [u8]>> {
+
+    // encoding_rs does not encode into UTF-16 (it outputs UTF-8 instead).
+    if encoding == UTF_16LE || encoding == UTF_16BE {
+        let data = content.encode_utf16()
+            .flat_map(|unit| if encoding == UTF_16LE { unit.to_le_bytes() } else { unit.to_be_bytes() } )
+            .collect();
+        return Ok(Cow::Owned(data));
+    }
+    let (data, _, had_errors) = encoding.encode(content);
+    if had_errors {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Content cannot be saved as {}", encoding.name())));
+    }
+    Ok(data)
+}
+
+// The single newline keeps the line ending (\n or \r\n) of the last line.
+fn apply_newline_policy(content : &str, policy : NewlinePolicy) -> Cow<'_, str> {
+    let trimmed = content.trim_end_matches(['\n', '\r']);
+    match policy {
+        NewlinePolicy::Keep => Cow::Borrowed(content),
+        NewlinePolicy::Strip => Cow::Borrowed(trimmed),
+        NewlinePolicy::EnsureSingle => {
+            if trimmed.is_empty() {
+                return Cow::Borrowed(trimmed);
+            }
+            let newline = if content[trimmed.len()..].starts_with("\r\n") { "\r\n" } else { "\n" };
+            if content.len() == trimmed.len() + newline.len() && content.ends_with(newline) {
+                Cow::Borrowed(content)
+            } else {
+                Cow::Owned(format!("{}{}", trimmed, newline))
+            }
+        }
+    }
+}
+
+fn needs_normalization(s : &str, policy : NormalizationPolicy) -> bool {
+    match policy {
+        NormalizationPolicy::Keep => false,
+        NormalizationPolicy::Nfc => !unicode_normalization::is_nfc(s)
+    }
+}
+
+// Normalizes the file name of a path requested at "Save as". The parent directories
+// are kept, since they already exist with their own names.
+pub(crate) fn normalize_file_name(path : PathBuf, policy : NormalizationPolicy) -> PathBuf {
+    match path.file_name().and_then(|name| name.to_str() ) {
+        Some(name) if needs_normalization(name, policy) => {
+            let name : String = name.nfc().collect();
+            path.with_file_name(name)
+        },
+        _ => path
+    }
+}
+
+// Directories can only be opened (and synced) as files on unix.
+#[cfg(unix)]
+fn sync_parent_dir(path : &Path) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn sync_parent_dir(_path : &Path) -> io::Result<()> {
+    Ok(())
+}
+
+// The mode is also passed at creation (where the umask can only remove bits), so that
+// the file is never more permissive than requested before the mode is set.
+#[cfg(unix)]
+fn create_file(path : &Path, mode : Option<u32>) -> io::Result<File> {
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+    let Some(mode) = mode else {
+        return File::create(path);
+    };
+    let existed = path.exists();
+    let f = fs::OpenOptions::new().write(true).create(true).truncate(true).mode(mode).open(path)?;
+    if !existed {
+        f.set_permissions(fs::Permissions::from_mode(mode))?;
+    }
+    Ok(f)
+}
+
+#[cfg(not(unix))]
+fn create_file(path : &Path, _mode : Option<u32>) -> io::Result<File> {
+    File::create(path)
+}
+
+// Metadata of a file, as queried by the gio backend.
+#[derive(Debug, Clone)]
+pub(crate) struct GioMetadata {
+    pub(crate) is_dir : bool,
+    pub(crate) is_file : bool,
+    pub(crate) len : u64,
+    pub(crate) modified : SystemTime,
+    pub(crate) readonly : bool
+}
+
+// A file read by the gio backend.
+#[derive(Debug, Clone)]
+pub(crate) struct GioRead {
+    pub(crate) content : String,
+    pub(crate) format : TextFormat,
+
+    // MIME type of the file, and whether its content can be treated as text (see sniff_mime).
+    pub(crate) mime : String,
+    pub(crate) is_text : bool
+}
+
+/// Queries the metadata of the file at the main context (see IoBackend::Gio). Returns
+/// None if the file does not exist. The file might be at a path or at a URI (see gio_location).
+pub(crate) async fn gio_metadata(file : &gio::File) -> io::Result<Option<GioMetadata>> {
+    let info = match file.query_info_future(GIO_ATTRIBUTES, gio::FileQueryInfoFlags::NONE, glib::Priority::DEFAULT).await {
+        Ok(info) => info,
+        Err(e) if e.matches(gio::IOErrorEnum::NotFound) => return Ok(None),
+        Err(e) => return Err(gio_error(e))
+    };
+    let nanos = if info.has_attribute("time::modified-nsec") {
+        info.attribute_uint32("time::modified-nsec")
+    } else {
+        info.attribute_uint32("time::modified-usec") * 1000
+    };
+    Ok(Some(GioMetadata {
+        is_dir : info.file_type() == gio::FileType::Directory,
+        is_file : info.file_type() == gio::FileType::Regular,
+        len : info.size().max(0) as u64,
+        modified : SystemTime::UNIX_EPOCH + Duration::new(info.attribute_uint64("time::modified"), nanos),
+
+        // Backends that do not report the access rights are assumed to be writable.
+        readonly : info.has_attribute("access::can-write") && !info.attribute_boolean("access::can-write")
+    }))
+}
+
+/// Reads the whole file at the main context (see IoBackend::Gio), decompressing and
+/// decoding it like read_content. Decompression and decoding run at the IO pool.
+pub(crate) async fn gio_read(file : &gio::File) -> io::Result<GioRead> {
+    let (data, _etag) = file.load_contents_future().await.map_err(gio_error)?;
+    let location = gio_location(file);
+    run_io(move || {
+        let data : Cow<[u8]> = if is_gzip_path(&location) {
+            let mut decompressed = Vec::new();
+            gzip_decoder(&data[..]).read_to_end(&mut decompressed)?;
+            Cow::Owned(decompressed)
+        } else {
+            Cow::Borrowed(&data[..])
+        };
+        let (mime, is_text) = guess_mime(&location, &data);
+        let mut content = String::new();
+        let format = decode_content(&data, &mut content)?;
+        Ok(GioRead { content, format, mime, is_text })
+    }).await?
+}
+
+/// Writes the content to the file at the main context (see IoBackend::Gio), following the
+/// save options like write_content. Encoding and compression run at the IO pool. Returns
+/// the location of the backup (see gio_location), if one was created.
+pub(crate) async fn gio_write(file : &gio::File, content : &str, format : &TextFormat, opts : &SaveOptions) -> io::Result<Option<PathBuf>> {
+    let location = gio_location(file);
+    let data = {
+        let (content, format, opts, location) = (content.to_string(), format.clone(), opts.clone(), location.clone());
+        run_io(move || {
+            let data = encode_for_save(&content, &format, &opts)?;
+            if is_gzip_path(&location) { compress(&data) } else { Ok(data.into_owned()) }
+        }).await??
+    };
+
+    // The file is not overwritten if the backup fails.
+    let metadata = gio_metadata(file).await?;
+    let backup = match &metadata {
+        Some(metadata) if metadata.is_file => gio_backup_file(file, &opts.backup)?,
+        _ => None
+    };
+    if let Some(backup) = &backup {
+        let (copy, _progress) = file.copy_future(backup, gio::FileCopyFlags::OVERWRITE, glib::Priority::DEFAULT);
+        copy.await.map_err(gio_error)?;
+    }
+
+    // GIO writes to a temporary file before replacing the original, so both take space.
+    let dir = if metadata.is_some() { Some(file.clone()) } else { file.parent() };
+    if let Some(dir) = dir {
+        let free = dir.query_filesystem_info_future(FREE_ATTRIBUTE, glib::Priority::DEFAULT).await.ok()
+            .filter(|info| info.has_attribute(FREE_ATTRIBUTE) )
+            .map(|info| info.attribute_uint64(FREE_ATTRIBUTE) );
+        if let Some(free) = free {
+            space_error(data.len() as u64, free)?;
+        }
+    }
+
+    // GIO writes through symbolic links unless asked to replace the destination.
+    let flags = match opts.symlink {
+        SymlinkPolicy::WriteThrough => gio::FileCreateFlags::NONE,
+        SymlinkPolicy::Replace => gio::FileCreateFlags::REPLACE_DESTINATION
+    };
+    file.replace_contents_future(data, None, false, flags).await.map_err(|(_, e)| gio_error(e) )?;
+    Ok(backup.map(|backup| gio_location(&backup) ))
+}
+
+/// Mounts the volume holding the file (e.g. an sftp:// location not mounted yet) if it
+/// is not mounted. Volumes that ask for credentials cannot be mounted this way.
+pub(crate) async fn gio_mount(file : &gio::File) -> io::Result<()> {
+    if file.is_native() {
+        return Ok(());
+    }
+    match file.mount_enclosing_volume_future(gio::MountMountFlags::NONE, None::<&gio::MountOperation>).await {
+        Err(e) if !e.matches(gio::IOErrorEnum::AlreadyMounted) => Err(gio_error(e)),
+        _ => Ok(())
+    }
+}
+
+/// The local path of the file, or its URI (e.g. sftp://host/notes.sql) for locations that
+/// have no local path. Used to name and report files of the gio backend.
+pub(crate) fn gio_location(file : &gio::File) -> PathBuf {
+    file.path().unwrap_or_else(|| PathBuf::from(file.uri().as_str()) )
+}
+
+// Tilde backups are written next to the file, also at remote locations. Backups at a
+// directory are local (see backup_path).
+fn gio_backup_file(file : &gio::File, policy : &BackupPolicy) -> io::Result<Option<gio::File>> {
+    match (policy, file.path()) {
+        (BackupPolicy::Tilde, None) => {
+            let Some((parent, name)) = file.parent().zip(file.basename()) else {
+                return Ok(None);
+            };
+            let mut name = name.into_os_string();
+            name.push("~");
+            Ok(Some(parent.child(name)))
+        },
+        (_, path) => {
+            let path = path.unwrap_or_else(|| gio_location(file) );
+            Ok(backup_path(&path, policy)?.map(gio::File::for_path))
+        }
+    }
+}
+
+// Runs the CPU-bound part of a gio operation at the IO pool, resolving to its result at
+// the main context. Each call has its own key, since it does not touch the filesystem.
+async fn run_io<F, R>(f : F) -> io::Result<R>
+where
+    F : FnOnce() -> R + Send + 'static,
+    R : Send + 'static
+{
+    static NEXT_KEY : AtomicU64 = AtomicU64::new(0);
+    let key = PathBuf::from(format!("gio-{}", NEXT_KEY.fetch_add(1, Ordering::Relaxed)));
+    let shared : Arc<Mutex<PoolResult<R>>> = Default::default();
+    submit_io(&key, {
+        let shared = shared.clone();
+        move || {
+            let res = panic::catch_unwind(AssertUnwindSafe(f)).map_err(panic_message);
+            let mut pending = lock_result(&shared);
+            pending.res = Some(res);
+            if let Some(waker) = pending.waker.take() {
+                waker.wake();
+            }
+        }
+    });
+    let res = std::future::poll_fn(|cx| {
+        let mut pending = lock_result(&shared);
+        match pending.res.take() {
+            Some(res) => Poll::Ready(res),
+            None => {
+                pending.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }).await;
+    res.map_err(|msg| io::Error::other(format!("IO worker panicked: {}", msg)) )
+}
+
+// Result of a run_io job, and the waker of the future awaiting it.
+struct PoolResult<R> {
+    res : Option<Result<R, String>>,
+    waker : Option<Waker>
+}
+
+impl<R> Default for PoolResult<R> {
+
+    fn default() -> Self {
+        Self { res : None, waker : None }
+    }
+
+}
+
+fn lock_result<R>(shared : &Mutex<PoolResult<R>>) -> MutexGuard<'_, PoolResult<R>> {
+    shared.lock().unwrap_or_else(|e| e.into_inner() )
+}
+
+/// Carried by the io::Error of a save that was not attempted because the filesystem
+/// does not have enough free space for the content (see ArchiverError::InsufficientSpace).
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct InsufficientSpace {
+    pub(crate) required : u64,
+    pub(crate) available : u64
+}
+
+impl std::fmt::Display for InsufficientSpace {
+
+    fn fmt(&self, f : &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Not enough disk space to save the file ({} bytes required, {} bytes available)", self.required, self.available)
+    }
+
+}
+
+impl std::error::Error for InsufficientSpace { }
+
+// Checks the free space of the filesystem holding the path (or its directory, if the path
+// does not exist yet), counting the bytes that are freed by the write as available. Saves
+// go on when the filesystem does not report its free space.
+fn check_space(path : &Path, required : u64, freed : u64) -> io::Result<()> {
+    let dir = if path.exists() { Some(path) } else { path.parent() };
+    let Some(dir) = dir else {
+        return Ok(());
+    };
+    let Ok(info) = gio::File::for_path(dir).query_filesystem_info(FREE_ATTRIBUTE, None::<&gio::Cancellable>) else {
+        return Ok(());
+    };
+    if !info.has_attribute(FREE_ATTRIBUTE) {
+        return Ok(());
+    }
+    space_error(required, info.attribute_uint64(FREE_ATTRIBUTE).saturating_add(freed))
+}
+
+fn space_error(required : u64, available : u64) -> io::Result<()> {
+    if required > available {
+        Err(io::Error::other(InsufficientSpace { required, available }))
+    } else {
+        Ok(())
+    }
+}
+
+/// Whether a save failed because the location of the file cannot be reached (e.g. the NFS
+/// server or the GVfs share holding it went away), so that it can be written again once the
+/// location is back (see is_reachable). Must be called from the worker thread.
+pub(crate) fn is_offline_error(path : &Path, e : &io::Error) -> bool {
+    is_network_error(e) || path.parent().is_some_and(|dir| !dir.is_dir() && is_remote_location(dir) )
+}
+
+/// Like is_offline_error, but only looks at the error, without touching the filesystem,
+/// so that it can be called from the main thread (e.g. by the gio backend, whose errors
+/// tell when a location cannot be reached).
+pub(crate) fn is_network_error(e : &io::Error) -> bool {
+    use io::ErrorKind::*;
+    matches!(e.kind(), NotConnected | TimedOut | HostUnreachable | NetworkUnreachable | NetworkDown | StaleNetworkFileHandle)
+}
+
+/// Whether the user cannot write the file at the path. The mode bits alone do not tell
+/// (e.g. a file owned by root with mode 0644 is not writable by other users), so the access
+/// rights are asked to GIO, falling back to the mode bits if it does not report them.
+/// Must be called from the worker thread.
+pub(crate) fn is_read_only(path : &Path, metadata : &fs::Metadata) -> bool {
+    match gio::File::for_path(path).query_info(WRITE_ATTRIBUTE, gio::FileQueryInfoFlags::NONE, None::<&gio::Cancellable>) {
+        Ok(info) if info.has_attribute(WRITE_ATTRIBUTE) => !info.attribute_boolean(WRITE_ATTRIBUTE),
+        _ => metadata.permissions().readonly()
+    }
+}
+
+/// Whether the directory of the path can be reached again (see is_offline_error).
+pub(crate) fn is_reachable(path : &Path) -> bool {
+    path.parent().is_some_and(|dir| dir.is_dir() )
+}
+
+// Asks the filesystem of the closest existing ancestor (the mount point of an unmounted
+// share is usually left in place). GVfs locations are always remote.
+fn is_remote_location(path : &Path) -> bool {
+    let Some(ancestor) = path.ancestors().find(|p| p.exists() ) else {
+        return false;
+    };
+    let file = gio::File::for_path(ancestor);
+    if !file.is_native() {
+        return true;
+    }
+    file.query_filesystem_info(REMOTE_ATTRIBUTE, None::<&gio::Cancellable>)
+        .is_ok_and(|info| info.has_attribute(REMOTE_ATTRIBUTE) && info.attribute_boolean(REMOTE_ATTRIBUTE) )
+}
+
+fn gio_error(e : glib::Error) -> io::Error {
+    let kind = if e.matches(gio::IOErrorEnum::NotFound) {
+        io::ErrorKind::NotFound
+    } else if e.matches(gio::IOErrorEnum::PermissionDenied) {
+        io::ErrorKind::PermissionDenied
+    } else if e.matches(gio::IOErrorEnum::TimedOut) {
+        io::ErrorKind::TimedOut
+    } else if e.matches(gio::IOErrorEnum::HostUnreachable) {
+        io::ErrorKind::HostUnreachable
+    } else if e.matches(gio::IOErrorEnum::NetworkUnreachable) {
+        io::ErrorKind::NetworkUnreachable
+    } else if e.matches(gio::IOErrorEnum::NotConnected) || e.matches(gio::IOErrorEnum::ConnectionClosed) || e.matches(gio::IOErrorEnum::NotMounted) {
+        io::ErrorKind::NotConnected
+    } else {
+        io::ErrorKind::Other
+    };
+    io::Error::new(kind, e.message().to_string())
+}
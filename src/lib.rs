@@ -43,6 +43,60 @@ pub use datadir::*;
 
 mod config;
 
+mod recent;
+
+pub use recent::*;
+
+mod recent_manager;
+
+pub use recent_manager::*;
+
+mod model;
+
+pub use model::*;
+
+mod drop_target;
+
+pub use drop_target::*;
+
+#[cfg(feature = "v4_10")]
+mod file_dialog;
+
+#[cfg(feature = "v4_10")]
+pub use file_dialog::*;
+
+mod metrics;
+
+pub use metrics::*;
+
+mod activation;
+
+pub use activation::*;
+
+mod startup;
+
+pub use startup::*;
+
+mod io;
+
+mod pool;
+
+mod autosave;
+
+mod session;
+
+pub use autosave::*;
+
+mod paths;
+
+pub use paths::*;
+
+mod error;
+
+pub use error::*;
+
+pub use io::{SaveOptions, SymlinkPolicy, Durability, BomPolicy, NewlinePolicy, BackupPolicy, NormalizationPolicy, IoBackend, is_gzip_path};
+
 mod icons;
 
 pub use icons::*;
@@ -53,4 +107,10 @@ pub fn log_err<E : std::error::Error>(err : E) {
     eprintln!("{}", err);
 }
 
+// Evaluates the window close veto stage. Any listener returning true holds the
+// window open, even if all files are saved.
+pub(crate) fn close_vetoed(on_window_close_veto : &stateful::ValuedCallbacks<(), bool>) -> bool {
+    on_window_close_veto.call_with_values(()).iter().any(|veto| *veto )
+}
+
 
@@ -0,0 +1,62 @@
+/*Copyright (c) 2022 Diego da Silva Lima. All rights reserved.
+
+This work is licensed under the terms of the MIT license.  
+For a copy, see <https://opensource.org/licenses/MIT>.*/
+
+use std::time::{Duration, Instant};
+
+/// Counters of the operations done by an archiver during the current session
+/// (e.g. for a diagnostics page). Latencies are measured at the main thread, from
+/// the request to the moment the worker reports it is done, so they include the
+/// time an operation waited for the previous one.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ArchiverMetrics {
+
+    // Files opened (or reloaded) successfully.
+    pub opens : u64,
+
+    // Files saved successfully.
+    pub saves : u64,
+
+    pub open_errors : u64,
+
+    // Failed saves, including the ones rejected by a save validator.
+    pub save_errors : u64,
+
+    // Number of operations whose latency was measured, and their total and longest latency.
+    pub timed : u64,
+
+    pub total_latency : Duration,
+
+    pub max_latency : Duration
+
+}
+
+impl ArchiverMetrics {
+
+    pub fn errors(&self) -> u64 {
+        self.open_errors + self.save_errors
+    }
+
+    /// Average latency of the opens and saves, if any finished.
+    pub fn average_latency(&self) -> Option<Duration> {
+        if self.timed == 0 {
+            return None;
+        }
+        Some(Duration::from_nanos((self.total_latency.as_nanos() / self.timed as u128) as u64))
+    }
+
+    pub(crate) fn record_latency(&mut self, latency : Duration) {
+        self.timed += 1;
+        self.total_latency += latency;
+        self.max_latency = self.max_latency.max(latency);
+    }
+
+    // Records the latency of the operation started at the given instant, if any.
+    pub(crate) fn record_since(&mut self, started : Option<Instant>) {
+        if let Some(started) = started {
+            self.record_latency(started.elapsed());
+        }
+    }
+
+}
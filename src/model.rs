@@ -0,0 +1,163 @@
+/*Copyright (c) 2022 Diego da Silva Lima. All rights reserved.
+
+This work is licensed under the terms of the MIT license.
+For a copy, see <https://opensource.org/licenses/MIT>.*/
+
+use std::path::PathBuf;
+use gtk4::glib;
+use gtk4::gio;
+use gtk4::prelude::*;
+use gtk4::glib::subclass::prelude::*;
+use super::OpenedFile;
+
+// Icon of files without a known MIME type (e.g. untitled files).
+const DEFAULT_ICON : &str = "text-x-generic";
+
+mod imp {
+
+    use std::cell::{Cell, RefCell};
+    use std::sync::OnceLock;
+    use super::*;
+
+    #[derive(Default)]
+    pub struct FileEntry {
+        pub(super) name : RefCell<String>,
+        pub(super) path : RefCell<Option<PathBuf>>,
+        pub(super) saved : Cell<bool>,
+        pub(super) icon : RefCell<String>,
+        pub(super) index : Cell<usize>
+    }
+
+    #[glib::object_subclass]
+    impl ObjectSubclass for FileEntry {
+        const NAME : &'static str = "FilecaseFileEntry";
+        type Type = super::FileEntry;
+    }
+
+    impl ObjectImpl for FileEntry {
+
+        fn properties() -> &'static [glib::ParamSpec] {
+            static PROPERTIES : OnceLock<Vec<glib::ParamSpec>> = OnceLock::new();
+            PROPERTIES.get_or_init(|| {
+                vec![
+                    glib::ParamSpecString::builder("name").read_only().build(),
+                    glib::ParamSpecString::builder("path").read_only().build(),
+                    glib::ParamSpecBoolean::builder("saved").read_only().build(),
+                    glib::ParamSpecString::builder("icon").read_only().build()
+                ]
+            })
+        }
+
+        fn property(&self, _id : usize, pspec : &glib::ParamSpec) -> glib::Value {
+            match pspec.name() {
+                "name" => self.name.borrow().to_value(),
+                "path" => self.path.borrow().as_ref().map(|path| path.display().to_string() ).to_value(),
+                "saved" => self.saved.get().to_value(),
+                "icon" => self.icon.borrow().to_value(),
+                _ => unreachable!()
+            }
+        }
+
+    }
+
+}
+
+glib::wrapper! {
+    pub struct FileEntry(ObjectSubclass<imp::FileEntry>);
+}
+
+/*
+An opened file, as an item of the model returned by MultiArchiverImpl::file_model. Its name,
+path (None for untitled files), saved and icon (a themed icon name) properties can be bound
+to the widgets of a list item (e.g. with gtk::PropertyExpression), and are notified when they
+change. Entries are positional: the entry at a given position of the model shows whatever file
+is at that position of the exposed order (see MultiArchiverImpl::set_order).
+*/
+impl FileEntry {
+
+    fn new(file : &OpenedFile) -> Self {
+        let entry : Self = glib::Object::new();
+        entry.update(file);
+        entry
+    }
+
+    pub fn name(&self) -> String {
+        self.imp().name.borrow().clone()
+    }
+
+    pub fn path(&self) -> Option<PathBuf> {
+        self.imp().path.borrow().clone()
+    }
+
+    pub fn saved(&self) -> bool {
+        self.imp().saved.get()
+    }
+
+    pub fn icon(&self) -> String {
+        self.imp().icon.borrow().clone()
+    }
+
+    /// Index of the file at the archiver (e.g. to send a SelectRequest when the row is
+    /// activated).
+    pub fn index(&self) -> usize {
+        self.imp().index.get()
+    }
+
+    // Shows the file, notifying the properties that changed.
+    fn update(&self, file : &OpenedFile) {
+        let imp = self.imp();
+        imp.index.set(file.index);
+        if *imp.name.borrow() != file.name {
+            imp.name.replace(file.name.clone());
+            self.notify("name");
+        }
+        if *imp.path.borrow() != file.path {
+            imp.path.replace(file.path.clone());
+            self.notify("path");
+        }
+        if imp.saved.get() != file.saved {
+            imp.saved.set(file.saved);
+            self.notify("saved");
+        }
+        let icon = icon_name(file);
+        if *imp.icon.borrow() != icon {
+            imp.icon.replace(icon);
+            self.notify("icon");
+        }
+    }
+
+}
+
+fn icon_name(file : &OpenedFile) -> String {
+    file.mime.as_deref()
+        .and_then(gio::content_type_from_mime_type)
+        .and_then(|content_type| gio::content_type_get_generic_icon_name(&content_type) )
+        .map(String::from)
+        .unwrap_or_else(|| String::from(DEFAULT_ICON) )
+}
+
+/// Updates the model to show the files in the given order (indices into files), changing
+/// the entries in place, and appending or removing entries at the end when the number
+/// of files changes.
+pub(crate) fn sync_file_model(model : &gio::ListStore, files : &[OpenedFile], order : &[usize]) {
+    let n_items = model.n_items() as usize;
+    let mut added = Vec::new();
+    for (pos, ix) in order.iter().enumerate() {
+        let Some(file) = files.get(*ix) else {
+            continue;
+        };
+        let entry = (pos < n_items)
+            .then(|| model.item(pos as u32) )
+            .flatten()
+            .and_then(|item| item.downcast::<FileEntry>().ok() );
+        match entry {
+            Some(entry) => entry.update(file),
+            None => added.push(FileEntry::new(file))
+        }
+    }
+    if n_items > order.len() {
+        model.splice(order.len() as u32, (n_items - order.len()) as u32, &[] as &[FileEntry]);
+    } else if !added.is_empty() {
+        model.splice(n_items as u32, 0, &added);
+    }
+}
@@ -3,17 +3,36 @@
 This work is licensed under the terms of the MIT license.  
 For a copy, see <https://opensource.org/licenses/MIT>.*/
 
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::thread;
-use std::fs::File;
-use std::io::{Read, Write};
-use std::path::{Path};
-use std::thread::JoinHandle;
 use serde::{Serialize, Deserialize};
 use std::rc::Rc;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::sync::atomic::{AtomicU64, Ordering};
 use gtk4::glib;
+use gtk4::gio;
+use gtk4::prelude::*;
 use stateful::{Callbacks, ValuedCallbacks, Inherit};
-use std::time::SystemTime;
+use std::time::{SystemTime, Duration, Instant};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::future::Future;
+use std::pin::Pin;
+use std::ops::Range;
+use super::RecentStore;
+use super::ArchiverMetrics;
+use super::{same_path, canonicalize_path, infer_extension, normalize_path, expand_tilde, contract_tilde, sanitize_file_name, CaseSensitivity};
+use super::paths::has_prefix;
+use super::{ArchiverError, SpecialFileKind};
+use super::autosave::{ArchiverAutosave, AutosaveRecord, PendingRecovery, spawn_write_recovery, spawn_scan_recoveries, spawn_remove_recovery, take_recovery};
+use super::io::{SaveOptions, SymlinkPolicy, Durability, BomPolicy, NewlinePolicy, BackupPolicy, NormalizationPolicy, IoBackend, IoTask, CancelToken, Progress, TextFormat, read_content, read_prefix, read_range, write_content, normalize_file_name, is_gzip_path, sniff_mime, spawn_worker, is_offline_error, is_network_error, is_reachable, gio_metadata, gio_read, gio_write, gio_mount, is_read_only, write_replacing};
+use super::pool::{IoJob, submit_local};
+use super::session::{SessionDraft, write_session_archive, read_session_archive};
+use super::recent_manager::SystemRecent;
+use super::model::{FileEntry, sync_file_model};
 
 pub trait MultiArchiverImpl : Inherit<Parent = MultiArchiver> {
 
@@ -21,690 +40,5640 @@ pub trait MultiArchiverImpl : Inherit<Parent = MultiArchiver> {
         self.parent().final_state.clone()
     }
 
-    fn add_files(&self, files : &[OpenedFile]) {
-        for f in files.iter() {
-            self.parent().send.send(MultiArchiverAction::Add(f.clone()))
-                .unwrap_or_else(super::log_err);
-        }
+    fn save_history<P : AsRef<Path>>(&self, path : P) -> Vec<SaveRecord> {
+        self.parent().save_history(path)
     }
 
-    fn sender(&self) -> &glib::Sender<MultiArchiverAction> {
-        &self.parent().send
+    fn metrics(&self) -> ArchiverMetrics {
+        self.parent().metrics()
     }
 
-    fn connect_new<F>(&self, f : F)
+    fn selected_index(&self) -> Option<usize> {
+        self.parent().selected_index()
+    }
+
+    fn order(&self) -> Vec<usize> {
+        self.parent().order()
+    }
+
+    fn ordered_files(&self) -> Vec<OpenedFile> {
+        self.parent().ordered_files()
+    }
+
+    fn file_model(&self) -> gio::ListModel {
+        self.parent().file_model()
+    }
+
+    fn set_order(&self, order : FileOrder) {
+        self.parent().send.send(MultiArchiverAction::SetOrder(order))
+            .unwrap_or_else(super::log_err);
+    }
+
+    // Called with the file indices in the exposed order every time this order changes
+    // (after SetOrder or MoveFile, or when files are opened, created or closed).
+    fn connect_order_changed<F>(&self, f : F)
     where
-        F : Fn(OpenedFile) + 'static
+        F : Fn(Vec<usize>) + 'static
     {
-        self.parent().on_new.bind(f);
+        self.parent().on_order_changed.bind(f);
     }
 
-    // When the user requested to open a file that was already opened. Gives
-    // the client a chance to do someting, such as making the file view receive
-    // the focs.
-    fn connect_reopen<F>(&self, f : F)
+    fn len(&self) -> usize {
+        self.parent().len()
+    }
+
+    fn pending_recoveries(&self) -> Vec<PendingRecovery> {
+        self.parent().pending_recoveries()
+    }
+
+    // Restores all drafts of untitled files (see MultiArchiverAction::AdoptDrafts).
+    fn adopt_drafts(&self) {
+        self.parent().send.send(MultiArchiverAction::AdoptDrafts)
+            .unwrap_or_else(super::log_err);
+    }
+
+    fn pending_io(&self) -> Vec<(PathBuf, IoKind)> {
+        self.parent().pending_io()
+    }
+
+    // Called with the pending operations every time an operation is requested or
+    // finishes (e.g. to show a spinner, or to hold the window open until the
+    // pending saves finish).
+    fn connect_io_queue_changed<F>(&self, f : F)
     where
-        F : Fn(OpenedFile) + 'static
+        F : Fn(Vec<(PathBuf, IoKind)>) + 'static
     {
-        self.parent().on_reopen.bind(f);
+        self.parent().io_queue.on_changed.bind(f);
     }
 
-    fn connect_added<F>(&self, f : F)
+    fn is_empty(&self) -> bool {
+        self.parent().is_empty()
+    }
+
+    fn capacity(&self) -> usize {
+        self.parent().capacity()
+    }
+
+    fn remaining_slots(&self) -> usize {
+        self.parent().remaining_slots()
+    }
+
+    // Called with the number of remaining slots every time a file is opened, created
+    // or closed (e.g. to disable the new and open actions when the limit is reached).
+    fn connect_capacity_changed<F>(&self, f : F)
     where
-        F : Fn(OpenedFile) + 'static
+        F : Fn(usize) + 'static
     {
-        self.parent().on_added.bind(f);
+        self.parent().on_capacity_changed.bind(f);
     }
 
-    fn connect_selected<F>(&self, f : F)
+    fn selected_file(&self) -> Option<OpenedFile> {
+        self.parent().selected_file()
+    }
+
+    fn dirty_files(&self) -> Vec<OpenedFile> {
+        self.parent().dirty_files()
+    }
+
+    fn for_each_dirty<F>(&self, f : F)
     where
-        F : Fn(Option<OpenedFile>) + 'static
+        F : Fn(&OpenedFile)
     {
-        self.parent().on_selected.bind(f);
+        self.parent().for_each_dirty(f)
     }
 
-    fn connect_opened<F>(&self, f : F)
+    // Saves the selected file (asking for a path if it is untitled).
+    fn save_selected(&self) {
+        self.parent().send.send(MultiArchiverAction::SaveRequest { path : None, overwrite : false })
+            .unwrap_or_else(super::log_err);
+    }
+
+    // Writes a copy of the file at the given position to the path (see
+    // MultiArchiverAction::SaveCopyRequest).
+    fn save_copy<P : AsRef<Path>>(&self, ix : usize, path : P) {
+        self.parent().send.send(MultiArchiverAction::SaveCopyRequest(ix, path.as_ref().to_path_buf()))
+            .unwrap_or_else(super::log_err);
+    }
+
+    // Saves every file with unsaved changes that has a path (see connect_all_saved).
+    fn save_all(&self) {
+        self.parent().send.send(MultiArchiverAction::SaveAllRequest)
+            .unwrap_or_else(super::log_err);
+    }
+
+    // Closes the selected file (asking for confirmation if it is unsaved).
+    fn close_selected(&self) {
+        if let Some(ix) = self.selected_index() {
+            self.parent().send.send(MultiArchiverAction::CloseRequest(ix, false))
+                .unwrap_or_else(super::log_err);
+        }
+    }
+
+    // Closes all files in sequence (see MultiArchiverAction::CloseAllRequest).
+    fn close_all(&self) {
+        self.parent().send.send(MultiArchiverAction::CloseAllRequest)
+            .unwrap_or_else(super::log_err);
+    }
+
+    // Closes all files except the ones at the given positions (see MultiArchiverAction::CloseAllExcept).
+    fn close_all_except(&self, kept : &[usize]) {
+        self.parent().send.send(MultiArchiverAction::CloseAllExcept(kept.to_vec()))
+            .unwrap_or_else(super::log_err);
+    }
+
+    // Evaluated with the content of every save before it is written (e.g. to run a
+    // secret scanner). The returned future runs at the main context. If any validator
+    // returns an error, the file is not written and the message is passed to
+    // on_user_error (as ArchiverError::Rejected).
+    fn connect_save_validator<F, R>(&self, f : F)
     where
-        F : Fn(OpenedFile) + 'static
+        F : Fn(&str) -> R + 'static,
+        R : Future<Output = Result<(), String>> + 'static
     {
-        self.parent().on_open.bind(f);
+        self.parent().save_validators.borrow_mut().push(Rc::new(move |content : &str| {
+            Box::pin(f(content)) as Pin<Box<dyn Future<Output = Result<(), String>>>>
+        }));
     }
 
-    fn connect_closed<F>(&self, f : F)
+    fn set_symlink_policy(&self, policy : SymlinkPolicy) {
+        self.parent().save_options.borrow_mut().symlink = policy;
+    }
+
+    fn set_durability(&self, durability : Durability) {
+        self.parent().save_options.borrow_mut().durability = durability;
+    }
+
+    fn set_save_timeout(&self, timeout : Option<Duration>) {
+        self.parent().save_options.borrow_mut().timeout = timeout;
+    }
+
+    fn set_bom_policy(&self, policy : BomPolicy) {
+        self.parent().save_options.borrow_mut().bom = policy;
+    }
+
+    fn set_newline_policy(&self, policy : NewlinePolicy) {
+        self.parent().save_options.borrow_mut().newline = policy;
+    }
+
+    fn set_normalization_policy(&self, policy : NormalizationPolicy) {
+        self.parent().save_options.borrow_mut().normalization = policy;
+    }
+
+    fn set_backup_policy(&self, policy : BackupPolicy) {
+        self.parent().save_options.borrow_mut().backup = policy;
+    }
+
+    // Permission bits of the files created by saves (see SaveOptions::new_file_mode).
+    fn set_new_file_mode(&self, mode : Option<u32>) {
+        self.parent().save_options.borrow_mut().new_file_mode = mode;
+    }
+
+    // Where the opens, reloads and saves that start after this call run (see IoBackend).
+    // Files opened with one backend should be saved with the same one, since the backends
+    // might find different modification times for the same file (see connect_save_conflict).
+    fn set_io_backend(&self, backend : IoBackend) {
+        self.parent().io_backend.set(backend);
+    }
+
+    // Registers the files this archiver opens or saves (except autosaves) with the recent list
+    // of the desktop (gtk::RecentManager), so that they show at the recent files of the file
+    // manager and at the recent tab of file choosers. Disabled by default.
+    fn set_register_recent(&self, register : bool) {
+        self.parent().system_recent.borrow_mut().register = register;
+    }
+
+    // Application and MIME type the files are registered with (see set_register_recent). By
+    // default, the application name and the MIME type detected when the file was opened.
+    fn set_recent_hint(&self, app_id : Option<&str>, mime_type : Option<&str>) {
+        let mut system_recent = self.parent().system_recent.borrow_mut();
+        system_recent.app_id = app_id.map(String::from);
+        system_recent.mime_type = mime_type.map(String::from);
+    }
+
+    // Resolves a relative path (e.g. the location of a diagnostic reported by a language
+    // server) like OpenRelativeRequest, without opening it. Returns None if neither a
+    // workspace root nor an allowed root is set (the directory inferred by SetInferRelative
+    // is not used).
+    fn resolve_relative(&self, rel_path : &str) -> Option<PathBuf> {
+        let parent = self.parent();
+        resolve_relative(Path::new(rel_path), parent.workspace_root.borrow().as_deref(), &parent.allowed_roots.borrow())
+    }
+
+    // Called instead of saving when the file was modified on disk since it was opened
+    // or last saved, with the file (without content), the modification time known by the
+    // archiver and the one found on disk. Send SaveRequest with overwrite set to save anyway.
+    fn connect_save_conflict<F>(&self, f : F)
     where
-        F : Fn((OpenedFile, usize)) + 'static
+        F : Fn((OpenedFile, SystemTime, SystemTime)) + 'static
     {
-        self.parent().on_file_closed.bind(f);
+        self.parent().on_save_conflict.bind(f);
     }
 
-    fn connect_close_confirm<F>(&self, f : F)
+    // Holds saves (including autosaves) whose content is shorter than the given fraction
+    // of the file on disk (e.g. 0.1 after an accidental select-all and delete), calling
+    // on_suspicious_save instead. None (the default) disables the check.
+    fn set_truncation_ratio(&self, ratio : Option<f64>) {
+        self.parent().truncation_ratio.set(ratio);
+    }
+
+    // Files larger than the given size (in bytes, 5MB by default) are not opened, and an
+    // ArchiverError::TooLarge is passed to on_user_error instead. The size on disk is checked
+    // before reading, and the size of the content after (for compressed or transcoded files).
+    fn set_max_file_size(&self, limit : u64) {
+        let limits = self.parent().open_limits.get();
+        self.parent().open_limits.set(OpenLimits { max_size : limit, ..limits });
+    }
+
+    // Paths that are not regular files or directories (FIFOs, sockets and devices) are not
+    // opened or peeked, and an ArchiverError::SpecialFile is passed to on_user_error instead.
+    // The filter is called with each of these paths, and can return true to open it anyway
+    // (e.g. to read a FIFO written by another process, in which case the open waits for
+    // the writer to close it).
+    fn set_special_file_filter<F>(&self, f : F)
     where
-        F : Fn(OpenedFile) + 'static
+        F : Fn(&Path) -> bool + 'static
     {
-        self.parent().on_close_confirm.bind(f);
+        self.parent().special_file_filter.replace(Some(Box::new(f)));
     }
 
-    fn connect_file_changed<F>(&self, f : F)
+    // If set, files over the maximum file size are opened with only their first bytes (up
+    // to the given length) instead of being rejected. These files have their truncated and
+    // read_only fields set, and the rest of the content can be read with ReadRangeRequest.
+    fn set_streaming_preview(&self, preview : Option<u64>) {
+        let limits = self.parent().open_limits.get();
+        self.parent().open_limits.set(OpenLimits { preview, ..limits });
+    }
+
+    // Reads a range of bytes of the file at the given position (see
+    // MultiArchiverAction::ReadRangeRequest).
+    fn read_range(&self, ix : usize, range : Range<u64>) {
+        self.parent().send.send(MultiArchiverAction::ReadRangeRequest(ix, range))
+            .unwrap_or_else(super::log_err);
+    }
+
+    // Called after ReadRangeRequest with the file (without content), the range of
+    // the bytes actually read and their content.
+    fn connect_range_read<F>(&self, f : F)
     where
-        F : Fn(OpenedFile) + 'static
+        F : Fn((OpenedFile, Range<u64>, String)) + 'static
     {
-        self.parent().on_file_changed.bind(f);
+        self.parent().on_range_read.bind(f);
     }
 
-    fn connect_file_persisted<F>(&self, f : F)
+    // Called instead of saving a suspiciously short content, with the file (without
+    // content), the length of the file on disk and the length of the content (in bytes).
+    // Send SaveRequest with overwrite set to save anyway.
+    fn connect_suspicious_save<F>(&self, f : F)
     where
-        F : Fn(OpenedFile) + 'static
+        F : Fn((OpenedFile, u64, u64)) + 'static
     {
-        self.parent().on_file_persisted.bind(f);
+        self.parent().on_suspicious_save.bind(f);
     }
 
-    fn connect_error<F>(&self, f : F)
+    // Called when all the saves started by SaveAllRequest finished, with the files (without
+    // content) that still have unsaved changes: untitled files, files outside the allowed roots and
+    // files that failed to save. An empty list means everything was saved (e.g. so the
+    // application can quit).
+    fn connect_all_saved<F>(&self, f : F)
     where
-        F : Fn(String) + 'static
+        F : Fn(Vec<OpenedFile>) + 'static
     {
-        self.parent().on_error.bind(f);
+        self.parent().on_all_saved.bind(f);
     }
 
-    fn connect_on_active_text_changed<F>(&self, f : F)
+    // Called when a SaveCopyRequest finished, with the file (without content) and the
+    // path the copy was written to.
+    fn connect_copy_saved<F>(&self, f : F)
     where
-        F : Fn(Option<String>) + 'static
+        F : Fn((OpenedFile, PathBuf)) + 'static
     {
-        self.parent().on_active_text_changed.bind(f);
+        self.parent().on_copy_saved.bind(f);
     }
 
-    fn connect_window_close<F>(&self, f : F)
+    // Called with the saved path and the backup path when a save copied the
+    // previous file (see BackupPolicy).
+    fn connect_backup_created<F>(&self, f : F)
     where
-        F : Fn(()) + 'static
+        F : Fn((PathBuf, PathBuf)) + 'static
     {
-        self.parent().on_window_close.bind(f);
+        self.parent().on_backup_created.bind(f);
     }
 
-    fn connect_save_unknown_path<F>(&self, f : F)
+    // Cancels the open at the given position of pending_io (see MultiArchiverAction::CancelOpen).
+    fn cancel_open(&self, pos : usize) {
+        self.parent().send.send(MultiArchiverAction::CancelOpen(pos))
+            .unwrap_or_else(super::log_err);
+    }
+
+    // Cancels the queued saves of the file at the given index (see MultiArchiverAction::CancelSave).
+    fn cancel_save(&self, ix : usize) {
+        self.parent().send.send(MultiArchiverAction::CancelSave(ix))
+            .unwrap_or_else(super::log_err);
+    }
+
+    // Called when an open or save is cancelled, with its path.
+    fn connect_cancelled<F>(&self, f : F)
     where
-        F : Fn(String) + 'static
+        F : Fn((PathBuf, IoKind)) + 'static
     {
-        self.parent().on_save_unknown_path.bind(f);
+        self.parent().on_cancelled.bind(f);
     }
 
-    fn connect_buffer_read_request<F>(&self, f : F)
+    // Called periodically while a file larger than a few megabytes is opened, reloaded or saved,
+    // with the path, the number of bytes done and the total number of bytes (e.g. to show a
+    // progress bar next to the pending operation).
+    fn connect_io_progress<F>(&self, f : F)
     where
-        F : Fn(usize)->String + 'static
+        F : Fn((PathBuf, u64, u64)) + 'static
     {
-        self.parent().on_buffer_read_request.bind(f);
+        self.parent().on_io_progress.bind(f);
     }
 
-    fn connect_name_changed<F>(&self, f : F)
+    // Called when a save did not finish within the save timeout. Carries the saved path.
+    fn connect_io_stalled<F>(&self, f : F)
     where
-        F : Fn((usize, String)) + 'static
+        F : Fn(PathBuf) + 'static
     {
-        self.parent().on_name_changed.bind(f);
+        self.parent().on_io_stalled.bind(f);
     }
 
-}
+    // Called when a save failed because the location of the file cannot be reached (e.g. a
+    // network mount went away), with the saved path. Instead of failing, the save is queued
+    // (and written to the recovery file, if the autosave has a recovery directory), and it
+    // is written again when the location is back (see connect_back_online). The file stays
+    // unsaved meanwhile.
+    fn connect_offline<F>(&self, f : F)
+    where
+        F : Fn(PathBuf) + 'static
+    {
+        self.parent().on_offline.bind(f);
+    }
 
-#[derive(Debug, Clone)]
-pub struct FinalState {
-    pub recent : Vec<OpenedFile>,
-    pub files : Vec<OpenedFile>
-}
+    // Called when the location of a path passed to on_offline can be reached again, just
+    // before its queued save is written (a file closed meanwhile is not saved).
+    fn connect_back_online<F>(&self, f : F)
+    where
+        F : Fn(PathBuf) + 'static
+    {
+        self.parent().on_back_online.bind(f);
+    }
 
-#[derive(Debug, Clone)]
-pub enum MultiArchiverAction {
+    // Saves the files with unsaved changes at their own path at every interval (None
+    // disables the autosave). Send MultiArchiverAction::SetAutosave with an
+    // ArchiverAutosave built with_recovery_dir to write recovery files instead.
+    fn set_autosave(&self, interval : Option<Duration>) {
+        self.parent().send.send(MultiArchiverAction::SetAutosave(interval.map(ArchiverAutosave::new)))
+            .unwrap_or_else(super::log_err);
+    }
 
-    OpenRequest(String),
-    
-    OpenRelativeRequest(String),
-    
-    SetPrefix(Option<String>),
+    // Called when the recovery directory set with SetAutosave was scanned, with the
+    // recovery files left by a previous session (e.g. so that the application offers
+    // to restore the unsaved changes after a crash with RecoverRequest). Not called if
+    // there are no recovery files.
+    fn connect_recoveries_found<F>(&self, f : F)
+    where
+        F : Fn(Vec<PendingRecovery>) + 'static
+    {
+        self.parent().on_recoveries_found.bind(f);
+    }
 
-    OpenSuccess(OpenedFile),
+    // Called after each file is autosaved (at its own path or at the recovery file).
+    fn connect_autosaved<F>(&self, f : F)
+    where
+        F : Fn(AutosaveRecord) + 'static
+    {
+        self.parent().on_autosaved.bind(f);
+    }
 
-    // Represents an addition to the recent script file list (not necessarily opened).
-    Add(OpenedFile),
+    // Evaluated with the path of every OpenRequest before the file is read. If any
+    // filter returns an error, the file is not opened and the message is passed to
+    // on_user_error (as ArchiverError::Rejected).
+    fn connect_open_filter<F>(&self, f : F)
+    where
+        F : Fn(&Path) -> Result<(), String> + 'static
+    {
+        self.parent().on_open_filter.bind(move |path : PathBuf| f(&path) );
+    }
 
-    OpenError(String),
+    // Reads the start of the file without opening it (e.g. for a preview when the user
+    // hovers a recent file). The content is passed to on_peek.
+    fn peek<P : AsRef<Path>>(&self, path : P) {
+        self.parent().send.send(MultiArchiverAction::PeekRequest(path.as_ref().to_path_buf()))
+            .unwrap_or_else(super::log_err);
+    }
 
-    // File position and whether the request is "forced" (i.e. asks for user confirmation).
-    CloseRequest(usize, bool),
+    // Called with the path and the first MAX_PEEK_SIZE bytes of the content after
+    // PeekRequest. Peeked files are not added to the opened files, so they do not
+    // count against the file limit.
+    fn connect_peek<F>(&self, f : F)
+    where
+        F : Fn((PathBuf, String)) + 'static
+    {
+        self.parent().on_peek.bind(f);
+    }
 
-    SaveRequest(Option<String>),
+    // Called when an opened file is modified on disk by another program (so that the
+    // application can offer to reload it or keep the unsaved content). Saves done by
+    // the archiver itself are not reported. The file is passed without content.
+    fn connect_externally_changed<F>(&self, f : F)
+    where
+        F : Fn(OpenedFile) + 'static
+    {
+        self.parent().on_externally_changed.bind(f);
+    }
 
-    SaveSuccess(usize, String),
+    // Called when an opened file is deleted or moved away by another program. The
+    // file is kept open (and can be saved again at its path).
+    fn connect_externally_deleted<F>(&self, f : F)
+    where
+        F : Fn(OpenedFile) + 'static
+    {
+        self.parent().on_externally_deleted.bind(f);
+    }
 
-    SaveError(String),
+    // Stops reporting external changes until resume_watching (see MultiArchiverAction::PauseWatching).
+    fn pause_watching(&self) {
+        self.parent().send.send(MultiArchiverAction::PauseWatching)
+            .unwrap_or_else(super::log_err);
+    }
 
-    NewRequest,
+    fn resume_watching(&self) {
+        self.parent().send.send(MultiArchiverAction::ResumeWatching)
+            .unwrap_or_else(super::log_err);
+    }
 
-    WindowCloseRequest,
+    // Called when watching resumes after pause_watching, with the files (without content)
+    // changed and the files deleted on disk while watching was paused (both might be empty).
+    fn connect_watching_resumed<F>(&self, f : F)
+    where
+        F : Fn((Vec<OpenedFile>, Vec<OpenedFile>)) + 'static
+    {
+        self.parent().on_watching_resumed.bind(f);
+    }
 
-    SetSaved(usize, bool),
+    // Stops or resumes reporting the external changes of the file at the given
+    // position (see MultiArchiverAction::MuteExternalChanges).
+    fn mute_external_changes(&self, ix : usize, mute : bool) {
+        self.parent().send.send(MultiArchiverAction::MuteExternalChanges(ix, mute))
+            .unwrap_or_else(super::log_err);
+    }
 
-    Select(Option<usize>),
+    // Called with the file (including the new content) after ReloadRequest, so that
+    // the application replaces the content of the editor.
+    fn connect_reloaded<F>(&self, f : F)
+    where
+        F : Fn(OpenedFile) + 'static
+    {
+        self.parent().on_reloaded.bind(f);
+    }
 
-}
+    // Suppresses on_open, on_added, on_added_many and on_closed until the matching
+    // end_bulk (e.g. when restoring a session), so that the application updates its
+    // file list only once, at on_bulk_done. Calls can be nested.
+    fn begin_bulk(&self) {
+        self.parent().send.send(MultiArchiverAction::BeginBulk)
+            .unwrap_or_else(super::log_err);
+    }
 
-pub struct MultiArchiver {
+    fn end_bulk(&self) {
+        self.parent().send.send(MultiArchiverAction::EndBulk)
+            .unwrap_or_else(super::log_err);
+    }
 
-    final_state : Rc<RefCell<FinalState>>,
+    // Called at the outermost end_bulk, with the events suppressed since begin_bulk.
+    fn connect_bulk_done<F>(&self, f : F)
+    where
+        F : Fn(BulkSummary) + 'static
+    {
+        self.parent().on_bulk_done.bind(f);
+    }
 
-    send : glib::Sender<MultiArchiverAction>,
+    // Adds each file with Add, so on_added is called for each of them (use
+    // MultiArchiverAction::AddMany to add a list at once, with on_added_many).
+    fn add_files(&self, files : &[OpenedFile]) {
+        for f in files.iter() {
+            self.parent().send.send(MultiArchiverAction::Add(f.clone()))
+                .unwrap_or_else(super::log_err);
+        }
+    }
 
-    on_open : Callbacks<OpenedFile>,
+    // Restores a saved session (see MultiArchiverAction::RestoreSession).
+    fn restore_session(&self, state : &FinalState) {
+        self.parent().send.send(MultiArchiverAction::RestoreSession(state.clone()))
+            .unwrap_or_else(super::log_err);
+    }
 
-    on_error : Callbacks<String>,
+    // Writes the session and the unsaved content of its files to a single archive (see
+    // MultiArchiverAction::ExportSessionArchive).
+    fn export_session_archive(&self, path : &Path) {
+        self.parent().send.send(MultiArchiverAction::ExportSessionArchive(path.to_path_buf()))
+            .unwrap_or_else(super::log_err);
+    }
 
-    on_reopen : Callbacks<OpenedFile>,
+    // Restores a session written by export_session_archive (see
+    // MultiArchiverAction::ImportSessionArchive).
+    fn import_session_archive(&self, path : &Path) {
+        self.parent().send.send(MultiArchiverAction::ImportSessionArchive(path.to_path_buf()))
+            .unwrap_or_else(super::log_err);
+    }
 
-    on_save_unknown_path : Callbacks<String>,
+    // Called when the archive of an ExportSessionArchive was written, with its path.
+    fn connect_session_exported<F>(&self, f : F)
+    where
+        F : Fn(PathBuf) + 'static
+    {
+        self.parent().on_session_exported.bind(f);
+    }
 
-    on_file_changed : Callbacks<OpenedFile>,
+    // Reads the metadata of the files at the recent list in the background (see
+    // MultiArchiverAction::PrefetchRecentMetadata).
+    fn prefetch_recent_metadata(&self) {
+        self.parent().send.send(MultiArchiverAction::PrefetchRecentMetadata)
+            .unwrap_or_else(super::log_err);
+    }
 
-    on_file_persisted : Callbacks<OpenedFile>,
+    // Called after PrefetchRecentMetadata, with the metadata of the files at the recent list
+    // (in the order of the list).
+    fn connect_recent_metadata<F>(&self, f : F)
+    where
+        F : Fn(Vec<RecentMeta>) + 'static
+    {
+        self.parent().on_recent_metadata.bind(f);
+    }
 
-    on_active_text_changed : Callbacks<Option<String>>,
+    fn sender(&self) -> &glib::Sender<MultiArchiverAction> {
+        &self.parent().send
+    }
 
-    // When user clicks new action
-    on_new : Callbacks<OpenedFile>,
+    fn connect_new<F>(&self, f : F)
+    where
+        F : Fn(OpenedFile) + 'static
+    {
+        self.parent().on_new.bind(f);
+    }
 
-    // Contains the index of the old closed file and the number of remaining files.
-    on_file_closed : Callbacks<(OpenedFile, usize)>,
+    // Called after on_open (or after the bulk operation adds the file to its summary) when
+    // the opened file is read-only, e.g. so that the application locks its buffer.
+    fn connect_readonly_opened<F>(&self, f : F)
+    where
+        F : Fn(OpenedFile) + 'static
+    {
+        self.parent().on_readonly_opened.bind(f);
+    }
 
-    on_close_confirm : Callbacks<OpenedFile>,
+    // Called after on_open when the opened file is not UTF-8 (see OpenedFile::encoding),
+    // e.g. so that the application displays the encoding.
+    fn connect_encoding_detected<F>(&self, f : F)
+    where
+        F : Fn(OpenedFile) + 'static
+    {
+        self.parent().on_encoding_detected.bind(f);
+    }
 
-    on_window_close : Callbacks<()>,
+    // When the user requested to open a file that was already opened. Gives
+    // the client a chance to do someting, such as making the file view receive
+    // the focs.
+    fn connect_reopen<F>(&self, f : F)
+    where
+        F : Fn(OpenedFile) + 'static
+    {
+        self.parent().on_reopen.bind(f);
+    }
 
-    on_buffer_read_request : ValuedCallbacks<usize, String>,
+    // Opens again the last closed file (e.g. bound to Ctrl+Shift+T).
+    fn reopen_last_closed(&self) {
+        self.parent().send.send(MultiArchiverAction::ReopenLastClosed)
+            .unwrap_or_else(super::log_err);
+    }
 
-    on_selected : Callbacks<Option<OpenedFile>>,
+    // Reverts the last open, close, rename or move of a file (see
+    // MultiArchiverAction::UndoLastOperation).
+    fn undo_last_operation(&self) {
+        self.parent().send.send(MultiArchiverAction::UndoLastOperation)
+            .unwrap_or_else(super::log_err);
+    }
 
-    // Called when file goes from untitled to having a name.
-    on_name_changed : Callbacks<(usize, String)>,
+    // Called after on_open when a file reopened by ReopenLastClosed is opened.
+    fn connect_reopened_closed<F>(&self, f : F)
+    where
+        F : Fn(OpenedFile) + 'static
+    {
+        self.parent().on_reopened_closed.bind(f);
+    }
 
-    // When the user state is being updated
-    on_added : Callbacks<OpenedFile>
+    // Called when a file was moved to the trash by TrashRequest (just before it is
+    // closed), with the file (without content) and the token that restores it with
+    // UndoTrashRequest (e.g. from an "Undo" button at a toast).
+    fn connect_trashed<F>(&self, f : F)
+    where
+        F : Fn((OpenedFile, TrashToken)) + 'static
+    {
+        self.parent().on_trashed.bind(f);
+    }
 
-}
+    fn connect_added<F>(&self, f : F)
+    where
+        F : Fn(OpenedFile) + 'static
+    {
+        self.parent().on_added.bind(f);
+    }
 
-// Some SQL files (e.g. generated by pg_dump) are too big for gtksourceview.
-// Limiting the file size prevents the application from freezing.
-const MAX_FILE_SIZE : usize = 5_000_000;
+    // Called once after AddMany, with the files that were actually added to
+    // the recent list (ordered from the oldest to the most recent).
+    fn connect_added_many<F>(&self, f : F)
+    where
+        F : Fn(Vec<OpenedFile>) + 'static
+    {
+        self.parent().on_added_many.bind(f);
+    }
 
-const MAX_NUM_FILES : usize = 16;
+    // Removes the path from the recent list (see MultiArchiverAction::RemoveRecent).
+    fn remove_recent(&self, path : &str) {
+        self.parent().send.send(MultiArchiverAction::RemoveRecent(path.to_string()))
+            .unwrap_or_else(super::log_err);
+    }
 
-impl MultiArchiver {
+    fn clear_recent(&self) {
+        self.parent().send.send(MultiArchiverAction::ClearRecent)
+            .unwrap_or_else(super::log_err);
+    }
 
-    pub fn final_state(&self) -> FinalState {
-        self.final_state.borrow().clone()
+    // Called after RemoveRecent, ClearRecent or ValidateRecent change the recent list, with the
+    // files left at it (files added to the list are reported by on_added and on_added_many instead).
+    fn connect_recent_changed<F>(&self, f : F)
+    where
+        F : Fn(Vec<OpenedFile>) + 'static
+    {
+        self.parent().on_recent_changed.bind(f);
     }
 
-    pub fn sender(&self) -> &glib::Sender<MultiArchiverAction> {
-        &self.send
+    // Checks whether the files at the recent list still exist (see MultiArchiverAction::ValidateRecent).
+    fn validate_recent(&self, remove_missing : bool) {
+        self.parent().send.send(MultiArchiverAction::ValidateRecent { remove_missing })
+            .unwrap_or_else(super::log_err);
     }
 
-    pub fn new(extension : String) -> Self {
-        let final_state = Rc::new(RefCell::new(FinalState { recent : Vec::new(), files : Vec::new() }));
-        let (send, recv) = glib::MainContext::channel::<MultiArchiverAction>(glib::source::Priority::DEFAULT);
-        let on_open : Callbacks<OpenedFile> = Default::default();
-        let on_new : Callbacks<OpenedFile> = Default::default();
-        let on_file_changed : Callbacks<OpenedFile> = Default::default();
-        let on_file_persisted : Callbacks<OpenedFile> = Default::default();
-        let on_reopen : Callbacks<OpenedFile> = Default::default();
-        let on_selected : Callbacks<Option<OpenedFile>> = Default::default();
-        let on_file_closed : Callbacks<(OpenedFile, usize)> = Default::default();
-        let on_active_text_changed : Callbacks<Option<String>> = Default::default();
-        let on_close_confirm : Callbacks<OpenedFile> = Default::default();
-        let on_window_close : Callbacks<()> = Default::default();
-        let on_save_unknown_path : Callbacks<String> = Default::default();
-        let on_buffer_read_request : ValuedCallbacks<usize, String> = Default::default();
-        let on_name_changed : Callbacks<(usize, String)> = Default::default();
-        let on_error : Callbacks<String> = Default::default();
-        let on_added : Callbacks<OpenedFile> = Default::default();
+    // Called after ValidateRecent, with the files of the recent list that still exist.
+    fn connect_recent_validated<F>(&self, f : F)
+    where
+        F : Fn(Vec<OpenedFile>) + 'static
+    {
+        self.parent().on_recent_validated.bind(f);
+    }
 
-        // Holds the files opened at the editor the user sees on the side panel
-        let mut files : Vec<OpenedFile> = Vec::new();
+    fn connect_selected<F>(&self, f : F)
+    where
+        F : Fn(Option<OpenedFile>) + 'static
+    {
+        self.parent().on_selected.bind(f);
+    }
 
-        // Holds the files shown on the recent script list before the editor is opened. The files
-        // are loaded on startup. If the user saves or opens any files not already on this list,
-        // the list is updated. This list is sent to the final_state just before the application
-        // closes.
-        let mut recent_files : Vec<OpenedFile> = Vec::new();
+    fn connect_opened<F>(&self, f : F)
+    where
+        F : Fn(OpenedFile) + 'static
+    {
+        self.parent().on_open.bind(f);
+    }
 
-        let mut selected : Option<usize> = None;
+    fn connect_closed<F>(&self, f : F)
+    where
+        F : Fn((OpenedFile, usize)) + 'static
+    {
+        self.parent().on_file_closed.bind(f);
+    }
 
-        let mut win_close_request = false;
-        recv.attach(None, {
-            let send = send.clone();
-            let (on_open, on_new, on_selected, on_file_closed, on_close_confirm, on_file_changed, on_file_persisted, on_reopen) = (
-                on_open.clone(),
-                on_new.clone(),
-                on_selected.clone(),
-                on_file_closed.clone(),
-                on_close_confirm.clone(),
-                on_file_changed.clone(),
-                on_file_persisted.clone(),
-                on_reopen.clone()
-            );
-            let (_on_active_text_changed, on_window_close, on_buffer_read_request, on_save_unknown_path) = (
-                on_active_text_changed.clone(),
-                on_window_close.clone(),
-                on_buffer_read_request.clone(),
-                on_save_unknown_path.clone()
-            );
-            let on_added = on_added.clone();
-            let on_name_changed = on_name_changed.clone();
-            let on_error = on_error.clone();
-            let mut file_open_handle : Option<JoinHandle<bool>> = None;
-            let mut file_save_handle : Option<JoinHandle<bool>> = None;
+    // Called once after a CloseAllExcept sequence ends, with the files it closed
+    // (on_file_closed is not called for them).
+    fn connect_files_closed<F>(&self, f : F)
+    where
+        F : Fn(Vec<OpenedFile>) + 'static
+    {
+        self.parent().on_files_closed.bind(f);
+    }
 
-            let mut last_closed_file : Option<OpenedFile> = None;
-            let final_state = final_state.clone();
-            
-            // If set, any file operations are only done if the path satisfies
-            // this prefix (e.g. multiarchiver does not touch anything outside
-            // /home/user/myproject if prefix is set to this value.
-            let mut prefix : Option<String> = None;
+    fn connect_close_confirm<F>(&self, f : F)
+    where
+        F : Fn(OpenedFile) + 'static
+    {
+        self.parent().on_close_confirm.bind(f);
+    }
 
-            move |action| {
+    fn connect_file_changed<F>(&self, f : F)
+    where
+        F : Fn(OpenedFile) + 'static
+    {
+        self.parent().on_file_changed.bind(f);
+    }
 
-                match action {
+    fn connect_file_persisted<F>(&self, f : F)
+    where
+        F : Fn(OpenedFile) + 'static
+    {
+        self.parent().on_file_persisted.bind(f);
+    }
 
-                    // When user clicks "new file"
-                    MultiArchiverAction::NewRequest => {
-                        if files.len() == MAX_NUM_FILES {
-                            send.send(MultiArchiverAction::OpenError(format!("Maximum number of files opened"))).unwrap();
-                            return glib::ControlFlow::Continue;
-                        }
-                        let n_untitled = files.iter().filter(|f| f.name.starts_with("Untitled") )
-                            .last()
-                            .map(|f| f.name.split(" ").nth(1).unwrap().trim_end_matches(&format!(".{}", extension)).parse::<usize>().unwrap() )
-                            .unwrap_or(0);
-                        let new_file = OpenedFile {
-                            path : None,
-                            name : format!("Untitled {}.{}", n_untitled + 1, extension),
-                            saved : true,
-                            content : None,
-                            index : files.len(),
-                            dt : Some(SystemTime::now())
-                        };
-                        files.push(new_file.clone());
-                        on_new.call(new_file);
-                    },
+    // Called with errors worth showing to the user (e.g. as a toast).
+    fn connect_user_error<F>(&self, f : F)
+    where
+        F : Fn(ArchiverError) + 'static
+    {
+        self.parent().on_user_error.bind(f);
+    }
 
-                    // When the user state is being updated
-                    MultiArchiverAction::Add(file) => {
-                        recent_files.push(file.clone());
-                        on_added.call(file);
-                    },
-                    MultiArchiverAction::OpenRelativeRequest(rel_path) => {
-                    
-                        if let Some(pr) = &prefix {
-                            let abs = Path::new(pr).to_path_buf().join(rel_path);
-                            send.send(MultiArchiverAction::OpenRequest(abs.display().to_string())).unwrap();                            
-                        } else {
-                            send.send(MultiArchiverAction::OpenError(format!("No path prefix set"))).unwrap();
-                        }
-                    },
-                    MultiArchiverAction::OpenRequest(path) => {
+    // Same as connect_user_error.
+    fn connect_error<F>(&self, f : F)
+    where
+        F : Fn(ArchiverError) + 'static
+    {
+        self.connect_user_error(f);
+    }
 
-                        if let Some(pr) = &prefix {
-                            if !path.starts_with(pr) {
-                                send.send(MultiArchiverAction::OpenError(format!("Cannot open file outside prefix {}", pr))).unwrap();
-                                return glib::ControlFlow::Continue;
-                            }
-                        }
-                        
-                        if let Some(already_opened) = files.iter().find(|f| f.path.as_ref().map(|p| &p[..] == &path[..] ).unwrap_or(false) ) {
-                            on_reopen.call(already_opened.clone());
-                            return glib::ControlFlow::Continue;
-                        }
+    // Called with inconsistent requests that were ignored (e.g. an invalid file
+    // index), which are usually worth logging only. If no listener is bound, the
+    // warnings are printed to stderr.
+    fn connect_internal_warning<F>(&self, f : F)
+    where
+        F : Fn(ArchiverError) + 'static
+    {
+        self.parent().on_internal_warning.bind(f);
+    }
 
-                        if files.len() == MAX_NUM_FILES {
-                            send.send(MultiArchiverAction::OpenError(format!("File list limit reached"))).unwrap();
-                            return glib::ControlFlow::Continue;
-                        }
+    // Called with the text forwarded by SetActiveText, only when it differs
+    // from the last forwarded text.
+    fn connect_on_active_text_changed<F>(&self, f : F)
+    where
+        F : Fn(Option<String>) + 'static
+    {
+        self.parent().on_active_text_changed.bind(f);
+    }
 
-                        // We could have a problem if the user attempts to open
-                        // two files in extremely quick succession, and/or for any reason opening the first
-                        // file takes too long (e.g. a busy hard drive). If a second file is opened
-                        // before the first file opening thread ends, the two files would receive the
-                        // same index, since the file index is moved when the thead is spawned.
-                        // The ocurrence should be rare enough to justify blocking the main thread here.
-                        if let Some(handle) = file_open_handle.take() {
-                            handle.join().unwrap();
-                        }
+    fn connect_window_close<F>(&self, f : F)
+    where
+        F : Fn(()) + 'static
+    {
+        self.parent().on_window_close.bind(f);
+    }
 
-                        file_open_handle = Some(spawn_open_file(send.clone(), path, files.len()));
-                    },
-                    MultiArchiverAction::CloseRequest(ix, force) => {
+    // Evaluated just before on_window_close is fired. Returning true holds the
+    // window open (e.g. the application has an unfinished transaction). The
+    // listener is responsible for sending WindowCloseRequest again when it is
+    // ready to quit.
+    fn connect_window_close_veto<F>(&self, f : F)
+    where
+        F : Fn(()) -> bool + 'static
+    {
+        self.parent().on_window_close_veto.bind(f);
+    }
 
-                        if ix >= files.len() {
-                            eprintln!("Invalid file index at close request: {}", ix);
-                            return glib::ControlFlow::Continue;
-                        }
-                        
-                        // This force=true branch will be hit by a request from the toast button
-                        // clicked when the user wants to ignore an unsaved file. If win_close_request=true,
-                        // the action originated from a application window close. If win_close_request=false,
-                        // the action originated from a file list item close.
-                        if force {
-                            let closed_file = remove_file(&mut files, ix, &mut selected);
-                            assert!(closed_file.index == ix);
-                            last_closed_file = Some(closed_file.clone());
-                            let n = files.len();
-                            on_file_closed.call((closed_file, n));
-                            if win_close_request {
-                                on_window_close.call(());
-                            }
-                        } else {
-                            if files[ix].saved {
-                                let closed_file = remove_file(&mut files, ix, &mut selected);
-                                assert!(closed_file.index == ix);
-                                last_closed_file = Some(closed_file.clone());
-                                let n = files.len();
-                                on_file_closed.call((closed_file, n));
-                            } else {
-                                on_close_confirm.call(files[ix].clone());
-                            }
-                        }
-                        win_close_request = false;
-                        final_state.replace(FinalState { recent : recent_files.clone(), files : files.clone() });
-                    },
-                    MultiArchiverAction::SaveRequest(opt_path) => {
-                        if let Some(ix) = selected {
-                        
-                            if ix >= files.len() {
-                                eprintln!("Invalid file index after save success: {}", ix);
-                                return glib::ControlFlow::Continue;
-                            }
-                        
-                            if let Some(path) = opt_path {
-                            
-                                if let Some(pr) = &prefix {
-                                    if !path.starts_with(pr) {
-                                        send.send(MultiArchiverAction::OpenError(format!("Cannot save file outside prefix {}", pr))).unwrap();
-                                        return glib::ControlFlow::Continue;
-                                    }
-                                }
-                                
-                                for (i, f) in files.iter().enumerate() {
-                                    if let Some(other_path) = &f.path {
-                                        if ix != i && &other_path[..] == &path[..] {
-                                            send.send(MultiArchiverAction::OpenError(format!("Cannot save file to a path that is already opened"))).unwrap();
-                                            return glib::ControlFlow::Continue;
-                                        }
-                                    }
-                                }
-                                
-                                let content = on_buffer_read_request.call_with_values(ix).remove(0);
-                                if let Some(handle) = file_save_handle.take() {
-                                    handle.join().unwrap();
-                                }
-                                file_save_handle = Some(spawn_save_file(path, ix, content, send.clone()));
-                            } else {
-                                if let Some(path) = files[ix].path.clone() {
-                                
-                                    if let Some(pr) = &prefix {
-                                        if !path.starts_with(pr) {
-                                            send.send(MultiArchiverAction::OpenError(format!("Cannot save file outside prefix {}", pr))).unwrap();
-                                            return glib::ControlFlow::Continue;
-                                        }
-                                    }
-                                    
-                                    let content = on_buffer_read_request.call_with_values(ix).remove(0);
-                                    if let Some(handle) = file_save_handle.take() {
-                                        handle.join().unwrap();
-                                    }
-                                    file_save_handle = Some(spawn_save_file(path, ix, content, send.clone()));
-                                } else {
-                                    on_save_unknown_path.call(files[ix].name.clone());
-                                }
-                            }
-                        } else {
-                            eprintln!("No file selected to be saved");
-                        }
-                    },
-                    MultiArchiverAction::SaveSuccess(ix, path) => {
-                    
-                        if ix >= files.len() {
-                            eprintln!("Invalid file index after save success: {}", ix);
-                            return glib::ControlFlow::Continue;
-                        }
-                        
-                        if files[ix].name.starts_with("Untitled") {
-                            files[ix].name = path.clone();
-                            files[ix].path = Some(path.clone());
-                            on_name_changed.call((ix, path.clone()));
+    fn connect_save_unknown_path<F>(&self, f : F)
+    where
+        F : Fn(String) + 'static
+    {
+        self.parent().on_save_unknown_path.bind(f);
+    }
 
-                            if recent_files.iter().find(|f| &f.path.as_ref().unwrap()[..] == &path[..] ).is_none() {
-                                recent_files.push(files[ix].clone());
-                            }
-                        }
-                        send.send(MultiArchiverAction::SetSaved(ix, true))
-                            .unwrap_or_else(super::log_err);
-                    },
-                    MultiArchiverAction::SaveError(e) => {
-                        on_error.call(e);
-                    },
-                    MultiArchiverAction::SetSaved(ix, saved) => {
+    fn connect_buffer_read_request<F>(&self, f : F)
+    where
+        F : Fn(usize)->String + 'static
+    {
+        self.parent().on_buffer_read_request.bind(f);
+    }
 
-                        if ix >= files.len() {
-                            eprintln!("Invalid file index at set saved: {}", ix);
-                            return glib::ControlFlow::Continue;
+    // Evaluated at NewRequest with the number of the new untitled file (e.g. 2 for
+    // "Untitled 2.sql"), to produce its initial content (e.g. a header comment or a
+    // preamble), which is passed to on_new as the file content. New files are empty
+    // if nothing is connected.
+    fn connect_untitled_content<F>(&self, f : F)
+    where
+        F : Fn(usize)->String + 'static
+    {
+        assert!(self.parent().on_untitled_content.count_bounded() == 0);
+        self.parent().on_untitled_content.bind(f);
+    }
+
+    fn connect_name_changed<F>(&self, f : F)
+    where
+        F : Fn((usize, String)) + 'static
+    {
+        self.parent().on_name_changed.bind(f);
+    }
+
+    // When the user requested to open a file that is already opened by another
+    // archiver sharing the same RecentStore (e.g. at another window).
+    fn connect_opened_elsewhere<F>(&self, f : F)
+    where
+        F : Fn(PathBuf) + 'static
+    {
+        self.parent().on_opened_elsewhere.bind(f);
+    }
+
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FinalState {
+    pub recent : Vec<OpenedFile>,
+    pub files : Vec<OpenedFile>,
+
+    // Key (path or untitled name) of the selected file.
+    #[serde(default)]
+    pub selected : Option<PathBuf>
+}
+
+impl FinalState {
+
+    /// Loads a session snapshot written by the archiver (see SetSnapshot), e.g. to
+    /// restore the opened files after the application crashed. The files of the
+    /// snapshot do not carry their content.
+    pub fn load<P : AsRef<Path>>(path : P) -> Option<Self> {
+        let f = File::open(path).map_err(|e| eprintln!("Could not load session snapshot: {}", e) ).ok()?;
+        serde_json::from_reader(f).map_err(|e| eprintln!("Could not load session snapshot: {}", e) ).ok()
+    }
+
+}
+
+/// Actions processed by the MultiArchiver main loop. New actions are added as features
+/// are added to the crate, so matches on this enum outside of it need a catch-all arm
+/// (e.g. `_ => { }` at a React implementation that only intercepts a few actions).
+/// Existing variants are not changed by minor releases: an action that needs more
+/// data gets a new variant (or a struct variant with named fields) instead.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum MultiArchiverAction {
+
+    OpenRequest(PathBuf),
+
+    // Opens the file without giving it the focus (e.g. when opening all files of a
+    // folder). The opened file has its background field set, so that the on_open
+    // listeners know not to select it.
+    OpenBackgroundRequest(PathBuf),
+    
+    // Opens a file created by the application itself (e.g. a report generated at /tmp).
+    // Adopted files are not subject to the allowed roots, are never added to the recent list,
+    // and are optionally deleted when closed.
+    AdoptRequest(PathBuf, AdoptOptions),
+
+    // Opens the file like OpenRequest, passing the position to on_open (or to on_reopen, if
+    // the file is already opened) at OpenedFile::position, so that the view can scroll to it
+    // (e.g. to jump to a diagnostic or to a search result).
+    OpenAtRequest { path : PathBuf, line : usize, column : usize },
+
+    // Opens the file in view-only mode: the opened file has its read_only field set
+    // (see connect_readonly_opened), and it can only be saved at another path.
+    OpenReadOnlyRequest(PathBuf),
+
+    // Opens the file at the URI (e.g. sftp://host/notes.sql, or a file:// URI given by the
+    // document portal). Locations that are not local are opened at the local path GVfs mounts
+    // them at (see OpenedFile::uri). URIs without a local path are read and saved back with the
+    // gio backend, mounting their volume first if needed. These files have no path, and are named
+    // and keyed by their URI. They are outside any allowed root, are not watched for external
+    // changes and are not saved again later when their location cannot be reached. A volume
+    // that cannot be mounted (e.g. one that asks for a password) fails with ArchiverError::NoLocalPath.
+    OpenUriRequest(String),
+
+    OpenRelativeRequest(PathBuf),
+
+    // If true, an OpenRelativeRequest received when no root is set is resolved against
+    // the directory of the selected file (or of the last opened file), reporting
+    // ArchiverError::InferredPrefix as an internal warning, instead of failing with NoPrefix.
+    SetInferRelative(bool),
+    
+    // Limits opens, saves, renames and peeks to paths inside any of the given directories
+    // (e.g. the folders of a workspace, or a project and a system include directory). Paths
+    // outside all of them fail with ArchiverError::OutsideRoots. An empty list (the default)
+    // allows any path.
+    SetAllowedRoots(Vec<PathBuf>),
+
+    // Sets the directory relative paths are resolved against (e.g. the workspace of a
+    // language server, whose diagnostics are relative to it), which does not limit the
+    // paths that can be opened. An OpenRelativeRequest is resolved against the first of
+    // the workspace root, the allowed roots and the current directory that holds the
+    // path, or against the workspace root (or the first allowed root) if none does.
+    SetWorkspaceRoot(Option<PathBuf>),
+
+    // Sets how paths are compared when checking whether a file is already opened
+    // or already at the recent list.
+    SetCaseSensitivity(CaseSensitivity),
+
+    // Renames an untitled file (e.g. Untitled 1.sql to report.sql) before it is saved.
+    // The name must have the archiver extension. The file is still untitled (it has
+    // no path), and the name is passed to on_save_unknown_path as the suggested
+    // file name when it is saved.
+    SetName(usize, String),
+
+    // If true, the names passed to on_save_unknown_path are made valid file names
+    // (see sanitize_file_name). False by default.
+    SetSanitizeNames(bool),
+
+    // Renames (or moves) the file at the given position on disk to the given path, which
+    // must be inside the allowed roots (if set) and must not exist. The file keeps its content
+    // and saved state, and on_name_changed is called with the new path.
+    RenameRequest(usize, PathBuf),
+
+    // Sent by the rename thread. Carries the old and new paths.
+    RenameSuccess(PathBuf, PathBuf),
+
+    // Sent by the rename thread. Carries the old path.
+    RenameError(PathBuf, ArchiverError),
+
+    // Moves the file at the given position to the trash and closes it, discarding
+    // any unsaved changes (see connect_trashed).
+    TrashRequest(usize),
+
+    // Sent by the trash thread. Carries the trashed path.
+    TrashSuccess(PathBuf),
+
+    // Sent by the trash thread. Carries the path.
+    TrashError(PathBuf, ArchiverError),
+
+    // Restores a file moved to the trash by TrashRequest to its original path, and
+    // opens it again. Each token can be used once.
+    UndoTrashRequest(TrashToken),
+
+    // Sets the order in which the files are exposed (see MultiArchiver::order).
+    SetOrder(FileOrder),
+
+    // Moves the file shown at the first position to the second position, switching
+    // to the manual order (the current order becomes the initial manual order).
+    MoveFile(usize, usize),
+
+    OpenSuccess(OpenedFile),
+
+    // Represents an addition to the recent script file list (not necessarily opened).
+    Add(OpenedFile),
+
+    // Adds many files to the recent list at once (e.g. when restoring it at startup),
+    // ignoring files already in the list.
+    AddMany(Vec<OpenedFile>),
+
+    OpenError(ArchiverError),
+
+    // Sent by the open thread of the given path after OpenSuccess or OpenError.
+    OpenDone(PathBuf),
+
+    // Sent periodically by the open and save threads of files larger than a few megabytes.
+    // Carries the path, the kind of operation, the number of bytes done and the total number of bytes.
+    IoProgress(PathBuf, IoKind, u64, u64),
+
+    // Cancels the open at the given position of pending_io. A queued open is dropped, and a file
+    // being read is discarded when the read finishes, while the next queued open starts right
+    // away. Calls on_cancelled, unless the file was read already (its open then completes as usual).
+    CancelOpen(usize),
+
+    // Cancels the saves of the file at the given index that are waiting for a previous operation
+    // on the same path, calling on_cancelled for each of them. Saves that started writing are not cancelled.
+    CancelSave(usize),
+
+    // Sent by the save thread of the given path after SaveSuccess or SaveError.
+    SaveDone(PathBuf),
+
+    // Sent when all save validators accepted the content. Carries the file index
+    // and key at the save request, the saved path, the content and the checks the
+    // save thread runs against the file on disk.
+    SaveValidated(usize, PathBuf, PathBuf, String, SaveChecks),
+
+    // File position and whether the request is "forced" (i.e. asks for user confirmation).
+    CloseRequest(usize, bool),
+
+    // Opens again the last closed file that is not currently opened (untitled files
+    // cannot be reopened, and are skipped).
+    ReopenLastClosed,
+
+    // Reverts the last operation of the journal kept by the archiver, independently of
+    // the undo history of the buffers: an opened file is closed, the files closed by a
+    // CloseRequest (or by a whole CloseAllRequest, CloseOthersRequest or CloseAllExcept)
+    // are opened again, a renamed file is renamed back and a moved file goes back to
+    // its position. Untitled files are not journaled (their content is lost when they
+    // are closed), and an opened file with unsaved changes is closed only after
+    // confirmation. Operations that cannot be reverted anymore (e.g. a renamed file
+    // that was closed since) are skipped.
+    UndoLastOperation,
+
+    // Closes all files, one at a time, as if a CloseRequest (not forced) was sent for each.
+    // The sequence waits while the close of an unsaved file is being confirmed, and goes on
+    // once it is closed (a new CloseAllRequest, CloseOthersRequest or CloseAllExcept restarts it).
+    CloseAllRequest,
+
+    // Like CloseAllRequest, but keeps the file at the given position open.
+    CloseOthersRequest(usize),
+
+    // Like CloseAllRequest, but keeps the files at the given positions open (e.g. to close
+    // the saved files, or the files after a given one). The closed files are passed to a
+    // single on_files_closed call when the sequence ends, instead of one on_file_closed
+    // call for each.
+    CloseAllExcept(Vec<usize>),
+
+    // Sent to close the next file of a CloseAllRequest, CloseOthersRequest or CloseAllExcept.
+    CloseNext,
+
+    // Saves the selected file at the given path (or at its own path if None). Unless
+    // overwrite is set, a file modified on disk since it was opened (or last saved) is
+    // not overwritten, and on_save_conflict is called instead.
+    SaveRequest { path : Option<PathBuf>, overwrite : bool },
+
+    // Writes the content of the file at the given position to the given path (e.g. to
+    // export a copy), going through the save validators. Unlike a save at another path,
+    // the file keeps its own path and saved state. The path cannot be the path of an
+    // opened file.
+    SaveCopyRequest(usize, PathBuf),
+
+    // Sent when the save validators accepted the content of a SaveCopyRequest. Carries the
+    // file index and key at the request, the path of the copy and the content.
+    CopyValidated(usize, PathBuf, PathBuf, String),
+
+    // Carries the file index and the path of the copy after it was written.
+    CopySaved(usize, PathBuf),
+
+    // Saves every file with unsaved changes that has a path. The saves run concurrently
+    // (without the save timeout), and on_file_persisted is called for each saved file.
+    SaveAllRequest,
+
+    // Sent after the last save started by SaveAllRequest is done.
+    SaveAllDone,
+
+    // Sent when a save validator rejected the content. Carries the path and the message.
+    SaveRejected(PathBuf, String),
+
+    // Sent by the save thread instead of writing the file. Carries the file index, the
+    // path, the modification time known by the archiver and the one found on disk.
+    SaveConflict(usize, PathBuf, SystemTime, SystemTime),
+
+    // Sent by the save thread instead of writing a content much shorter than the file
+    // on disk. Carries the file index, the path, and the lengths on disk and of the content.
+    SuspiciousSave(usize, PathBuf, u64, u64),
+
+    SaveSuccess(usize, PathBuf, SaveRecord),
+
+    // Sent by the save thread before SaveSuccess. Carries the saved path and the backup path.
+    BackupCreated(PathBuf, PathBuf),
+
+    SaveError(ArchiverError),
+
+    NewRequest,
+
+    WindowCloseRequest,
+
+    SetSaved(usize, bool),
+
+    Select(Option<usize>),
+
+    // Sent by a shared RecentStore when another archiver added a file to the recent list.
+    SyncRecent(OpenedFile),
+
+    // Removes the path from the recent list (e.g. from a "Remove from list" item at the recent
+    // menu). Opened files are kept opened. If the archiver shares a RecentStore, the path is
+    // removed from the store and from the recent lists of the other archivers as well.
+    RemoveRecent(String),
+
+    // Removes all files from the recent list (and from the shared RecentStore, like RemoveRecent).
+    ClearRecent,
+
+    // Checks whether each file at the recent list still exists in a separate thread, calling
+    // on_recent_validated with the ones that do (sent by StartupState::restore_multi). If
+    // remove_missing is set, the missing files are also removed like RemoveRecent. Files
+    // whose existence cannot be checked (e.g. without permission) are kept.
+    ValidateRecent { remove_missing : bool },
+
+    // Sent by the validation thread with the missing paths.
+    RecentValidated { missing : Vec<PathBuf>, remove_missing : bool },
+
+    // If set, a dirty file with a known path is saved after it receives no changes
+    // (SetSaved(_, false)) for the given interval (e.g. when the user pauses typing).
+    SetIdleAutosave(Option<Duration>),
+
+    // Sent when the save timeout of the save of the given path and generation elapses.
+    SaveTimeout(PathBuf, u64),
+
+    // Sent by the save thread instead of SaveError when the location of the file cannot be
+    // reached (see connect_offline). Carries the index of the file, the saved path, the
+    // content and the checks of the save.
+    SaveOffline(usize, PathBuf, String, SaveChecks),
+
+    // Sent periodically while saves are queued by SaveOffline, to check whether their
+    // locations are back.
+    CheckOnline,
+
+    // Sent by CheckOnline when the location of a queued save can be reached again.
+    BackOnline(PathBuf),
+
+    // Sent by CheckOnline when the location of a queued save still cannot be reached.
+    StillOffline(PathBuf),
+
+    // Sent when the idle interval of the file at the given path elapses. Carries the
+    // generation of the change that scheduled it, so that stale timeouts are ignored.
+    IdleTimeout(PathBuf, u64),
+
+    // Enables (or disables, with None) the periodic autosave.
+    SetAutosave(Option<ArchiverAutosave>),
+
+    // Sent when the autosave interval elapses. Carries the generation of the
+    // SetAutosave that scheduled it.
+    AutosaveTimeout(u64),
+
+    // Sent by the thread writing a recovery file.
+    RecoveryWritten(Result<AutosaveRecord, String>),
+
+    // Sent by the thread scanning the recovery directory.
+    RecoveriesFound(Vec<PendingRecovery>),
+
+    // Opens the file with the given source (see PendingRecovery) with the content of its
+    // recovery file, as a file with unsaved changes.
+    RecoverRequest(PathBuf),
+
+    // Removes the recovery file of the given source without restoring it.
+    DiscardRecovery(PathBuf),
+
+    // Opens the recovery files of all untitled files left by a previous session (see
+    // pending_recoveries) as new untitled files with unsaved changes. Drafts beyond the
+    // file limit are left pending.
+    AdoptDrafts,
+
+    // If set, the session (opened and recent files, without content) is written to the
+    // JSON file at the given path at every interval, so that the list of opened files
+    // survives a crash. The file is only rewritten if the session changed.
+    SetSnapshot(Option<(PathBuf, Duration)>),
+
+    // Sent when the snapshot interval elapses. Carries the generation of the
+    // SetSnapshot that scheduled it.
+    SnapshotTimeout(u64),
+
+    // Restores a saved session (e.g. loaded with FinalState::load): adds its recent files
+    // and opens its files in the background. The previously selected file is selected
+    // after the last of them is opened, with a single on_selected call.
+    RestoreSession(FinalState),
+
+    // Writes the session (as restored by RestoreSession) and the unsaved content of its
+    // files, pulled through on_buffer_read_request, to a single tar archive at the path
+    // (e.g. to move the working set to another machine, or to attach it to a bug report).
+    // Failures are reported like save errors.
+    ExportSessionArchive(PathBuf),
+
+    // Sent by the export thread after the archive is written.
+    SessionExported(PathBuf),
+
+    // Restores a session written by ExportSessionArchive like RestoreSession. The files
+    // that had unsaved content are opened with it instead (as if recovered with RecoverRequest,
+    // see OpenedFile::recovered), and untitled ones are numbered after the untitled files of
+    // this session. Failures are reported like open errors.
+    ImportSessionArchive(PathBuf),
+
+    // Sent by the import thread with the session and its files with unsaved content.
+    SessionImported(FinalState, Vec<OpenedFile>),
+
+    // Reads the size and modification time of all files at the recent list in a single
+    // separate thread (e.g. when the recent panel is shown), so that list item factories do
+    // not query each file from the main thread. Requests sent while a prefetch is running
+    // are ignored.
+    PrefetchRecentMetadata,
+
+    // Sent by the prefetch thread, to be forwarded to on_recent_metadata.
+    RecentMetadata(Vec<RecentMeta>),
+
+    // Sent after the opens of a RestoreSession are queued, and after each of them is done.
+    RestoreQueued,
+
+    // Sent by the resolve thread with an open request (OpenRequest, OpenBackgroundRequest,
+    // OpenReadOnlyRequest, OpenUriRequest or AdoptRequest) and its resolved path. The paths
+    // of requests are resolved away from the main thread, since this might block on network
+    // mounts, and one at a time, so that requests are still handled in the order they were sent.
+    OpenResolved(Box<MultiArchiverAction>, ResolvedPath),
+
+    // Sent by the resolve thread after OpenResolved (or after it panicked), with the path
+    // as given at the request.
+    OpenSettled(PathBuf),
+
+    // Sent by the resolve thread with the key of the file to be saved at the resolved
+    // path (see SaveRequest).
+    SaveResolved { key : PathBuf, overwrite : bool, resolved : ResolvedPath },
+
+    // Sent by the resolve thread with the key of the file to be copied to the resolved
+    // path (see SaveCopyRequest).
+    SaveCopyResolved(PathBuf, ResolvedPath),
+
+    // Sent by the resolve thread after PeekRequest.
+    PeekResolved(ResolvedPath),
+
+    // Sent by the resolve thread with the path of the file to be renamed to the resolved
+    // path (see RenameRequest).
+    RenameResolved(PathBuf, ResolvedPath),
+
+    // Sent by the resolve thread with the generation of the allowed roots (see SetAllowedRoots)
+    // and their canonical forms, with whether each of them is compared ignoring case.
+    RootsResolved(u64, Vec<(PathBuf, bool)>),
+
+    // Sent by the resolve thread after ResumeWatching, with the files (without content)
+    // whose modification time on disk changed, with that time, and the files deleted.
+    WatchingRescanned(Vec<(OpenedFile, SystemTime)>, Vec<OpenedFile>),
+
+    // Sent by the application when the text of the active editor changes (or None
+    // when no editor is active), to be forwarded to on_active_text_changed.
+    SetActiveText(Option<String>),
+
+    // Reads the start of the file at the given path, without opening it.
+    PeekRequest(PathBuf),
+
+    // Carries path and content read after PeekRequest.
+    PeekSuccess(PathBuf, String),
+
+    // Reads the bytes of the file at the given position within the range (e.g. the chunks
+    // after the preview of a truncated file). Offsets refer to the decompressed content of
+    // gzip files. The range is narrowed to whole UTF-8 characters.
+    ReadRangeRequest(usize, Range<u64>),
+
+    // Carries the file index and key at the request, the range of the bytes read and their content.
+    RangeRead(usize, PathBuf, Range<u64>, String),
+
+    // Sent by the monitor of an opened file when it is modified or created
+    // (e.g. replaced by another program).
+    ExternalChange(PathBuf),
+
+    // Sent by the monitor of an opened file when it is deleted or moved away.
+    ExternalDeletion(PathBuf),
+
+    // Stops reporting external changes and deletions of all files (e.g. while the application
+    // checks out a branch or generates code). Pauses nest: reporting resumes after as many
+    // ResumeWatching as PauseWatching were sent.
+    PauseWatching,
+
+    // Resumes reporting external changes. The opened files are then checked against the disk
+    // once, and the files changed or deleted meanwhile are passed to a single on_watching_resumed call.
+    ResumeWatching,
+
+    // Stops (true) or resumes (false) reporting the external changes and deletions of the
+    // file at the given position (e.g. a build output kept open for viewing, which is
+    // regenerated on purpose). The file is still watched. Muting ends when the file is closed.
+    MuteExternalChanges(usize, bool),
+
+    // Re-reads the file at the given position from disk, discarding unsaved changes.
+    ReloadRequest(usize),
+
+    // Carries the file read after ReloadRequest.
+    ReloadSuccess(OpenedFile),
+
+    // Sent by the reload thread of the given path after ReloadSuccess or OpenError.
+    ReloadDone(PathBuf),
+
+    BeginBulk,
+
+    EndBulk,
+
+}
+
+pub struct MultiArchiver {
+
+    final_state : Rc<RefCell<FinalState>>,
+
+    // Saves done during this session, for each file path.
+    save_history : Rc<RefCell<HashMap<PathBuf, Vec<SaveRecord>>>>,
+
+    save_options : Rc<RefCell<SaveOptions>>,
+
+    // See set_truncation_ratio.
+    truncation_ratio : Rc<Cell<Option<f64>>>,
+
+    // See set_max_file_size and set_streaming_preview.
+    open_limits : Rc<Cell<OpenLimits>>,
+
+    special_file_filter : SpecialFileFilter,
+
+    io_backend : Rc<Cell<IoBackend>>,
+
+    // See set_register_recent.
+    system_recent : Rc<RefCell<SystemRecent>>,
+
+    // See SetWorkspaceRoot and SetAllowedRoots.
+    workspace_root : Rc<RefCell<Option<PathBuf>>>,
+
+    allowed_roots : Rc<RefCell<Vec<PathBuf>>>,
+
+    on_range_read : Callbacks<(OpenedFile, Range<u64>, String)>,
+
+    on_watching_resumed : Callbacks<(Vec<OpenedFile>, Vec<OpenedFile>)>,
+
+    on_cancelled : Callbacks<(PathBuf, IoKind)>,
+
+    on_io_progress : Callbacks<(PathBuf, u64, u64)>,
+
+    view : Rc<RefCell<View>>,
+
+    // See file_model.
+    file_model : gio::ListStore,
+
+    io_queue : IoQueue,
+
+    save_validators : Rc<RefCell<Vec<SaveValidator>>>,
+
+    send : glib::Sender<MultiArchiverAction>,
+
+    on_open : Callbacks<OpenedFile>,
+
+    on_user_error : Callbacks<ArchiverError>,
+
+    on_internal_warning : Callbacks<ArchiverError>,
+
+    on_reopen : Callbacks<OpenedFile>,
+
+    on_readonly_opened : Callbacks<OpenedFile>,
+
+    on_encoding_detected : Callbacks<OpenedFile>,
+
+    on_reopened_closed : Callbacks<OpenedFile>,
+
+    on_session_exported : Callbacks<PathBuf>,
+
+    on_recent_metadata : Callbacks<Vec<RecentMeta>>,
+
+    on_trashed : Callbacks<(OpenedFile, TrashToken)>,
+
+    on_save_unknown_path : Callbacks<String>,
+
+    on_file_changed : Callbacks<OpenedFile>,
+
+    on_file_persisted : Callbacks<OpenedFile>,
+
+    on_active_text_changed : Callbacks<Option<String>>,
+
+    // When user clicks new action
+    on_new : Callbacks<OpenedFile>,
+
+    // Contains the index of the old closed file and the number of remaining files.
+    on_file_closed : Callbacks<(OpenedFile, usize)>,
+
+    on_files_closed : Callbacks<Vec<OpenedFile>>,
+
+    on_close_confirm : Callbacks<OpenedFile>,
+
+    on_window_close : Callbacks<()>,
+
+    on_window_close_veto : ValuedCallbacks<(), bool>,
+
+    on_buffer_read_request : ValuedCallbacks<usize, String>,
+
+    on_untitled_content : ValuedCallbacks<usize, String>,
+
+    on_selected : Callbacks<Option<OpenedFile>>,
+
+    // Called when file goes from untitled to having a name (or when an untitled file
+    // is renamed with SetName, or an opened file with RenameRequest).
+    on_name_changed : Callbacks<(usize, String)>,
+
+    // When the user state is being updated
+    on_added : Callbacks<OpenedFile>,
+
+    on_added_many : Callbacks<Vec<OpenedFile>>,
+
+    on_recent_changed : Callbacks<Vec<OpenedFile>>,
+
+    on_recent_validated : Callbacks<Vec<OpenedFile>>,
+
+    on_opened_elsewhere : Callbacks<PathBuf>,
+
+    on_io_stalled : Callbacks<PathBuf>,
+
+    on_offline : Callbacks<PathBuf>,
+
+    on_back_online : Callbacks<PathBuf>,
+
+    on_autosaved : Callbacks<AutosaveRecord>,
+
+    on_backup_created : Callbacks<(PathBuf, PathBuf)>,
+
+    on_copy_saved : Callbacks<(OpenedFile, PathBuf)>,
+
+    on_save_conflict : Callbacks<(OpenedFile, SystemTime, SystemTime)>,
+
+    on_suspicious_save : Callbacks<(OpenedFile, u64, u64)>,
+
+    on_all_saved : Callbacks<Vec<OpenedFile>>,
+
+    on_recoveries_found : Callbacks<Vec<PendingRecovery>>,
+
+    // Recovery files left by a previous session, not restored or discarded yet.
+    recoveries : Rc<RefCell<Vec<PendingRecovery>>>,
+
+    on_bulk_done : Callbacks<BulkSummary>,
+
+    on_peek : Callbacks<(PathBuf, String)>,
+
+    on_open_filter : ValuedCallbacks<PathBuf, Result<(), String>>,
+
+    on_capacity_changed : Callbacks<usize>,
+
+    on_order_changed : Callbacks<Vec<usize>>,
+
+    on_externally_changed : Callbacks<OpenedFile>,
+
+    on_externally_deleted : Callbacks<OpenedFile>,
+
+    on_reloaded : Callbacks<OpenedFile>
+
+}
+
+/// Options of a file opened with AdoptRequest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct AdoptOptions {
+
+    // Whether the file is deleted when it is closed (or when the window is closed).
+    pub delete_on_close : bool
+
+}
+
+/// The per-file events suppressed between begin_bulk and end_bulk.
+#[derive(Debug, Clone, Default)]
+pub struct BulkSummary {
+
+    // Opened files (with their content, as they would be passed to on_open).
+    pub opened : Vec<OpenedFile>,
+
+    // Files added to the recent list.
+    pub added : Vec<OpenedFile>,
+
+    pub closed : Vec<OpenedFile>
+
+}
+
+// Some SQL files (e.g. generated by pg_dump) are too big for gtksourceview.
+// Limiting the file size prevents the application from freezing.
+// Default size limit, in bytes (see set_max_file_size).
+const MAX_FILE_SIZE : u64 = 5_000_000;
+
+// Size limits applied by the open threads.
+#[derive(Debug, Clone, Copy)]
+struct OpenLimits {
+
+    max_size : u64,
+
+    // Bytes read from files over max_size, if they are opened truncated
+    // instead of rejected (see set_streaming_preview).
+    preview : Option<u64>,
+
+    // Whether the path is opened even if it is not a regular file (see
+    // set_special_file_filter). Set for each open.
+    allow_special : bool
+
+}
+
+type SpecialFileFilter = Rc<RefCell<Option<Box<dyn Fn(&Path) -> bool>>>>;
+
+// The limits of an open of the path, allowing special files if the filter accepts it.
+fn limits_for(limits : &Cell<OpenLimits>, filter : &SpecialFileFilter, path : &Path) -> OpenLimits {
+    let allow_special = filter.borrow().as_ref().map(|f| f(path) ).unwrap_or(false);
+    OpenLimits { allow_special, ..limits.get() }
+}
+
+impl Default for OpenLimits {
+
+    fn default() -> Self {
+        Self { max_size : MAX_FILE_SIZE, preview : None, allow_special : false }
+    }
+
+}
+
+// Operation kept for UndoLastOperation.
+#[derive(Debug, Clone)]
+enum Operation {
+
+    // Path of an opened file.
+    Opened(PathBuf),
+
+    // Paths of the files closed by a single request or close sequence.
+    Closed(Vec<PathBuf>),
+
+    // Old and new path of a renamed file.
+    Renamed(PathBuf, PathBuf),
+
+    // Key of a file moved by MoveFile and its previous position.
+    Moved(PathBuf, usize)
+
+}
+
+// Default file limit (see MultiArchiver::with_limit).
+const MAX_NUM_FILES : usize = 16;
+
+// Maximum number of bytes read by PeekRequest.
+pub const MAX_PEEK_SIZE : usize = 64_000;
+
+// Number of saves kept at the history of each file.
+const MAX_SAVE_HISTORY : usize = 16;
+
+// Number of closed files kept for ReopenLastClosed.
+const MAX_CLOSED_HISTORY : usize = 16;
+
+// Interval between the checks of the locations of the saves queued by SaveOffline.
+const ONLINE_CHECK_INTERVAL : Duration = Duration::from_secs(5);
+
+// Number of operations kept for UndoLastOperation.
+const MAX_JOURNAL_LEN : usize = 64;
+
+// Files smaller than this (in bytes) are overwritten without the truncation check,
+// since deleting most of a small file is usually intentional.
+const MIN_TRUNCATION_CHECK_LEN : u64 = 1024;
+
+/// Identifies a file moved to the trash by TrashRequest (see UndoTrashRequest).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TrashToken(u64);
+
+/// The checks the save thread runs against the file on disk before overwriting
+/// it (all of them are skipped by a SaveRequest with overwrite set).
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct SaveChecks {
+
+    // Modification time the file on disk is expected to have, if it should be checked.
+    pub expected : Option<SystemTime>,
+
+    // If set, the save is held when the content is shorter than this fraction of the file on disk.
+    pub truncation : Option<f64>
+
+}
+
+/// A path given at a request, as resolved by the archiver (see MultiArchiverAction::OpenResolved).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedPath {
+
+    // The path as given at the request.
+    pub requested : PathBuf,
+
+    // Canonical form of the path (see canonicalize_path). The paths to be written keep
+    // their last component, so that a link at the path is handled by SymlinkPolicy.
+    pub path : PathBuf,
+
+    // Whether the path is compared ignoring case (see CaseSensitivity).
+    pub ignore_case : bool
+
+}
+
+/// Describes a single save of a file during the current session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SaveRecord {
+
+    pub dt : SystemTime,
+
+    // Size of the written content, in bytes.
+    pub size : usize,
+
+    // Hash of the written content. This is only meaningful for comparison
+    // with other records of the same session.
+    pub checksum : u64,
+
+    // Modification time of the file right after it was written.
+    pub mtime : Option<SystemTime>
+
+}
+
+impl SaveRecord {
+
+    fn build(content : &str, mtime : Option<SystemTime>) -> Self {
+        let mut hasher = DefaultHasher::new();
+        content.hash(&mut hasher);
+        Self { dt : SystemTime::now(), size : content.len(), checksum : hasher.finish(), mtime }
+    }
+
+}
+
+impl MultiArchiver {
+
+    pub fn final_state(&self) -> FinalState {
+        self.final_state.borrow().clone()
+    }
+
+    /// The recovery files found at the recovery directory (see connect_recoveries_found)
+    /// that were not restored or discarded yet.
+    pub fn pending_recoveries(&self) -> Vec<PendingRecovery> {
+        self.recoveries.borrow().clone()
+    }
+
+    /// The open and save operations requested and not finished yet (including
+    /// opens waiting for the previous open to finish), identified by the path.
+    pub fn pending_io(&self) -> Vec<(PathBuf, IoKind)> {
+        self.io_queue.pending.borrow().clone()
+    }
+
+    /// Counters of the opens and saves done during this session.
+    pub fn metrics(&self) -> ArchiverMetrics {
+        self.io_queue.metrics.borrow().clone()
+    }
+
+    /// Number of opened files.
+    pub fn len(&self) -> usize {
+        self.view.borrow().files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Maximum number of opened files.
+    pub fn capacity(&self) -> usize {
+        self.view.borrow().capacity
+    }
+
+    /// How many more files can be opened (or created) before the file limit is
+    /// reached. Files still being opened are not taken into account.
+    pub fn remaining_slots(&self) -> usize {
+        self.capacity().saturating_sub(self.len())
+    }
+
+    pub fn selected_index(&self) -> Option<usize> {
+        self.view.borrow().selected
+    }
+
+    /// Indices of the opened files, in the order set with SetOrder. File indices
+    /// (used by all actions) keep the open order, whatever the exposed order.
+    pub fn order(&self) -> Vec<usize> {
+        self.view.borrow().order.clone()
+    }
+
+    /// The opened files (without their content), in the order set with SetOrder.
+    pub fn ordered_files(&self) -> Vec<OpenedFile> {
+        let view = self.view.borrow();
+        view.order.iter().map(|ix| view.files[*ix].clone() ).collect()
+    }
+
+    /// The opened files as a list of FileEntry objects, in the order set with SetOrder,
+    /// kept in sync by the archiver (e.g. to be shown by a gtk::ListView at a sidebar).
+    pub fn file_model(&self) -> gio::ListModel {
+        self.file_model.clone().upcast()
+    }
+
+    /// Returns the selected file (without its content).
+    pub fn selected_file(&self) -> Option<OpenedFile> {
+        let view = self.view.borrow();
+        view.selected.and_then(|ix| view.files.get(ix).cloned() )
+    }
+
+    /// The opened files (without their content) with unsaved changes, including
+    /// untitled files, in the open order.
+    pub fn dirty_files(&self) -> Vec<OpenedFile> {
+        self.view.borrow().files.iter().filter(|f| !f.saved ).cloned().collect()
+    }
+
+    /// Calls f with each opened file (without its content) with unsaved changes, in the
+    /// open order.
+    pub fn for_each_dirty<F>(&self, f : F)
+    where
+        F : Fn(&OpenedFile)
+    {
+        for file in self.view.borrow().files.iter().filter(|f| !f.saved ) {
+            f(file);
+        }
+    }
+
+    /// Returns the saves done to the given path during this session, from the oldest
+    /// to the most recent (only the last few saves are kept).
+    pub fn save_history<P : AsRef<Path>>(&self, path : P) -> Vec<SaveRecord> {
+        self.save_history.borrow().get(path.as_ref()).cloned().unwrap_or_default()
+    }
+
+    pub fn sender(&self) -> &glib::Sender<MultiArchiverAction> {
+        &self.send
+    }
+
+    pub fn new(extension : String) -> Self {
+        Self::build(extension, None, MAX_NUM_FILES)
+    }
+
+    /// Builds an archiver that accepts up to limit opened files (instead of the
+    /// default 16). Use usize::MAX to remove the limit.
+    pub fn with_limit(extension : String, limit : usize) -> Self {
+        Self::build(extension, None, limit)
+    }
+
+    /// Builds an archiver that shares its recent file list with all other archivers
+    /// built with the same store (e.g. one archiver per application window).
+    pub fn with_recent_store(extension : String, store : &RecentStore) -> Self {
+        Self::build(extension, Some(store.clone()), MAX_NUM_FILES)
+    }
+
+    fn build(extension : String, recent_store : Option<RecentStore>, limit : usize) -> Self {
+        let final_state = Rc::new(RefCell::new(FinalState { recent : Vec::new(), files : Vec::new(), selected : None }));
+        let save_history : Rc<RefCell<HashMap<PathBuf, Vec<SaveRecord>>>> = Default::default();
+        let save_options : Rc<RefCell<SaveOptions>> = Default::default();
+        let truncation_ratio : Rc<Cell<Option<f64>>> = Default::default();
+        let open_limits : Rc<Cell<OpenLimits>> = Default::default();
+        let special_file_filter : SpecialFileFilter = Default::default();
+        let io_backend : Rc<Cell<IoBackend>> = Default::default();
+        let system_recent : Rc<RefCell<SystemRecent>> = Default::default();
+        let workspace_root : Rc<RefCell<Option<PathBuf>>> = Default::default();
+        let allowed_roots : Rc<RefCell<Vec<PathBuf>>> = Default::default();
+        let on_range_read : Callbacks<(OpenedFile, Range<u64>, String)> = Default::default();
+        let on_watching_resumed : Callbacks<(Vec<OpenedFile>, Vec<OpenedFile>)> = Default::default();
+        let on_cancelled : Callbacks<(PathBuf, IoKind)> = Default::default();
+        let on_io_progress : Callbacks<(PathBuf, u64, u64)> = Default::default();
+        let view = Rc::new(RefCell::new(View { capacity : limit, ..Default::default() }));
+        let file_model = gio::ListStore::new::<FileEntry>();
+        let io_queue = IoQueue::default();
+        let save_validators : Rc<RefCell<Vec<SaveValidator>>> = Default::default();
+        let (send, recv) = glib::MainContext::channel::<MultiArchiverAction>(glib::source::Priority::DEFAULT);
+        let on_open : Callbacks<OpenedFile> = Default::default();
+        let on_new : Callbacks<OpenedFile> = Default::default();
+        let on_file_changed : Callbacks<OpenedFile> = Default::default();
+        let on_file_persisted : Callbacks<OpenedFile> = Default::default();
+        let on_reopen : Callbacks<OpenedFile> = Default::default();
+        let on_readonly_opened : Callbacks<OpenedFile> = Default::default();
+        let on_encoding_detected : Callbacks<OpenedFile> = Default::default();
+        let on_reopened_closed : Callbacks<OpenedFile> = Default::default();
+        let on_session_exported : Callbacks<PathBuf> = Default::default();
+        let on_recent_metadata : Callbacks<Vec<RecentMeta>> = Default::default();
+        let on_trashed : Callbacks<(OpenedFile, TrashToken)> = Default::default();
+        let on_selected : Callbacks<Option<OpenedFile>> = Default::default();
+        let on_file_closed : Callbacks<(OpenedFile, usize)> = Default::default();
+        let on_files_closed : Callbacks<Vec<OpenedFile>> = Default::default();
+        let on_active_text_changed : Callbacks<Option<String>> = Default::default();
+        let on_close_confirm : Callbacks<OpenedFile> = Default::default();
+        let on_window_close : Callbacks<()> = Default::default();
+        let on_window_close_veto : ValuedCallbacks<(), bool> = Default::default();
+        let on_save_unknown_path : Callbacks<String> = Default::default();
+        let on_buffer_read_request : ValuedCallbacks<usize, String> = Default::default();
+        let on_untitled_content : ValuedCallbacks<usize, String> = Default::default();
+        let on_name_changed : Callbacks<(usize, String)> = Default::default();
+        let on_user_error : Callbacks<ArchiverError> = Default::default();
+        let on_internal_warning : Callbacks<ArchiverError> = Default::default();
+        let on_added : Callbacks<OpenedFile> = Default::default();
+        let on_added_many : Callbacks<Vec<OpenedFile>> = Default::default();
+        let on_recent_changed : Callbacks<Vec<OpenedFile>> = Default::default();
+        let on_recent_validated : Callbacks<Vec<OpenedFile>> = Default::default();
+        let on_opened_elsewhere : Callbacks<PathBuf> = Default::default();
+        let on_io_stalled : Callbacks<PathBuf> = Default::default();
+        let on_offline : Callbacks<PathBuf> = Default::default();
+        let on_back_online : Callbacks<PathBuf> = Default::default();
+        let on_autosaved : Callbacks<AutosaveRecord> = Default::default();
+        let on_backup_created : Callbacks<(PathBuf, PathBuf)> = Default::default();
+        let on_copy_saved : Callbacks<(OpenedFile, PathBuf)> = Default::default();
+        let on_save_conflict : Callbacks<(OpenedFile, SystemTime, SystemTime)> = Default::default();
+        let on_suspicious_save : Callbacks<(OpenedFile, u64, u64)> = Default::default();
+        let on_all_saved : Callbacks<Vec<OpenedFile>> = Default::default();
+        let on_recoveries_found : Callbacks<Vec<PendingRecovery>> = Default::default();
+        let recoveries : Rc<RefCell<Vec<PendingRecovery>>> = Default::default();
+        let on_bulk_done : Callbacks<BulkSummary> = Default::default();
+        let on_peek : Callbacks<(PathBuf, String)> = Default::default();
+        let on_open_filter : ValuedCallbacks<PathBuf, Result<(), String>> = Default::default();
+        let on_capacity_changed : Callbacks<usize> = Default::default();
+        let on_order_changed : Callbacks<Vec<usize>> = Default::default();
+        let on_externally_changed : Callbacks<OpenedFile> = Default::default();
+        let on_externally_deleted : Callbacks<OpenedFile> = Default::default();
+        let on_reloaded : Callbacks<OpenedFile> = Default::default();
+
+        // Holds the files opened at the editor the user sees on the side panel
+        let mut files : Vec<OpenedFile> = Vec::new();
+
+        // Holds the files shown on the recent script list before the editor is opened. The files
+        // are loaded on startup. If the user saves or opens any files not already on this list,
+        // the list is updated. This list is sent to the final_state just before the application
+        // closes.
+        let mut recent_files : Vec<OpenedFile> = Vec::new();
+
+        let mut selected : Option<usize> = None;
+
+        let mut win_close_request = false;
+        let store_id = recent_store.as_ref().map(|store| store.register(send.clone()) ).unwrap_or(0);
+        recv.attach(None, {
+            let send = send.clone();
+            let (on_open, on_new, on_selected, on_file_closed, on_close_confirm, on_file_changed, on_file_persisted, on_reopen) = (
+                on_open.clone(),
+                on_new.clone(),
+                on_selected.clone(),
+                on_file_closed.clone(),
+                on_close_confirm.clone(),
+                on_file_changed.clone(),
+                on_file_persisted.clone(),
+                on_reopen.clone()
+            );
+            let (on_active_text_changed, on_window_close, on_buffer_read_request, on_save_unknown_path) = (
+                on_active_text_changed.clone(),
+                on_window_close.clone(),
+                on_buffer_read_request.clone(),
+                on_save_unknown_path.clone()
+            );
+            let on_window_close_veto = on_window_close_veto.clone();
+            let on_added = on_added.clone();
+            let on_added_many = on_added_many.clone();
+            let on_recent_changed = on_recent_changed.clone();
+            let on_recent_validated = on_recent_validated.clone();
+            let on_name_changed = on_name_changed.clone();
+            let on_user_error = on_user_error.clone();
+            let on_internal_warning = on_internal_warning.clone();
+            let on_opened_elsewhere = on_opened_elsewhere.clone();
+            let on_io_stalled = on_io_stalled.clone();
+            let on_offline = on_offline.clone();
+            let on_back_online = on_back_online.clone();
+            let on_autosaved = on_autosaved.clone();
+            let on_backup_created = on_backup_created.clone();
+            let on_copy_saved = on_copy_saved.clone();
+            let on_save_conflict = on_save_conflict.clone();
+            let on_suspicious_save = on_suspicious_save.clone();
+            let on_all_saved = on_all_saved.clone();
+            let on_recoveries_found = on_recoveries_found.clone();
+            let recoveries = recoveries.clone();
+            let on_bulk_done = on_bulk_done.clone();
+            let on_peek = on_peek.clone();
+            let on_open_filter = on_open_filter.clone();
+            let on_externally_changed = on_externally_changed.clone();
+            let on_externally_deleted = on_externally_deleted.clone();
+            let on_reloaded = on_reloaded.clone();
+            let on_reopened_closed = on_reopened_closed.clone();
+            let on_session_exported = on_session_exported.clone();
+            let on_recent_metadata = on_recent_metadata.clone();
+            let on_files_closed = on_files_closed.clone();
+            let on_readonly_opened = on_readonly_opened.clone();
+            let on_encoding_detected = on_encoding_detected.clone();
+            let on_trashed = on_trashed.clone();
+            let on_untitled_content = on_untitled_content.clone();
+            let listeners = ViewListeners {
+                on_capacity_changed : on_capacity_changed.clone(),
+                on_order_changed : on_order_changed.clone(),
+                file_model : file_model.clone()
+            };
+
+            // OpenRequests received while another file is being opened (path and whether
+            // to open in background). Files are opened one at a time, in request order.
+            let mut open_queue : VecDeque<(PathBuf, bool)> = VecDeque::new();
+            let mut opening = false;
+
+            // Path and cancellation token of the file being opened.
+            let mut open_task : Option<(PathBuf, CancelToken)> = None;
+
+            // Index of the saved file and cancellation token of the saves in flight, by saved path.
+            let mut save_tokens : HashMap<PathBuf, (usize, CancelToken)> = HashMap::new();
+
+            // Paths of the adopted files (see AdoptRequest).
+            let mut adopted : HashMap<PathBuf, AdoptOptions> = HashMap::new();
+
+            // Paths last requested with OpenReadOnlyRequest.
+            let mut view_only : HashSet<PathBuf> = HashSet::new();
+            let mut save_task = IoTask::default();
+
+            // Monitors of the paths of the opened files (dropping a monitor stops it).
+            let mut monitors : HashMap<PathBuf, gio::FileMonitor> = HashMap::new();
+
+            // Modification time of the last external change passed to on_externally_changed
+            // for each path, since a single change might be reported more than once.
+            let mut reported_changes : HashMap<PathBuf, SystemTime> = HashMap::new();
+
+            // Paths of the opened files whose external changes are not reported (see MuteExternalChanges).
+            let mut muted : HashSet<PathBuf> = HashSet::new();
+            let mut watch_pauses : usize = 0;
+
+            let mut last_closed_file : Option<OpenedFile> = None;
+
+            // Files closed during this session (the last closed at the end), and the
+            // paths being reopened by ReopenLastClosed.
+            let mut closed_history : Vec<OpenedFile> = Vec::new();
+            let mut reopening : Vec<PathBuf> = Vec::new();
+
+            // Paths still being opened by RestoreSession, and the key of the file to select
+            // once they are done (the outer option is set while a restore is pending).
+            let mut restoring : Vec<PathBuf> = Vec::new();
+            let mut pending_selection : Option<Option<PathBuf>> = None;
+
+            // Positions given by OpenAtRequest, by the path being opened.
+            let mut positions : HashMap<PathBuf, TextPosition> = HashMap::new();
+
+            // Operations that UndoLastOperation can revert (the last at the end), the paths
+            // closed by the current close sequence, the paths being opened, closed or renamed
+            // by UndoLastOperation (which are not journaled) and whether the next MoveFile
+            // was sent by it.
+            let mut journal : Vec<Operation> = Vec::new();
+            let mut close_group : Option<Vec<PathBuf>> = None;
+            let mut undoing : Vec<PathBuf> = Vec::new();
+            let mut undoing_move = false;
+
+            // Paths of the files moved to the trash during this session, by undo token.
+            let mut trashed : HashMap<TrashToken, PathBuf> = HashMap::new();
+            let mut next_trash_token : u64 = 0;
+            let final_state = final_state.clone();
+            let save_history = save_history.clone();
+            let save_options = save_options.clone();
+            let truncation_ratio = truncation_ratio.clone();
+            let open_limits = open_limits.clone();
+            let special_file_filter = special_file_filter.clone();
+            let io_backend = io_backend.clone();
+            let system_recent = system_recent.clone();
+            let workspace_root = workspace_root.clone();
+            let on_range_read = on_range_read.clone();
+            let on_watching_resumed = on_watching_resumed.clone();
+            let on_cancelled = on_cancelled.clone();
+            let on_io_progress = on_io_progress.clone();
+            let view = view.clone();
+            let io_queue = io_queue.clone();
+            let save_validators = save_validators.clone();
+            
+            // If not empty, any file operations are only done if the path is inside
+            // one of these directories (e.g. multiarchiver does not touch anything outside
+            // /home/user/myproject if it is the only root).
+            let roots = allowed_roots.clone();
+
+            let mut case_sensitivity = CaseSensitivity::default();
+
+            // Canonical forms of the roots, and whether each of them is compared ignoring case.
+            // They are resolved by the resolve thread after SetAllowedRoots, and only the
+            // result for the last generation is kept.
+            let mut canonical_roots : Vec<(PathBuf, bool)> = Vec::new();
+            let mut roots_generation : u64 = 0;
+
+            // Request paths are resolved one at a time for each archiver (see OpenResolved).
+            let resolve_key = next_resolve_key();
+
+            // Paths of the open requests still being resolved, as given at the request.
+            let mut resolving : Vec<PathBuf> = Vec::new();
+
+            let mut infer_relative = false;
+            let mut sanitize_names = false;
+
+            let mut idle_autosave : Option<Duration> = None;
+
+            // Generation of the last change of each path. Every change schedules a new
+            // timeout, but only the timeout matching the last generation triggers a save.
+            let mut idle_generations : HashMap<PathBuf, u64> = HashMap::new();
+            let mut idle_generation : u64 = 0;
+
+            let mut autosave : Option<ArchiverAutosave> = None;
+            let mut autosave_generation : u64 = 0;
+
+            // Paths saved by the autosave and not done yet, so that their SaveSuccess
+            // is also reported to on_autosaved.
+            let mut autosaving : Vec<PathBuf> = Vec::new();
+
+            // Paths saved by the SaveAllRequest in progress and not done yet.
+            let mut save_all : Option<Vec<PathBuf>> = None;
+
+            // Keys of the files still to be closed by a CloseAllRequest, CloseOthersRequest or CloseAllExcept,
+            // and the key of the file whose close was requested last.
+            let mut close_queue : VecDeque<PathBuf> = VecDeque::new();
+            let mut awaiting_close : Option<PathBuf> = None;
+
+            // Content and checks of the saves queued by SaveOffline, by path, and whether a
+            // CheckOnline is scheduled.
+            let mut offline : HashMap<PathBuf, (String, SaveChecks)> = HashMap::new();
+            let mut checking_online = false;
+
+            // Paths whose online check has not finished, which might be waiting behind a
+            // stalled operation on the path. They are not checked again until it finishes.
+            let mut online_checks : HashSet<PathBuf> = HashSet::new();
+
+            // Whether a PrefetchRecentMetadata is running.
+            let mut prefetching = false;
+
+            // Files closed by the current CloseAllExcept.
+            let mut batch_closed : Option<Vec<OpenedFile>> = None;
+
+            let mut snapshot : Option<(PathBuf, Duration)> = None;
+            let mut snapshot_generation : u64 = 0;
+
+            // Hash of the last written snapshot.
+            let mut last_snapshot : Option<u64> = None;
+
+            let mut active_text : Option<String> = None;
+
+            // Nesting level of begin_bulk calls, and the events suppressed so far.
+            let mut bulk_depth : usize = 0;
+            let mut bulk = BulkSummary::default();
+
+            // Number of EndBulk received while files were opening.
+            let mut deferred_end_bulk : usize = 0;
+
+            move |action| {
+
+                match action {
+
+                    // When user clicks "new file"
+                    MultiArchiverAction::NewRequest => {
+                        if files.len() >= limit {
+                            send.send(MultiArchiverAction::OpenError(ArchiverError::FileLimit)).unwrap();
+                            return glib::ControlFlow::Continue;
+                        }
+                        let n = next_untitled(&files, &extension);
+                        let mut new_file = OpenedFile::untitled(n, &extension);
+                        new_file.content = on_untitled_content.call_with_values(n).into_iter().next();
+                        new_file.index = files.len();
+                        files.push(new_file.clone());
+                        update_view(&view, &files, selected, &listeners);
+                        on_new.call(new_file);
+                    },
+
+                    // When the user state is being updated
+                    MultiArchiverAction::Add(file) => {
+                        if let Some(store) = &recent_store {
+                            store.push(store_id, &file);
+                        }
+                        recent_files.push(file.clone());
+                        if bulk_depth > 0 {
+                            bulk.added.push(file);
+                        } else {
+                            on_added.call(file);
+                        }
+                    },
+                    MultiArchiverAction::AddMany(mut new_files) => {
+                        new_files.sort_by_key(|f| f.dt );
+                        let mut added : Vec<OpenedFile> = Vec::new();
+                        for file in new_files {
+                            let Some(path) = file.path.clone() else {
+                                continue;
+                            };
+                            let ignore_case = case_sensitivity.ignores_case(&path);
+                            if contains_path(&recent_files, &path, ignore_case) {
+                                continue;
+                            }
+
+                            // Keep only the most recent entry for the same path.
+                            added.retain(|f| !same_path(f.path.as_ref().unwrap(), &path, ignore_case) );
+                            added.push(file);
+                        }
+                        for file in &added {
+                            if let Some(store) = &recent_store {
+                                store.push(store_id, file);
+                            }
+                            recent_files.push(file.clone());
+                        }
+                        if bulk_depth > 0 {
+                            bulk.added.extend(added);
+                        } else {
+                            on_added_many.call(added);
+                        }
+                    },
+                    MultiArchiverAction::SyncRecent(file) => {
+                        let in_recent = file.path.as_ref()
+                            .map(|path| contains_path(&recent_files, path, case_sensitivity.ignores_case(path)) )
+                            .unwrap_or(true);
+                        if !in_recent {
+                            recent_files.push(file.clone());
+                            if bulk_depth > 0 {
+                                bulk.added.push(file);
+                            } else {
+                                on_added.call(file);
+                            }
+                        }
+                    },
+                    MultiArchiverAction::RemoveRecent(path) => {
+                        let path = expand_tilde(&path);
+                        let ignore_case = case_sensitivity.ignores_case(&path);
+                        let n = recent_files.len();
+                        recent_files.retain(|f| !f.path.as_ref().map(|p| same_path(p, &path, ignore_case) ).unwrap_or(false) );
+                        if let Some(store) = &recent_store {
+                            store.remove(store_id, &path);
+                        }
+                        if recent_files.len() < n {
+                            final_state.replace(FinalState { recent : recent_files.clone(), files : files.clone(), selected : selected_key(&files, selected) });
+                            on_recent_changed.call(recent_files.clone());
+                        }
+                    },
+                    MultiArchiverAction::ClearRecent => {
+                        if let Some(store) = &recent_store {
+                            store.clear(store_id);
+                        }
+                        if !recent_files.is_empty() {
+                            recent_files.clear();
+                            final_state.replace(FinalState { recent : recent_files.clone(), files : files.clone(), selected : selected_key(&files, selected) });
+                            on_recent_changed.call(recent_files.clone());
+                        }
+                    },
+                    MultiArchiverAction::ValidateRecent { remove_missing } => {
+                        let paths = recent_files.iter().filter_map(|f| f.path.clone() ).collect();
+                        spawn_validate_recent(send.clone(), paths, remove_missing);
+                    },
+                    MultiArchiverAction::RecentValidated { missing, remove_missing } => {
+                        let is_missing = |f : &OpenedFile| f.path.as_ref().map(|p| missing.contains(p) ).unwrap_or(false);
+                        if remove_missing && recent_files.iter().any(is_missing) {
+                            recent_files.retain(|f| !is_missing(f) );
+                            if let Some(store) = &recent_store {
+                                for path in &missing {
+                                    store.remove(store_id, path);
+                                }
+                            }
+                            final_state.replace(FinalState { recent : recent_files.clone(), files : files.clone(), selected : selected_key(&files, selected) });
+                            on_recent_changed.call(recent_files.clone());
+                        }
+                        on_recent_validated.call(recent_files.iter().filter(|f| !is_missing(f) ).cloned().collect());
+                    },
+                    MultiArchiverAction::OpenRelativeRequest(rel_path) => {
+                        let workspace = workspace_root.borrow().clone();
+                        let bases = roots.borrow().clone();
+                        if workspace.is_some() || !bases.is_empty() {
+                            let on_panic = {
+                                let send = send.clone();
+                                move |msg| {
+                                    send.send(MultiArchiverAction::OpenError(ArchiverError::WorkerPanic(msg)))
+                                        .unwrap_or_else(super::log_err);
+                                }
+                            };
+
+                            // Finding the base that has the file touches the filesystem (see
+                            // resolve_relative), so it is done at the resolve thread.
+                            let send = send.clone();
+                            spawn_worker(&resolve_key, on_panic, move || {
+                                if let Some(abs) = resolve_relative(&rel_path, workspace.as_deref(), &bases) {
+                                    send.send(MultiArchiverAction::OpenRequest(abs))
+                                        .unwrap_or_else(super::log_err);
+                                }
+                                true
+                            });
+                        } else if let Some(dir) = infer_relative.then(|| infer_base_dir(&files, selected) ).flatten() {
+                            warn(&on_internal_warning, ArchiverError::InferredPrefix(dir.clone()));
+                            let abs = dir.join(rel_path);
+                            send.send(MultiArchiverAction::OpenRequest(abs))
+                                .unwrap_or_else(super::log_err);
+                        } else {
+                            send.send(MultiArchiverAction::OpenError(ArchiverError::NoPrefix)).unwrap();
+                        }
+                    },
+                    MultiArchiverAction::SetInferRelative(infer) => {
+                        infer_relative = infer;
+                    },
+                    MultiArchiverAction::SetSanitizeNames(sanitize) => {
+                        sanitize_names = sanitize;
+                    },
+                    MultiArchiverAction::OpenAtRequest { path, line, column } => {
+                        positions.insert(path.clone(), TextPosition { line, column });
+                        send.send(MultiArchiverAction::OpenRequest(path))
+                            .unwrap_or_else(super::log_err);
+                    },
+                    action @ (
+                        MultiArchiverAction::OpenRequest(_) |
+                        MultiArchiverAction::OpenBackgroundRequest(_) |
+                        MultiArchiverAction::OpenReadOnlyRequest(_) |
+                        MultiArchiverAction::OpenUriRequest(_) |
+                        MultiArchiverAction::AdoptRequest(_, _)
+                    ) => {
+                        let requested = match &action {
+                            MultiArchiverAction::OpenRequest(path) |
+                            MultiArchiverAction::OpenBackgroundRequest(path) |
+                            MultiArchiverAction::OpenReadOnlyRequest(path) |
+                            MultiArchiverAction::AdoptRequest(path, _) => path.clone(),
+                            MultiArchiverAction::OpenUriRequest(uri) => match gio::File::for_uri(uri).path() {
+                                Some(path) => path,
+                                None if key_uri(Path::new(uri)).is_some() => PathBuf::from(uri),
+                                None => {
+                                    send.send(MultiArchiverAction::OpenError(ArchiverError::NoLocalPath(uri.clone())))
+                                        .unwrap_or_else(super::log_err);
+                                    return glib::ControlFlow::Continue;
+                                }
+                            },
+                            _ => unreachable!()
+                        };
+                        resolving.push(requested.clone());
+                        let on_panic = {
+                            let send = send.clone();
+                            let requested = requested.clone();
+                            move |msg| {
+                                send.send(MultiArchiverAction::OpenError(ArchiverError::WorkerPanic(msg)))
+                                    .unwrap_or_else(super::log_err);
+                                send.send(MultiArchiverAction::OpenSettled(requested))
+                                    .unwrap_or_else(super::log_err);
+                            }
+                        };
+                        spawn_resolve(&resolve_key, send.clone(), requested.clone(), false, case_sensitivity, on_panic, move |resolved| {
+                            vec![MultiArchiverAction::OpenResolved(Box::new(action), resolved), MultiArchiverAction::OpenSettled(requested)]
+                        });
+                    },
+                    MultiArchiverAction::OpenResolved(action, resolved) => {
+                        let (background, adopt) = match action.as_ref() {
+                            MultiArchiverAction::OpenBackgroundRequest(_) => (true, None),
+                            MultiArchiverAction::AdoptRequest(_, opts) => (false, Some(*opts)),
+                            _ => (false, None)
+                        };
+                        let read_only = matches!(action.as_ref(), MultiArchiverAction::OpenReadOnlyRequest(_));
+
+                        // Other spellings of an opened path (or links to it) find the opened file.
+                        let ResolvedPath { requested, path, ignore_case } = resolved;
+                        if requested != path {
+                            if let Some(position) = positions.remove(&requested) {
+                                positions.insert(path.clone(), position);
+                            }
+                            for restored in restoring.iter_mut().filter(|p| **p == requested ) {
+                                *restored = path.clone();
+                            }
+                        }
+
+                        if adopt.is_none() {
+                            if let Err(e) = check_roots(&path, &roots.borrow(), &canonical_roots) {
+                                positions.remove(&path);
+                                send.send(MultiArchiverAction::OpenError(e)).unwrap();
+                                return glib::ControlFlow::Continue;
+                            }
+                        }
+
+                        let already_opened = files.iter()
+                            .find(|f| match &f.path {
+                                Some(p) => same_path(p, &path, ignore_case),
+                                None => f.uri.is_some() && f.key() == path.as_path()
+                            });
+                        if let Some(already_opened) = already_opened {
+                            let mut reopened = already_opened.clone();
+                            reopened.position = positions.remove(&path);
+                            on_reopen.call(reopened);
+                            return glib::ControlFlow::Continue;
+                        }
+
+                        // Also skipped while the same path is being read.
+                        let in_flight = open_task.as_ref().map(|(p, _)| p.as_path() );
+                        let mut pending = open_queue.iter().map(|(queued, _)| queued.as_path() ).chain(in_flight);
+                        // The queued open keeps the position (if any) for OpenSuccess.
+                        if pending.any(|p| same_path(p, &path, ignore_case) ) {
+                            return glib::ControlFlow::Continue;
+                        }
+
+                        // Kept for OpenSuccess only if the file is opened.
+                        let position = positions.remove(&path);
+
+                        if let Some(store) = &recent_store {
+                            if store.opened_elsewhere(store_id, &path) {
+                                on_opened_elsewhere.call(path);
+                                return glib::ControlFlow::Continue;
+                            }
+                        }
+
+                        if files.len() + open_queue.len() + opening as usize >= limit {
+                            send.send(MultiArchiverAction::OpenError(ArchiverError::FileLimit)).unwrap();
+                            return glib::ControlFlow::Continue;
+                        }
+
+                        if let Some(Err(msg)) = on_open_filter.call_with_values(path.clone()).into_iter().find(|res| res.is_err() ) {
+                            on_user_error.call(ArchiverError::Rejected(msg));
+                            return glib::ControlFlow::Continue;
+                        }
+
+                        match adopt {
+                            Some(opts) => {
+                                adopted.insert(path.clone(), opts);
+                            },
+                            None => {
+                                adopted.remove(&path);
+                            }
+                        }
+                        if read_only {
+                            view_only.insert(path.clone());
+                        } else {
+                            view_only.remove(&path);
+                        }
+
+                        if let Some(position) = position {
+                            positions.insert(path.clone(), position);
+                        }
+                        io_queue.push(&path, IoKind::Open);
+                        if opening {
+                            open_queue.push_back((path, background));
+                        } else {
+                            opening = true;
+                            let limits = limits_for(&open_limits, &special_file_filter, &path);
+                            open_task = Some(spawn_open_file(send.clone(), path, background, limits, io_backend.get()));
+                        }
+                    },
+                    MultiArchiverAction::OpenDone(path) => {
+                        io_queue.remove(&path, IoKind::Open);
+                        positions.remove(&path);
+                        open_task = None;
+
+                        // The reopen failed if the path is still here after OpenSuccess.
+                        reopening.retain(|p| p != &path );
+                        if !restoring.is_empty() {
+                            send.send(MultiArchiverAction::RestoreQueued)
+                                .unwrap_or_else(super::log_err);
+                        }
+                        if let Some((path, background)) = open_queue.pop_front() {
+                            let limits = limits_for(&open_limits, &special_file_filter, &path);
+                            open_task = Some(spawn_open_file(send.clone(), path, background, limits, io_backend.get()));
+                        } else {
+                            opening = false;
+                            while deferred_end_bulk > 0 {
+                                deferred_end_bulk -= 1;
+                                end_bulk(&mut bulk_depth, &mut bulk, &on_bulk_done);
+                            }
+                        }
+                    },
+                    MultiArchiverAction::OpenSettled(requested) => {
+                        if let Some(pos) = resolving.iter().position(|p| *p == requested ) {
+                            resolving.remove(pos);
+                        }
+                        if !restoring.is_empty() {
+                            send.send(MultiArchiverAction::RestoreQueued)
+                                .unwrap_or_else(super::log_err);
+                        }
+
+                        // The request might have been rejected, with no other file opening.
+                        if !opening && resolving.is_empty() {
+                            while deferred_end_bulk > 0 {
+                                deferred_end_bulk -= 1;
+                                end_bulk(&mut bulk_depth, &mut bulk, &on_bulk_done);
+                            }
+                        }
+                    },
+                    MultiArchiverAction::IoProgress(path, kind, done, total) => {
+
+                        // Reported by a cancelled operation before it noticed.
+                        if io_queue.contains(&path, kind) {
+                            on_io_progress.call((path, done, total));
+                        }
+                    },
+                    MultiArchiverAction::CancelOpen(pos) => {
+                        let Some((path, IoKind::Open)) = io_queue.pending.borrow().get(pos).cloned() else {
+                            warn(&on_internal_warning, ArchiverError::InvalidIndex(pos));
+                            return glib::ControlFlow::Continue;
+                        };
+                        if let Some(queued) = open_queue.iter().position(|(p, _)| p == &path ) {
+                            open_queue.remove(queued);
+                            io_queue.remove(&path, IoKind::Open);
+                            on_cancelled.call((path, IoKind::Open));
+                        } else if let Some((_, cancel)) = open_task.as_ref().filter(|(p, _)| p == &path ) {
+
+                            // The open thread does not send anything after it is cancelled, so it
+                            // is done here instead.
+                            if cancel.cancel() {
+                                on_cancelled.call((path.clone(), IoKind::Open));
+                                send.send(MultiArchiverAction::OpenDone(path))
+                                    .unwrap_or_else(super::log_err);
+                            }
+                        } else {
+
+                            // Reloads are not cancellable.
+                            warn(&on_internal_warning, ArchiverError::InvalidIndex(pos));
+                        }
+                    },
+                    MultiArchiverAction::CancelSave(ix) => {
+                        let cancelled : Vec<PathBuf> = save_tokens.iter()
+                            .filter(|(_, (index, _))| *index == ix )
+                            .map(|(path, _)| path.clone() )
+                            .collect();
+                        for path in cancelled {
+                            let Some((_, cancel)) = save_tokens.get(&path) else {
+                                continue;
+                            };
+
+                            // As for opens, the save thread does not send anything after it is cancelled.
+                            if cancel.cancel() {
+                                save_tokens.remove(&path);
+                                on_cancelled.call((path.clone(), IoKind::Save));
+                                send.send(MultiArchiverAction::SaveDone(path))
+                                    .unwrap_or_else(super::log_err);
+                            }
+                        }
+                    },
+                    MultiArchiverAction::CloseRequest(ix, force) => {
+
+                        if ix >= files.len() {
+                            warn(&on_internal_warning, ArchiverError::InvalidIndex(ix));
+                            return glib::ControlFlow::Continue;
+                        }
+                        
+                        // This force=true branch will be hit by a request from the toast button
+                        // clicked when the user wants to ignore an unsaved file. If win_close_request=true,
+                        // the action originated from a application window close. If win_close_request=false,
+                        // the action originated from a file list item close.
+                        if force {
+                            let closed_file = remove_file(&mut files, ix, &mut selected);
+                            assert!(closed_file.index == ix);
+                            last_closed_file = Some(closed_file.clone());
+                            push_closed(&mut closed_history, &closed_file);
+                            journal_closed(&mut journal, &mut close_group, &mut undoing, &closed_file);
+                            release_adopted(&mut adopted, &closed_file);
+                            if let Some(path) = &closed_file.path {
+                                monitors.remove(path);
+                                reported_changes.remove(path);
+                                muted.remove(path);
+                            }
+                            if let Some(autosave) = &autosave {
+                                autosave.discard(closed_file.key());
+                            }
+                            if let (Some(store), Some(path)) = (&recent_store, &closed_file.path) {
+                                store.set_opened(store_id, path, false);
+                            }
+                            let n = files.len();
+                            update_view(&view, &files, selected, &listeners);
+                            if bulk_depth > 0 {
+                                bulk.closed.push(closed_file);
+                            } else if let Some(batch) = &mut batch_closed {
+                                batch.push(closed_file);
+                            } else {
+                                on_file_closed.call((closed_file, n));
+                            }
+                            if win_close_request && !super::close_vetoed(&on_window_close_veto) {
+                                for file in &files {
+                                    release_adopted(&mut adopted, file);
+                                }
+                                on_window_close.call(());
+                                if let Some(store) = &recent_store {
+                                    store.unregister(store_id);
+                                }
+                            }
+                        } else {
+                            if files[ix].saved {
+                                let closed_file = remove_file(&mut files, ix, &mut selected);
+                                assert!(closed_file.index == ix);
+                                last_closed_file = Some(closed_file.clone());
+                                push_closed(&mut closed_history, &closed_file);
+                                journal_closed(&mut journal, &mut close_group, &mut undoing, &closed_file);
+                                release_adopted(&mut adopted, &closed_file);
+                                if let Some(path) = &closed_file.path {
+                                    monitors.remove(path);
+                                    reported_changes.remove(path);
+                                    muted.remove(path);
+                                }
+                                if let Some(autosave) = &autosave {
+                                    autosave.discard(closed_file.key());
+                                }
+                                if let (Some(store), Some(path)) = (&recent_store, &closed_file.path) {
+                                    store.set_opened(store_id, path, false);
+                                }
+                                let n = files.len();
+                                update_view(&view, &files, selected, &listeners);
+                                if bulk_depth > 0 {
+                                    bulk.closed.push(closed_file);
+                                } else if let Some(batch) = &mut batch_closed {
+                                    batch.push(closed_file);
+                                } else {
+                                    on_file_closed.call((closed_file, n));
+                                }
+                            } else {
+                                on_close_confirm.call(files[ix].clone());
+                            }
+                        }
+                        win_close_request = false;
+                        final_state.replace(FinalState { recent : recent_files.clone(), files : files.clone(), selected : selected_key(&files, selected) });
+                        if let Some(key) = &awaiting_close {
+                            if !files.iter().any(|f| f.key() == key.as_path() ) {
+                                awaiting_close = None;
+                                send.send(MultiArchiverAction::CloseNext)
+                                    .unwrap_or_else(super::log_err);
+                            }
+                        }
+                    },
+                    MultiArchiverAction::CloseAllRequest => {
+                        if let Some(closed) = batch_closed.take() {
+                            on_files_closed.call(closed);
+                        }
+                        flush_close_group(&mut journal, &mut close_group);
+                        close_group = Some(Vec::new());
+                        close_queue = files.iter().map(|f| f.key().to_path_buf() ).collect();
+                        awaiting_close = None;
+                        send.send(MultiArchiverAction::CloseNext)
+                            .unwrap_or_else(super::log_err);
+                    },
+                    MultiArchiverAction::CloseOthersRequest(ix) => {
+                        if ix >= files.len() {
+                            warn(&on_internal_warning, ArchiverError::InvalidIndex(ix));
+                            return glib::ControlFlow::Continue;
+                        }
+                        if let Some(closed) = batch_closed.take() {
+                            on_files_closed.call(closed);
+                        }
+                        flush_close_group(&mut journal, &mut close_group);
+                        close_group = Some(Vec::new());
+                        close_queue = files.iter()
+                            .enumerate()
+                            .filter(|(i, _)| *i != ix )
+                            .map(|(_, f)| f.key().to_path_buf() )
+                            .collect();
+                        awaiting_close = None;
+                        send.send(MultiArchiverAction::CloseNext)
+                            .unwrap_or_else(super::log_err);
+                    },
+                    MultiArchiverAction::CloseAllExcept(kept) => {
+                        if let Some(ix) = kept.iter().find(|ix| **ix >= files.len() ) {
+                            warn(&on_internal_warning, ArchiverError::InvalidIndex(*ix));
+                            return glib::ControlFlow::Continue;
+                        }
+                        if let Some(closed) = batch_closed.replace(Vec::new()) {
+                            on_files_closed.call(closed);
+                        }
+                        flush_close_group(&mut journal, &mut close_group);
+                        close_group = Some(Vec::new());
+                        close_queue = files.iter()
+                            .enumerate()
+                            .filter(|(i, _)| !kept.contains(i) )
+                            .map(|(_, f)| f.key().to_path_buf() )
+                            .collect();
+                        awaiting_close = None;
+                        send.send(MultiArchiverAction::CloseNext)
+                            .unwrap_or_else(super::log_err);
+                    },
+                    MultiArchiverAction::CloseNext => {
+
+                        // Files closed meanwhile are skipped.
+                        while let Some(key) = close_queue.pop_front() {
+                            if let Some(ix) = files.iter().position(|f| f.key() == key.as_path() ) {
+                                awaiting_close = Some(key);
+                                send.send(MultiArchiverAction::CloseRequest(ix, false))
+                                    .unwrap_or_else(super::log_err);
+                                return glib::ControlFlow::Continue;
+                            }
+                        }
+                        if let Some(closed) = batch_closed.take() {
+                            on_files_closed.call(closed);
+                        }
+                        flush_close_group(&mut journal, &mut close_group);
+                    },
+                    MultiArchiverAction::SaveRequest { path : opt_path, overwrite } => {
+                        let opt_path = opt_path.map(|path| normalize_file_name(expand_tilde(&path), save_options.borrow().normalization) );
+                        if let Some(ix) = selected {
+                        
+                            if ix >= files.len() {
+                                warn(&on_internal_warning, ArchiverError::InvalidIndex(ix));
+                                return glib::ControlFlow::Continue;
+                            }
+                        
+                            if let Some(path) = opt_path {
+                                let key = files[ix].key().to_path_buf();
+                                let on_panic = {
+                                    let send = send.clone();
+                                    move |msg| {
+                                        send.send(MultiArchiverAction::SaveError(ArchiverError::WorkerPanic(msg)))
+                                            .unwrap_or_else(super::log_err);
+                                    }
+                                };
+                                spawn_resolve(&resolve_key, send.clone(), path, true, case_sensitivity, on_panic, move |resolved| {
+                                    vec![MultiArchiverAction::SaveResolved { key, overwrite, resolved }]
+                                });
+                            } else {
+                                if let Some(path) = files[ix].location() {
+
+                                    if files[ix].read_only {
+                                        send.send(MultiArchiverAction::SaveError(ArchiverError::ReadOnly(path))).unwrap();
+                                        return glib::ControlFlow::Continue;
+                                    }
+                                
+                                    if let Err(e) = check_roots(&path, &roots.borrow(), &canonical_roots) {
+                                        send.send(MultiArchiverAction::SaveError(e)).unwrap();
+                                        return glib::ControlFlow::Continue;
+                                    }
+                                    
+                                    let checks = if overwrite {
+                                        SaveChecks::default()
+                                    } else {
+                                        SaveChecks { expected : files[ix].mtime, truncation : truncation_ratio.get() }
+                                    };
+                                    let content = on_buffer_read_request.call_with_values(ix).remove(0);
+                                    validate_save(&save_validators.borrow(), ix, files[ix].key().to_path_buf(), path, content, checks, &send);
+                                } else {
+                                    if sanitize_names {
+                                        on_save_unknown_path.call(sanitize_file_name(&files[ix].name));
+                                    } else {
+                                        on_save_unknown_path.call(files[ix].name.clone());
+                                    }
+                                }
+                            }
+                        } else {
+
+                            // The save action might be out of sync with the selection
+                            // (e.g. an accelerator pressed with no file selected).
+                            on_user_error.call(ArchiverError::NoSelection);
+                        }
+                    },
+                    MultiArchiverAction::SaveResolved { key, overwrite, resolved } => {
+                        let ResolvedPath { path, ignore_case, .. } = resolved;
+
+                        // The file might have been closed while the path was resolved, or moved
+                        // to another index (if a file before it was closed).
+                        let Some(ix) = files.iter().position(|f| f.key() == key.as_path() ) else {
+                            on_user_error.call(ArchiverError::ClosedBeforeSave(path));
+                            return glib::ControlFlow::Continue;
+                        };
+
+                        // Saving a read-only file at its own path (e.g. a Save As dialog where
+                        // the same file was chosen) would overwrite it, like a plain save.
+                        let same_file = files[ix].path.as_ref().is_some_and(|p| same_path(p, &path, ignore_case) );
+                        if same_file && files[ix].read_only {
+                            send.send(MultiArchiverAction::SaveError(ArchiverError::ReadOnly(path))).unwrap();
+                            return glib::ControlFlow::Continue;
+                        }
+
+                        if let Err(e) = check_roots(&path, &roots.borrow(), &canonical_roots) {
+                            send.send(MultiArchiverAction::SaveError(e)).unwrap();
+                            return glib::ControlFlow::Continue;
+                        }
+
+                        for (i, f) in files.iter().enumerate() {
+                            if let Some(other_path) = &f.path {
+                                if ix != i && same_path(other_path, &path, ignore_case) {
+                                    send.send(MultiArchiverAction::SaveError(ArchiverError::AlreadyOpened(path.clone()))).unwrap();
+                                    return glib::ControlFlow::Continue;
+                                }
+                            }
+                        }
+
+                        let checks = if overwrite {
+                            SaveChecks::default()
+                        } else {
+                            SaveChecks {
+                                expected : files[ix].mtime.filter(|_| same_file ),
+                                truncation : truncation_ratio.get()
+                            }
+                        };
+                        let content = on_buffer_read_request.call_with_values(ix).remove(0);
+                        validate_save(&save_validators.borrow(), ix, key, path, content, checks, &send);
+                    },
+                    MultiArchiverAction::SaveCopyRequest(ix, path) => {
+                        if ix >= files.len() {
+                            warn(&on_internal_warning, ArchiverError::InvalidIndex(ix));
+                            return glib::ControlFlow::Continue;
+                        }
+                        let path = normalize_file_name(expand_tilde(&path), save_options.borrow().normalization);
+                        let key = files[ix].key().to_path_buf();
+                        let on_panic = {
+                            let send = send.clone();
+                            move |msg| {
+                                send.send(MultiArchiverAction::SaveError(ArchiverError::WorkerPanic(msg)))
+                                    .unwrap_or_else(super::log_err);
+                            }
+                        };
+                        spawn_resolve(&resolve_key, send.clone(), path, true, case_sensitivity, on_panic, move |resolved| {
+                            vec![MultiArchiverAction::SaveCopyResolved(key, resolved)]
+                        });
+                    },
+                    MultiArchiverAction::SaveCopyResolved(key, resolved) => {
+                        let ResolvedPath { path, ignore_case, .. } = resolved;
+                        let Some(ix) = files.iter().position(|f| f.key() == key.as_path() ) else {
+                            on_user_error.call(ArchiverError::ClosedBeforeSave(path));
+                            return glib::ControlFlow::Continue;
+                        };
+                        if let Err(e) = check_roots(&path, &roots.borrow(), &canonical_roots) {
+                            send.send(MultiArchiverAction::SaveError(e)).unwrap();
+                            return glib::ControlFlow::Continue;
+                        }
+                        if contains_path(&files, &path, ignore_case) {
+                            send.send(MultiArchiverAction::SaveError(ArchiverError::AlreadyOpened(path))).unwrap();
+                            return glib::ControlFlow::Continue;
+                        }
+                        let content = on_buffer_read_request.call_with_values(ix).remove(0);
+                        run_validators(&save_validators.borrow(), path.clone(), content, &send, move |content| {
+                            MultiArchiverAction::CopyValidated(ix, key, path, content)
+                        });
+                    },
+                    MultiArchiverAction::CopyValidated(ix, key, path, content) => {
+                        if ix >= files.len() || files[ix].key() != key.as_path() {
+                            warn(&on_internal_warning, ArchiverError::InvalidIndex(ix));
+                            return glib::ControlFlow::Continue;
+                        }
+                        io_queue.push(&path, IoKind::Save);
+                        spawn_save_copy(ix, path, content, files[ix].text_format(), save_options.borrow().clone(), io_backend.get(), send.clone());
+                    },
+                    MultiArchiverAction::CopySaved(ix, path) => {
+                        io_queue.metrics.borrow_mut().saves += 1;
+                        if ix >= files.len() {
+                            warn(&on_internal_warning, ArchiverError::InvalidIndex(ix));
+                            return glib::ControlFlow::Continue;
+                        }
+                        on_copy_saved.call((files[ix].without_content(), path));
+                    },
+                    MultiArchiverAction::SaveAllRequest => {
+                        let pending = save_all.get_or_insert_with(Vec::new);
+                        for (ix, file) in files.iter().enumerate() {
+                            if file.saved || file.read_only {
+                                continue;
+                            }
+                            let Some(path) = file.location() else {
+                                continue;
+                            };
+                            if check_roots(&path, &roots.borrow(), &canonical_roots).is_err() {
+                                continue;
+                            }
+                            let content = on_buffer_read_request.call_with_values(ix).remove(0);
+                            pending.push(path.clone());
+                            validate_save(&save_validators.borrow(), ix, file.key().to_path_buf(), path, content, SaveChecks { expected : file.mtime, truncation : truncation_ratio.get() }, &send);
+                        }
+                        if pending.is_empty() {
+                            save_all = None;
+                            send.send(MultiArchiverAction::SaveAllDone)
+                                .unwrap_or_else(super::log_err);
+                        }
+                    },
+                    MultiArchiverAction::SaveAllDone => {
+                        on_all_saved.call(files.iter().filter(|f| !f.saved ).map(|f| f.without_content() ).collect());
+                    },
+                    MultiArchiverAction::SaveSuccess(ix, path, record) => {
+                        io_queue.metrics.borrow_mut().saves += 1;
+
+                        if ix >= files.len() {
+                            warn(&on_internal_warning, ArchiverError::InvalidIndex(ix));
+                            return glib::ControlFlow::Continue;
+                        }
+
+                        let mtime = record.mtime;
+                        let dt = record.dt;
+                        {
+                            let mut save_history = save_history.borrow_mut();
+                            let history = save_history.entry(path.clone()).or_default();
+                            history.push(record);
+                            if history.len() > MAX_SAVE_HISTORY {
+                                history.remove(0);
+                            }
+                        }
+                        
+                        // Files opened at a URI are saved back at it (see OpenUriRequest).
+                        if files[ix].path.is_none() && files[ix].key() != path.as_path() {
+                            let old_key = files[ix].key().to_path_buf();
+                            if let Some(autosave) = &autosave {
+                                autosave.discard(&old_key);
+                            }
+                            files[ix].name = path.display().to_string();
+                            if let Some(uri) = key_uri(&path) {
+                                files[ix].uri = Some(uri.to_string());
+                            } else {
+                                files[ix].path = Some(path.clone());
+                                files[ix].uri = remote_uri(&path);
+                                watch_file(&mut monitors, &path, &send, &on_internal_warning);
+                            }
+                            view.borrow_mut().rename_key(&old_key, &path);
+                            update_view(&view, &files, selected, &listeners);
+                            on_name_changed.call((ix, path.display().to_string()));
+
+                            if !contains_path(&recent_files, &path, case_sensitivity.ignores_case(&path)) {
+                                recent_files.push(files[ix].clone());
+                            }
+                            if let Some(store) = &recent_store {
+                                store.set_opened(store_id, &path, true);
+                                store.push(store_id, &files[ix]);
+                            }
+                        }
+                        if files[ix].key() == path.as_path() {
+                            files[ix].mtime = mtime;
+                            reported_changes.remove(&path);
+                        }
+                        if let Some(autosave) = &autosave {
+                            autosave.discard(&path);
+                        }
+                        if autosaving.contains(&path) {
+                            on_autosaved.call(AutosaveRecord {
+                                source : path.clone(),
+                                target : path.clone(),
+                                recovery : false,
+                                dt
+                            });
+                        } else {
+                            system_recent.borrow().register(&files[ix]);
+                        }
+                        send.send(MultiArchiverAction::SetSaved(ix, true))
+                            .unwrap_or_else(super::log_err);
+                    },
+                    MultiArchiverAction::SaveValidated(_, key, path, content, checks) => {
+
+                        // The file might have been closed while the content was validated, or
+                        // moved to another index (if a file before it was closed).
+                        let Some(ix) = files.iter().position(|f| f.key() == key.as_path() ) else {
+                            on_user_error.call(ArchiverError::ClosedBeforeSave(path.clone()));
+                            settle_save_all(&mut save_all, &path, &send);
+                            return glib::ControlFlow::Continue;
+                        };
+                        io_queue.push(&path, IoKind::Save);
+                        let cancel = CancelToken::default();
+                        save_tokens.insert(path.clone(), (ix, cancel.clone()));
+                        let save = PendingSave { index : ix, content, format : files[ix].text_format(), checks, cancel };
+                        start_save(&mut save_task, path, save, &save_options.borrow(), io_backend.get(), &send);
+                    },
+                    MultiArchiverAction::BackupCreated(path, backup) => {
+                        on_backup_created.call((path, backup));
+                    },
+                    MultiArchiverAction::SaveDone(path) => {
+                        io_queue.remove(&path, IoKind::Save);
+                        save_tokens.remove(&path);
+                        autosaving.retain(|p| p != &path );
+                        settle_save_all(&mut save_all, &path, &send);
+                    },
+                    MultiArchiverAction::SaveRejected(path, msg) => {
+                        io_queue.metrics.borrow_mut().save_errors += 1;
+                        autosaving.retain(|p| p != &path );
+                        settle_save_all(&mut save_all, &path, &send);
+                        on_user_error.call(ArchiverError::Rejected(msg));
+                    },
+                    MultiArchiverAction::SaveConflict(ix, path, expected, found) => {
+                        if ix >= files.len() {
+                            warn(&on_internal_warning, ArchiverError::InvalidIndex(ix));
+                            return glib::ControlFlow::Continue;
+                        }
+                        autosaving.retain(|p| p != &path );
+                        on_save_conflict.call((files[ix].without_content(), expected, found));
+                    },
+                    MultiArchiverAction::SuspiciousSave(ix, path, old_len, new_len) => {
+                        if ix >= files.len() {
+                            warn(&on_internal_warning, ArchiverError::InvalidIndex(ix));
+                            return glib::ControlFlow::Continue;
+                        }
+                        autosaving.retain(|p| p != &path );
+                        on_suspicious_save.call((files[ix].without_content(), old_len, new_len));
+                    },
+                    MultiArchiverAction::SaveError(e) => {
+                        io_queue.metrics.borrow_mut().save_errors += 1;
+                        on_user_error.call(e);
+                    },
+                    MultiArchiverAction::SaveTimeout(path, generation) => {
+                        if save_task.check_stalled(generation) {
+                            on_io_stalled.call(path);
+                        }
+                    },
+                    MultiArchiverAction::SaveOffline(ix, path, content, checks) => {
+                        autosaving.retain(|p| p != &path );
+                        if let (Some(autosave), Some(file)) = (&autosave, files.get(ix)) {
+                            if autosave.recovery_dir().is_some() {
+                                spawn_write_recovery(
+                                    autosave,
+                                    file.key().to_path_buf(),
+                                    content.clone(),
+                                    send.clone(),
+                                    MultiArchiverAction::RecoveryWritten
+                                );
+                            }
+                        }
+
+                        // A later save of the same path replaces the queued one.
+                        if offline.insert(path.clone(), (content, checks)).is_none() {
+                            on_offline.call(path);
+                        }
+                        if !checking_online {
+                            checking_online = true;
+                            schedule_online_check(&send);
+                        }
+                    },
+                    MultiArchiverAction::CheckOnline => {
+                        checking_online = !offline.is_empty();
+                        for path in offline.keys() {
+                            if online_checks.insert(path.clone()) {
+                                spawn_online_check(path.clone(), send.clone());
+                            }
+                        }
+                        if checking_online {
+                            schedule_online_check(&send);
+                        }
+                    },
+                    MultiArchiverAction::StillOffline(path) => {
+                        online_checks.remove(&path);
+                    },
+                    MultiArchiverAction::BackOnline(path) => {
+                        online_checks.remove(&path);
+                        let Some((content, checks)) = offline.remove(&path) else {
+                            return glib::ControlFlow::Continue;
+                        };
+                        on_back_online.call(path.clone());
+                        if let Some(ix) = files.iter().position(|f| f.path.as_deref() == Some(path.as_path()) ) {
+                            let key = files[ix].key().to_path_buf();
+                            send.send(MultiArchiverAction::SaveValidated(ix, key, path, content, checks))
+                                .unwrap_or_else(super::log_err);
+                        }
+                    },
+                    MultiArchiverAction::SetSaved(ix, saved) => {
+
+                        if ix >= files.len() {
+                            warn(&on_internal_warning, ArchiverError::InvalidIndex(ix));
+                            return glib::ControlFlow::Continue;
+                        }
+                        
+                        // SetSaved will be called when a buffer is cleared after a file is closed,
+                        // so we just ignore the call in this case, since the file won't be at the
+                        // buffer anymore (impl React<QueriesEditor> for MultiArchiver).
+                        if last_closed_file.clone().map(|f| f.index == ix ).unwrap_or(false) {
+                            last_closed_file = None;
+                            return glib::ControlFlow::Continue;
+                        }
+
+                        if saved {
+                            files[ix].saved = true;
+                            update_view(&view, &files, selected, &listeners);
+                            on_file_persisted.call(files[ix].clone());
+                        } else {
+                        
+                            if files[ix].saved {
+                                files[ix].saved = false;
+                                update_view(&view, &files, selected, &listeners);
+                                on_file_changed.call(files[ix].clone());
+                            }
+
+                            if let (Some(interval), Some(path)) = (idle_autosave, files[ix].path.clone()) {
+                                idle_generation += 1;
+                                idle_generations.insert(path.clone(), idle_generation);
+                                let send = send.clone();
+                                let generation = idle_generation;
+                                glib::timeout_add_local_once(interval, move || {
+                                    send.send(MultiArchiverAction::IdleTimeout(path, generation))
+                                        .unwrap_or_else(super::log_err);
+                                });
+                            }
+                        }
+                    },
+                    MultiArchiverAction::SetIdleAutosave(opt_interval) => {
+                        idle_autosave = opt_interval;
+                        idle_generations.clear();
+                    },
+                    MultiArchiverAction::IdleTimeout(path, generation) => {
+                        if idle_autosave.is_none() || idle_generations.get(&path) != Some(&generation) {
+                            return glib::ControlFlow::Continue;
+                        }
+                        idle_generations.remove(&path);
+                        if let Some(ix) = files.iter().position(|f| !f.saved && !f.read_only && f.path.as_ref() == Some(&path) ) {
+                            let content = on_buffer_read_request.call_with_values(ix).remove(0);
+                            validate_save(&save_validators.borrow(), ix, files[ix].key().to_path_buf(), path, content, SaveChecks { expected : files[ix].mtime, truncation : truncation_ratio.get() }, &send);
+                        }
+                    },
+                    MultiArchiverAction::SetAutosave(opt_autosave) => {
+
+                        // The recovery files are only listed when the directory changes, since the
+                        // files written during this session are not left by a crash.
+                        let old_dir = autosave.as_ref().and_then(|a| a.recovery_dir().map(|d| d.to_path_buf() ) );
+                        if let Some(dir) = opt_autosave.as_ref().and_then(|a| a.recovery_dir() ) {
+                            if old_dir.as_deref() != Some(dir) {
+                                spawn_scan_recoveries(dir.to_path_buf(), send.clone(), MultiArchiverAction::RecoveriesFound);
+                            }
+                        }
+                        autosave = opt_autosave;
+                        autosave_generation += 1;
+                        if let Some(autosave) = &autosave {
+                            schedule_autosave(autosave, &send, autosave_generation);
+                        }
+                    },
+                    MultiArchiverAction::AutosaveTimeout(generation) => {
+                        if generation != autosave_generation {
+                            return glib::ControlFlow::Continue;
+                        }
+                        let Some(autosave) = &autosave else {
+                            return glib::ControlFlow::Continue;
+                        };
+                        for (ix, file) in files.iter().enumerate() {
+                            if file.saved {
+                                continue;
+                            }
+                            if autosave.recovery_dir().is_some() {
+                                let content = on_buffer_read_request.call_with_values(ix).remove(0);
+                                spawn_write_recovery(
+                                    autosave,
+                                    file.key().to_path_buf(),
+                                    content,
+                                    send.clone(),
+                                    MultiArchiverAction::RecoveryWritten
+                                );
+                            } else if let (Some(path), false) = (file.path.clone(), file.read_only) {
+                                let content = on_buffer_read_request.call_with_values(ix).remove(0);
+                                if !autosaving.contains(&path) {
+                                    autosaving.push(path.clone());
+                                }
+                                validate_save(&save_validators.borrow(), ix, file.key().to_path_buf(), path, content, SaveChecks { expected : file.mtime, truncation : truncation_ratio.get() }, &send);
+                            }
+                        }
+                        schedule_autosave(autosave, &send, generation);
+                    },
+                    MultiArchiverAction::RecoveryWritten(res) => {
+                        match res {
+                            Ok(record) => on_autosaved.call(record),
+                            Err(msg) => on_user_error.call(ArchiverError::Io(msg))
+                        }
+                    },
+                    MultiArchiverAction::RecoveriesFound(found) => {
+                        let found : Vec<_> = found.into_iter()
+                            .filter(|r| !files.iter().any(|f| f.key() == r.source.as_path() ) )
+                            .collect();
+                        *recoveries.borrow_mut() = found.clone();
+                        if !found.is_empty() {
+                            on_recoveries_found.call(found);
+                        }
+                    },
+                    MultiArchiverAction::RecoverRequest(source) => {
+                        let Some(pos) = recoveries.borrow().iter().position(|r| r.source == source ) else {
+                            warn(&on_internal_warning, ArchiverError::Internal(format!("No recovery file for {}", source.display())));
+                            return glib::ControlFlow::Continue;
+                        };
+                        if files.iter().any(|f| f.key() == source.as_path() ) {
+                            on_user_error.call(ArchiverError::AlreadyOpened(source));
+                            return glib::ControlFlow::Continue;
+                        }
+                        if files.len() >= limit {
+                            on_user_error.call(ArchiverError::FileLimit);
+                            return glib::ControlFlow::Continue;
+                        }
+                        let recovery = recoveries.borrow_mut().remove(pos);
+
+                        // Recovered untitled files are numbered after the untitled files of this session.
+                        let recovered = if source.is_absolute() {
+                            OpenedFile::for_path(&source)
+                        } else {
+                            Ok(OpenedFile::untitled(next_untitled(&files, &extension), &extension))
+                        };
+                        match recovered {
+                            Ok(file) => spawn_recover_file(send.clone(), file, recovery.swap, open_limits.get()),
+                            Err(e) => on_user_error.call(e)
+                        }
+                    },
+                    MultiArchiverAction::AdoptDrafts => {
+                        let (mut drafts, others) : (Vec<PendingRecovery>, Vec<PendingRecovery>) = recoveries.take()
+                            .into_iter()
+                            .partition(|r| !r.source.is_absolute() );
+                        *recoveries.borrow_mut() = others;
+                        let room = limit.saturating_sub(files.len());
+                        if drafts.len() > room {
+                            recoveries.borrow_mut().extend(drafts.split_off(room));
+                            on_user_error.call(ArchiverError::FileLimit);
+                        }
+
+                        // The drafts are opened by separate threads, so they are numbered here
+                        // instead of after each other's OpenSuccess.
+                        let first = next_untitled(&files, &extension);
+                        for (i, draft) in drafts.into_iter().enumerate() {
+                            spawn_recover_file(send.clone(), OpenedFile::untitled(first + i, &extension), draft.swap, open_limits.get());
+                        }
+                    },
+                    MultiArchiverAction::DiscardRecovery(source) => {
+                        let pos = recoveries.borrow().iter().position(|r| r.source == source );
+                        if let Some(pos) = pos {
+                            let recovery = recoveries.borrow_mut().remove(pos);
+                            spawn_remove_recovery(recovery.swap);
+                        }
+                    },
+                    MultiArchiverAction::SetSnapshot(opt_snapshot) => {
+                        snapshot = opt_snapshot;
+                        snapshot_generation += 1;
+                        last_snapshot = None;
+                        if let Some((_, interval)) = &snapshot {
+                            schedule_snapshot(&send, *interval, snapshot_generation);
+                        }
+                    },
+                    MultiArchiverAction::SnapshotTimeout(generation) => {
+                        if generation != snapshot_generation {
+                            return glib::ControlFlow::Continue;
+                        }
+                        if let Some((path, interval)) = &snapshot {
+                            let state = FinalState {
+                                recent : recent_files.clone(),
+                                files : files.iter().map(|f| f.without_content() ).collect(),
+                                selected : selected_key(&files, selected)
+                            };
+                            match serde_json::to_string_pretty(&state) {
+                                Ok(json) => {
+                                    let mut hasher = DefaultHasher::new();
+                                    json.hash(&mut hasher);
+                                    let hash = hasher.finish();
+                                    if last_snapshot != Some(hash) {
+                                        last_snapshot = Some(hash);
+                                        spawn_write_snapshot(path.clone(), json);
+                                    }
+                                },
+                                Err(e) => {
+                                    warn(&on_internal_warning, ArchiverError::Internal(format!("Could not serialize session snapshot: {}", e)));
+                                }
+                            }
+                            schedule_snapshot(&send, *interval, generation);
+                        }
+                    },
+                    MultiArchiverAction::RestoreSession(state) => {
+                        send.send(MultiArchiverAction::AddMany(state.recent))
+                            .unwrap_or_else(super::log_err);
+                        for file in state.files {
+                            let action = match (&file.path, &file.uri) {
+                                (Some(path), _) => MultiArchiverAction::OpenBackgroundRequest(path.clone()),
+                                (None, Some(uri)) => MultiArchiverAction::OpenUriRequest(uri.clone()),
+                                (None, None) => continue
+                            };
+                            restoring.extend(file.location());
+                            send.send(action).unwrap_or_else(super::log_err);
+                        }
+                        pending_selection = Some(state.selected);
+                        send.send(MultiArchiverAction::RestoreQueued)
+                            .unwrap_or_else(super::log_err);
+                    },
+                    MultiArchiverAction::ExportSessionArchive(path) => {
+                        let state = FinalState {
+                            recent : recent_files.iter().map(OpenedFile::without_content).collect(),
+                            files : files.iter().map(OpenedFile::without_content).collect(),
+                            selected : selected_key(&files, selected)
+                        };
+                        let drafts : Vec<SessionDraft> = (0..files.len())
+                            .filter(|ix| !files[*ix].saved )
+                            .map(|ix| SessionDraft {
+                                source : files[ix].key().to_path_buf(),
+                                content : on_buffer_read_request.call_with_values(ix).remove(0)
+                            })
+                            .collect();
+                        spawn_export_session(send.clone(), expand_tilde(&path), state, drafts);
+                    },
+                    MultiArchiverAction::SessionExported(path) => {
+                        on_session_exported.call(path);
+                    },
+                    MultiArchiverAction::PrefetchRecentMetadata => {
+                        if !prefetching {
+                            prefetching = true;
+                            let paths = recent_files.iter().filter_map(|f| f.path.clone() ).collect();
+                            spawn_prefetch_metadata(send.clone(), paths);
+                        }
+                    },
+                    MultiArchiverAction::RecentMetadata(meta) => {
+                        prefetching = false;
+                        on_recent_metadata.call(meta);
+                    },
+                    MultiArchiverAction::ImportSessionArchive(path) => {
+                        spawn_import_session(send.clone(), expand_tilde(&path), open_limits.get());
+                    },
+                    MultiArchiverAction::SessionImported(mut state, drafts) => {
+                        let room = limit.saturating_sub(files.len());
+                        let first = next_untitled(&files, &extension);
+                        let mut n_untitled = 0;
+                        let mut n_opened = 0;
+                        for mut draft in drafts {
+
+                            // Drafts are checked like the files of an OpenRequest, since the
+                            // archive might come from another machine.
+                            let size = draft.content.as_ref().map(|content| content.len() as u64 ).unwrap_or(0);
+                            let max_size = open_limits.get().max_size;
+                            if size > max_size {
+                                on_user_error.call(ArchiverError::TooLarge { size, limit : max_size });
+                                continue;
+                            }
+                            if let Some(path) = &draft.path {
+
+                                // The draft is opened instead of the file on disk.
+                                state.files.retain(|f| f.path.as_ref() != Some(path) );
+                                if let Err(e) = check_roots(path, &roots.borrow(), &canonical_roots) {
+                                    on_user_error.call(e);
+                                    continue;
+                                }
+                                if let Some(Err(msg)) = on_open_filter.call_with_values(path.clone()).into_iter().find(|res| res.is_err() ) {
+                                    on_user_error.call(ArchiverError::Rejected(msg));
+                                    continue;
+                                }
+                                if contains_path(&files, path, case_sensitivity.ignores_case(path)) {
+                                    on_user_error.call(ArchiverError::AlreadyOpened(path.clone()));
+                                    continue;
+                                }
+                            } else {
+                                let name = OpenedFile::untitled(first + n_untitled, &extension).name;
+                                n_untitled += 1;
+                                if state.selected.as_deref() == Some(Path::new(&draft.name)) {
+                                    state.selected = Some(PathBuf::from(&name));
+                                }
+                                draft.name = name;
+                            }
+                            if n_opened == room {
+                                on_user_error.call(ArchiverError::FileLimit);
+                                break;
+                            }
+                            n_opened += 1;
+                            send.send(MultiArchiverAction::OpenSuccess(draft))
+                                .unwrap_or_else(super::log_err);
+                        }
+                        send.send(MultiArchiverAction::RestoreSession(state))
+                            .unwrap_or_else(super::log_err);
+                    },
+                    MultiArchiverAction::RestoreQueued => {
+
+                        // Requests that were rejected (or that failed) are not waited for.
+                        restoring.retain(|p| io_queue.contains(p, IoKind::Open) || resolving.contains(p) );
+                        if !restoring.is_empty() {
+                            return glib::ControlFlow::Continue;
+                        }
+                        if let Some(key) = pending_selection.take() {
+                            selected = key.and_then(|key| files.iter().position(|f| f.key() == key ) );
+                            update_view(&view, &files, selected, &listeners);
+                            on_selected.call(selected.map(|ix| files[ix].clone() ));
+                        }
+                    },
+                    MultiArchiverAction::SetActiveText(opt_text) => {
+                        if opt_text != active_text {
+                            active_text = opt_text;
+                            on_active_text_changed.call(active_text.clone());
+                        }
+                    },
+                    MultiArchiverAction::PeekRequest(path) => {
+                        let on_panic = {
+                            let send = send.clone();
+                            move |msg| {
+                                send.send(MultiArchiverAction::OpenError(ArchiverError::WorkerPanic(msg)))
+                                    .unwrap_or_else(super::log_err);
+                            }
+                        };
+                        spawn_resolve(&resolve_key, send.clone(), path, false, case_sensitivity, on_panic, |resolved| {
+                            vec![MultiArchiverAction::PeekResolved(resolved)]
+                        });
+                    },
+                    MultiArchiverAction::PeekResolved(resolved) => {
+                        if let Err(e) = check_roots(&resolved.path, &roots.borrow(), &canonical_roots) {
+                            on_user_error.call(e);
+                            return glib::ControlFlow::Continue;
+                        }
+
+                        // The file is read (and reported) at the path as requested.
+                        let path = expand_tilde(&resolved.requested);
+                        let allow_special = limits_for(&open_limits, &special_file_filter, &path).allow_special;
+                        spawn_peek_file(send.clone(), path, allow_special);
+                    },
+                    MultiArchiverAction::PeekSuccess(path, content) => {
+                        on_peek.call((path, content));
+                    },
+                    MultiArchiverAction::ReadRangeRequest(ix, range) => {
+                        if ix >= files.len() {
+                            warn(&on_internal_warning, ArchiverError::InvalidIndex(ix));
+                            return glib::ControlFlow::Continue;
+                        }
+                        let Some(path) = files[ix].path.clone() else {
+                            return glib::ControlFlow::Continue;
+                        };
+                        spawn_read_range(send.clone(), ix, path, range);
+                    },
+                    MultiArchiverAction::RangeRead(ix, key, range, content) => {
+
+                        // The file might have been closed while the range was read.
+                        if ix >= files.len() || files[ix].key() != key.as_path() {
+                            return glib::ControlFlow::Continue;
+                        }
+                        on_range_read.call((files[ix].without_content(), range, content));
+                    },
+                    MultiArchiverAction::ExternalChange(path) => {
+
+                        // The saves done by the archiver also trigger the monitor. They are ignored
+                        // while pending, and afterwards by comparing the modification time with
+                        // the one recorded at SaveSuccess.
+                        if io_queue.contains(&path, IoKind::Save) || muted.contains(&path) || watch_pauses > 0 {
+                            return glib::ControlFlow::Continue;
+                        }
+                        let Some(ix) = files.iter().position(|f| f.path.as_deref() == Some(path.as_path()) ) else {
+                            return glib::ControlFlow::Continue;
+                        };
+                        let Ok(mtime) = fs::metadata(&path).and_then(|m| m.modified() ) else {
+                            return glib::ControlFlow::Continue;
+                        };
+                        if files[ix].mtime == Some(mtime) || reported_changes.get(&path) == Some(&mtime) {
+                            return glib::ControlFlow::Continue;
+                        }
+                        reported_changes.insert(path, mtime);
+                        on_externally_changed.call(files[ix].without_content());
+                    },
+                    MultiArchiverAction::ExternalDeletion(path) => {
+                        let Some(ix) = files.iter().position(|f| f.path.as_deref() == Some(path.as_path()) ) else {
+                            return glib::ControlFlow::Continue;
+                        };
+
+                        // Programs that save by replacing the file delete it just before
+                        // creating it again (which is reported as a change).
+                        if muted.contains(&path) || watch_pauses > 0 || path.exists() {
+                            return glib::ControlFlow::Continue;
+                        }
+                        on_externally_deleted.call(files[ix].without_content());
+                    },
+                    MultiArchiverAction::PauseWatching => {
+                        watch_pauses += 1;
+                    },
+                    MultiArchiverAction::ResumeWatching => {
+                        if watch_pauses == 0 {
+                            warn(&on_internal_warning, ArchiverError::Internal(String::from("ResumeWatching sent without PauseWatching")));
+                            return glib::ControlFlow::Continue;
                         }
-                        
-                        // SetSaved will be called when a buffer is cleared after a file is closed,
-                        // so we just ignore the call in this case, since the file won't be at the
-                        // buffer anymore (impl React<QueriesEditor> for MultiArchiver).
-                        if last_closed_file.clone().map(|f| f.index == ix ).unwrap_or(false) {
-                            last_closed_file = None;
+                        watch_pauses -= 1;
+                        if watch_pauses > 0 {
+                            return glib::ControlFlow::Continue;
+                        }
+                        let watched : Vec<OpenedFile> = files.iter()
+                            .filter(|f| f.path.as_ref().is_some_and(|path| !muted.contains(path) && !io_queue.contains(path, IoKind::Save) ) )
+                            .map(|f| f.without_content() )
+                            .collect();
+                        let on_panic = {
+                            let send = send.clone();
+                            move |msg| {
+                                send.send(MultiArchiverAction::OpenError(ArchiverError::WorkerPanic(msg)))
+                                    .unwrap_or_else(super::log_err);
+                            }
+                        };
+
+                        // Network files might block, so they are checked away from the main thread.
+                        let send = send.clone();
+                        spawn_worker(&resolve_key, on_panic, move || {
+                            let mut changed = Vec::new();
+                            let mut deleted = Vec::new();
+                            for file in watched {
+                                let Some(path) = &file.path else {
+                                    continue;
+                                };
+                                match fs::metadata(path).and_then(|m| m.modified() ) {
+                                    Ok(mtime) => {
+                                        if file.mtime != Some(mtime) {
+                                            changed.push((file, mtime));
+                                        }
+                                    },
+                                    Err(_) => {
+                                        if !path.exists() {
+                                            deleted.push(file);
+                                        }
+                                    }
+                                }
+                            }
+                            send.send(MultiArchiverAction::WatchingRescanned(changed, deleted))
+                                .unwrap_or_else(super::log_err);
+                            true
+                        });
+                    },
+                    MultiArchiverAction::WatchingRescanned(rescanned, deleted) => {
+
+                        // Files might have been closed, saved or muted while they were checked.
+                        let is_watched = |file : &OpenedFile| -> Option<&OpenedFile> {
+                            let path = file.path.as_ref()?;
+                            if muted.contains(path) || io_queue.contains(path, IoKind::Save) {
+                                return None;
+                            }
+                            files.iter().find(|f| f.path.as_ref() == Some(path) )
+                        };
+                        let mut changed = Vec::new();
+                        for (file, mtime) in rescanned {
+                            let Some(current) = is_watched(&file) else {
+                                continue;
+                            };
+                            let path = current.path.clone().unwrap();
+                            if current.mtime != Some(mtime) && reported_changes.get(&path) != Some(&mtime) {
+                                reported_changes.insert(path, mtime);
+                                changed.push(file);
+                            }
+                        }
+                        let deleted = deleted.into_iter().filter(|f| is_watched(f).is_some() ).collect();
+                        on_watching_resumed.call((changed, deleted));
+                    },
+                    MultiArchiverAction::MuteExternalChanges(ix, mute) => {
+                        if ix >= files.len() {
+                            warn(&on_internal_warning, ArchiverError::InvalidIndex(ix));
+                            return glib::ControlFlow::Continue;
+                        }
+
+                        // Untitled files are not watched.
+                        let Some(path) = files[ix].path.clone() else {
+                            return glib::ControlFlow::Continue;
+                        };
+                        if mute {
+                            muted.insert(path);
+                        } else {
+                            muted.remove(&path);
+                        }
+                    },
+                    MultiArchiverAction::ReloadRequest(ix) => {
+                        if ix >= files.len() {
+                            warn(&on_internal_warning, ArchiverError::InvalidIndex(ix));
+                            return glib::ControlFlow::Continue;
+                        }
+
+                        // Untitled files have nothing to be reloaded from.
+                        let Some(path) = files[ix].location() else {
+                            return glib::ControlFlow::Continue;
+                        };
+                        io_queue.push(&path, IoKind::Open);
+                        let limits = limits_for(&open_limits, &special_file_filter, &path);
+                        spawn_reload_file(send.clone(), path, limits, io_backend.get());
+                    },
+                    MultiArchiverAction::ReloadSuccess(file) => {
+                        io_queue.metrics.borrow_mut().opens += 1;
+
+                        // The file might have been closed while it was read.
+                        let Some(ix) = files.iter().position(|f| f.path.is_some() && f.path == file.path ) else {
+                            return glib::ControlFlow::Continue;
+                        };
+                        files[ix].content = file.content;
+                        files[ix].compressed = file.compressed;
+                        files[ix].mime = file.mime;
+                        files[ix].inferred_extension = file.inferred_extension;
+                        files[ix].bom = file.bom;
+                        files[ix].encoding = file.encoding;
+                        files[ix].truncated = file.truncated;
+                        files[ix].mtime = file.mtime;
+                        if let Some(path) = &file.path {
+                            files[ix].read_only = file.read_only || view_only.contains(path);
+                            reported_changes.remove(path);
+                        }
+                        update_view(&view, &files, selected, &listeners);
+                        on_reloaded.call(files[ix].clone());
+                        send.send(MultiArchiverAction::SetSaved(ix, true))
+                            .unwrap_or_else(super::log_err);
+                    },
+                    MultiArchiverAction::ReloadDone(path) => {
+                        io_queue.remove(&path, IoKind::Open);
+                    },
+                    MultiArchiverAction::BeginBulk => {
+                        bulk_depth += 1;
+                    },
+                    MultiArchiverAction::EndBulk => {
+                        if bulk_depth == 0 {
+                            warn(&on_internal_warning, ArchiverError::UnmatchedEndBulk);
+                            return glib::ControlFlow::Continue;
+                        }
+
+                        // Files requested during the bulk operation might still be opening.
+                        // The bulk operation ends after the last of them (at OpenDone or OpenSettled).
+                        if opening || !resolving.is_empty() {
+                            deferred_end_bulk += 1;
+                            return glib::ControlFlow::Continue;
+                        }
+
+                        end_bulk(&mut bulk_depth, &mut bulk, &on_bulk_done);
+                    },
+                    MultiArchiverAction::OpenSuccess(mut file) => {
+
+                        // Opens that did not go through OpenRequest (e.g. session restores) might
+                        // still race with one for the same path.
+                        if let Some(path) = &file.path {
+                            if contains_path(&files, path, case_sensitivity.ignores_case(path)) {
+                                return glib::ControlFlow::Continue;
+                            }
+                        } else if file.uri.is_some() && files.iter().any(|f| f.path.is_none() && f.key() == file.key() ) {
+                            return glib::ControlFlow::Continue;
+                        }
+                        io_queue.metrics.borrow_mut().opens += 1;
+                        file.index = files.len();
+                        file.adopted = file.path.as_ref().map(|p| adopted.contains_key(p) ).unwrap_or(false);
+                        file.read_only |= file.path.as_ref().map(|p| view_only.contains(p) ).unwrap_or(false);
+                        files.push(file.clone());
+                        if let Some(path) = &file.path {
+                            watch_file(&mut monitors, path, &send, &on_internal_warning);
+                            file.position = positions.remove(path);
+                            system_recent.borrow().register(&file);
+                        }
+                        update_view(&view, &files, selected, &listeners);
+                        if bulk_depth > 0 {
+                            bulk.opened.push(file.clone());
+                        } else {
+                            on_open.call(file.clone());
+                        }
+                        if file.read_only {
+                            on_readonly_opened.call(file.clone());
+                        }
+                        if file.encoding.is_some() {
+                            on_encoding_detected.call(file.clone());
+                        }
+                        send.send(MultiArchiverAction::SetSaved(file.index, true))
+                            .unwrap_or_else(super::log_err);
+                        if file.recovered {
+                            send.send(MultiArchiverAction::SetSaved(file.index, false))
+                                .unwrap_or_else(super::log_err);
+                        }
+                        if let Some(pos) = reopening.iter().position(|p| Some(p) == file.path.as_ref() ) {
+                            reopening.remove(pos);
+                            on_reopened_closed.call(file.clone());
+                        }
+                        if let Some(path) = &file.path {
+                            if let Some(pos) = undoing.iter().position(|p| p == path ) {
+                                undoing.remove(pos);
+                            } else if !restoring.contains(path) {
+                                push_operation(&mut journal, Operation::Opened(path.clone()));
+                            }
+                        }
+
+                        // Only recovered files and files opened at a URI might have no path.
+                        if let Some(path) = &file.path {
+                            if !file.adopted && !contains_path(&recent_files, path, case_sensitivity.ignores_case(path)) {
+                                recent_files.push(file.clone());
+                            }
+                        }
+                        if let Some(store) = &recent_store {
+                            if let Some(path) = &file.path {
+                                store.set_opened(store_id, path, true);
+                            }
+                            if !file.adopted {
+                                store.push(store_id, &file);
+                            }
+                        }
+                    },
+                    MultiArchiverAction::OpenError(msg) => {
+                        io_queue.metrics.borrow_mut().open_errors += 1;
+                        on_user_error.call(msg.clone());
+                    },
+                    MultiArchiverAction::ReopenLastClosed => {
+
+                        // Files opened again meanwhile are skipped.
+                        while let Some(closed) = closed_history.pop() {
+                            let Some(path) = closed.path else {
+                                continue;
+                            };
+                            if !contains_path(&files, &path, case_sensitivity.ignores_case(&path)) {
+                                reopening.push(path.clone());
+                                send.send(MultiArchiverAction::OpenRequest(path))
+                                    .unwrap_or_else(super::log_err);
+                                break;
+                            }
+                        }
+                    },
+                    MultiArchiverAction::UndoLastOperation => {
+                        while let Some(op) = journal.pop() {
+                            let find = |path : &Path| files.iter().position(|f| f.path.as_deref() == Some(path) );
+                            match op {
+                                Operation::Opened(path) => {
+                                    let Some(ix) = find(&path) else {
+                                        continue;
+                                    };
+
+                                    // The close of an unsaved file is confirmed first, and journaled as
+                                    // any other close once confirmed.
+                                    if files[ix].saved {
+                                        undoing.push(path);
+                                    }
+                                    send.send(MultiArchiverAction::CloseRequest(ix, false))
+                                        .unwrap_or_else(super::log_err);
+                                },
+                                Operation::Closed(paths) => {
+                                    let closed : Vec<PathBuf> = paths.into_iter()
+                                        .filter(|p| !contains_path(&files, p, case_sensitivity.ignores_case(p)) )
+                                        .collect();
+                                    if closed.is_empty() {
+                                        continue;
+                                    }
+                                    for path in closed {
+                                        undoing.push(path.clone());
+                                        send.send(MultiArchiverAction::OpenRequest(path))
+                                            .unwrap_or_else(super::log_err);
+                                    }
+                                },
+                                Operation::Renamed(old_path, new_path) => {
+                                    let Some(ix) = find(&new_path) else {
+                                        continue;
+                                    };
+                                    undoing.push(old_path.clone());
+                                    send.send(MultiArchiverAction::RenameRequest(ix, old_path))
+                                        .unwrap_or_else(super::log_err);
+                                },
+                                Operation::Moved(key, from) => {
+                                    let (pos, n) = {
+                                        let view = view.borrow();
+                                        let pos = view.order.iter().position(|ix| view.files[*ix].key() == key.as_path() );
+                                        (pos, view.order.len())
+                                    };
+                                    let Some(pos) = pos else {
+                                        continue;
+                                    };
+                                    undoing_move = true;
+                                    send.send(MultiArchiverAction::MoveFile(pos, from.min(n - 1)))
+                                        .unwrap_or_else(super::log_err);
+                                }
+                            }
+                            break;
+                        }
+                    },
+                    MultiArchiverAction::SetWorkspaceRoot(dir) => {
+                        *workspace_root.borrow_mut() = dir.map(expand_tilde);
+                    },
+                    MultiArchiverAction::SetAllowedRoots(dirs) => {
+                        *roots.borrow_mut() = dirs.iter().map(expand_tilde).collect();
+                        roots_generation += 1;
+                        canonical_roots = lexical_roots(&roots.borrow(), case_sensitivity);
+                        spawn_resolve_roots(&resolve_key, send.clone(), roots_generation, roots.borrow().clone(), case_sensitivity);
+                    },
+                    MultiArchiverAction::RootsResolved(generation, resolved) => {
+                        if generation == roots_generation {
+                            canonical_roots = resolved;
+                        }
+                    },
+                    MultiArchiverAction::SetCaseSensitivity(case) => {
+                        case_sensitivity = case;
+                        if !roots.borrow().is_empty() {
+                            roots_generation += 1;
+                            canonical_roots = lexical_roots(&roots.borrow(), case_sensitivity);
+                            spawn_resolve_roots(&resolve_key, send.clone(), roots_generation, roots.borrow().clone(), case_sensitivity);
+                        }
+                    },
+                    MultiArchiverAction::SetName(ix, name) => {
+                        if ix >= files.len() {
+                            warn(&on_internal_warning, ArchiverError::InvalidIndex(ix));
+                            return glib::ControlFlow::Continue;
+                        }
+                        let stem = name.strip_suffix(&format!(".{}", extension)[..]).unwrap_or("");
+                        let valid = files[ix].path.is_none() && files[ix].uri.is_none() &&
+                            !stem.trim().is_empty() &&
+                            !name.contains(std::path::is_separator) &&
+                            !files.iter().enumerate().any(|(i, f)| i != ix && f.path.is_none() && f.name == name );
+                        if !valid {
+                            on_user_error.call(ArchiverError::InvalidName(name));
+                            return glib::ControlFlow::Continue;
+                        }
+                        let old_key = files[ix].key().to_path_buf();
+                        files[ix].name = name.clone();
+                        view.borrow_mut().rename_key(&old_key, Path::new(&name));
+                        update_view(&view, &files, selected, &listeners);
+                        on_name_changed.call((ix, name));
+                    },
+                    MultiArchiverAction::RenameRequest(ix, new_path) => {
+                        if ix >= files.len() {
+                            warn(&on_internal_warning, ArchiverError::InvalidIndex(ix));
+                            return glib::ControlFlow::Continue;
+                        }
+
+                        // Untitled files are renamed with SetName.
+                        let Some(old_path) = files[ix].path.clone() else {
+                            on_user_error.call(ArchiverError::InvalidName(new_path.display().to_string()));
+                            return glib::ControlFlow::Continue;
+                        };
+                        let new_path = expand_tilde(&new_path);
+                        if !new_path.is_absolute() {
+                            on_user_error.call(ArchiverError::NonAbsolutePath(new_path));
+                            return glib::ControlFlow::Continue;
+                        }
+                        let on_panic = {
+                            let send = send.clone();
+                            let old_path = old_path.clone();
+                            move |msg| {
+                                send.send(MultiArchiverAction::RenameError(old_path, ArchiverError::WorkerPanic(msg)))
+                                    .unwrap_or_else(super::log_err);
+                            }
+                        };
+                        spawn_resolve(&resolve_key, send.clone(), new_path, true, case_sensitivity, on_panic, move |resolved| {
+                            vec![MultiArchiverAction::RenameResolved(old_path, resolved)]
+                        });
+                    },
+                    MultiArchiverAction::RenameResolved(old_path, resolved) => {
+
+                        // The file might have been closed (or renamed) while the path was resolved.
+                        if !files.iter().any(|f| f.path.as_deref() == Some(old_path.as_path()) ) {
+                            return glib::ControlFlow::Continue;
+                        }
+                        let ResolvedPath { path : new_path, ignore_case, .. } = resolved;
+                        if let Err(e) = check_roots(&new_path, &roots.borrow(), &canonical_roots) {
+                            on_user_error.call(e);
+                            return glib::ControlFlow::Continue;
+                        }
+                        if contains_path(&files, &new_path, ignore_case) {
+                            on_user_error.call(ArchiverError::AlreadyOpened(new_path));
+                            return glib::ControlFlow::Continue;
+                        }
+                        if io_queue.contains(&old_path, IoKind::Save) {
+                            on_user_error.call(ArchiverError::Io(format!("File is being saved: {}", old_path.display())));
+                            return glib::ControlFlow::Continue;
+                        }
+
+                        // Otherwise the rename would be reported as an external deletion.
+                        monitors.remove(&old_path);
+                        spawn_rename_file(send.clone(), old_path, new_path);
+                    },
+                    MultiArchiverAction::RenameSuccess(old_path, new_path) => {
+
+                        // The file might have been closed while it was renamed.
+                        let Some(ix) = files.iter().position(|f| f.path.as_deref() == Some(old_path.as_path()) ) else {
+                            return glib::ControlFlow::Continue;
+                        };
+                        files[ix].name = new_path.display().to_string();
+                        files[ix].path = Some(new_path.clone());
+                        files[ix].uri = remote_uri(&new_path);
+                        if let Some(pos) = undoing.iter().position(|p| *p == new_path ) {
+                            undoing.remove(pos);
+                        } else {
+                            push_operation(&mut journal, Operation::Renamed(old_path.clone(), new_path.clone()));
+                        }
+                        view.borrow_mut().rename_key(&old_path, &new_path);
+                        update_view(&view, &files, selected, &listeners);
+                        watch_file(&mut monitors, &new_path, &send, &on_internal_warning);
+                        reported_changes.remove(&old_path);
+                        idle_generations.remove(&old_path);
+                        if muted.remove(&old_path) {
+                            muted.insert(new_path.clone());
+                        }
+                        if let Some(opts) = adopted.remove(&old_path) {
+                            adopted.insert(new_path.clone(), opts);
+                        }
+                        if view_only.remove(&old_path) {
+                            view_only.insert(new_path.clone());
+                        }
+                        if let Some(autosave) = &autosave {
+                            autosave.discard(&old_path);
+                        }
+                        {
+                            let mut save_history = save_history.borrow_mut();
+                            if let Some(history) = save_history.remove(&old_path) {
+                                save_history.insert(new_path.clone(), history);
+                            }
+                        }
+                        for f in recent_files.iter_mut() {
+                            if f.path.as_deref() == Some(old_path.as_path()) {
+                                f.name = new_path.display().to_string();
+                                f.path = Some(new_path.clone());
+                            }
+                        }
+                        if let Some(store) = &recent_store {
+                            store.set_opened(store_id, &old_path, false);
+                            store.set_opened(store_id, &new_path, true);
+                            store.rename(&old_path, &files[ix]);
+                        }
+                        final_state.replace(FinalState { recent : recent_files.clone(), files : files.clone(), selected : selected_key(&files, selected) });
+                        on_name_changed.call((ix, new_path.display().to_string()));
+                    },
+                    MultiArchiverAction::RenameError(old_path, e) => {
+                        if files.iter().any(|f| f.path.as_deref() == Some(old_path.as_path()) ) {
+                            watch_file(&mut monitors, &old_path, &send, &on_internal_warning);
+                        }
+                        on_user_error.call(e);
+                    },
+                    MultiArchiverAction::TrashRequest(ix) => {
+                        if ix >= files.len() {
+                            warn(&on_internal_warning, ArchiverError::InvalidIndex(ix));
+                            return glib::ControlFlow::Continue;
+                        }
+                        let Some(path) = files[ix].path.clone() else {
+                            on_user_error.call(ArchiverError::Io(format!("{} was never saved", files[ix].name)));
+                            return glib::ControlFlow::Continue;
+                        };
+
+                        // Otherwise the trash would be reported as an external deletion.
+                        monitors.remove(&path);
+                        spawn_trash_file(send.clone(), path);
+                    },
+                    MultiArchiverAction::TrashSuccess(path) => {
+                        let Some(ix) = files.iter().position(|f| f.path.as_deref() == Some(path.as_path()) ) else {
                             return glib::ControlFlow::Continue;
+                        };
+                        let token = TrashToken(next_trash_token);
+                        next_trash_token += 1;
+                        trashed.insert(token, path);
+                        on_trashed.call((files[ix].without_content(), token));
+                        send.send(MultiArchiverAction::CloseRequest(ix, true))
+                            .unwrap_or_else(super::log_err);
+                    },
+                    MultiArchiverAction::TrashError(path, e) => {
+                        if files.iter().any(|f| f.path.as_deref() == Some(path.as_path()) ) {
+                            watch_file(&mut monitors, &path, &send, &on_internal_warning);
+                        }
+                        on_user_error.call(e);
+                    },
+                    MultiArchiverAction::UndoTrashRequest(token) => {
+                        match trashed.remove(&token) {
+                            Some(path) => spawn_restore_trashed(send.clone(), path),
+                            None => warn(&on_internal_warning, ArchiverError::Internal(format!("Unknown trash token: {:?}", token)))
+                        }
+                    },
+                    MultiArchiverAction::SetOrder(order) => {
+                        view.borrow_mut().order_policy = order;
+                        update_view(&view, &files, selected, &listeners);
+                    },
+                    MultiArchiverAction::MoveFile(from, to) => {
+                        {
+                            let mut view = view.borrow_mut();
+                            if from >= view.order.len() || to >= view.order.len() {
+                                warn(&on_internal_warning, ArchiverError::InvalidIndex(from.max(to)));
+                                return glib::ControlFlow::Continue;
+                            }
+                            let mut manual : Vec<PathBuf> = view.order.iter()
+                                .map(|ix| view.files[*ix].key().to_path_buf() )
+                                .collect();
+                            let key = manual.remove(from);
+                            if !std::mem::take(&mut undoing_move) {
+                                push_operation(&mut journal, Operation::Moved(key.clone(), from));
+                            }
+                            manual.insert(to, key);
+                            view.manual = manual;
+                            view.order_policy = FileOrder::Manual;
+                        }
+                        update_view(&view, &files, selected, &listeners);
+                    },
+                    MultiArchiverAction::Select(opt_ix) => {
+                        
+                        if let Some(ix) = opt_ix {
+                            if ix >= files.len() {
+                                warn(&on_internal_warning, ArchiverError::InvalidIndex(ix));
+                                return glib::ControlFlow::Continue;
+                            }
+                        }
+                        
+                        selected = opt_ix;
+                        update_view(&view, &files, selected, &listeners);
+                        on_selected.call(opt_ix.map(|ix| files[ix].clone() ));
+                    },
+                    MultiArchiverAction::WindowCloseRequest => {
+                        if let Some(file) = files.iter().filter(|file| !file.saved ).next() {
+                            on_close_confirm.call(file.clone());
+                            win_close_request = true;
+                        } else if !super::close_vetoed(&on_window_close_veto) {
+                            for file in &files {
+                                release_adopted(&mut adopted, file);
+                            }
+                            on_window_close.call(());
+                            if let Some(store) = &recent_store {
+                                store.unregister(store_id);
+                            }
                         }
+                        final_state.replace(FinalState { recent : recent_files.clone(), files : files.clone(), selected : selected_key(&files, selected) });
+                    }
+                }
+                glib::ControlFlow::Continue
+            }
+        });
+
+        Self {
+            on_open,
+            on_new,
+            send,
+            on_selected,
+            on_file_closed,
+            on_close_confirm,
+            on_file_changed,
+            on_file_persisted,
+            on_active_text_changed,
+            on_window_close,
+            on_window_close_veto,
+            on_buffer_read_request,
+            on_untitled_content,
+            on_save_unknown_path,
+            on_name_changed,
+            on_user_error,
+            on_internal_warning,
+            on_added,
+            on_added_many,
+            on_recent_changed,
+            on_recent_validated,
+            on_reopen,
+            on_readonly_opened,
+            on_encoding_detected,
+            on_reopened_closed,
+            on_session_exported,
+            on_recent_metadata,
+            on_files_closed,
+            on_trashed,
+            on_opened_elsewhere,
+            on_io_stalled,
+            on_offline,
+            on_back_online,
+            on_autosaved,
+            on_backup_created,
+            on_copy_saved,
+            on_save_conflict,
+            on_suspicious_save,
+            on_all_saved,
+            on_recoveries_found,
+            recoveries,
+            on_bulk_done,
+            on_peek,
+            on_open_filter,
+            on_capacity_changed,
+            on_order_changed,
+            on_externally_changed,
+            on_externally_deleted,
+            on_reloaded,
+            final_state,
+            save_history,
+            save_options,
+            truncation_ratio,
+            open_limits,
+            special_file_filter,
+            io_backend,
+            system_recent,
+            workspace_root,
+            allowed_roots,
+            on_range_read,
+            on_watching_resumed,
+            on_cancelled,
+            on_io_progress,
+            view,
+            file_model,
+            io_queue,
+            save_validators
+        }
+    }
+
+}
+
+/// Order in which the opened files are exposed (e.g. the order of the tabs).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FileOrder {
+
+    // The order set with MoveFile (the open order if no file was moved).
+    #[default]
+    Manual,
+
+    // By file name, ignoring case.
+    Alphabetical,
+
+    // The most recently opened files first.
+    Recency,
+
+    // By the directory containing the file, then by file name.
+    Directory
+
+}
+
+// Copy of the opened files (without their content) and of the selection, exposed
+// by the MultiArchiver getters.
+#[derive(Debug, Clone, Default)]
+struct View {
+    files : Vec<OpenedFile>,
+    selected : Option<usize>,
+
+    // Indices of the files in the exposed order.
+    order : Vec<usize>,
+
+    order_policy : FileOrder,
+
+    // Keys of the files in the manual order. Keys are used instead of indices
+    // so that the manual order survives files being closed.
+    manual : Vec<PathBuf>,
+
+    // Maximum number of opened files.
+    capacity : usize
+}
+
+impl View {
+
+    fn rename_key(&mut self, old_key : &Path, new_key : &Path) {
+        if let Some(key) = self.manual.iter_mut().find(|k| k.as_path() == old_key ) {
+            *key = new_key.to_path_buf();
+        }
+    }
+
+    fn sorted(&mut self) -> Vec<usize> {
+        let keys : Vec<PathBuf> = self.files.iter().map(|f| f.key().to_path_buf() ).collect();
+        self.manual.retain(|k| keys.contains(k) );
+        for key in &keys {
+            if !self.manual.contains(key) {
+                self.manual.push(key.clone());
+            }
+        }
+        let mut order : Vec<usize> = (0..self.files.len()).collect();
+        let files = &self.files;
+        match self.order_policy {
+            FileOrder::Manual => {
+                order.sort_by_key(|ix| self.manual.iter().position(|k| k == &keys[*ix] ) );
+            },
+            FileOrder::Alphabetical => {
+                order.sort_by_cached_key(|ix| files[*ix].file_name().to_lowercase() );
+            },
+            FileOrder::Recency => {
+                order.sort_by(|a, b| files[*b].dt.cmp(&files[*a].dt) );
+            },
+            FileOrder::Directory => {
+                order.sort_by_cached_key(|ix| {
+                    let dir = files[*ix].path.as_ref()
+                        .and_then(|p| p.parent() )
+                        .map(|p| p.display().to_string() )
+                        .unwrap_or_default();
+                    (dir, files[*ix].file_name().to_lowercase())
+                });
+            }
+        }
+        order
+    }
+
+}
+
+/// Kind of a pending IO operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoKind {
+    Open,
+    Save
+}
+
+// IO operations in flight, shared with the MultiArchiver getters.
+#[derive(Clone, Default)]
+struct IoQueue {
+    pending : Rc<RefCell<Vec<(PathBuf, IoKind)>>>,
+
+    // When each pending operation was requested (in the same order as pending).
+    started : Rc<RefCell<Vec<Instant>>>,
+
+    // Updated with the latency of every operation removed from the queue.
+    metrics : Rc<RefCell<ArchiverMetrics>>,
+    on_changed : Callbacks<Vec<(PathBuf, IoKind)>>
+}
+
+impl IoQueue {
+
+    fn push(&self, path : &Path, kind : IoKind) {
+        self.pending.borrow_mut().push((path.to_path_buf(), kind));
+        self.started.borrow_mut().push(Instant::now());
+        self.on_changed.call(self.pending.borrow().clone());
+    }
+
+    fn contains(&self, path : &Path, kind : IoKind) -> bool {
+        self.pending.borrow().iter().any(|(p, k)| p.as_path() == path && *k == kind )
+    }
+
+    // Removes the oldest operation of the kind at the path (the same path
+    // might be saved several times in a row).
+    fn remove(&self, path : &Path, kind : IoKind) {
+        let removed = {
+            let mut pending = self.pending.borrow_mut();
+            match pending.iter().position(|(p, k)| p.as_path() == path && *k == kind ) {
+                Some(pos) => {
+                    pending.remove(pos);
+                    let started = self.started.borrow_mut().remove(pos);
+                    self.metrics.borrow_mut().record_latency(started.elapsed());
+                    true
+                },
+                None => false
+            }
+        };
+        if removed {
+            self.on_changed.call(self.pending.borrow().clone());
+        }
+    }
+
+}
 
-                        if saved {
-                            files[ix].saved = true;
-                            on_file_persisted.call(files[ix].clone());
-                        } else {
-                        
-                            if files[ix].saved {
-                                files[ix].saved = false;
-                                on_file_changed.call(files[ix].clone());
-                            }
-                        }
-                    },
-                    MultiArchiverAction::OpenSuccess(file) => {
-                        if file.index != files.len() {
-                            eprintln!("Error: New file has index {}, but it should be {}", file.index, files.len());
-                        }
-                        files.push(file.clone());
-                        on_open.call(file.clone());
-                        send.send(MultiArchiverAction::SetSaved(file.index, true))
-                            .unwrap_or_else(super::log_err);
+// Callbacks fired by update_view, and the model it keeps in sync.
+struct ViewListeners {
+    on_capacity_changed : Callbacks<usize>,
+    on_order_changed : Callbacks<Vec<usize>>,
+    file_model : gio::ListStore
+}
 
-                        if recent_files.iter().find(|f| &f.path.as_ref().unwrap()[..] == &file.path.as_ref().unwrap()[..] ).is_none() {
-                            recent_files.push(file.clone());
-                        }
-                    },
-                    MultiArchiverAction::OpenError(msg) => {
-                        on_error.call(msg.clone());
-                    },
-                    MultiArchiverAction::SetPrefix(opt_path) => {
-                        prefix = opt_path;
-                    },
-                    MultiArchiverAction::Select(opt_ix) => {
-                        
-                        if let Some(ix) = opt_ix {
-                            if ix >= files.len() {
-                                eprintln!("Invalid file index at selection: {}", ix);
-                                return glib::ControlFlow::Continue;
-                            }
-                        }
-                        
-                        selected = opt_ix;
-                        on_selected.call(opt_ix.map(|ix| files[ix].clone() ));
+// Must be called by the action loop after the files or the selection change, and
+// before the corresponding callbacks are called, so that the getters are consistent
+// inside the callbacks. Calls on_capacity_changed if the number of files changed,
+// and on_order_changed if the exposed order changed. The file model is updated
+// before the callbacks are called as well.
+fn update_view(
+    view : &Rc<RefCell<View>>,
+    files : &[OpenedFile],
+    selected : Option<usize>,
+    listeners : &ViewListeners
+) {
+    let (len_changed, new_order) = {
+        let mut view = view.borrow_mut();
+        let len_changed = view.files.len() != files.len();
+        view.files = files.iter().map(|f| f.without_content() ).collect();
+        view.selected = selected;
+        let order = view.sorted();
+        let new_order = if order != view.order {
+            view.order = order.clone();
+            Some(order)
+        } else {
+            None
+        };
+        (len_changed, new_order)
+    };
+    {
+        let view = view.borrow();
+        sync_file_model(&listeners.file_model, files, &view.order);
+    }
+    if len_changed {
+        listeners.on_capacity_changed.call(view.borrow().capacity.saturating_sub(files.len()));
+    }
+    if let Some(order) = new_order {
+        listeners.on_order_changed.call(order);
+    }
+}
+
+fn remove_file(files : &mut Vec<OpenedFile>, ix : usize, selected : &mut Option<usize>) -> OpenedFile {
+    files[(ix+1)..].iter_mut().for_each(|f| f.index -= 1 );
+    if let Some(sel) = selected.as_mut() {
+        if *sel >= ix+1 {
+            *sel -= 1;
+        } else if *sel == ix {
+            *selected = None;
+        }
+    }
+    files.remove(ix)
+}
+
+// Untitled files renamed with SetName are not numbered.
+fn next_untitled(files : &[OpenedFile], extension : &str) -> usize {
+    let suffix = format!(".{}", extension);
+    let n_untitled = files.iter().filter(|f| f.path.is_none() )
+        .filter_map(|f| f.name.strip_prefix("Untitled ")?.strip_suffix(&suffix[..])?.parse::<usize>().ok() )
+        .max()
+        .unwrap_or(0);
+    n_untitled + 1
+}
+
+fn spawn_prefetch_metadata(send : glib::Sender<MultiArchiverAction>, paths : Vec<PathBuf>) {
+    thread::spawn(move || {
+        let meta = paths.into_iter()
+            .map(|path| {
+                match fs::metadata(&path) {
+                    Ok(m) => RecentMeta {
+                        exists : true,
+                        size : Some(m.len()),
+                        mtime : m.modified().ok(),
+                        path
                     },
-                    MultiArchiverAction::WindowCloseRequest => {
-                        if let Some(file) = files.iter().filter(|file| !file.saved ).next() {
-                            on_close_confirm.call(file.clone());
-                            win_close_request = true;
-                        } else {
-                            on_window_close.call(());
-                        }
-                        final_state.replace(FinalState { recent : recent_files.clone(), files : files.clone() });
-                    }
+                    Err(_) => RecentMeta { path, exists : false, size : None, mtime : None }
                 }
-                glib::ControlFlow::Continue
+            })
+            .collect();
+        send.send(MultiArchiverAction::RecentMetadata(meta))
+            .unwrap_or_else(super::log_err);
+    });
+}
+
+fn spawn_validate_recent(send : glib::Sender<MultiArchiverAction>, paths : Vec<PathBuf>, remove_missing : bool) {
+    thread::spawn(move || {
+        let missing = paths.into_iter()
+            .filter(|path| matches!(path.try_exists(), Ok(false)) )
+            .collect();
+        send.send(MultiArchiverAction::RecentValidated { missing, remove_missing })
+            .unwrap_or_else(super::log_err);
+    });
+}
+
+fn spawn_trash_file(send : glib::Sender<MultiArchiverAction>, path : PathBuf) {
+    thread::spawn(move || {
+        let action = match gio::File::for_path(&path).trash(None::<&gio::Cancellable>) {
+            Ok(_) => MultiArchiverAction::TrashSuccess(path),
+            Err(e) => {
+                let e = ArchiverError::Io(format!("Could not move {} to the trash: {}", path.display(), e));
+                MultiArchiverAction::TrashError(path, e)
+            }
+        };
+        send.send(action).unwrap_or_else(super::log_err);
+    });
+}
+
+// Moves the most recently trashed file with the original path back to it, and opens it.
+fn spawn_restore_trashed(send : glib::Sender<MultiArchiverAction>, path : PathBuf) {
+    thread::spawn(move || {
+        match restore_trashed(&path) {
+            Ok(_) => {
+                send.send(MultiArchiverAction::OpenRequest(path))
+                    .unwrap_or_else(super::log_err);
+            },
+            Err(msg) => {
+                send.send(MultiArchiverAction::OpenError(ArchiverError::Io(msg)))
+                    .unwrap_or_else(super::log_err);
+            }
+        }
+    });
+}
+
+fn restore_trashed(path : &Path) -> Result<(), String> {
+    if path.exists() {
+        return Err(format!("Cannot restore {}, since the file exists", path.display()));
+    }
+    let trash = gio::File::for_uri("trash:///");
+    let children = trash.enumerate_children(
+        "standard::name,trash::orig-path,trash::deletion-date",
+        gio::FileQueryInfoFlags::NONE,
+        None::<&gio::Cancellable>
+    ).map_err(|e| format!("Could not read the trash: {}", e) )?;
+
+    // Deletion dates are ISO 8601 strings, so they compare chronologically.
+    let mut latest : Option<(String, gio::File)> = None;
+    while let Some(info) = children.next_file(None::<&gio::Cancellable>).map_err(|e| format!("Could not read the trash: {}", e) )? {
+        let orig = info.attribute_byte_string("trash::orig-path");
+        if orig.as_deref().map(Path::new) != Some(path) {
+            continue;
+        }
+        let date = info.attribute_string("trash::deletion-date").map(|d| d.to_string() ).unwrap_or_default();
+        if latest.as_ref().map(|(latest_date, _)| date > *latest_date ).unwrap_or(true) {
+            latest = Some((date, children.child(&info)));
+        }
+    }
+    let Some((_, trashed)) = latest else {
+        return Err(format!("{} is not in the trash anymore", path.display()));
+    };
+    trashed.move_(&gio::File::for_path(path), gio::FileCopyFlags::NONE, None::<&gio::Cancellable>, None)
+        .map_err(|e| format!("Could not restore {}: {}", path.display(), e) )
+}
+
+// Renames the file, refusing to replace an existing file (which fs::rename would do silently).
+fn spawn_rename_file(send : glib::Sender<MultiArchiverAction>, old_path : PathBuf, new_path : PathBuf) {
+    thread::spawn(move || {
+        let res = if fs::symlink_metadata(&new_path).is_ok() {
+            Err(ArchiverError::AlreadyExists(new_path.clone()))
+        } else {
+            fs::rename(&old_path, &new_path).map_err(ArchiverError::from)
+        };
+        let action = match res {
+            Ok(_) => MultiArchiverAction::RenameSuccess(old_path, new_path),
+            Err(e) => MultiArchiverAction::RenameError(old_path, e)
+        };
+        send.send(action).unwrap_or_else(super::log_err);
+    });
+}
+
+// Reads the recovery file into the given file, which is then opened as usual (the file
+// keeps the metadata of the source if it still exists).
+fn spawn_recover_file(send : glib::Sender<MultiArchiverAction>, file : OpenedFile, swap : PathBuf, limits : OpenLimits) {
+    thread::spawn(move || {
+        let content = match take_recovery(&swap) {
+            Ok(content) => content,
+            Err(e) => {
+                send.send(MultiArchiverAction::OpenError(ArchiverError::Io(format!("Could not read recovery file {}: {}", swap.display(), e))))
+                    .unwrap_or_else(super::log_err);
+                return;
+            }
+        };
+        let mut file = match &file.path {
+            Some(path) if path.is_file() => read_file(path, false, limits, &|_, _| { }).unwrap_or(file),
+            _ => file
+        };
+        file.content = Some(content);
+        file.recovered = true;
+        send.send(MultiArchiverAction::OpenSuccess(file))
+            .unwrap_or_else(super::log_err);
+    });
+}
+
+fn spawn_export_session(send : glib::Sender<MultiArchiverAction>, path : PathBuf, state : FinalState, drafts : Vec<SessionDraft>) {
+    let on_panic = {
+        let send = send.clone();
+        move |msg| {
+            send.send(MultiArchiverAction::SaveError(ArchiverError::WorkerPanic(msg)))
+                .unwrap_or_else(super::log_err);
+        }
+    };
+    let key = path.clone();
+    spawn_worker(&key, on_panic, move || {
+        let action = match write_session_archive(&path, &state, &drafts) {
+            Ok(()) => MultiArchiverAction::SessionExported(path),
+            Err(e) => MultiArchiverAction::SaveError(ArchiverError::from(e))
+        };
+        send.send(action).unwrap_or_else(super::log_err);
+        true
+    });
+}
+
+// The drafts of files with a path are read like the files recovered by spawn_recover_file.
+// Untitled drafts keep their exported name, and are numbered at SessionImported.
+fn spawn_import_session(send : glib::Sender<MultiArchiverAction>, path : PathBuf, limits : OpenLimits) {
+    let on_panic = {
+        let send = send.clone();
+        move |msg| {
+            send.send(MultiArchiverAction::OpenError(ArchiverError::WorkerPanic(msg)))
+                .unwrap_or_else(super::log_err);
+        }
+    };
+    let key = path.clone();
+    spawn_worker(&key, on_panic, move || {
+        let (state, drafts) = match read_session_archive(&path) {
+            Ok(session) => session,
+            Err(e) => {
+                send.send(MultiArchiverAction::OpenError(ArchiverError::from(e)))
+                    .unwrap_or_else(super::log_err);
+                return false;
+            }
+        };
+        let drafts = drafts.into_iter()
+            .filter_map(|draft| {
+                let mut file = if draft.source.is_absolute() {
+                    let file = OpenedFile::for_path(&draft.source).ok()?;
+                    read_file(&draft.source, false, limits, &|_, _| { }).unwrap_or(file)
+                } else {
+                    let mut file = OpenedFile::untitled(0, "");
+                    file.name = draft.source.display().to_string();
+                    file
+                };
+                file.content = Some(draft.content);
+                file.recovered = true;
+                Some(file)
+            })
+            .collect();
+        send.send(MultiArchiverAction::SessionImported(state, drafts))
+            .unwrap_or_else(super::log_err);
+        true
+    });
+}
+
+fn schedule_autosave(autosave : &ArchiverAutosave, send : &glib::Sender<MultiArchiverAction>, generation : u64) {
+    let send = send.clone();
+    autosave.schedule(move || {
+        send.send(MultiArchiverAction::AutosaveTimeout(generation))
+            .unwrap_or_else(super::log_err);
+    });
+}
+
+fn schedule_snapshot(send : &glib::Sender<MultiArchiverAction>, interval : Duration, generation : u64) {
+    let send = send.clone();
+    glib::timeout_add_local_once(interval, move || {
+        send.send(MultiArchiverAction::SnapshotTimeout(generation))
+            .unwrap_or_else(super::log_err);
+    });
+}
+
+// The snapshot replaces the previous one (see write_replacing). Snapshots of the same
+// path are written in the order they were taken.
+fn spawn_write_snapshot(path : PathBuf, json : String) {
+    let key = path.clone();
+    let on_panic = |msg| eprintln!("Could not write session snapshot: {}", msg);
+    spawn_worker(&key, on_panic, move || {
+        if let Err(e) = write_replacing(&path, |f| f.write_all(json.as_bytes()) ) {
+            eprintln!("Could not write session snapshot: {}", e);
+            return false;
+        }
+        true
+    });
+}
+
+type SaveValidator = Rc<dyn Fn(&str) -> Pin<Box<dyn Future<Output = Result<(), String>>>>>;
+
+// Runs the save validators, sending SaveValidated if all of them accept the content.
+fn validate_save(
+    validators : &[SaveValidator],
+    index : usize,
+    key : PathBuf,
+    path : PathBuf,
+    content : String,
+    checks : SaveChecks,
+    send : &glib::Sender<MultiArchiverAction>
+) {
+    run_validators(validators, path.clone(), content, send, move |content| {
+        MultiArchiverAction::SaveValidated(index, key, path, content, checks)
+    });
+}
+
+// Sends the action built by done with the content if all validators accept it,
+// or SaveRejected otherwise.
+fn run_validators<D>(
+    validators : &[SaveValidator],
+    path : PathBuf,
+    content : String,
+    send : &glib::Sender<MultiArchiverAction>,
+    done : D
+)
+where
+    D : FnOnce(String) -> MultiArchiverAction + 'static
+{
+    if validators.is_empty() {
+        send.send(done(content))
+            .unwrap_or_else(super::log_err);
+        return;
+    }
+    let futures : Vec<_> = validators.iter().map(|validate| validate(&content) ).collect();
+    let send = send.clone();
+    glib::MainContext::default().spawn_local(async move {
+        for future in futures {
+            if let Err(msg) = future.await {
+                send.send(MultiArchiverAction::SaveRejected(path, msg))
+                    .unwrap_or_else(super::log_err);
+                return;
+            }
+        }
+        send.send(done(content))
+            .unwrap_or_else(super::log_err);
+    });
+}
+
+fn push_closed(closed_history : &mut Vec<OpenedFile>, closed_file : &OpenedFile) {
+    if closed_file.path.is_none() {
+        return;
+    }
+    closed_history.push(closed_file.without_content());
+    if closed_history.len() > MAX_CLOSED_HISTORY {
+        closed_history.remove(0);
+    }
+}
+
+fn push_operation(journal : &mut Vec<Operation>, op : Operation) {
+    journal.push(op);
+    if journal.len() > MAX_JOURNAL_LEN {
+        journal.remove(0);
+    }
+}
+
+// Files closed during a close sequence are journaled together when it ends (see flush_close_group).
+fn journal_closed(
+    journal : &mut Vec<Operation>,
+    close_group : &mut Option<Vec<PathBuf>>,
+    undoing : &mut Vec<PathBuf>,
+    closed_file : &OpenedFile
+) {
+    let Some(path) = &closed_file.path else {
+        return;
+    };
+    if let Some(pos) = undoing.iter().position(|p| p == path ) {
+        undoing.remove(pos);
+        return;
+    }
+    match close_group {
+        Some(group) => group.push(path.clone()),
+        None => push_operation(journal, Operation::Closed(vec![path.clone()]))
+    }
+}
+
+fn flush_close_group(journal : &mut Vec<Operation>, close_group : &mut Option<Vec<PathBuf>>) {
+    if let Some(group) = close_group.take() {
+        if !group.is_empty() {
+            push_operation(journal, Operation::Closed(group));
+        }
+    }
+}
+
+// Removes a save of the path from the SaveAllRequest in progress, sending SaveAllDone
+// after the last one.
+fn settle_save_all(save_all : &mut Option<Vec<PathBuf>>, path : &Path, send : &glib::Sender<MultiArchiverAction>) {
+    let Some(pending) = save_all else {
+        return;
+    };
+    if let Some(pos) = pending.iter().position(|p| p == path ) {
+        pending.remove(pos);
+        if pending.is_empty() {
+            *save_all = None;
+            send.send(MultiArchiverAction::SaveAllDone)
+                .unwrap_or_else(super::log_err);
+        }
+    }
+}
+
+// A save that passed the save validators.
+struct PendingSave {
+
+    // Index of the saved file, sent back at SaveSuccess.
+    index : usize,
+
+    content : String,
+
+    // How the file was stored when it was opened.
+    format : TextFormat,
+
+    checks : SaveChecks,
+
+    // Checked after the previous operations on the path finished (see CancelSave).
+    cancel : CancelToken
+
+}
+
+fn schedule_online_check(send : &glib::Sender<MultiArchiverAction>) {
+    let send = send.clone();
+    glib::timeout_add_local_once(ONLINE_CHECK_INTERVAL, move || {
+        send.send(MultiArchiverAction::CheckOnline)
+            .unwrap_or_else(super::log_err);
+    });
+}
+
+// Runs after the operations already queued for the path, which might be stalled at
+// the unreachable location.
+fn spawn_online_check(path : PathBuf, send : glib::Sender<MultiArchiverAction>) {
+    let on_panic = {
+        let send = send.clone();
+        let path = path.clone();
+        move |msg| {
+            eprintln!("Online check panicked: {}", msg);
+            send.send(MultiArchiverAction::StillOffline(path))
+                .unwrap_or_else(super::log_err);
+        }
+    };
+    let key = path.clone();
+    spawn_worker(&key, on_panic, move || {
+        let checked = if is_reachable(&path) {
+            MultiArchiverAction::BackOnline(path)
+        } else {
+            MultiArchiverAction::StillOffline(path)
+        };
+        send.send(checked)
+            .unwrap_or_else(super::log_err);
+        true
+    });
+}
+
+// Submits the save, scheduling the stall check if a save timeout is set.
+fn start_save(
+    save_task : &mut IoTask,
+    path : PathBuf,
+    save : PendingSave,
+    opts : &SaveOptions,
+    backend : IoBackend,
+    send : &glib::Sender<MultiArchiverAction>
+) {
+    let job = match backend_for(&path, backend) {
+        IoBackend::Threads => spawn_save_file(path.clone(), save, opts.clone(), send.clone()),
+        IoBackend::Gio => spawn_gio_save(path.clone(), save, opts.clone(), send.clone())
+    };
+    let generation = save_task.start(job);
+    if let Some(timeout) = opts.timeout {
+        let send = send.clone();
+        glib::timeout_add_local_once(timeout, move || {
+            send.send(MultiArchiverAction::SaveTimeout(path, generation))
+                .unwrap_or_else(super::log_err);
+        });
+    }
+}
+
+fn spawn_save_file(
+    path : PathBuf,
+    save : PendingSave,
+    opts : SaveOptions,
+    send : glib::Sender<MultiArchiverAction>
+) -> IoJob {
+    let on_panic = {
+        let send = send.clone();
+        let path = path.clone();
+        move |msg| {
+            send.send(MultiArchiverAction::SaveError(ArchiverError::WorkerPanic(msg)))
+                .unwrap_or_else(super::log_err);
+            send.send(MultiArchiverAction::SaveDone(path))
+                .unwrap_or_else(super::log_err);
+        }
+    };
+    let key = path.clone();
+    spawn_worker(&key, on_panic, move || {
+        if !save.cancel.commit() {
+            return false;
+        }
+        let saved = save_file(&send, &path, &save, &opts);
+        send.send(MultiArchiverAction::SaveDone(path))
+            .unwrap_or_else(super::log_err);
+        saved
+    })
+}
+
+fn spawn_gio_save(
+    path : PathBuf,
+    save : PendingSave,
+    opts : SaveOptions,
+    send : glib::Sender<MultiArchiverAction>
+) -> IoJob {
+    let key = path.clone();
+    submit_local(&key, async move {
+        if !save.cancel.commit() {
+            return;
+        }
+        gio_save_file(&send, &path, &save, &opts).await;
+        send.send(MultiArchiverAction::SaveDone(path))
+            .unwrap_or_else(super::log_err);
+    })
+}
+
+// Writes the file, sending SaveSuccess or SaveError.
+fn save_file(
+    send : &glib::Sender<MultiArchiverAction>,
+    path : &Path,
+    save : &PendingSave,
+    opts : &SaveOptions
+) -> bool {
+
+    #[cfg(feature = "tracing")]
+    let _span = tracing::debug_span!("save", path = %path.display()).entered();
+
+    if !path.is_absolute() {
+        send.send(MultiArchiverAction::SaveError(ArchiverError::NonAbsolutePath(path.to_path_buf())))
+            .unwrap_or_else(super::log_err);
+        return false;
+    }
+    
+    if path.is_dir() {
+        send.send(MultiArchiverAction::SaveError(ArchiverError::IsDirectory(path.to_path_buf())))
+            .unwrap_or_else(super::log_err);
+        return false;
+    }
+    
+    // A file that does not exist anymore is written again without conflict.
+    if let Some(expected) = save.checks.expected {
+        if let Ok(found) = fs::metadata(path).and_then(|m| m.modified() ) {
+            if found != expected {
+                send.send(MultiArchiverAction::SaveConflict(save.index, path.to_path_buf(), expected, found))
+                    .unwrap_or_else(super::log_err);
+                return false;
             }
+        }
+    }
+
+    // The size of compressed files says little about the length of their content.
+    if let Some(ratio) = save.checks.truncation.filter(|_| !is_gzip_path(path) ) {
+        if let Ok(old_len) = fs::metadata(path).map(|m| m.len() ) {
+            let new_len = save.content.len() as u64;
+            if old_len >= MIN_TRUNCATION_CHECK_LEN && (new_len as f64) < (old_len as f64) * ratio {
+                send.send(MultiArchiverAction::SuspiciousSave(save.index, path.to_path_buf(), old_len, new_len))
+                    .unwrap_or_else(super::log_err);
+                return false;
+            }
+        }
+    }
+
+    let progress = |done, total| {
+        send.send(MultiArchiverAction::IoProgress(path.to_path_buf(), IoKind::Save, done, total))
+            .unwrap_or_else(super::log_err);
+    };
+    match write_content(path, &save.content, &save.format, opts, &progress) {
+        Ok(backup) => {
+            if let Some(backup) = backup {
+                send.send(MultiArchiverAction::BackupCreated(path.to_path_buf(), backup))
+                    .unwrap_or_else(super::log_err);
+            }
+            let mtime = fs::metadata(path).and_then(|m| m.modified() ).ok();
+            let record = SaveRecord::build(&save.content, mtime);
+            send.send(MultiArchiverAction::SaveSuccess(save.index, path.to_path_buf(), record))
+                .unwrap_or_else(super::log_err);
+            true
+        },
+        Err(e) if is_offline_error(path, &e) => {
+            send.send(MultiArchiverAction::SaveOffline(save.index, path.to_path_buf(), save.content.clone(), save.checks))
+                .unwrap_or_else(super::log_err);
+            false
+        },
+        Err(e) => {
+            send.send(MultiArchiverAction::SaveError(ArchiverError::from(e)))
+                .unwrap_or_else(super::log_err);
+            false
+        }
+    }
+}
+
+// Writes the file like save_file, with the gio backend.
+async fn gio_save_file(
+    send : &glib::Sender<MultiArchiverAction>,
+    path : &Path,
+    save : &PendingSave,
+    opts : &SaveOptions
+) -> bool {
+    let uri = key_uri(path);
+    if !path.is_absolute() && uri.is_none() {
+        send.send(MultiArchiverAction::SaveError(ArchiverError::NonAbsolutePath(path.to_path_buf())))
+            .unwrap_or_else(super::log_err);
+        return false;
+    }
+
+    let file = location_file(path);
+    if let Err(e) = gio_mount(&file).await {
+        send.send(MultiArchiverAction::SaveError(ArchiverError::from(e)))
+            .unwrap_or_else(super::log_err);
+        return false;
+    }
+    let metadata = match gio_metadata(&file).await {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            send.send(MultiArchiverAction::SaveError(ArchiverError::from(e)))
+                .unwrap_or_else(super::log_err);
+            return false;
+        }
+    };
+
+    if metadata.as_ref().is_some_and(|m| m.is_dir ) {
+        send.send(MultiArchiverAction::SaveError(ArchiverError::IsDirectory(path.to_path_buf())))
+            .unwrap_or_else(super::log_err);
+        return false;
+    }
+
+    if let (Some(expected), Some(found)) = (save.checks.expected, metadata.as_ref().map(|m| m.modified )) {
+        if found != expected {
+            send.send(MultiArchiverAction::SaveConflict(save.index, path.to_path_buf(), expected, found))
+                .unwrap_or_else(super::log_err);
+            return false;
+        }
+    }
+
+    if let Some(ratio) = save.checks.truncation.filter(|_| !is_gzip_path(path) ) {
+        if let Some(old_len) = metadata.as_ref().map(|m| m.len ) {
+            let new_len = save.content.len() as u64;
+            if old_len >= MIN_TRUNCATION_CHECK_LEN && (new_len as f64) < (old_len as f64) * ratio {
+                send.send(MultiArchiverAction::SuspiciousSave(save.index, path.to_path_buf(), old_len, new_len))
+                    .unwrap_or_else(super::log_err);
+                return false;
+            }
+        }
+    }
+
+    match gio_write(&file, &save.content, &save.format, opts).await {
+        Ok(backup) => {
+            if let Some(backup) = backup {
+                send.send(MultiArchiverAction::BackupCreated(path.to_path_buf(), backup))
+                    .unwrap_or_else(super::log_err);
+            }
+            let mtime = gio_metadata(&file).await.ok().flatten().map(|m| m.modified );
+            let record = SaveRecord::build(&save.content, mtime);
+            send.send(MultiArchiverAction::SaveSuccess(save.index, path.to_path_buf(), record))
+                .unwrap_or_else(super::log_err);
+            true
+        },
+        // Only paths are checked for when they can be reached again (see is_reachable).
+        Err(e) if is_network_error(&e) && uri.is_none() => {
+            send.send(MultiArchiverAction::SaveOffline(save.index, path.to_path_buf(), save.content.clone(), save.checks))
+                .unwrap_or_else(super::log_err);
+            false
+        },
+        Err(e) => {
+            send.send(MultiArchiverAction::SaveError(ArchiverError::from(e)))
+                .unwrap_or_else(super::log_err);
+            false
+        }
+    }
+}
+
+// Writes the copy of a SaveCopyRequest. The checks against the file on disk do not
+// apply, since the copy is not expected to be the file last read or written.
+fn spawn_save_copy(
+    index : usize,
+    path : PathBuf,
+    content : String,
+    format : TextFormat,
+    opts : SaveOptions,
+    backend : IoBackend,
+    send : glib::Sender<MultiArchiverAction>
+) {
+    if backend_for(&path, backend) == IoBackend::Gio {
+        let key = path.clone();
+        submit_local(&key, async move {
+            let written = if path.is_absolute() {
+                gio_write(&gio::File::for_path(&path), &content, &format, &opts).await.map_err(ArchiverError::from)
+            } else {
+                Err(ArchiverError::NonAbsolutePath(path.clone()))
+            };
+            copy_saved(&send, index, path, written);
         });
+        return;
+    }
+    let on_panic = {
+        let send = send.clone();
+        let path = path.clone();
+        move |msg| {
+            send.send(MultiArchiverAction::SaveError(ArchiverError::WorkerPanic(msg)))
+                .unwrap_or_else(super::log_err);
+            send.send(MultiArchiverAction::SaveDone(path))
+                .unwrap_or_else(super::log_err);
+        }
+    };
+    let key = path.clone();
+    spawn_worker(&key, on_panic, move || {
+
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("save", path = %path.display()).entered();
+
+        let written = if !path.is_absolute() {
+            Err(ArchiverError::NonAbsolutePath(path.clone()))
+        } else if path.is_dir() {
+            Err(ArchiverError::IsDirectory(path.clone()))
+        } else {
+            let progress = |done, total| {
+                send.send(MultiArchiverAction::IoProgress(path.clone(), IoKind::Save, done, total))
+                    .unwrap_or_else(super::log_err);
+            };
+            write_content(&path, &content, &format, &opts, &progress).map_err(ArchiverError::from)
+        };
+        copy_saved(&send, index, path, written)
+    });
+}
+
+// Sends CopySaved or SaveError, then SaveDone.
+fn copy_saved(
+    send : &glib::Sender<MultiArchiverAction>,
+    index : usize,
+    path : PathBuf,
+    written : Result<Option<PathBuf>, ArchiverError>
+) -> bool {
+    let saved = match written {
+        Ok(backup) => {
+            if let Some(backup) = backup {
+                send.send(MultiArchiverAction::BackupCreated(path.clone(), backup))
+                    .unwrap_or_else(super::log_err);
+            }
+            send.send(MultiArchiverAction::CopySaved(index, path.clone()))
+                .unwrap_or_else(super::log_err);
+            true
+        },
+        Err(e) => {
+            send.send(MultiArchiverAction::SaveError(e))
+                .unwrap_or_else(super::log_err);
+            false
+        }
+    };
+    send.send(MultiArchiverAction::SaveDone(path))
+        .unwrap_or_else(super::log_err);
+    saved
+}
 
-        Self {
-            on_open,
-            on_new,
-            send,
-            on_selected,
-            on_file_closed,
-            on_close_confirm,
-            on_file_changed,
-            on_file_persisted,
-            on_active_text_changed,
-            on_window_close,
-            on_buffer_read_request,
-            on_save_unknown_path,
-            on_name_changed,
-            on_error,
-            on_added,
-            on_reopen,
-            final_state
+// Forgets an adopted file, deleting it if it was adopted with delete_on_close.
+fn release_adopted(adopted : &mut HashMap<PathBuf, AdoptOptions>, file : &OpenedFile) {
+    let Some(path) = &file.path else {
+        return;
+    };
+    if let Some(opts) = adopted.remove(path) {
+        if opts.delete_on_close {
+            let path = path.clone();
+            thread::spawn(move || {
+                if let Err(e) = fs::remove_file(&path) {
+                    eprintln!("Could not delete adopted file {}: {}", path.display(), e);
+                }
+            });
         }
     }
+}
 
+// See SetWorkspaceRoot. The current directory is only tried when another base is set.
+fn resolve_relative(rel_path : &Path, workspace : Option<&Path>, roots : &[PathBuf]) -> Option<PathBuf> {
+    let bases : Vec<PathBuf> = workspace.map(Path::to_path_buf).into_iter()
+        .chain(roots.iter().cloned())
+        .collect();
+    let first = bases.first()?.join(rel_path);
+    let found = bases.into_iter()
+        .chain(std::env::current_dir().ok())
+        .map(|base| base.join(rel_path) )
+        .find(|abs| abs.exists() );
+    Some(found.unwrap_or(first))
 }
 
-fn remove_file(files : &mut Vec<OpenedFile>, ix : usize, selected : &mut Option<usize>) -> OpenedFile {
-    files[(ix+1)..].iter_mut().for_each(|f| f.index -= 1 );
-    if let Some(sel) = selected.as_mut() {
-        if *sel >= ix+1 {
-            *sel -= 1;
-        } else if *sel == ix {
-            *selected = None;
+// Directory of the selected file, or of the most recently opened file with a path.
+fn infer_base_dir(files : &[OpenedFile], selected : Option<usize>) -> Option<PathBuf> {
+    let dir = |f : &OpenedFile| -> Option<PathBuf> {
+        f.path.as_ref()?.parent().map(|p| p.to_path_buf() )
+    };
+    selected.and_then(|ix| files.get(ix) ).and_then(dir)
+        .or_else(|| files.iter().filter(|f| f.path.is_some() ).max_by_key(|f| f.dt ).and_then(dir) )
+}
+
+fn warn(on_internal_warning : &Callbacks<ArchiverError>, e : ArchiverError) {
+    if on_internal_warning.count_bounded() == 0 {
+        eprintln!("{}", e);
+    } else {
+        on_internal_warning.call(e);
+    }
+}
+
+// Any path is allowed if no root is set (see SetAllowedRoots). The path is compared with the
+// canonical roots without touching the filesystem, so it should already be canonical (e.g.
+// a ResolvedPath or the path of an opened file).
+fn check_roots(path : &Path, roots : &[PathBuf], canonical_roots : &[(PathBuf, bool)]) -> Result<(), ArchiverError> {
+    if roots.is_empty() || canonical_roots.iter().any(|(root, ignore_case)| has_prefix(path, root, *ignore_case) ) {
+        Ok(())
+    } else {
+        Err(ArchiverError::OutsideRoots(roots.to_vec()))
+    }
+}
+
+fn contains_path(files : &[OpenedFile], path : &Path, ignore_case : bool) -> bool {
+    files.iter().any(|f| f.path.as_ref().map(|p| same_path(p, path, ignore_case) ).unwrap_or(false) )
+}
+
+// Used until the roots are resolved (see RootsResolved). Case is only ignored if set so,
+// since detecting it would touch the filesystem.
+fn lexical_roots(roots : &[PathBuf], case_sensitivity : CaseSensitivity) -> Vec<(PathBuf, bool)> {
+    let ignore_case = case_sensitivity == CaseSensitivity::Insensitive;
+    roots.iter().map(|root| (normalize_path(root), ignore_case) ).collect()
+}
+
+// Each archiver resolves the paths of its requests under its own key, so that they are
+// resolved one at a time, in the order of the requests.
+fn next_resolve_key() -> PathBuf {
+    static NEXT_KEY : AtomicU64 = AtomicU64::new(0);
+    PathBuf::from(format!("resolve-{}", NEXT_KEY.fetch_add(1, Ordering::Relaxed)))
+}
+
+// Resolves the path of a request at the IO pool (see ResolvedPath), sending the actions built
+// from it. Targets are paths to be written (see canonical_target).
+fn spawn_resolve<P, F>(
+    key : &Path,
+    send : glib::Sender<MultiArchiverAction>,
+    requested : PathBuf,
+    target : bool,
+    case_sensitivity : CaseSensitivity,
+    on_panic : P,
+    f : F
+)
+where
+    P : FnOnce(String) + Send + 'static,
+    F : FnOnce(ResolvedPath) -> Vec<MultiArchiverAction> + Send + 'static
+{
+    spawn_worker(key, on_panic, move || {
+        let expanded = expand_tilde(&requested);
+
+        // URIs without a local path (see OpenUriRequest) are kept as they are.
+        if key_uri(&expanded).is_some() {
+            for action in f(ResolvedPath { requested, path : expanded, ignore_case : false }) {
+                send.send(action).unwrap_or_else(super::log_err);
+            }
+            return true;
+        }
+        let path = if target {
+            canonical_target(&expanded)
+        } else {
+            canonicalize_path(&expanded)
+        };
+
+        // Also keeps the detected case sensitivity, so that the main thread finds it.
+        let ignore_case = case_sensitivity.ignores_case(&path);
+        for action in f(ResolvedPath { requested, path, ignore_case }) {
+            send.send(action).unwrap_or_else(super::log_err);
+        }
+        true
+    });
+}
+
+fn spawn_resolve_roots(key : &Path, send : glib::Sender<MultiArchiverAction>, generation : u64, roots : Vec<PathBuf>, case_sensitivity : CaseSensitivity) {
+    let on_panic = {
+        let send = send.clone();
+        move |msg| {
+            send.send(MultiArchiverAction::OpenError(ArchiverError::WorkerPanic(msg)))
+                .unwrap_or_else(super::log_err);
         }
+    };
+    spawn_worker(key, on_panic, move || {
+        let resolved = roots.iter()
+            .map(|root| {
+                let root = canonicalize_path(root);
+                let ignore_case = case_sensitivity.ignores_case(&root);
+                (root, ignore_case)
+            })
+            .collect();
+        send.send(MultiArchiverAction::RootsResolved(generation, resolved))
+            .unwrap_or_else(super::log_err);
+        true
+    });
+}
+
+// Canonical form of a path to be written, which keeps its last component (so that a
+// link at the path is handled by SymlinkPolicy instead of being resolved).
+fn canonical_target(path : &Path) -> PathBuf {
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => canonicalize_path(parent).join(name),
+        _ => canonicalize_path(path)
     }
-    files.remove(ix)
 }
 
-fn spawn_save_file(
-    path : String,
-    index : usize,
-    content : String,
-    send : glib::Sender<MultiArchiverAction>
-) -> JoinHandle<bool> {
-    thread::spawn(move || {
-    
-        if !Path::new(&path[..]).is_absolute() {
-            send.send(MultiArchiverAction::SaveError(String::from("Using non-absolute path")))
+fn selected_key(files : &[OpenedFile], selected : Option<usize>) -> Option<PathBuf> {
+    selected.and_then(|ix| files.get(ix) ).map(|f| f.key().to_path_buf() )
+}
+
+fn end_bulk(bulk_depth : &mut usize, bulk : &mut BulkSummary, on_bulk_done : &Callbacks<BulkSummary>) {
+    *bulk_depth -= 1;
+    if *bulk_depth == 0 {
+        on_bulk_done.call(std::mem::take(bulk));
+    }
+}
+
+// Range threads are not tracked, since they do not change the archiver state.
+fn spawn_read_range(send : glib::Sender<MultiArchiverAction>, index : usize, path : PathBuf, range : Range<u64>) {
+    let on_panic = {
+        let send = send.clone();
+        move |msg| {
+            send.send(MultiArchiverAction::OpenError(ArchiverError::WorkerPanic(msg)))
+                .unwrap_or_else(super::log_err);
+        }
+    };
+    let key = path.clone();
+    spawn_worker(&key, on_panic, move || {
+        let action = match read_range(&path, range) {
+            Ok((range, content)) => MultiArchiverAction::RangeRead(index, path, range, content),
+            Err(e) => MultiArchiverAction::OpenError(e.into())
+        };
+        let read = matches!(action, MultiArchiverAction::RangeRead(..));
+        send.send(action).unwrap_or_else(super::log_err);
+        read
+    });
+}
+
+// Peek threads are not tracked, since they do not change the archiver state.
+fn spawn_peek_file(send : glib::Sender<MultiArchiverAction>, path : PathBuf, allow_special : bool) {
+    let on_panic = {
+        let send = send.clone();
+        move |msg| {
+            send.send(MultiArchiverAction::OpenError(ArchiverError::WorkerPanic(msg)))
+                .unwrap_or_else(super::log_err);
+        }
+    };
+    let key = path.clone();
+    spawn_worker(&key, on_panic, move || {
+
+        if !path.is_absolute() {
+            send.send(MultiArchiverAction::OpenError(ArchiverError::NonAbsolutePath(path.clone())))
                 .unwrap_or_else(super::log_err);
             return false;
         }
-        
-        if Path::new(&path[..]).is_dir() {
-            send.send(MultiArchiverAction::SaveError(String::from("Tried to save file to directory path")))
+
+        if let Err(e) = check_special(&path, allow_special) {
+            send.send(MultiArchiverAction::OpenError(e))
                 .unwrap_or_else(super::log_err);
             return false;
         }
-        
-        match File::create(&path) {
-            Ok(mut f) => {
-                match f.write_all(content.as_bytes()) {
-                    Ok(_) => {
-                        send.send(MultiArchiverAction::SaveSuccess(index, path))
-                            .unwrap_or_else(super::log_err);
-                        true
-                    },
-                    Err(e) => {
-                        send.send(MultiArchiverAction::SaveError(format!("{}", e)))
-                            .unwrap_or_else(super::log_err);
-                        false
-                    }
-                }
+
+        let res = sniff_mime(&path).and_then(|(mime, is_text)| {
+            if is_text {
+                read_prefix(&path, MAX_PEEK_SIZE).map(Some)
+            } else {
+                send.send(MultiArchiverAction::OpenError(ArchiverError::NotText(mime)))
+                    .unwrap_or_else(super::log_err);
+                Ok(None)
+            }
+        });
+        match res {
+            Ok(Some(content)) => {
+                send.send(MultiArchiverAction::PeekSuccess(path, content))
+                    .unwrap_or_else(super::log_err);
+                true
             },
+            Ok(None) => false,
             Err(e) => {
-                send.send(MultiArchiverAction::SaveError(format!("{}", e)))
+                send.send(MultiArchiverAction::OpenError(ArchiverError::from(e)))
                     .unwrap_or_else(super::log_err);
                 false
             }
         }
-    })
+    });
 }
 
-fn spawn_open_file(send : glib::Sender<MultiArchiverAction>, path : String, n_files : usize) -> JoinHandle<bool> {
-    thread::spawn(move || {
-    
-        if !Path::new(&path[..]).is_absolute() {
-            send.send(MultiArchiverAction::SaveError(String::from("Using non-absolute path")))
+// Open threads are not joined. The thread sends OpenDone when it finishes, so that
+// the next queued OpenRequest can start.
+// Returns the path and the cancellation token of the open.
+fn spawn_open_file(
+    send : glib::Sender<MultiArchiverAction>,
+    path : PathBuf,
+    background : bool,
+    limits : OpenLimits,
+    backend : IoBackend
+) -> (PathBuf, CancelToken) {
+    let cancel = CancelToken::default();
+    if backend_for(&path, backend) == IoBackend::Gio {
+        submit_local(&path, {
+            let path = path.clone();
+            let cancel = cancel.clone();
+            async move {
+                let read = gio_read_file(&path, background, limits).await;
+                if !cancel.commit() {
+                    return;
+                }
+                open_file(&send, read);
+                send.send(MultiArchiverAction::OpenDone(path))
+                    .unwrap_or_else(super::log_err);
+            }
+        });
+        return (path, cancel);
+    }
+    let on_panic = {
+        let send = send.clone();
+        let path = path.clone();
+        let cancel = cancel.clone();
+        move |msg| {
+            if cancel.commit() {
+                send.send(MultiArchiverAction::OpenError(ArchiverError::WorkerPanic(msg)))
+                    .unwrap_or_else(super::log_err);
+                send.send(MultiArchiverAction::OpenDone(path))
+                    .unwrap_or_else(super::log_err);
+            }
+        }
+    };
+    spawn_worker(&path, on_panic, {
+        let path = path.clone();
+        let cancel = cancel.clone();
+        move || {
+            let read = read_file(&path, background, limits, &open_progress(&send, &path));
+            if !cancel.commit() {
+                return false;
+            }
+            let opened = open_file(&send, read);
+            send.send(MultiArchiverAction::OpenDone(path))
                 .unwrap_or_else(super::log_err);
-            return false;
+            opened
         }
-        
-        match File::open(&path) {
-            Ok(mut f) => {
-                let mut content = String::new();
-                if let Err(e) = f.read_to_string(&mut content) {
-                    send.send(MultiArchiverAction::OpenError(format!("{}", e)))
-                        .unwrap_or_else(super::log_err);
-                }
+    });
+    (path, cancel)
+}
 
-                if content.len() > MAX_FILE_SIZE {
-                    send.send(MultiArchiverAction::OpenError(format!("File extrapolates maximum size"))).unwrap();
-                    return false;
-                }
+fn spawn_reload_file(send : glib::Sender<MultiArchiverAction>, path : PathBuf, limits : OpenLimits, backend : IoBackend) {
+    if backend_for(&path, backend) == IoBackend::Gio {
+        let key = path.clone();
+        submit_local(&key, async move {
+            let read = gio_read_file(&path, false, limits).await;
+            reload_file(&send, read);
+            send.send(MultiArchiverAction::ReloadDone(path))
+                .unwrap_or_else(super::log_err);
+        });
+        return;
+    }
+    let on_panic = {
+        let send = send.clone();
+        let path = path.clone();
+        move |msg| {
+            send.send(MultiArchiverAction::OpenError(ArchiverError::WorkerPanic(msg)))
+                .unwrap_or_else(super::log_err);
+            send.send(MultiArchiverAction::ReloadDone(path))
+                .unwrap_or_else(super::log_err);
+        }
+    };
+    let key = path.clone();
+    spawn_worker(&key, on_panic, move || {
+        let reloaded = reload_file(&send, read_file(&path, false, limits, &open_progress(&send, &path)));
+        send.send(MultiArchiverAction::ReloadDone(path))
+            .unwrap_or_else(super::log_err);
+        reloaded
+    });
+}
 
-                let new_file = OpenedFile {
-                    path : Some(path.clone()),
-                    name : path.clone(),
-                    saved : true,
-                    content : Some(content),
-                    index : n_files,
-                    dt : Some(SystemTime::now())
-                };
-                send.send(MultiArchiverAction::OpenSuccess(new_file)).unwrap();
-                true
-            },
-            Err(e) => {
-                send.send(MultiArchiverAction::OpenError(format!("{}", e))).unwrap();
-                false
+// Starts watching the path of an opened file, forwarding the changes to the action loop.
+fn watch_file(
+    monitors : &mut HashMap<PathBuf, gio::FileMonitor>,
+    path : &Path,
+    send : &glib::Sender<MultiArchiverAction>,
+    on_internal_warning : &Callbacks<ArchiverError>
+) {
+    let monitor = match gio::File::for_path(path).monitor_file(gio::FileMonitorFlags::NONE, None::<&gio::Cancellable>) {
+        Ok(monitor) => monitor,
+        Err(e) => {
+            warn(on_internal_warning, ArchiverError::Internal(format!("Could not watch {}: {}", path.display(), e)));
+            return;
+        }
+    };
+    monitor.connect_changed({
+        let send = send.clone();
+        let path = path.to_path_buf();
+        move |_, _, _, ev| {
+            let action = match ev {
+                gio::FileMonitorEvent::ChangesDoneHint | gio::FileMonitorEvent::Created => {
+                    MultiArchiverAction::ExternalChange(path.clone())
+                },
+                gio::FileMonitorEvent::Deleted | gio::FileMonitorEvent::MovedOut => {
+                    MultiArchiverAction::ExternalDeletion(path.clone())
+                },
+                _ => return
+            };
+            send.send(action).unwrap_or_else(super::log_err);
+        }
+    });
+    monitors.insert(path.to_path_buf(), monitor);
+}
+
+// Reads the file, sending OpenSuccess or OpenError. The index of the file is
+// assigned at OpenSuccess.
+fn open_file(send : &glib::Sender<MultiArchiverAction>, read : Result<OpenedFile, ArchiverError>) -> bool {
+    match read {
+        Ok(file) => {
+            send.send(MultiArchiverAction::OpenSuccess(file)).unwrap_or_else(super::log_err);
+            true
+        },
+        Err(e) => {
+            send.send(MultiArchiverAction::OpenError(e)).unwrap_or_else(super::log_err);
+            false
+        }
+    }
+}
+
+// Files at locations that are not local (see OpenedFile::uri) always use the gio backend,
+// since GVfs handles them better than its FUSE mount does.
+fn backend_for(path : &Path, backend : IoBackend) -> IoBackend {
+    if key_uri(path).is_some() || remote_uri(path).is_some() {
+        IoBackend::Gio
+    } else {
+        backend
+    }
+}
+
+// Files opened at a URI without a local path are keyed (and saved) by the URI (see
+// OpenUriRequest), which is never an absolute path.
+fn key_uri(path : &Path) -> Option<&str> {
+    let s = path.to_str().filter(|_| !path.is_absolute() )?;
+    let (scheme, _) = s.split_once("://")?;
+    let valid = scheme.starts_with(|c : char| c.is_ascii_alphabetic() ) &&
+        scheme.chars().all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.' );
+    valid.then_some(s)
+}
+
+// The file at the path, or at the URI of a file keyed by it (see key_uri).
+fn location_file(path : &Path) -> gio::File {
+    match key_uri(path) {
+        Some(uri) => gio::File::for_uri(uri),
+        None => gio::File::for_path(path)
+    }
+}
+
+// The URI of the location GVfs mounts at the path, if it is not local.
+fn remote_uri(path : &Path) -> Option<String> {
+    let file = gio::File::for_path(path);
+    (!file.is_native()).then(|| file.uri().to_string() )
+}
+
+// Sends ReloadSuccess or OpenError.
+fn reload_file(send : &glib::Sender<MultiArchiverAction>, read : Result<OpenedFile, ArchiverError>) -> bool {
+    match read {
+        Ok(file) => {
+            send.send(MultiArchiverAction::ReloadSuccess(file)).unwrap_or_else(super::log_err);
+            true
+        },
+        Err(e) => {
+            send.send(MultiArchiverAction::OpenError(e)).unwrap_or_else(super::log_err);
+            false
+        }
+    }
+}
+
+// Reports the progress of an open or reload of the path.
+fn open_progress<'a>(send : &'a glib::Sender<MultiArchiverAction>, path : &'a Path) -> impl Fn(u64, u64) + 'a {
+    move |done, total| {
+        send.send(MultiArchiverAction::IoProgress(path.to_path_buf(), IoKind::Open, done, total))
+            .unwrap_or_else(super::log_err);
+    }
+}
+
+fn read_file(path : &Path, background : bool, limits : OpenLimits, progress : Progress) -> Result<OpenedFile, ArchiverError> {
+
+    #[cfg(feature = "tracing")]
+    let _span = tracing::debug_span!("open", path = %path.display()).entered();
+
+    if !path.is_absolute() {
+        return Err(ArchiverError::NonAbsolutePath(path.to_path_buf()));
+    }
+
+    // Checked before any read, which would block on a FIFO.
+    check_special(path, limits.allow_special)?;
+
+    let mime = match sniff_mime(path)? {
+        (mime, true) => mime,
+        (mime, false) => return Err(ArchiverError::NotText(mime))
+    };
+
+    // Taken before reading, so that a change during the read is still reported.
+    let metadata = fs::metadata(path).ok();
+    let mtime = metadata.as_ref().and_then(|m| m.modified().ok() );
+
+    // Checked before reading, so that a huge file is not loaded into memory. The preview
+    // of a large file is read as UTF-8.
+    let too_large = metadata.as_ref().map(|m| m.len() ).filter(|size| *size > limits.max_size );
+    let (content, format, truncated) = match (too_large, limits.preview) {
+        (Some(size), None) => {
+            return Err(ArchiverError::TooLarge { size, limit : limits.max_size });
+        },
+        (Some(_), Some(preview)) => {
+            (read_prefix(path, preview as usize)?, TextFormat::default(), true)
+        },
+        (None, _) => {
+            let mut f = File::open(path)?;
+            let mut content = String::new();
+            let format = read_content(&mut f, path, &mut content, progress)?;
+            if content.len() as u64 > limits.max_size {
+                return Err(ArchiverError::TooLarge { size : content.len() as u64, limit : limits.max_size });
             }
+            (content, format, false)
         }
-    })
+    };
+
+    let mut new_file = OpenedFile::for_path(path)?;
+    new_file.inferred_extension = infer_extension(path, &content);
+    new_file.content = Some(content);
+    new_file.compressed = is_gzip_path(path);
+    new_file.mime = Some(mime);
+    new_file.bom = format.bom;
+    new_file.encoding = format.encoding;
+    new_file.background = background;
+    new_file.mtime = mtime;
+    new_file.truncated = truncated;
+
+    // Saving a truncated file would discard the rest of it.
+    new_file.read_only = truncated || metadata.is_some_and(|m| is_read_only(path, &m) );
+    Ok(new_file)
+}
+
+// Rejects paths that are not regular files or directories (directories are rejected
+// by OpenedFile::for_path). Paths that cannot be queried are left to the read.
+fn check_special(path : &Path, allow_special : bool) -> Result<(), ArchiverError> {
+    if allow_special {
+        return Ok(());
+    }
+    let Ok(metadata) = fs::metadata(path) else {
+        return Ok(());
+    };
+    let ty = metadata.file_type();
+    if ty.is_file() || ty.is_dir() {
+        return Ok(());
+    }
+
+    #[cfg(unix)]
+    let kind = {
+        use std::os::unix::fs::FileTypeExt;
+        if ty.is_fifo() {
+            SpecialFileKind::Fifo
+        } else if ty.is_socket() {
+            SpecialFileKind::Socket
+        } else if ty.is_char_device() {
+            SpecialFileKind::CharDevice
+        } else if ty.is_block_device() {
+            SpecialFileKind::BlockDevice
+        } else {
+            SpecialFileKind::Other
+        }
+    };
+
+    #[cfg(not(unix))]
+    let kind = SpecialFileKind::Other;
+
+    Err(ArchiverError::SpecialFile(path.to_path_buf(), kind))
+}
+
+// Reads the file like read_file, with the gio backend. Files over the maximum size are
+// always rejected, since the gio backend does not read previews.
+async fn gio_read_file(path : &Path, background : bool, limits : OpenLimits) -> Result<OpenedFile, ArchiverError> {
+    let uri = key_uri(path);
+    if !path.is_absolute() && uri.is_none() {
+        return Err(ArchiverError::NonAbsolutePath(path.to_path_buf()));
+    }
+    let file = location_file(path);
+    if let (Some(uri), Err(_)) = (uri, gio_mount(&file).await) {
+        return Err(ArchiverError::NoLocalPath(uri.to_string()));
+    }
+
+    // Taken before reading, so that a change during the read is still reported.
+    let metadata = gio_metadata(&file).await?;
+    if metadata.as_ref().is_some_and(|m| m.is_dir ) {
+        return Err(ArchiverError::IsDirectory(path.to_path_buf()));
+    }
+    if metadata.as_ref().is_some_and(|m| !m.is_file ) && !limits.allow_special {
+        return Err(ArchiverError::SpecialFile(path.to_path_buf(), SpecialFileKind::Other));
+    }
+    if let Some(size) = metadata.as_ref().map(|m| m.len ).filter(|size| *size > limits.max_size ) {
+        return Err(ArchiverError::TooLarge { size, limit : limits.max_size });
+    }
+    let read = gio_read(&file).await?;
+    if !read.is_text {
+        return Err(ArchiverError::NotText(read.mime));
+    }
+    if read.content.len() as u64 > limits.max_size {
+        return Err(ArchiverError::TooLarge { size : read.content.len() as u64, limit : limits.max_size });
+    }
+
+    // The path was already resolved (see ResolvedPath), and is not canonicalized again at
+    // the main context.
+    let mut new_file = match uri {
+        Some(uri) => OpenedFile::saved_at_uri(uri),
+        None => OpenedFile::saved_at(path)
+    };
+    new_file.inferred_extension = infer_extension(path, &read.content);
+    new_file.content = Some(read.content);
+    new_file.compressed = is_gzip_path(path);
+    new_file.mime = Some(read.mime);
+    new_file.bom = read.format.bom;
+    new_file.encoding = read.format.encoding;
+    new_file.background = background;
+    new_file.mtime = metadata.as_ref().map(|m| m.modified );
+    new_file.read_only = metadata.is_some_and(|m| m.readonly );
+    if uri.is_none() {
+        new_file.uri = remote_uri(path);
+    }
+    Ok(new_file)
 }
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct OpenedFile {
     pub name : String,
-    pub path : Option<String>,
+
+    // The canonical path for files opened by the archiver, so that paths compare equal
+    // to it whatever their spelling (see canonicalize_path). For a file saved at a link,
+    // only the directory of the link is resolved (see SymlinkPolicy).
+    pub path : Option<PathBuf>,
     pub content : Option<String>,
     pub saved : bool,
     pub dt : Option<SystemTime>,
-    pub index : usize
-}
-
-// File change watch thread
-/*let (tx, rx) = channel();
-let mut watcher = notify::watcher(tx, Duration::from_secs(5)).unwrap();
-thread::spawn({
-    let sender = sender.clone();
-    move|| {
-        loop {
-            match rx.recv() {
-                Ok(event) => {
-                    /*match event.op {
-                        Ok(notify::op::Op::WRITE)
-                        Ok(notify::op::Op::CREATE)
-                        Ok(notify::op::Op::RENAME)
-                        Ok(notify::op::Op::CHMOD)
-                        Ok(notify::op::Op::REMOVE)
-                    }*/
-                },
-               Err(_) => { },
+    pub index : usize,
+
+    // Whether the file is stored gzip-compressed (decompressed at open
+    // and recompressed at save).
+    #[serde(default)]
+    pub compressed : bool,
+
+    // MIME type guessed when the file was opened (e.g. text/x-sql), which
+    // applications might use to choose the syntax highlighting.
+    #[serde(default)]
+    pub mime : Option<String>,
+
+    // Whether the file started with a byte order mark, which is stripped
+    // from the content at open and written back at save (see BomPolicy).
+    #[serde(default)]
+    pub bom : bool,
+
+    // Encoding of the file on disk, if not UTF-8 (e.g. windows-1252 or UTF-16LE).
+    // The content is converted to UTF-8 at open and back to this encoding at save.
+    #[serde(default)]
+    pub encoding : Option<String>,
+
+    // Whether the file was opened with OpenBackgroundRequest. Only meaningful
+    // for the file passed to on_open.
+    #[serde(skip)]
+    pub background : bool,
+
+    // Whether the file was opened with AdoptRequest.
+    #[serde(skip)]
+    pub adopted : bool,
+
+    // Modification time of the file on disk when it was last opened, reloaded
+    // or saved by the archiver.
+    #[serde(skip)]
+    pub mtime : Option<SystemTime>,
+
+    // Whether the content was restored from a recovery file with RecoverRequest
+    // (the file is opened with unsaved changes).
+    #[serde(skip)]
+    pub recovered : bool,
+
+    // Whether the file cannot be saved at its own path, either because its permissions
+    // did not allow writing when it was opened (or reloaded), or because it was opened
+    // with OpenReadOnlyRequest.
+    #[serde(default)]
+    pub read_only : bool,
+
+    // Whether the content holds only the start of the file (see set_streaming_preview).
+    #[serde(skip)]
+    pub truncated : bool,
+
+    // URI of a file at a location that is not local (e.g. sftp://host/notes.sql), which is
+    // opened at the local path GVfs mounts it at, if there is one (see OpenUriRequest). These
+    // files are always read and saved with the gio backend (see IoBackend).
+    #[serde(default)]
+    pub uri : Option<String>,
+
+    // Position given by OpenAtRequest. Only meaningful for the file passed to on_open
+    // or on_reopen.
+    #[serde(skip)]
+    pub position : Option<TextPosition>,
+
+    // Extension guessed from the name or content of a file whose path has none (see
+    // infer_extension), e.g. to pick a syntax highlighting, or to suggest a name at the
+    // save dialog instead of appending the archiver extension (see OpenedFile::extension).
+    #[serde(default)]
+    pub inferred_extension : Option<String>
+}
+
+/// Metadata of a file at the recent list (see MultiArchiverAction::PrefetchRecentMetadata).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecentMeta {
+
+    pub path : PathBuf,
+
+    // Whether the file could be queried (size and mtime are None otherwise).
+    pub exists : bool,
+
+    pub size : Option<u64>,
+
+    pub mtime : Option<SystemTime>
+
+}
+
+/// A position in the content of a file (zero-based line, and column in characters).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TextPosition {
+    pub line : usize,
+    pub column : usize
+}
+
+impl OpenedFile {
+
+    /// Builds a saved file with the given absolute path (e.g. to be added to the recent
+    /// list when restoring a session). The index is assigned by the archiver. The path is
+    /// canonicalized (see canonicalize_path), like the paths of the files opened by the
+    /// archiver, so that files built for other spellings of a path are equal.
+    pub fn for_path<P : AsRef<Path>>(path : P) -> Result<Self, ArchiverError> {
+        let path = path.as_ref();
+        if !path.is_absolute() {
+            return Err(ArchiverError::NonAbsolutePath(path.to_path_buf()));
+        }
+        let path = canonicalize_path(path);
+        if path.is_dir() {
+            return Err(ArchiverError::IsDirectory(path));
+        }
+        Ok(Self::saved_at(&path))
+    }
+
+    // Builds a saved file for a URI without a local path (see OpenUriRequest).
+    fn saved_at_uri(uri : &str) -> Self {
+        Self {
+            name : uri.to_string(),
+            path : None,
+            uri : Some(uri.to_string()),
+            ..Self::saved_at(Path::new(uri))
+        }
+    }
+
+    // The path of the file, or the URI it is keyed by if it was opened at a URI without
+    // a local path (see key_uri).
+    fn location(&self) -> Option<PathBuf> {
+        self.path.clone().or_else(|| self.uri.as_ref().map(PathBuf::from) )
+    }
+
+    // Builds a file for the path like for_path, without checking nor canonicalizing it.
+    fn saved_at(path : &Path) -> Self {
+        Self {
+            name : path.display().to_string(),
+            path : Some(path.to_path_buf()),
+            content : None,
+            saved : true,
+            dt : Some(SystemTime::now()),
+            index : 0,
+            compressed : is_gzip_path(path),
+            mime : None,
+            bom : false,
+            encoding : None,
+            background : false,
+            adopted : false,
+            mtime : None,
+            recovered : false,
+            read_only : false,
+            truncated : false,
+            uri : None,
+            position : None,
+            inferred_extension : None
+        }
+    }
+
+    /// The extension of the path (without the leading dot), or the inferred one for files
+    /// whose path has no extension. Returns None for untitled files.
+    pub fn extension(&self) -> Option<String> {
+        let path = self.path.as_ref()?;
+        path.extension()
+            .map(|ext| ext.to_string_lossy().to_string() )
+            .or_else(|| self.inferred_extension.clone() )
+    }
+
+    // Copies all fields except the content, which might be large.
+    fn without_content(&self) -> Self {
+        Self {
+            name : self.name.clone(),
+            path : self.path.clone(),
+            content : None,
+            saved : self.saved,
+            dt : self.dt,
+            index : self.index,
+            compressed : self.compressed,
+            mime : self.mime.clone(),
+            bom : self.bom,
+            encoding : self.encoding.clone(),
+            background : self.background,
+            adopted : self.adopted,
+            mtime : self.mtime,
+            recovered : self.recovered,
+            read_only : self.read_only,
+            truncated : self.truncated,
+            uri : self.uri.clone(),
+            position : None,
+            inferred_extension : self.inferred_extension.clone()
+        }
+    }
+
+    /// Builds the n-th untitled file with the given extension (without the leading dot).
+    pub fn untitled(n : usize, extension : &str) -> Self {
+        Self {
+            name : format!("Untitled {}.{}", n, extension),
+            path : None,
+            content : None,
+            saved : true,
+            dt : Some(SystemTime::now()),
+            index : 0,
+            compressed : false,
+            mime : None,
+            bom : false,
+            encoding : None,
+            background : false,
+            adopted : false,
+            mtime : None,
+            recovered : false,
+            read_only : false,
+            truncated : false,
+            uri : None,
+            position : None,
+            inferred_extension : None
+        }
+    }
+
+    fn text_format(&self) -> TextFormat {
+        TextFormat { bom : self.bom, encoding : self.encoding.clone() }
+    }
+
+    // Files are identified by their path. Untitled files (which have no path
+    // yet) are identified by their name.
+    fn key(&self) -> &Path {
+        self.path.as_deref().unwrap_or(Path::new(&self.name))
+    }
+
+    /// The last component of the path (or the untitled name).
+    pub fn file_name(&self) -> String {
+        match &self.path {
+            Some(path) => path.file_name()
+                .map(|n| n.to_string_lossy().to_string() )
+                .unwrap_or_else(|| path.display().to_string() ),
+            None => self.name.clone()
+        }
+    }
+
+    /// The file name, prefixed by as many parent directories as required to
+    /// distinguish it from the other files with the same file name
+    /// (e.g. src/lib.rs and tests/lib.rs).
+    pub fn disambiguated_name(&self, others : &[OpenedFile]) -> String {
+        let Some(path) = &self.path else {
+            return self.name.clone();
+        };
+        let comps : Vec<_> = path.components().rev()
+            .map(|c| c.as_os_str().to_string_lossy().to_string() )
+            .collect();
+        let others : Vec<Vec<String>> = others.iter()
+            .filter(|f| f.path.is_some() && f.path != self.path )
+            .map(|f| f.path.as_ref().unwrap().components().rev()
+                .map(|c| c.as_os_str().to_string_lossy().to_string() )
+                .collect()
+            )
+            .collect();
+        for n in 1..=comps.len() {
+            if !others.iter().any(|other| other.len() >= n && other[..n] == comps[..n] ) {
+                return comps[..n].iter().rev().cloned().collect::<Vec<_>>().join("/");
             }
         }
+        path.display().to_string()
+    }
+
+}
+
+// Files are equal (and hash and order alike) when their keys are: the path, which is
+// canonical for opened files (see OpenedFile::for_path), or the name of untitled files.
+impl PartialEq for OpenedFile {
+
+    fn eq(&self, other : &Self) -> bool {
+        self.key() == other.key()
+    }
+
+}
+
+impl Eq for OpenedFile { }
+
+impl Hash for OpenedFile {
+
+    fn hash<H : Hasher>(&self, state : &mut H) {
+        self.key().hash(state);
+    }
+
+}
+
+// Ordered by key, consistently with PartialEq. Recent file lists are sorted by
+// recency explicitly instead (e.g. sort_by_key(|f| f.dt ), which puts the files without
+// a date first).
+impl Ord for OpenedFile {
+
+    fn cmp(&self, other : &Self) -> std::cmp::Ordering {
+        self.key().cmp(other.key())
+    }
+
+}
+
+impl PartialOrd for OpenedFile {
+
+    fn partial_cmp(&self, other : &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+
+}
+
+// Shows the file name followed by the directory containing it (with the
+// home directory shown as ~).
+impl std::fmt::Display for OpenedFile {
+
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.path.as_ref().and_then(|p| p.parent() ) {
+            Some(parent) if !parent.as_os_str().is_empty() => {
+                write!(f, "{} ({})", self.file_name(), contract_tilde(parent))
+            },
+            _ => write!(f, "{}", self.file_name())
+        }
     }
-});*/
+
+}
@@ -0,0 +1,304 @@
+/*Copyright (c) 2022 Diego da Silva Lima. All rights reserved.
+
+This work is licensed under the terms of the MIT license.  
+For a copy, see <https://opensource.org/licenses/MIT>.*/
+
+use std::fs;
+use std::path::{Path, PathBuf, Component};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::collections::HashMap;
+use gtk4::glib;
+
+// Longest file name (in bytes) accepted by the common filesystems (ext4, NTFS, APFS).
+const MAX_FILE_NAME_LEN : usize = 255;
+
+// Extensions of files usually named without one, by file name.
+const KNOWN_NAMES : [(&str, &str); 12] = [
+    ("Makefile", "mk"), ("makefile", "mk"), ("GNUmakefile", "mk"),
+    ("Dockerfile", "dockerfile"), ("Containerfile", "dockerfile"),
+    ("Rakefile", "rb"), ("Gemfile", "rb"), ("Vagrantfile", "rb"),
+    (".bashrc", "sh"), (".bash_profile", "sh"), (".profile", "sh"), (".zshrc", "sh")
+];
+
+// Extensions of the files run by the interpreter named at the shebang (version
+// suffixes such as python3.11 are removed before the lookup).
+const INTERPRETERS : [(&str, &str); 19] = [
+    ("sh", "sh"), ("bash", "sh"), ("dash", "sh"), ("zsh", "sh"), ("ksh", "sh"),
+    ("fish", "fish"), ("python", "py"), ("ruby", "rb"), ("perl", "pl"),
+    ("node", "js"), ("nodejs", "js"), ("deno", "ts"), ("php", "php"), ("lua", "lua"),
+    ("Rscript", "r"), ("julia", "jl"), ("tclsh", "tcl"), ("awk", "awk"), ("make", "mk")
+];
+
+// Device names reserved by Windows, with or without an extension (e.g. con.txt).
+const RESERVED_NAMES : [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9"
+];
+
+/// Normalizes a path lexically (without touching the filesystem), removing
+/// "." segments, resolving ".." segments against the preceding segment and
+/// dropping trailing separators, so that equivalent spellings of the same
+/// path compare equal.
+pub fn normalize_path<P : AsRef<Path>>(path : P) -> PathBuf {
+    let mut norm = PathBuf::new();
+    for comp in path.as_ref().components() {
+        match comp {
+            Component::CurDir => { },
+            Component::ParentDir => {
+                if !norm.pop() {
+                    norm.push("..");
+                }
+            },
+            other => norm.push(other.as_os_str())
+        }
+    }
+    norm
+}
+
+/// Resolves the symlinks and the "." and ".." segments of the path, so that it can be
+/// compared component by component with other paths. The part of the path that does not
+/// exist yet (e.g. the target of a save) is normalized lexically and appended to the
+/// canonical form of its longest existing ancestor.
+pub fn canonicalize_path<P : AsRef<Path>>(path : P) -> PathBuf {
+    let path = path.as_ref();
+    for ancestor in path.ancestors() {
+        let Ok(mut canon) = fs::canonicalize(ancestor) else {
+            continue;
+        };
+        let Ok(rest) = path.strip_prefix(ancestor) else {
+            continue;
+        };
+        for comp in rest.components() {
+            match comp {
+                Component::CurDir => { },
+                Component::ParentDir => {
+                    canon.pop();
+                },
+                other => canon.push(other.as_os_str())
+            }
+        }
+        return canon;
+    }
+    normalize_path(path)
+}
+
+/// Whether the path is the prefix directory or is inside it. The canonical forms of
+/// both are compared component by component (see canonicalize_path), so that a sibling
+/// sharing the start of the prefix name (/home/user/project2 for /home/user/project),
+/// or a path escaping the prefix through ".." segments or symlinks, is outside of it.
+pub fn is_within_prefix<P : AsRef<Path>, Q : AsRef<Path>>(path : P, prefix : Q, ignore_case : bool) -> bool {
+    has_prefix(canonicalize_path(path), canonicalize_path(prefix), ignore_case)
+}
+
+/// Like is_within_prefix, but only normalizes both paths (see normalize_path), so that it
+/// does not touch the filesystem. Paths that should not escape the prefix through
+/// symlinks must already be canonical.
+pub(crate) fn has_prefix<P : AsRef<Path>, Q : AsRef<Path>>(path : P, prefix : Q, ignore_case : bool) -> bool {
+    let (path, prefix) = (normalize_path(path), normalize_path(prefix));
+    if ignore_case {
+        lower_case(&path).starts_with(lower_case(&prefix))
+    } else {
+        path.starts_with(&prefix)
+    }
+}
+
+/// Replaces a leading ~ by the home directory (e.g. ~/project/file.sql), so that
+/// paths from command lines and configuration files pass the absolute path checks.
+/// Other paths (including ~user paths) are returned unchanged.
+pub fn expand_tilde<P : AsRef<Path>>(path : P) -> PathBuf {
+    let path = path.as_ref();
+    match path.strip_prefix("~") {
+        Ok(rest) if rest.as_os_str().is_empty() => glib::home_dir(),
+        Ok(rest) => glib::home_dir().join(rest),
+        Err(_) => path.to_path_buf()
+    }
+}
+
+/// Makes the name usable as a file name on the common filesystems (e.g. a name derived
+/// from an untitled label or a document title, offered at a rename or save dialog). Path
+/// separators, control characters (including NUL) and the characters Windows does not accept
+/// (<>:"|?*) are replaced by underscores, surrounding whitespace and trailing dots are removed,
+/// names reserved by Windows (e.g. CON or lpt1.txt) are prefixed by an underscore, and names
+/// longer than 255 bytes are shortened, keeping the extension. Returns "_" if nothing is left.
+pub fn sanitize_file_name(name : &str) -> String {
+    let mut name : String = name.trim().chars()
+        .map(|c| if c.is_control() || matches!(c, '/' | '\\' | '<' | '>' | ':' | '"' | '|' | '?' | '*') { '_' } else { c } )
+        .collect();
+    name.truncate(name.trim_end_matches(['.', ' ']).len());
+    if name.is_empty() {
+        return String::from("_");
+    }
+    let stem = name.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES.iter().any(|reserved| reserved.eq_ignore_ascii_case(stem.trim_end()) ) {
+        name.insert(0, '_');
+    }
+    if name.len() > MAX_FILE_NAME_LEN {
+
+        // Extensions too long to be kept are cut with the rest of the name.
+        let ext_len = name.rfind('.')
+            .filter(|dot| *dot > 0 && name.len() - dot <= MAX_FILE_NAME_LEN / 2 )
+            .map(|dot| name.len() - dot )
+            .unwrap_or(0);
+        let mut end = MAX_FILE_NAME_LEN - ext_len;
+        while !name.is_char_boundary(end) {
+            end -= 1;
+        }
+        name.replace_range(end..name.len() - ext_len, "");
+    }
+    name
+}
+
+/// Guesses the extension (without the leading dot) of a file whose path has none, from
+/// its name (e.g. mk for a Makefile), the interpreter at its shebang (e.g. py for
+/// #!/usr/bin/env python3) or the start of its content (e.g. xml). Returns None if the
+/// path has an extension or nothing is recognized.
+pub fn infer_extension<P : AsRef<Path>>(path : P, content : &str) -> Option<String> {
+    let path = path.as_ref();
+    if path.extension().is_some() {
+        return None;
+    }
+    let name = path.file_name()?.to_str()?;
+    if let Some((_, ext)) = KNOWN_NAMES.iter().find(|(known, _)| *known == name ) {
+        return Some(ext.to_string());
+    }
+    let first_line = content.trim_start_matches('\u{feff}').lines().next().unwrap_or_default();
+    if let Some(shebang) = first_line.strip_prefix("#!") {
+
+        // The interpreter of #!/usr/bin/env is its first argument that is not an option
+        // (e.g. #!/usr/bin/env -S deno run).
+        let mut args = shebang.split_whitespace();
+        let mut interpreter = Path::new(args.next()?).file_name()?.to_str()?;
+        if interpreter == "env" {
+            interpreter = args.find(|arg| !arg.starts_with('-') && !arg.contains('=') )?;
+        }
+        let interpreter = interpreter.trim_end_matches(|c : char| c.is_ascii_digit() || c == '.' );
+        return INTERPRETERS.iter()
+            .find(|(known, _)| *known == interpreter )
+            .map(|(_, ext)| ext.to_string() );
+    }
+    let start = first_line.trim_start().to_lowercase();
+    if start.starts_with("<?xml") {
+        Some(String::from("xml"))
+    } else if start.starts_with("<!doctype html") || start.starts_with("<html") {
+        Some(String::from("html"))
+    } else {
+        None
+    }
+}
+
+/// Replaces the home directory at the start of the path by ~, for display.
+pub fn contract_tilde<P : AsRef<Path>>(path : P) -> String {
+    let path = path.as_ref();
+    let home = glib::home_dir();
+    match path.strip_prefix(&home) {
+        Ok(rest) if !home.as_os_str().is_empty() => {
+            if rest.as_os_str().is_empty() {
+                String::from("~")
+            } else {
+                format!("~/{}", rest.display())
+            }
+        },
+        _ => path.display().to_string()
+    }
+}
+
+/// How paths are compared when checking whether they refer to the same file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CaseSensitivity {
+
+    // Probes the filesystem holding the path, falling back to the platform
+    // default (case-insensitive on macOS and Windows) if the probe is inconclusive.
+    // The result is kept for the directory of the path, so that the filesystem is
+    // probed once per directory.
+    #[default]
+    Detect,
+
+    Sensitive,
+
+    Insensitive
+
+}
+
+impl CaseSensitivity {
+
+    /// Whether paths on the filesystem holding the given path should be
+    /// compared ignoring case.
+    pub fn ignores_case<P : AsRef<Path>>(&self, path : P) -> bool {
+        match self {
+            CaseSensitivity::Sensitive => false,
+            CaseSensitivity::Insensitive => true,
+            CaseSensitivity::Detect => {
+                let path = path.as_ref();
+                let dir = path.parent().unwrap_or(path).to_path_buf();
+                let detected = DETECTED_CASE.get_or_init(Default::default);
+                if let Some(ignores) = lock_detected(detected).get(&dir) {
+                    return *ignores;
+                }
+
+                // The lock is not held while probing, which might take long on network mounts.
+                let ignores = path.ancestors()
+                    .find_map(probe_case_insensitive)
+                    .unwrap_or(cfg!(any(target_os = "macos", target_os = "windows")));
+                lock_detected(detected).insert(dir, ignores);
+                ignores
+            }
+        }
+    }
+
+}
+
+// Case sensitivity found by CaseSensitivity::Detect, by directory.
+static DETECTED_CASE : OnceLock<Mutex<HashMap<PathBuf, bool>>> = OnceLock::new();
+
+fn lock_detected(detected : &Mutex<HashMap<PathBuf, bool>>) -> MutexGuard<'_, HashMap<PathBuf, bool>> {
+    detected.lock().unwrap_or_else(|e| e.into_inner() )
+}
+
+/// Whether both paths refer to the same file, after normalization.
+pub fn same_path<P : AsRef<Path>, Q : AsRef<Path>>(a : P, b : Q, ignore_case : bool) -> bool {
+    let (a, b) = (normalize_path(a), normalize_path(b));
+    if ignore_case {
+        lower_case(&a) == lower_case(&b)
+    } else {
+        a == b
+    }
+}
+
+// Paths that are not valid UTF-8 are compared as they are.
+fn lower_case(path : &Path) -> PathBuf {
+    match path.to_str() {
+        Some(s) => PathBuf::from(s.to_lowercase()),
+        None => path.to_path_buf()
+    }
+}
+
+// Looks for the path with the case of its last component swapped. Returns None if
+// the path does not exist or its last component has no cased characters, in which
+// case the caller might probe the parent directory.
+fn probe_case_insensitive(path : &Path) -> Option<bool> {
+    let name = path.file_name()?.to_str()?;
+    let swapped : String = name.chars()
+        .map(|c| if c.is_ascii_lowercase() { c.to_ascii_uppercase() } else { c.to_ascii_lowercase() } )
+        .collect();
+    if swapped == name {
+        return None;
+    }
+    let meta = fs::metadata(path).ok()?;
+    match fs::metadata(path.with_file_name(swapped)) {
+        Ok(other) => Some(same_file(&meta, &other)),
+        Err(_) => Some(false)
+    }
+}
+
+// On case-sensitive filesystems, the swapped path might be an unrelated file.
+#[cfg(unix)]
+fn same_file(a : &fs::Metadata, b : &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+#[cfg(not(unix))]
+fn same_file(_a : &fs::Metadata, _b : &fs::Metadata) -> bool {
+    true
+}
@@ -0,0 +1,293 @@
+/*Copyright (c) 2022 Diego da Silva Lima. All rights reserved.
+
+This work is licensed under the terms of the MIT license.  
+For a copy, see <https://opensource.org/licenses/MIT>.*/
+
+use std::thread;
+use std::time::{Duration, Instant};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::future::Future;
+use std::cell::RefCell;
+use std::sync::{Arc, Mutex, MutexGuard, Condvar, OnceLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::collections::{HashMap, HashSet, VecDeque};
+use gtk4::glib;
+
+// Number of threads of the IO pool, shared by all archivers.
+const IO_WORKERS : usize = 4;
+
+// A job running for longer than this is considered stalled (e.g. at a hung network mount).
+// If jobs are waiting meanwhile, its worker is replaced by a new one, so that stalled jobs
+// of any kind never take all workers.
+const STALL_DEADLINE : Duration = Duration::from_secs(30);
+
+// How often the pool looks for stalled jobs.
+const STALL_CHECK_INTERVAL : Duration = Duration::from_secs(5);
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+type LocalJob = Pin<Box<dyn Future<Output = ()> + 'static>>;
+
+struct PoolQueue<J> {
+
+    // Jobs waiting to run, in submission order, with their path and id.
+    waiting : VecDeque<(PathBuf, u64, J)>,
+
+    // Id of the running job of each path.
+    running : HashMap<PathBuf, u64>,
+
+    // When each running job started.
+    started : HashMap<u64, Instant>,
+
+    // Jobs whose worker was replaced by a new one (see IoPool::detach and
+    // IoPool::replace_stalled).
+    replaced : HashSet<u64>,
+
+    last_id : u64
+
+}
+
+impl<J> Default for PoolQueue<J> {
+
+    fn default() -> Self {
+        Self {
+            waiting : VecDeque::new(),
+            running : HashMap::new(),
+            started : HashMap::new(),
+            replaced : HashSet::new(),
+            last_id : 0
+        }
+    }
+
+}
+
+impl<J> PoolQueue<J> {
+
+    fn push(&mut self, path : &Path, job : J) -> u64 {
+        self.last_id += 1;
+        self.waiting.push_back((path.to_path_buf(), self.last_id, job));
+        self.last_id
+    }
+
+    // Takes the oldest job whose path has no running job, marking it as running.
+    fn take_next(&mut self) -> Option<(PathBuf, u64, J)> {
+        let pos = self.waiting.iter().position(|(path, _, _)| !self.running.contains_key(path) )?;
+        let (path, id, job) = self.waiting.remove(pos)?;
+        self.running.insert(path.clone(), id);
+        self.started.insert(id, Instant::now());
+        Some((path, id, job))
+    }
+
+    // A detached job does not release the path again when it finishes, since the path
+    // might be held by a newer job by then.
+    fn release(&mut self, path : &Path, id : u64) {
+        self.started.remove(&id);
+        if self.running.get(path) == Some(&id) {
+            self.running.remove(path);
+        }
+    }
+
+}
+
+thread_local! {
+
+    // Operations of the gio backend, which run as futures at the main context (see submit_local).
+    static LOCAL_QUEUE : RefCell<PoolQueue<LocalJob>> = RefCell::new(PoolQueue::default());
+
+}
+
+/*
+Persistent threads running the IO operations of the archivers, so that an operation
+does not spawn a thread of its own and the main thread never waits for a previous one.
+Operations on the same path run one at a time, in submission order (e.g. a reload waits
+for the save of the same file), while operations on distinct paths run in parallel.
+*/
+struct IoPool {
+    queue : Mutex<PoolQueue<Job>>,
+    changed : Condvar
+}
+
+/// Handle of an operation submitted with submit_io or submit_local.
+#[derive(Debug, Clone)]
+pub(crate) struct IoJob {
+    path : PathBuf,
+    id : u64,
+    done : Arc<AtomicBool>,
+    local : bool
+}
+
+impl IoJob {
+
+    pub(crate) fn is_finished(&self) -> bool {
+        self.done.load(Ordering::SeqCst)
+    }
+
+    // Lets the next operations on the path start before this one finishes (e.g. when it
+    // is stalled at a hung network mount). The worker running it is replaced by a new one,
+    // so that stalled operations never take all workers.
+    pub(crate) fn detach(&self) {
+        if self.local {
+            finish_local(&self.path, self.id);
+        } else {
+            pool().detach(&self.path, self.id, &self.done);
+        }
+    }
+
+}
+
+/// Queues the job at the IO pool, after the jobs previously submitted for the same path.
+/// The value returned by the job is dropped.
+pub(crate) fn submit_io<F, R>(path : &Path, f : F) -> IoJob
+where
+    F : FnOnce() -> R + Send + 'static
+{
+    let done = Arc::new(AtomicBool::new(false));
+    let job : Job = {
+        let done = done.clone();
+        Box::new(move || {
+            f();
+            done.store(true, Ordering::SeqCst);
+        })
+    };
+    let pool = pool();
+    let id = pool.lock().push(path, job);
+    pool.changed.notify_all();
+    IoJob { path : path.to_path_buf(), id, done, local : false }
+}
+
+/// Queues the future at the main context, after the futures previously submitted for the
+/// same path (operations on the same path keep their order like at submit_io). Must be
+/// called from the main thread.
+pub(crate) fn submit_local<F>(path : &Path, f : F) -> IoJob
+where
+    F : Future<Output = ()> + 'static
+{
+    let done = Arc::new(AtomicBool::new(false));
+    let job : LocalJob = {
+        let done = done.clone();
+        Box::pin(async move {
+            f.await;
+            done.store(true, Ordering::SeqCst);
+        })
+    };
+    let id = LOCAL_QUEUE.with(|queue| queue.borrow_mut().push(path, job) );
+    start_local();
+    IoJob { path : path.to_path_buf(), id, done, local : true }
+}
+
+// The queue is not borrowed while the futures run, since they might submit other ones.
+fn start_local() {
+    while let Some((path, id, job)) = LOCAL_QUEUE.with(|queue| queue.borrow_mut().take_next() ) {
+        glib::MainContext::default().spawn_local(async move {
+            job.await;
+            finish_local(&path, id);
+        });
+    }
+}
+
+fn finish_local(path : &Path, id : u64) {
+    LOCAL_QUEUE.with(|queue| queue.borrow_mut().release(path, id) );
+    start_local();
+}
+
+fn pool() -> &'static Arc<IoPool> {
+    static POOL : OnceLock<Arc<IoPool>> = OnceLock::new();
+    POOL.get_or_init(|| {
+        let pool = Arc::new(IoPool { queue : Mutex::new(PoolQueue::default()), changed : Condvar::new() });
+        for _ in 0..IO_WORKERS {
+            spawn_runner(&pool);
+        }
+        thread::spawn({
+            let pool = pool.clone();
+            move || loop {
+                thread::sleep(STALL_CHECK_INTERVAL);
+                pool.replace_stalled();
+            }
+        });
+        pool
+    })
+}
+
+fn spawn_runner(pool : &Arc<IoPool>) {
+    let pool = pool.clone();
+    thread::spawn(move || pool.run() );
+}
+
+impl IoPool {
+
+    // Jobs catch their own panics (see io::worker), so a poisoned lock is still consistent.
+    fn lock(&self) -> MutexGuard<'_, PoolQueue<Job>> {
+        self.queue.lock().unwrap_or_else(|e| e.into_inner() )
+    }
+
+    // The worker exits after a detached job, since another worker took its place.
+    fn run(&self) {
+        loop {
+            let (path, id, job) = self.next();
+            job();
+            if self.finish(&path, id) {
+                return;
+            }
+        }
+    }
+
+    // Waits for the oldest job whose path has no running job.
+    fn next(&self) -> (PathBuf, u64, Job) {
+        let mut queue = self.lock();
+        loop {
+            if let Some(next) = queue.take_next() {
+                return next;
+            }
+            queue = self.changed.wait(queue).unwrap_or_else(|e| e.into_inner() );
+        }
+    }
+
+    // Returns whether the job was detached while it ran.
+    fn finish(&self, path : &Path, id : u64) -> bool {
+        let replaced = {
+            let mut queue = self.lock();
+            queue.release(path, id);
+            queue.replaced.remove(&id)
+        };
+        self.changed.notify_all();
+        replaced
+    }
+
+    // Replaces the workers running stalled jobs while other jobs could start. Unlike
+    // detach, the paths of the stalled jobs are not released, so the jobs queued for
+    // the same paths still wait for them.
+    fn replace_stalled(self : &Arc<Self>) {
+        {
+            let mut queue = self.lock();
+            let runnable = queue.waiting.iter().any(|(path, _, _)| !queue.running.contains_key(path) );
+            if !runnable {
+                return;
+            }
+            let stalled : Vec<u64> = queue.started.iter()
+                .filter(|(id, started)| started.elapsed() > STALL_DEADLINE && !queue.replaced.contains(id) )
+                .map(|(id, _)| *id )
+                .collect();
+            for id in stalled {
+                queue.replaced.insert(id);
+                spawn_runner(self);
+            }
+        }
+        self.changed.notify_all();
+    }
+
+    // The done flag is checked with the queue locked, so that a job finishing meanwhile
+    // either sees it replaced (and its worker exits) or is not replaced at all.
+    fn detach(self : &Arc<Self>, path : &Path, id : u64, done : &AtomicBool) {
+        {
+            let mut queue = self.lock();
+            // The worker might already be replaced (see replace_stalled).
+            if queue.running.get(path) == Some(&id) && !done.load(Ordering::SeqCst) && queue.replaced.insert(id) {
+                spawn_runner(self);
+            }
+            queue.release(path, id);
+        }
+        self.changed.notify_all();
+    }
+
+}
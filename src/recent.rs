@@ -0,0 +1,428 @@
+/*Copyright (c) 2022 Diego da Silva Lima. All rights reserved.
+
+This work is licensed under the terms of the MIT license.  
+For a copy, see <https://opensource.org/licenses/MIT>.*/
+
+use std::thread;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::time::Duration;
+use gtk4::glib;
+use gtk4::gio;
+use gtk4::prelude::*;
+use stateful::Callbacks;
+use super::{OpenedFile, MultiArchiverAction};
+
+// How many times a writer tries to acquire the lock file before giving up, and how
+// long it waits between attempts.
+const LOCK_ATTEMPTS : usize = 50;
+
+const LOCK_INTERVAL : Duration = Duration::from_millis(10);
+
+// Writers hold the lock for a single read and write of the list, so a lock file older than
+// this was left by a writer that did not finish (e.g. a process killed while saving).
+const LOCK_STALE_AGE : Duration = Duration::from_secs(30);
+
+/*
+A recent file list shared by several MultiArchiver instances (e.g. one per application
+window), and by SingleArchiver instances (e.g. when an application edits one document type
+with a SingleArchiver and another with a MultiArchiver, showing a single recent list at the
+start page). Adding a file to the recent list of any archiver pushes it to the store, which then
+forwards it to the other archivers (which fire their on_added callbacks, updating the recent
+menu of their windows). The store also tracks which paths are opened by each archiver, so that an
+archiver can detect that a file is already opened at another window.
+
+If the store is persistent, the list is written to a JSON file guarded by a lock file (so that
+several application processes can share it), and the file is watched so that changes written by
+other processes are merged back into the store. Paths removed from the list are dropped from
+the file, and are not merged back from it.
+*/
+#[derive(Clone)]
+pub struct RecentStore {
+    state : Rc<RefCell<RecentStoreState>>,
+    send : glib::Sender<Vec<OpenedFile>>,
+    on_changed : Callbacks<Vec<OpenedFile>>
+}
+
+struct RecentStoreState {
+
+    path : Option<PathBuf>,
+
+    recent : Vec<OpenedFile>,
+
+    // Archiver identifier and the sender used to notify it.
+    archivers : Vec<(usize, glib::Sender<MultiArchiverAction>)>,
+
+    // Archiver identifier and the path it holds open.
+    opened : Vec<(usize, PathBuf)>,
+
+    // Paths removed from the list (with remove or clear) since the store was built. They
+    // are dropped from the list on disk when it is saved, and are not merged back when it
+    // is loaded, until the path is pushed again.
+    removed : Vec<PathBuf>,
+
+    next_id : usize,
+
+    // Must be kept alive for the change notifications to be delivered.
+    _monitor : Option<gio::FileMonitor>
+
+}
+
+impl Default for RecentStore {
+
+    fn default() -> Self {
+        Self::new()
+    }
+
+}
+
+impl RecentStore {
+
+    /// Builds a store that lives only for the process lifetime.
+    pub fn new() -> Self {
+        Self::build(None)
+    }
+
+    /// Builds a store persisted at the given JSON file. The file is loaded
+    /// in a separate thread, and is re-loaded every time it changes on disk.
+    pub fn persistent<P : AsRef<Path>>(path : P) -> Self {
+        let path = path.as_ref().to_path_buf();
+        let store = Self::build(Some(path.clone()));
+        spawn_load_recent(path, store.send.clone());
+        store
+    }
+
+    fn build(path : Option<PathBuf>) -> Self {
+        let (send, recv) = glib::MainContext::channel::<Vec<OpenedFile>>(glib::source::Priority::DEFAULT);
+        let monitor = path.as_ref().and_then(|path| {
+            let monitor = gio::File::for_path(path)
+                .monitor_file(gio::FileMonitorFlags::NONE, None::<&gio::Cancellable>)
+                .map_err(|e| eprintln!("Could not watch recent file list: {}", e) )
+                .ok()?;
+            monitor.connect_changed({
+                let send = send.clone();
+                let path = path.clone();
+                move |_, _, _, ev| {
+                    if ev == gio::FileMonitorEvent::ChangesDoneHint || ev == gio::FileMonitorEvent::Created {
+                        spawn_load_recent(path.clone(), send.clone());
+                    }
+                }
+            });
+            Some(monitor)
+        });
+        let state = Rc::new(RefCell::new(RecentStoreState {
+            path,
+            recent : Vec::new(),
+            archivers : Vec::new(),
+            opened : Vec::new(),
+            removed : Vec::new(),
+            next_id : 0,
+            _monitor : monitor
+        }));
+        let on_changed : Callbacks<Vec<OpenedFile>> = Default::default();
+        recv.attach(None, {
+            let state = state.clone();
+            let on_changed = on_changed.clone();
+
+            // Merges the list loaded from disk (potentially written by another process)
+            // with the in-memory list.
+            move |loaded| {
+                let mut added = Vec::new();
+                {
+                    let mut state = state.borrow_mut();
+                    for file in loaded {
+                        if !state.contains(&file) && !state.was_removed(&file) {
+                            state.recent.push(file.clone());
+                            added.push(file);
+                        }
+                    }
+                    for file in &added {
+                        state.notify(None, file);
+                    }
+                }
+                if !added.is_empty() {
+                    on_changed.call(state.borrow().recent.clone());
+                }
+                glib::ControlFlow::Continue
+            }
+        });
+        Self { state, send, on_changed }
+    }
+
+    pub fn recent(&self) -> Vec<OpenedFile> {
+        self.state.borrow().recent.clone()
+    }
+
+    /// Called every time the recent list changes, either because an archiver
+    /// added a file or because another process updated the persisted list.
+    pub fn connect_changed<F>(&self, f : F)
+    where
+        F : Fn(Vec<OpenedFile>) + 'static
+    {
+        self.on_changed.bind(f);
+    }
+
+    // Registers an archiver, returning the identifier it should use in all other calls.
+    pub(crate) fn register(&self, send : glib::Sender<MultiArchiverAction>) -> usize {
+        let mut state = self.state.borrow_mut();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.archivers.push((id, send));
+        id
+    }
+
+    // Registers an archiver that is not notified of the files pushed by the other
+    // archivers (i.e. a SingleArchiver, which has no recent list of its own).
+    pub(crate) fn register_observer(&self) -> usize {
+        let mut state = self.state.borrow_mut();
+        let id = state.next_id;
+        state.next_id += 1;
+        id
+    }
+
+    // Called when the window of an archiver is closed.
+    pub(crate) fn unregister(&self, id : usize) {
+        let mut state = self.state.borrow_mut();
+        state.archivers.retain(|(arch_id, _)| *arch_id != id );
+        state.opened.retain(|(arch_id, _)| *arch_id != id );
+    }
+
+    // Adds a file to the recent list, notifying all archivers other than the one
+    // identified by id. Returns false if the file was already in the list.
+    pub(crate) fn push(&self, id : usize, file : &OpenedFile) -> bool {
+        let mut file = file.clone();
+        file.content = None;
+        {
+            let mut state = self.state.borrow_mut();
+            if file.path.is_none() || state.contains(&file) {
+                return false;
+            }
+            state.removed.retain(|path| file.path.as_ref() != Some(path) );
+            state.recent.push(file.clone());
+            state.notify(Some(id), &file);
+            state.save();
+        }
+        self.on_changed.call(self.recent());
+        true
+    }
+
+    // Replaces the entry of the old path by the renamed file, if the old path is in the list.
+    pub(crate) fn rename(&self, old_path : &Path, file : &OpenedFile) {
+        let mut file = file.clone();
+        file.content = None;
+        {
+            let mut state = self.state.borrow_mut();
+            let Some(pos) = state.recent.iter().position(|f| f.path.as_deref() == Some(old_path) ) else {
+                return;
+            };
+            state.removed.retain(|path| file.path.as_ref() != Some(path) );
+            state.removed.push(old_path.to_path_buf());
+            if state.contains(&file) {
+                state.recent.remove(pos);
+            } else {
+                state.recent[pos] = file;
+            }
+            state.save();
+        }
+        self.on_changed.call(self.recent());
+    }
+
+    // Removes the path from the recent list, notifying all archivers other than the one
+    // identified by id (which remove it from their own lists). Returns false if the path
+    // was not in the list.
+    pub(crate) fn remove(&self, id : usize, path : &Path) -> bool {
+        {
+            let mut state = self.state.borrow_mut();
+            let n = state.recent.len();
+            state.recent.retain(|f| f.path.as_deref() != Some(path) );
+            if state.recent.len() == n {
+                return false;
+            }
+            state.removed.push(path.to_path_buf());
+            state.broadcast(Some(id), MultiArchiverAction::RemoveRecent(path.display().to_string()));
+            state.save();
+        }
+        self.on_changed.call(self.recent());
+        true
+    }
+
+    // Removes all files from the recent list, notifying all archivers other than the one
+    // identified by id. Returns false if the list was already empty.
+    pub(crate) fn clear(&self, id : usize) -> bool {
+        {
+            let mut state = self.state.borrow_mut();
+            if state.recent.is_empty() {
+                return false;
+            }
+            let cleared : Vec<PathBuf> = state.recent.drain(..).filter_map(|f| f.path ).collect();
+            state.removed.extend(cleared);
+            state.broadcast(Some(id), MultiArchiverAction::ClearRecent);
+            state.save();
+        }
+        self.on_changed.call(self.recent());
+        true
+    }
+
+    pub(crate) fn set_opened(&self, id : usize, path : &Path, opened : bool) {
+        let mut state = self.state.borrow_mut();
+        state.opened.retain(|(arch_id, p)| !(*arch_id == id && p == path) );
+        if opened {
+            state.opened.push((id, path.to_path_buf()));
+        }
+    }
+
+    // Whether the path is opened by any archiver other than the one identified by id.
+    pub(crate) fn opened_elsewhere(&self, id : usize, path : &Path) -> bool {
+        self.state.borrow().opened.iter().any(|(arch_id, p)| *arch_id != id && p == path )
+    }
+
+}
+
+impl RecentStoreState {
+
+    fn contains(&self, file : &OpenedFile) -> bool {
+        self.recent.iter().any(|f| f.path.is_some() && f.path == file.path )
+    }
+
+    fn was_removed(&self, file : &OpenedFile) -> bool {
+        file.path.as_ref().is_some_and(|path| self.removed.contains(path) )
+    }
+
+    fn save(&self) {
+        if let Some(path) = &self.path {
+            spawn_save_recent(path.clone(), self.recent.clone(), self.removed.clone());
+        }
+    }
+
+    fn notify(&self, origin : Option<usize>, file : &OpenedFile) {
+        self.broadcast(origin, MultiArchiverAction::SyncRecent(file.clone()));
+    }
+
+    fn broadcast(&self, origin : Option<usize>, action : MultiArchiverAction) {
+        for (id, send) in &self.archivers {
+            if Some(*id) != origin {
+                send.send(action.clone())
+                    .unwrap_or_else(super::log_err);
+            }
+        }
+    }
+
+}
+
+// Holds the lock file for as long as it is alive. The lock file holds the id of the
+// process that created it.
+struct RecentLock(PathBuf);
+
+impl RecentLock {
+
+    fn acquire(path : &Path) -> Option<Self> {
+        let mut lock_path = path.as_os_str().to_owned();
+        lock_path.push(".lock");
+        let lock_path = PathBuf::from(lock_path);
+        for _ in 0..LOCK_ATTEMPTS {
+            match OpenOptions::new().write(true).create_new(true).open(&lock_path) {
+                Ok(mut f) => {
+                    let _ = write!(f, "{}", std::process::id());
+                    return Some(RecentLock(lock_path));
+                },
+                Err(_) if is_stale_lock(&lock_path) => {
+                    let _ = fs::remove_file(&lock_path);
+                },
+                Err(_) => thread::sleep(LOCK_INTERVAL)
+            }
+        }
+        eprintln!("Could not acquire lock for {}", path.display());
+        None
+    }
+
+}
+
+// A lock is stale if the process that created it is gone, or if it was held for too long.
+// The process is only checked on Linux, which lists the running processes at /proc.
+fn is_stale_lock(lock_path : &Path) -> bool {
+    let age = fs::metadata(lock_path).ok()
+        .and_then(|m| m.modified().ok() )
+        .and_then(|modified| modified.elapsed().ok() );
+    if age.is_some_and(|age| age > LOCK_STALE_AGE ) {
+        return true;
+    }
+    let pid = fs::read_to_string(lock_path).ok()
+        .and_then(|s| s.trim().parse::<u32>().ok() );
+    match pid {
+        Some(pid) if cfg!(target_os = "linux") => pid != std::process::id() && !Path::new("/proc").join(pid.to_string()).exists(),
+        _ => false
+    }
+}
+
+impl Drop for RecentLock {
+
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+
+}
+
+fn spawn_load_recent(path : PathBuf, send : glib::Sender<Vec<OpenedFile>>) {
+    thread::spawn(move || {
+        if !path.exists() {
+            return;
+        }
+
+        // The list is loaded again at its next change.
+        let Some(_lock) = RecentLock::acquire(&path) else {
+            return;
+        };
+        match File::open(&path) {
+            Ok(f) => {
+                match serde_json::from_reader::<_, Vec<OpenedFile>>(f) {
+                    Ok(recent) => {
+                        send.send(recent).unwrap_or_else(super::log_err);
+                    },
+                    Err(e) => {
+                        eprintln!("Could not load recent file list: {}", e);
+                    }
+                }
+            },
+            Err(e) => {
+                eprintln!("Could not load recent file list: {}", e);
+            }
+        }
+    });
+}
+
+// The list on disk is merged with the given list, except for the removed paths.
+fn spawn_save_recent(path : PathBuf, recent : Vec<OpenedFile>, removed : Vec<PathBuf>) {
+    thread::spawn(move || {
+
+        // Writing without the lock might lose the files added by another process. Each save
+        // writes the whole list, so a skipped save is made up for by the next one.
+        let Some(_lock) = RecentLock::acquire(&path) else {
+            eprintln!("Recent file list not saved");
+            return;
+        };
+
+        // Another process might have added files since this list was loaded.
+        let mut merged : Vec<OpenedFile> = File::open(&path).ok()
+            .and_then(|f| serde_json::from_reader(f).ok() )
+            .unwrap_or_default();
+        merged.retain(|f| !f.path.as_ref().is_some_and(|path| removed.contains(path) ) );
+        for file in recent {
+            if !merged.iter().any(|f : &OpenedFile| f.path == file.path ) {
+                merged.push(file);
+            }
+        }
+        match File::create(&path) {
+            Ok(f) => {
+                if let Err(e) = serde_json::to_writer_pretty(f, &merged) {
+                    eprintln!("Could not save recent file list: {}", e);
+                }
+            },
+            Err(e) => {
+                eprintln!("Could not save recent file list: {}", e);
+            }
+        }
+    });
+}
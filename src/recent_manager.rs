@@ -0,0 +1,505 @@
+/*Copyright (c) 2022 Diego da Silva Lima. All rights reserved.
+
+This work is licensed under the terms of the MIT license.
+For a copy, see <https://opensource.org/licenses/MIT>.*/
+
+use std::thread;
+use std::io::Write;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::time::{SystemTime, Duration};
+use gtk4::glib;
+use gtk4::prelude::*;
+use stateful::Callbacks;
+use super::{OpenedFile, MultiArchiverAction, MultiArchiverImpl, get_datadir};
+use super::io::{spawn_worker, write_replacing};
+
+/// Name of the recent file list at the data directory, when written as JSON (see RecentFormat).
+pub const RECENT_FILE : &str = "recent.json";
+
+/// Name of the recent file list at the data directory, when written as XBEL (see RecentFormat).
+pub const RECENT_XBEL_FILE : &str = "recent.xbel";
+
+// Default number of files kept by a RecentManager.
+const MAX_RECENT : usize = 50;
+
+// Ages (in days) up to which an access has the given weight at frecency_score.
+const FRECENCY_WEIGHTS : [(u64, f64); 4] = [(4, 100.0), (14, 70.0), (31, 50.0), (90, 30.0)];
+
+// Weight of accesses older than the last of FRECENCY_WEIGHTS.
+const MIN_FRECENCY_WEIGHT : f64 = 10.0;
+
+/// Ranks a file of a RecentManager, from its last access (OpenedFile::dt) and the number of
+/// times it was accessed. Files with higher scores are ranked ahead (see RecentManager::with_scorer).
+pub type RecentScorer = Rc<dyn Fn(&OpenedFile, u32) -> f64>;
+
+/// Scores files by the number of times they were accessed, weighted by the age of the last
+/// access, so that files used often stay ahead of files touched once more recently
+/// (the default scorer of RecentManager).
+pub fn frecency_score(file : &OpenedFile, count : u32) -> f64 {
+    let days = file.dt
+        .and_then(|dt| SystemTime::now().duration_since(dt).ok() )
+        .map(|age| age.as_secs() / 86_400 )
+        .unwrap_or(u64::MAX);
+    let weight = FRECENCY_WEIGHTS.iter()
+        .find(|(max_days, _)| days <= *max_days )
+        .map(|(_, weight)| *weight )
+        .unwrap_or(MIN_FRECENCY_WEIGHT);
+    count as f64 * weight
+}
+
+/// Scores files by their last access only.
+pub fn recency_score(file : &OpenedFile, _count : u32) -> f64 {
+    file.dt
+        .and_then(|dt| dt.duration_since(SystemTime::UNIX_EPOCH).ok() )
+        .map(|dt| dt.as_secs_f64() )
+        .unwrap_or(0.0)
+}
+
+/// How a RecentManager writes its list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RecentFormat {
+
+    // The OpenedFile entries (without content), as written by RecentStore::persistent,
+    // at RECENT_FILE.
+    #[default]
+    Json,
+
+    // An XML Bookmark Exchange Language file at RECENT_XBEL_FILE, with the path and the
+    // last access of each file (the format of the desktop recent list), so that other
+    // tools can read it.
+    Xbel
+
+}
+
+/*
+A recent file list persisted under the data directory of the application, which outlives the
+process (unlike the recent list of a MultiArchiver, which only lives as long as the archiver).
+The list is deduplicated by path, ranked by a scorer (from the least to the most relevant
+file), and capped at a maximum number of files, dropping the least relevant ones. The default
+scorer combines the number of accesses of each file with its last access (kept at
+OpenedFile::dt). The access counts are persisted next to the list (see usage_path).
+
+Archivers bound with bind_multi receive the persisted list once it is loaded (with AddMany),
+and every file they add to their recent list, open or save has its access recorded,
+and the list is then written back in a separate thread.
+*/
+#[derive(Clone)]
+pub struct RecentManager {
+    state : Rc<RefCell<RecentManagerState>>,
+    on_changed : Callbacks<Vec<OpenedFile>>
+}
+
+struct RecentManagerState {
+
+    path : Option<PathBuf>,
+
+    format : RecentFormat,
+
+    capacity : usize,
+
+    recent : Vec<OpenedFile>,
+
+    // Number of accesses of each file of the list.
+    usage : HashMap<PathBuf, u32>,
+
+    scorer : RecentScorer,
+
+    // Whether the list on disk was loaded (the archivers bound before that receive it once it is).
+    loaded : bool,
+
+    archivers : Vec<glib::Sender<MultiArchiverAction>>
+
+}
+
+impl RecentManager {
+
+    /// Builds a manager persisted at the data directory of the application (see get_datadir).
+    /// The list is loaded in a separate thread. If the data directory cannot be found, the
+    /// list lives only for the process lifetime.
+    pub fn for_app(app_id : &str, format : RecentFormat) -> Self {
+        let name = match format {
+            RecentFormat::Json => RECENT_FILE,
+            RecentFormat::Xbel => RECENT_XBEL_FILE
+        };
+        Self::persistent(get_datadir(app_id).map(|dir| dir.join(name) ), format)
+    }
+
+    /// Builds a manager persisted at the given file.
+    pub fn persistent(path : Option<PathBuf>, format : RecentFormat) -> Self {
+        let (send, recv) = glib::MainContext::channel::<(Vec<OpenedFile>, HashMap<PathBuf, u32>)>(glib::source::Priority::DEFAULT);
+        let state = Rc::new(RefCell::new(RecentManagerState {
+            path : path.clone(),
+            format,
+            capacity : MAX_RECENT,
+            recent : Vec::new(),
+            usage : HashMap::new(),
+            scorer : Rc::new(frecency_score),
+            loaded : path.is_none(),
+            archivers : Vec::new()
+        }));
+        let on_changed : Callbacks<Vec<OpenedFile>> = Default::default();
+        recv.attach(None, {
+            let state = state.clone();
+            let on_changed = on_changed.clone();
+
+            // Files accessed before the list was loaded are kept as the most recent ones.
+            move |(loaded, usage)| {
+                let recent = {
+                    let mut state = state.borrow_mut();
+                    let accessed = std::mem::take(&mut state.recent);
+                    let accessed_usage = std::mem::replace(&mut state.usage, usage);
+                    state.recent = loaded;
+                    for file in accessed {
+                        state.merge(file, &accessed_usage);
+                    }
+                    state.rank();
+                    state.loaded = true;
+                    for send in &state.archivers {
+                        send.send(MultiArchiverAction::AddMany(state.recent.clone()))
+                            .unwrap_or_else(super::log_err);
+                    }
+                    state.recent.clone()
+                };
+                on_changed.call(recent);
+                glib::ControlFlow::Break
+            }
+        });
+        if let Some(path) = path {
+            spawn_load(path, format, send);
+        }
+        Self { state, on_changed }
+    }
+
+    /// Sets the maximum number of files kept (the default is 50).
+    pub fn with_capacity(self, capacity : usize) -> Self {
+        {
+            let mut state = self.state.borrow_mut();
+            state.capacity = capacity;
+            state.rank();
+        }
+        self
+    }
+
+    /// Sets how files are ranked (frecency_score by default; use recency_score to
+    /// rank them by their last access only).
+    pub fn with_scorer<F>(self, f : F) -> Self
+    where
+        F : Fn(&OpenedFile, u32) -> f64 + 'static
+    {
+        {
+            let mut state = self.state.borrow_mut();
+            state.scorer = Rc::new(f);
+            state.rank();
+        }
+        self
+    }
+
+    /// Number of times the file was accessed (0 if it is not at the list).
+    pub fn access_count(&self, path : &Path) -> u32 {
+        self.state.borrow().usage.get(path).copied().unwrap_or(0)
+    }
+
+    /// The files of the list, from the least to the most relevant (see with_scorer).
+    pub fn recent(&self) -> Vec<OpenedFile> {
+        self.state.borrow().recent.clone()
+    }
+
+    /// Called every time the list changes (after it is loaded, and after each access).
+    pub fn connect_changed<F>(&self, f : F)
+    where
+        F : Fn(Vec<OpenedFile>) + 'static
+    {
+        self.on_changed.bind(f);
+    }
+
+    /// Adds the list to the recent list of the archiver, and records the files the
+    /// archiver adds to its recent list (Add), opens (OpenSuccess) or saves (SaveSuccess).
+    pub fn bind_multi<A : MultiArchiverImpl>(&self, archiver : &A) {
+        {
+            let mut state = self.state.borrow_mut();
+            if state.loaded {
+                archiver.sender().send(MultiArchiverAction::AddMany(state.recent.clone()))
+                    .unwrap_or_else(super::log_err);
+            }
+            state.archivers.push(archiver.sender().clone());
+        }
+        archiver.connect_added({
+            let manager = self.clone();
+            move |file| manager.access(file)
+        });
+        archiver.connect_opened({
+            let manager = self.clone();
+            move |file| manager.access(file)
+        });
+
+        // Called after SaveSuccess (with SetSaved).
+        archiver.connect_file_persisted({
+            let manager = self.clone();
+            move |file| manager.access(file)
+        });
+    }
+
+    // Records an access to the file and ranks the list again, writing the list back if it was loaded
+    // (otherwise it would overwrite the list on disk).
+    fn access(&self, file : OpenedFile) {
+        if file.path.is_none() {
+            return;
+        }
+        let recent = {
+            let mut state = self.state.borrow_mut();
+            state.touch(file);
+            state.rank();
+            if let (Some(path), true) = (&state.path, state.loaded) {
+                spawn_save(path.clone(), state.format, state.recent.clone(), state.usage.clone());
+            }
+            state.recent.clone()
+        };
+        self.on_changed.call(recent);
+    }
+
+}
+
+impl RecentManagerState {
+
+    fn touch(&mut self, mut file : OpenedFile) {
+        file.content = None;
+        file.dt = Some(SystemTime::now());
+        if let Some(path) = &file.path {
+            *self.usage.entry(path.clone()).or_insert(0) += 1;
+        }
+        self.recent.retain(|f| f.path != file.path );
+        self.recent.push(file);
+    }
+
+    // Adds a file accessed before the list was loaded, with the accesses done since then.
+    fn merge(&mut self, file : OpenedFile, accessed_usage : &HashMap<PathBuf, u32>) {
+        if let Some(path) = &file.path {
+            let count = accessed_usage.get(path).copied().unwrap_or(1);
+            *self.usage.entry(path.clone()).or_insert(0) += count;
+        }
+        self.recent.retain(|f| f.path != file.path );
+        self.recent.push(file);
+    }
+
+    // Sorts the list by score (ties by last access), drops the files over the capacity,
+    // and the counts of files no longer at the list. Files without a count (e.g. from a
+    // list written before counts were persisted) count as accessed once. The file accessed
+    // last is never dropped, since a file accessed for the first time would usually score
+    // lowest and never enter a full list.
+    fn rank(&mut self) {
+        let scorer = self.scorer.clone();
+        let usage = &self.usage;
+        let score = |f : &OpenedFile| {
+            let count = f.path.as_ref().and_then(|path| usage.get(path) ).copied().unwrap_or(1);
+            scorer(f, count)
+        };
+        self.recent.sort_by(|a, b| score(a).total_cmp(&score(b)).then(a.dt.cmp(&b.dt)) );
+        let newest = self.recent.iter().enumerate().max_by_key(|(_, f)| f.dt ).map(|(ix, _)| ix );
+        let newest = newest.filter(|_| self.capacity > 0 );
+        let mut excess = self.recent.len().saturating_sub(self.capacity);
+        let mut ix = 0;
+        self.recent.retain(|_| {
+            let keep = excess == 0 || Some(ix) == newest;
+            if !keep {
+                excess -= 1;
+            }
+            ix += 1;
+            keep
+        });
+        let recent = &self.recent;
+        self.usage.retain(|path, _| recent.iter().any(|f| f.path.as_ref() == Some(path) ) );
+    }
+
+}
+
+// How an archiver registers the files it opens and saves with the recent list of the
+// desktop (see MultiArchiverImpl::set_register_recent).
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SystemRecent {
+
+    pub(crate) register : bool,
+
+    // Application the files are registered with (the application name by default).
+    pub(crate) app_id : Option<String>,
+
+    // MIME type the files are registered with (the one detected at open by default).
+    pub(crate) mime_type : Option<String>
+
+}
+
+impl SystemRecent {
+
+    /// Adds the file to the default gtk::RecentManager, if registration is enabled.
+    /// Must be called from the main thread.
+    pub(crate) fn register(&self, file : &OpenedFile) {
+        if !self.register {
+            return;
+        }
+        let Some(path) = &file.path else {
+            return;
+        };
+        let uri = match glib::filename_to_uri(path, None) {
+            Ok(uri) => uri,
+            Err(e) => {
+                eprintln!("Could not register recent file: {}", e);
+                return;
+            }
+        };
+        let manager = gtk4::RecentManager::default();
+        let added = if self.app_id.is_none() && self.mime_type.is_none() {
+            manager.add_item(&uri)
+        } else {
+            let mime = self.mime_type.clone()
+                .or_else(|| file.mime.clone() )
+                .unwrap_or_else(|| String::from("text/plain") );
+            let app_name = self.app_id.clone()
+                .or_else(|| glib::application_name().map(|name| name.to_string() ) )
+                .unwrap_or_default();
+            let app_exec = format!("{} %u", glib::prgname().map(|name| name.to_string() ).unwrap_or_default());
+            let data = gtk4::RecentData::new(None, None, &mime, &app_name, &app_exec, &[], false);
+            manager.add_full(&uri, &data)
+        };
+        if !added {
+            eprintln!("Could not register recent file {}", uri);
+        }
+    }
+
+}
+
+/// File holding the access counts of the recent list at the given path (e.g. recent-usage.json
+/// for recent.json), as a JSON object from paths to counts.
+pub fn usage_path(path : &Path) -> PathBuf {
+    let stem = path.file_stem().map(|stem| stem.to_string_lossy().to_string() ).unwrap_or_default();
+    path.with_file_name(format!("{}-usage.json", stem))
+}
+
+fn spawn_load(path : PathBuf, format : RecentFormat, send : glib::Sender<(Vec<OpenedFile>, HashMap<PathBuf, u32>)>) {
+    thread::spawn(move || {
+        let loaded = if path.exists() {
+            load(&path, format).unwrap_or_else(|e| {
+                eprintln!("Could not load recent file list: {}", e);
+                Vec::new()
+            })
+        } else {
+            Vec::new()
+        };
+        let usage_path = usage_path(&path);
+        let usage = if usage_path.exists() {
+            File::open(&usage_path)
+                .map_err(|e| e.to_string() )
+                .and_then(|f| serde_json::from_reader(f).map_err(|e| e.to_string() ) )
+                .unwrap_or_else(|e| {
+                    eprintln!("Could not load recent file counts: {}", e);
+                    HashMap::new()
+                })
+        } else {
+            HashMap::new()
+        };
+        send.send((loaded, usage)).unwrap_or_else(super::log_err);
+    });
+}
+
+// Entries are sorted by their last access, since other tools might write them in any order.
+fn load(path : &Path, format : RecentFormat) -> Result<Vec<OpenedFile>, String> {
+    let mut recent = match format {
+        RecentFormat::Json => {
+            let f = File::open(path).map_err(|e| e.to_string() )?;
+            serde_json::from_reader::<_, Vec<OpenedFile>>(f).map_err(|e| e.to_string() )?
+        },
+        RecentFormat::Xbel => {
+            let xml = fs::read_to_string(path).map_err(|e| e.to_string() )?;
+            parse_xbel(&xml)
+        }
+    };
+    recent.sort_by_key(|f| f.dt );
+    Ok(recent)
+}
+
+// Saves of the same list run one at a time, in the order of the accesses, so that the
+// last list written is always the newest one.
+fn spawn_save(path : PathBuf, format : RecentFormat, recent : Vec<OpenedFile>, usage : HashMap<PathBuf, u32>) {
+    let key = path.clone();
+    spawn_worker(&key, |msg| eprintln!("Recent file list save panicked: {}", msg), move || {
+        let data = match format {
+            RecentFormat::Json => match serde_json::to_string_pretty(&recent) {
+                Ok(json) => json,
+                Err(e) => {
+                    eprintln!("Could not save recent file list: {}", e);
+                    return false;
+                }
+            },
+            RecentFormat::Xbel => write_xbel(&recent)
+        };
+        if let Err(e) = write_replacing(&path, |f| f.write_all(data.as_bytes()) ) {
+            eprintln!("Could not save recent file list: {}", e);
+        }
+        let res = serde_json::to_string_pretty(&usage)
+            .map_err(std::io::Error::other)
+            .and_then(|json| write_replacing(&usage_path(&path), |f| f.write_all(json.as_bytes()) ) );
+        if let Err(e) = res {
+            eprintln!("Could not save recent file counts: {}", e);
+            return false;
+        }
+        true
+    });
+}
+
+fn write_xbel(recent : &[OpenedFile]) -> String {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<xbel version=\"1.0\">\n");
+    for file in recent {
+        let Some(href) = file.path.as_ref().and_then(|path| glib::filename_to_uri(path, None).ok() ) else {
+            continue;
+        };
+        let secs = file.dt.and_then(|dt| dt.duration_since(SystemTime::UNIX_EPOCH).ok() ).map(|d| d.as_secs() ).unwrap_or(0);
+        let Some(visited) = glib::DateTime::from_unix_utc(secs as i64).ok().and_then(|dt| dt.format_iso8601().ok() ) else {
+            continue;
+        };
+        xml.push_str(&format!(
+            "  <bookmark href=\"{}\" added=\"{}\" modified=\"{}\" visited=\"{}\"/>\n",
+            glib::markup_escape_text(&href),
+            visited,
+            visited,
+            visited
+        ));
+    }
+    xml.push_str("</xbel>\n");
+    xml
+}
+
+// Reads the href and visited attributes of the bookmark elements. Bookmarks that are not
+// local files are skipped.
+fn parse_xbel(xml : &str) -> Vec<OpenedFile> {
+    xml.split("<bookmark ")
+        .skip(1)
+        .filter_map(|element| {
+            let tag = &element[..element.find('>')?];
+            let href = xml_attribute(tag, "href")?;
+            let (path, _host) = glib::filename_from_uri(&href).ok()?;
+            let mut file = OpenedFile::for_path(&path).ok()?;
+            let visited = xml_attribute(tag, "visited").or_else(|| xml_attribute(tag, "modified") );
+            file.dt = visited
+                .and_then(|visited| glib::DateTime::from_iso8601(&visited, None).ok() )
+                .map(|dt| SystemTime::UNIX_EPOCH + Duration::from_secs(dt.to_unix().max(0) as u64) );
+            Some(file)
+        })
+        .collect()
+}
+
+fn xml_attribute(tag : &str, name : &str) -> Option<String> {
+    let start = tag.find(&format!(" {}=\"", name))? + name.len() + 3;
+    let len = tag[start..].find('"')?;
+    Some(unescape_xml(&tag[start..start + len]))
+}
+
+fn unescape_xml(s : &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
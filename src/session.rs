@@ -0,0 +1,147 @@
+/*Copyright (c) 2022 Diego da Silva Lima. All rights reserved.
+
+This work is licensed under the terms of the MIT license.  
+For a copy, see <https://opensource.org/licenses/MIT>.*/
+
+use std::fs::File;
+use std::io::{self, Read, Write, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use serde::{Serialize, Deserialize};
+use super::FinalState;
+use super::io::write_replacing;
+
+// Name of the entry holding the manifest of a session archive.
+const MANIFEST_ENTRY : &str = "manifest.json";
+
+// Headers and contents of tar entries are padded to this size.
+const BLOCK_LEN : usize = 512;
+
+/*
+Session archives are plain tar files (readable by any archive tool, e.g. to inspect one
+attached to a bug report) holding a manifest and one entry with the content of each draft.
+The manifest is the session as restored by RestoreSession, plus the key of each draft (its
+path, or its untitled name) and the name of the entry holding its content.
+*/
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    state : FinalState,
+    drafts : Vec<(PathBuf, String)>
+}
+
+// The unsaved content of a file, exported with its session (see
+// MultiArchiverAction::ExportSessionArchive).
+#[derive(Debug, Clone)]
+pub(crate) struct SessionDraft {
+
+    // Key (path or untitled name) of the file.
+    pub(crate) source : PathBuf,
+
+    pub(crate) content : String
+
+}
+
+/// Writes the session and its drafts to the archive at the path. The archive replaces
+/// the previous one (see write_replacing), so that a failed write does not leave a
+/// truncated archive. Must be called from the worker thread.
+pub(crate) fn write_session_archive(path : &Path, state : &FinalState, drafts : &[SessionDraft]) -> io::Result<()> {
+    let manifest = Manifest {
+        state : state.clone(),
+        drafts : drafts.iter()
+            .enumerate()
+            .map(|(i, draft)| (draft.source.clone(), format!("drafts/{}.txt", i)) )
+            .collect()
+    };
+    let json = serde_json::to_vec_pretty(&manifest).map_err(io::Error::other)?;
+    write_replacing(path, |file| {
+        let mut f = BufWriter::new(&mut *file);
+        write_entry(&mut f, MANIFEST_ENTRY, &json)?;
+        for (draft, (_, entry)) in drafts.iter().zip(&manifest.drafts) {
+            write_entry(&mut f, entry, draft.content.as_bytes())?;
+        }
+        f.write_all(&[0; 2 * BLOCK_LEN])?;
+        f.flush()?;
+        drop(f);
+        file.sync_all()
+    })
+}
+
+/// Reads a session archive written by write_session_archive. Entries not listed at the
+/// manifest are ignored. Must be called from the worker thread.
+pub(crate) fn read_session_archive(path : &Path) -> io::Result<(FinalState, Vec<SessionDraft>)> {
+    let mut f = BufReader::new(File::open(path)?);
+    let mut entries = Vec::new();
+    while let Some(entry) = read_entry(&mut f)? {
+        entries.push(entry);
+    }
+    let Some((_, json)) = entries.iter().find(|(name, _)| name == MANIFEST_ENTRY ) else {
+        return Err(invalid("missing manifest"));
+    };
+    let manifest : Manifest = serde_json::from_slice(json).map_err(|e| invalid(&e.to_string()) )?;
+    let mut drafts = Vec::new();
+    for (source, entry) in manifest.drafts {
+        let Some((_, data)) = entries.iter().find(|(name, _)| *name == entry ) else {
+            return Err(invalid(&format!("missing entry {}", entry)));
+        };
+        let content = String::from_utf8(data.clone()).map_err(|e| invalid(&e.to_string()) )?;
+        drafts.push(SessionDraft { source, content });
+    }
+    Ok((manifest.state, drafts))
+}
+
+fn invalid(msg : &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Invalid session archive: {}", msg))
+}
+
+// Writes a regular file entry (ustar header, then the data padded to the block size).
+fn write_entry<W : Write>(w : &mut W, name : &str, data : &[u8]) -> io::Result<()> {
+    let mut header = [0u8; BLOCK_LEN];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    header[100..108].copy_from_slice(b"0000644\0");
+    header[108..116].copy_from_slice(b"0000000\0");
+    header[116..124].copy_from_slice(b"0000000\0");
+    header[124..136].copy_from_slice(format!("{:011o}\0", data.len()).as_bytes());
+    header[136..148].copy_from_slice(b"00000000000\0");
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    // The checksum is computed with its own field filled with spaces.
+    header[148..156].copy_from_slice(b"        ");
+    let sum : u32 = header.iter().map(|b| *b as u32 ).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+    w.write_all(&header)?;
+    w.write_all(data)?;
+    w.write_all(&vec![0; padding(data.len())])
+}
+
+// Reads the next entry, returning None at the end of the archive. Entries that are not
+// regular files (e.g. directories added by other tools) are returned empty.
+fn read_entry<R : Read>(r : &mut R) -> io::Result<Option<(String, Vec<u8>)>> {
+    let mut header = [0u8; BLOCK_LEN];
+    if let Err(e) = r.read_exact(&mut header) {
+        return if e.kind() == io::ErrorKind::UnexpectedEof { Ok(None) } else { Err(e) };
+    }
+    if header.iter().all(|b| *b == 0 ) {
+        return Ok(None);
+    }
+    let name_len = header[..100].iter().position(|b| *b == 0 ).unwrap_or(100);
+    let name = String::from_utf8(header[..name_len].to_vec()).map_err(|e| invalid(&e.to_string()) )?;
+    let size = std::str::from_utf8(&header[124..136]).ok()
+        .map(|s| s.trim_matches(|c : char| c == '\0' || c == ' ' ) )
+        .and_then(|s| u64::from_str_radix(s, 8).ok() )
+        .ok_or_else(|| invalid(&format!("bad size at entry {}", name)) )?;
+    let mut data = Vec::new();
+    r.by_ref().take(size).read_to_end(&mut data)?;
+    if (data.len() as u64) < size {
+        return Err(invalid(&format!("truncated entry {}", name)));
+    }
+    io::copy(&mut r.by_ref().take(padding(data.len()) as u64), &mut io::sink())?;
+    if header[156] != b'0' && header[156] != 0 {
+        data.clear();
+    }
+    Ok(Some((name, data)))
+}
+
+fn padding(len : usize) -> usize {
+    (BLOCK_LEN - len % BLOCK_LEN) % BLOCK_LEN
+}
@@ -5,22 +5,27 @@ For a copy, see <https://opensource.org/licenses/MIT>.*/
 
 use gtk4::*;
 use gtk4::prelude::*;
-use std::fs::File;
-use std::io::{Read, Write};
-use std::thread;
-use std::thread::JoinHandle;
-use std::time::SystemTime;
+use std::fs::{self, File};
+use std::thread::{self, JoinHandle};
+use std::sync::mpsc;
+use std::time::{SystemTime, Duration, Instant};
 use glib::signal::SignalHandlerId;
 use std::convert::AsRef;
 use stateful::Callbacks;
 use stateful::ValuedCallbacks;
-use super::{OpenDialog, SaveDialog};
+use super::{OpenDialog, SaveDialog, OpenedFile, RecentStore, ArchiverMetrics, ArchiverError};
+use super::expand_tilde;
+use super::autosave::{ArchiverAutosave, AutosaveRecord, spawn_write_recovery};
+use super::io::{SaveOptions, SymlinkPolicy, Durability, BomPolicy, NewlinePolicy, BackupPolicy, NormalizationPolicy, IoBackend, IoTask, CancelToken, TextFormat, read_content, write_content, normalize_file_name, worker, gio_metadata, gio_read, gio_write};
+use super::pool::{submit_io, submit_local};
 use crate::FileActions;
 use std::rc::Rc;
-use std::cell::RefCell;
-use std::path::{Path};
+use std::cell::{Cell, RefCell};
+use std::path::{Path, PathBuf};
 
-#[derive(Clone, Copy)]
+/// The action waiting for the user to confirm that the current file, which has
+/// unsaved changes, can be closed (Editing if there is none).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FileState {
     New,
     Editing,
@@ -28,29 +33,77 @@ pub enum FileState {
     CloseWindow
 }
 
+/// The path taken by open_last_or_new.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StartupChoice {
+
+    // The last document still exists and was requested to be opened.
+    Reopened(PathBuf),
+
+    // There was no last document (or it does not exist anymore), so a new file was created.
+    New
+
+}
+
+/// Actions processed by the SingleArchiver main loop. As for MultiArchiverAction, matches
+/// outside of this crate need a catch-all arm, since new actions are added as features are.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum SingleArchiverAction {
 
     // Whether to force or not
     NewRequest(bool),
 
-    SaveRequest(Option<String>),
+    SaveRequest(Option<PathBuf>),
+
+    SaveSuccess(PathBuf),
+
+    // Sent by the save thread before SaveSuccess. Carries the saved path and the backup path.
+    BackupCreated(PathBuf, PathBuf),
+
+    SaveError(ArchiverError),
 
-    SaveSuccess(String),
+    // Sent when the save timeout of the save of the given path and generation elapses.
+    SaveTimeout(PathBuf, u64),
 
-    SaveError(String),
+    // Sent periodically by the open and save threads of files larger than a few megabytes.
+    // Carries the path, the number of bytes done and the total number of bytes.
+    IoProgress(PathBuf, u64, u64),
+
+    // Enables (or disables, with None) the periodic autosave.
+    SetAutosave(Option<ArchiverAutosave>),
+
+    // Sent when the autosave interval elapses. Carries the generation of the
+    // SetAutosave that scheduled it.
+    AutosaveTimeout(u64),
+
+    // Sent by the thread writing a recovery file.
+    RecoveryWritten(Result<AutosaveRecord, String>),
 
     FileChanged,
 
-    OpenRequest(String),
+    OpenRequest(PathBuf),
+
+    // Carries path, content, whether the file had a byte order mark and
+    // the encoding of the file (if not UTF-8)
+    OpenSuccess(PathBuf, String, bool, Option<String>),
 
-    // Carries path and content
-    OpenSuccess(String, String),
+    OpenError(ArchiverError),
 
-    OpenError(String),
+    // Carries the document path and the content of its sidecar file.
+    SidecarLoaded(PathBuf, serde_json::Value),
+
+    // Sent when the sidecar could not be read or written (the document itself was opened or saved).
+    SidecarError(ArchiverError),
 
     RequestShowOpen,
 
+    // Opens the last document if it still exists, or creates a new file otherwise.
+    OpenLastOrNewRequest,
+
+    // Carries the last document path, if the pointer file was read and the document exists.
+    LastDocumentResolved(Option<PathBuf>),
+
     FileCloseRequest,
 
     WindowCloseRequest
@@ -59,17 +112,43 @@ pub enum SingleArchiverAction {
 
 pub struct SingleArchiver {
     send : glib::Sender<SingleArchiverAction>,
-    on_open : Callbacks<(String, String)>,
+    on_open : Callbacks<(PathBuf, String)>,
     on_open_request : Callbacks<()>,
     on_new : Callbacks<()>,
     on_buffer_read_request : ValuedCallbacks<(), String>,
-    on_file_changed : Callbacks<Option<String>>,
+    on_buffer_slice_request : ValuedCallbacks<(usize, usize), String>,
+
+    // Length of the slices the buffer is read in when saving, if the incremental read is set.
+    incremental_read : Rc<Cell<Option<usize>>>,
+    on_file_changed : Callbacks<Option<PathBuf>>,
     on_save_unknown_path : Callbacks<String>,
-    on_save : Callbacks<String>,
+    on_save : Callbacks<PathBuf>,
     on_close_confirm : Callbacks<String>,
     on_window_close : Callbacks<()>,
+    on_window_close_veto : ValuedCallbacks<(), bool>,
     on_show_open : Callbacks<()>,
-    on_error : Callbacks<String>
+    on_error : Callbacks<ArchiverError>,
+    on_io_stalled : Callbacks<PathBuf>,
+    on_autosaved : Callbacks<AutosaveRecord>,
+    on_io_busy : Callbacks<bool>,
+    on_io_progress : Callbacks<(PathBuf, u64, u64)>,
+    on_backup_created : Callbacks<(PathBuf, PathBuf)>,
+    on_encoding_detected : Callbacks<(PathBuf, String)>,
+    on_state_changed : Callbacks<FileState>,
+    file_state : Rc<Cell<FileState>>,
+    on_sidecar_loaded : Callbacks<(PathBuf, serde_json::Value)>,
+    on_sidecar_request : ValuedCallbacks<(), Option<serde_json::Value>>,
+    on_startup : Callbacks<StartupChoice>,
+    save_options : Rc<RefCell<SaveOptions>>,
+    io_backend : Rc<Cell<IoBackend>>,
+
+    // Suffix appended to the document path to build the sidecar path, if sidecars are enabled.
+    sidecar : Rc<RefCell<Option<String>>>,
+
+    // File holding the path of the last opened or saved document, if enabled.
+    last_document : Rc<RefCell<Option<PathBuf>>>,
+
+    metrics : Rc<RefCell<ArchiverMetrics>>
 }
 
 pub trait SingleArchiverImpl : AsRef<SingleArchiver> {
@@ -80,7 +159,7 @@ pub trait SingleArchiverImpl : AsRef<SingleArchiver> {
 
     fn connect_opened<F>(&self, f : F)
     where
-        F : Fn((String, String)) + 'static
+        F : Fn((PathBuf, String)) + 'static
     {
         self.as_ref().on_open.bind(f);
     }
@@ -112,6 +191,25 @@ pub trait SingleArchiverImpl : AsRef<SingleArchiver> {
         self.as_ref().on_buffer_read_request.bind(f);
     }
 
+    // Called with a character offset and length to read a slice of the buffer when the
+    // incremental read is set. Returning an empty string marks the end of the buffer.
+    fn connect_buffer_slice_request<F>(&self, f : F)
+    where
+        F : Fn((usize, usize))->String + 'static
+    {
+        assert!(self.as_ref().on_buffer_slice_request.count_bounded() == 0);
+        self.as_ref().on_buffer_slice_request.bind(f);
+    }
+
+    // Reads the buffer in slices of the given number of characters when saving, one slice
+    // per idle callback, streaming them to the save thread so that saving a huge buffer does
+    // not block the main loop (None reads the buffer at once, the default). Only takes effect
+    // if a slice request is connected. A save is aborted with an error if the buffer changes
+    // (i.e. FileChanged is sent) before it is fully read. Recovery files are still read at once.
+    fn set_incremental_read(&self, slice_len : Option<usize>) {
+        self.as_ref().incremental_read.set(slice_len.filter(|len| *len > 0 ));
+    }
+
     // This is the first save of a new file. Perhaps rename to "save new"
     fn connect_save_unknown_path<F>(&self, f : F)
     where
@@ -122,14 +220,14 @@ pub trait SingleArchiverImpl : AsRef<SingleArchiver> {
 
     fn connect_error<F>(&self, f : F)
     where
-        F : Fn(String)->() + 'static
+        F : Fn(ArchiverError)->() + 'static
     {
         self.as_ref().on_error.bind(f);
     }
 
     fn connect_save<F>(&self, f : F)
     where
-        F : Fn(String)->() + 'static
+        F : Fn(PathBuf)->() + 'static
     {
         self.as_ref().on_save.bind(f);
     }
@@ -143,7 +241,7 @@ pub trait SingleArchiverImpl : AsRef<SingleArchiver> {
 
     fn connect_file_changed<F>(&self, f : F)
     where
-        F : Fn(Option<String>) + 'static
+        F : Fn(Option<PathBuf>) + 'static
     {
         self.as_ref().on_file_changed.bind(f);
     }
@@ -155,6 +253,15 @@ pub trait SingleArchiverImpl : AsRef<SingleArchiver> {
         self.as_ref().on_window_close.bind(f);
     }
 
+    // Evaluated just before on_window_close is fired. Returning true holds the
+    // window open, even when the current file is saved.
+    fn connect_window_close_veto<F>(&self, f : F)
+    where
+        F : Fn(()) -> bool + 'static
+    {
+        self.as_ref().on_window_close_veto.bind(f);
+    }
+
     fn connect_show_open<F>(&self, f : F)
     where
         F : Fn(()) + 'static
@@ -162,8 +269,175 @@ pub trait SingleArchiverImpl : AsRef<SingleArchiver> {
         self.as_ref().on_show_open.bind(f);
     }
 
+    fn set_symlink_policy(&self, policy : SymlinkPolicy) {
+        self.as_ref().save_options.borrow_mut().symlink = policy;
+    }
+
+    fn set_durability(&self, durability : Durability) {
+        self.as_ref().save_options.borrow_mut().durability = durability;
+    }
+
+    fn set_bom_policy(&self, policy : BomPolicy) {
+        self.as_ref().save_options.borrow_mut().bom = policy;
+    }
+
+    fn set_newline_policy(&self, policy : NewlinePolicy) {
+        self.as_ref().save_options.borrow_mut().newline = policy;
+    }
+
+    fn set_normalization_policy(&self, policy : NormalizationPolicy) {
+        self.as_ref().save_options.borrow_mut().normalization = policy;
+    }
+
+    fn set_backup_policy(&self, policy : BackupPolicy) {
+        self.as_ref().save_options.borrow_mut().backup = policy;
+    }
+
+    // Permission bits of the files created by saves (see SaveOptions::new_file_mode).
+    fn set_new_file_mode(&self, mode : Option<u32>) {
+        self.as_ref().save_options.borrow_mut().new_file_mode = mode;
+    }
+
+    // Where the opens and saves that start after this call run (see IoBackend). The gio
+    // backend reads the buffer at once for saves, even if an incremental read is set.
+    fn set_io_backend(&self, backend : IoBackend) {
+        self.as_ref().io_backend.set(backend);
+    }
+
+    // Called with the saved path and the backup path when a save copied the
+    // previous file (see BackupPolicy).
+    fn connect_backup_created<F>(&self, f : F)
+    where
+        F : Fn((PathBuf, PathBuf)) + 'static
+    {
+        self.as_ref().on_backup_created.bind(f);
+    }
+
+    // Called after on_open with the path and the encoding of the opened file,
+    // when it is not UTF-8 (see CurrentFile::encoding).
+    fn connect_encoding_detected<F>(&self, f : F)
+    where
+        F : Fn((PathBuf, String)) + 'static
+    {
+        self.as_ref().on_encoding_detected.bind(f);
+    }
+
+    fn set_save_timeout(&self, timeout : Option<Duration>) {
+        self.as_ref().save_options.borrow_mut().timeout = timeout;
+    }
+
+    // Called when a save did not finish within the save timeout. Carries the saved path.
+    fn connect_io_stalled<F>(&self, f : F)
+    where
+        F : Fn(PathBuf) + 'static
+    {
+        self.as_ref().on_io_stalled.bind(f);
+    }
+
+    // Saves the file at its own path at every interval if it has unsaved changes (None
+    // disables the autosave). Send SingleArchiverAction::SetAutosave with an
+    // ArchiverAutosave built with_recovery_dir to write a recovery file instead.
+    fn set_autosave(&self, interval : Option<Duration>) {
+        self.as_ref().send.send(SingleArchiverAction::SetAutosave(interval.map(ArchiverAutosave::new)))
+            .unwrap_or_else(super::log_err);
+    }
+
+    fn connect_autosaved<F>(&self, f : F)
+    where
+        F : Fn(AutosaveRecord) + 'static
+    {
+        self.as_ref().on_autosaved.bind(f);
+    }
+
+    // Called with true when the document starts being opened or saved (while no other
+    // open or save is in flight), and with false when all of them finished (e.g. to show
+    // a spinner at the header bar).
+    fn connect_io_busy<F>(&self, f : F)
+    where
+        F : Fn(bool) + 'static
+    {
+        self.as_ref().on_io_busy.bind(f);
+    }
+
+    // Called periodically while a file larger than a few megabytes is opened or saved, with
+    // the path, the number of bytes done and the total number of bytes (e.g. to show a progress bar).
+    fn connect_io_progress<F>(&self, f : F)
+    where
+        F : Fn((PathBuf, u64, u64)) + 'static
+    {
+        self.as_ref().on_io_progress.bind(f);
+    }
+
+    fn file_state(&self) -> FileState {
+        self.as_ref().file_state.get()
+    }
+
+    // Counters of the opens and saves done during this session.
+    fn metrics(&self) -> ArchiverMetrics {
+        self.as_ref().metrics.borrow().clone()
+    }
+
+    // Called when the action waiting for a close confirmation changes (e.g. so that the
+    // application disables the template picker while a close confirmation is pending).
+    fn connect_state_changed<F>(&self, f : F)
+    where
+        F : Fn(FileState) + 'static
+    {
+        self.as_ref().on_state_changed.bind(f);
+    }
+
+    // Enables a sidecar JSON file kept next to the document (e.g. with the suffix
+    // .meta.json, the sidecar of document.tex is document.tex.meta.json), where the
+    // application can keep per-document settings. None disables the sidecar.
+    fn set_sidecar_suffix(&self, suffix : Option<&str>) {
+        *self.as_ref().sidecar.borrow_mut() = suffix.map(|s| s.to_string() );
+    }
+
+    // Called after on_open with the document path and the sidecar content, if
+    // the sidecar file exists.
+    fn connect_sidecar_loaded<F>(&self, f : F)
+    where
+        F : Fn((PathBuf, serde_json::Value)) + 'static
+    {
+        self.as_ref().on_sidecar_loaded.bind(f);
+    }
+
+    // Evaluated after the document is saved, to get the content written to the
+    // sidecar. Returning None leaves the sidecar untouched.
+    fn connect_sidecar_request<F>(&self, f : F)
+    where
+        F : Fn(()) -> Option<serde_json::Value> + 'static
+    {
+        assert!(self.as_ref().on_sidecar_request.count_bounded() == 0);
+        self.as_ref().on_sidecar_request.bind(f);
+    }
+
+    // Sets the file where the path of the last opened or saved document is written,
+    // which is read by open_last_or_new. None disables it.
+    fn set_last_document_file(&self, path : Option<&Path>) {
+        *self.as_ref().last_document.borrow_mut() = path.map(|p| p.to_path_buf() );
+    }
+
+    // Opens the last document if it still exists (checked outside the main thread),
+    // or creates a new file otherwise (e.g. when the application starts). The path
+    // taken is passed to on_startup.
+    fn open_last_or_new(&self) {
+        self.as_ref().send.send(SingleArchiverAction::OpenLastOrNewRequest)
+            .unwrap_or_else(super::log_err);
+    }
+
+    fn connect_startup<F>(&self, f : F)
+    where
+        F : Fn(StartupChoice) + 'static
+    {
+        self.as_ref().on_startup.bind(f);
+    }
+
 }
 
+// Name of the file before it is first saved.
+const UNTITLED_NAME : &str = "Untitled.tex";
+
 // If file was created via "New" action, path will be None and last_saved will be None.
 // If file was opened, path will be Some(path) and last_saved will be None. Every time
 // the file is saved via "Save" action, last_saved will be updated and the path is
@@ -175,22 +449,40 @@ pub struct CurrentFile {
 
     pub last_saved : Option<SystemTime>,
 
-    pub path : Option<String>,
+    pub path : Option<PathBuf>,
 
-    pub just_opened : bool
+    pub just_opened : bool,
+
+    // Whether the file had a byte order mark when it was opened.
+    pub bom : bool,
+
+    // Encoding of the file, if not UTF-8 (the content is saved back in this encoding).
+    pub encoding : Option<String>
 
 }
 
 impl CurrentFile {
 
+    pub(crate) fn text_format(&self) -> TextFormat {
+        TextFormat { bom : self.bom, encoding : self.encoding.clone() }
+    }
+
     pub fn reset(&mut self) {
         self.path = None;
         self.last_saved = Some(SystemTime::now());
         self.just_opened = true;
+        self.bom = false;
+        self.encoding = None;
     }
 
+    /// The path (or the untitled name) as shown to the user.
     pub fn path_or_untitled(&self) -> String {
-        self.path.clone().unwrap_or(String::from("Untitled.tex"))
+        self.path.as_ref().map(|p| p.display().to_string() ).unwrap_or(String::from(UNTITLED_NAME))
+    }
+
+    // Identifies the file at the recovery directory.
+    fn recovery_source(&self) -> PathBuf {
+        self.path.clone().unwrap_or_else(|| PathBuf::from(UNTITLED_NAME) )
     }
 
 }
@@ -198,41 +490,110 @@ impl CurrentFile {
 impl SingleArchiver {
 
     pub fn new() -> Self {
+        Self::build(None)
+    }
+
+    /// Builds an archiver that pushes the opened and saved documents to the recent
+    /// list of the store (which might be shared with other archivers).
+    pub fn with_recent_store(store : &RecentStore) -> Self {
+        Self::build(Some(store.clone()))
+    }
+
+    fn build(recent_store : Option<RecentStore>) -> Self {
 
         let (send, recv) = glib::MainContext::channel::<SingleArchiverAction>(glib::source::Priority::DEFAULT);
-        let on_open : Callbacks<(String, String)> = Default::default();
+        let on_open : Callbacks<(PathBuf, String)> = Default::default();
         let on_show_open : Callbacks<()> = Default::default();
         let on_new : Callbacks<()> = Default::default();
         let on_open_request : Callbacks<()> = Default::default();
         let on_buffer_read_request : ValuedCallbacks<(), String> = Default::default();
+        let on_buffer_slice_request : ValuedCallbacks<(usize, usize), String> = Default::default();
+        let incremental_read : Rc<Cell<Option<usize>>> = Default::default();
         let on_save_unknown_path : Callbacks<String> = Default::default();
-        let on_save : Callbacks<String> = Default::default();
-        let on_error : Callbacks<String> = Default::default();
+        let on_save : Callbacks<PathBuf> = Default::default();
+        let on_error : Callbacks<ArchiverError> = Default::default();
         let on_close_confirm : Callbacks<String> = Default::default();
         let on_window_close : Callbacks<()> = Default::default();
-        let on_file_changed : Callbacks<Option<String>> = Default::default();
+        let on_window_close_veto : ValuedCallbacks<(), bool> = Default::default();
+        let on_file_changed : Callbacks<Option<PathBuf>> = Default::default();
+        let save_options : Rc<RefCell<SaveOptions>> = Default::default();
+        let io_backend : Rc<Cell<IoBackend>> = Default::default();
+        let on_io_stalled : Callbacks<PathBuf> = Default::default();
+        let on_autosaved : Callbacks<AutosaveRecord> = Default::default();
+        let on_io_busy : Callbacks<bool> = Default::default();
+        let on_io_progress : Callbacks<(PathBuf, u64, u64)> = Default::default();
+        let on_backup_created : Callbacks<(PathBuf, PathBuf)> = Default::default();
+        let on_encoding_detected : Callbacks<(PathBuf, String)> = Default::default();
+        let on_state_changed : Callbacks<FileState> = Default::default();
+
+        // Holds an action that should happen after the currently-opened file is closed.
+        // This variable is updated at NewRequest, OpenRequest and WindowCloseRequest, and
+        // goes back to Editing after the action happens at FileCloseRequest.
+        let file_state = Rc::new(Cell::new(FileState::New));
+        let on_sidecar_loaded : Callbacks<(PathBuf, serde_json::Value)> = Default::default();
+        let on_sidecar_request : ValuedCallbacks<(), Option<serde_json::Value>> = Default::default();
+        let sidecar : Rc<RefCell<Option<String>>> = Default::default();
+        let on_startup : Callbacks<StartupChoice> = Default::default();
+        let last_document : Rc<RefCell<Option<PathBuf>>> = Default::default();
+        let metrics : Rc<RefCell<ArchiverMetrics>> = Default::default();
+        let store_id = recent_store.as_ref().map(|store| store.register_observer() ).unwrap_or(0);
         recv.attach(None, {
             let on_open = on_open.clone();
             let on_new = on_new.clone();
             let send = send.clone();
             let on_buffer_read_request = on_buffer_read_request.clone();
+            let reader = BufferReader {
+                on_buffer_read_request : on_buffer_read_request.clone(),
+                on_buffer_slice_request : on_buffer_slice_request.clone(),
+                incremental_read : incremental_read.clone(),
+                io_backend : io_backend.clone(),
+                generation : Default::default(),
+                stream : Default::default()
+            };
             let on_save_unknown_path = on_save_unknown_path.clone();
             let on_close_confirm = on_close_confirm.clone();
             let on_window_close = on_window_close.clone();
+            let on_window_close_veto = on_window_close_veto.clone();
             let on_file_changed = on_file_changed.clone();
             let _on_open_request = on_open_request.clone();
             let on_save = on_save.clone();
             let on_show_open = on_show_open.clone();
             let on_error = on_error.clone();
-
-            // Holds an action that should happen after the currently-opened file is closed.
-            // This variable is updated at NewRequest, OpenRequest and WindowCloseRequest.
-            let mut file_state = FileState::New;
+            let save_options = save_options.clone();
+            let io_backend = io_backend.clone();
+            let on_io_stalled = on_io_stalled.clone();
+            let on_autosaved = on_autosaved.clone();
+            let on_io_busy = on_io_busy.clone();
+            let on_io_progress = on_io_progress.clone();
+            let on_backup_created = on_backup_created.clone();
+            let on_encoding_detected = on_encoding_detected.clone();
+            let on_state_changed = on_state_changed.clone();
+            let file_state = file_state.clone();
+            let on_sidecar_loaded = on_sidecar_loaded.clone();
+            let on_sidecar_request = on_sidecar_request.clone();
+            let sidecar = sidecar.clone();
+            let on_startup = on_startup.clone();
+            let last_document = last_document.clone();
+            let metrics = metrics.clone();
 
             // Holds optional path and whether the file is saved.
             let mut curr_file : CurrentFile = Default::default();
-            let mut file_open_handle : Option<JoinHandle<bool>> = None;
-            let mut file_save_handle : Option<JoinHandle<bool>> = None;
+
+            // Cancels the open in flight, which is superseded by the next OpenRequest.
+            let mut open_cancel : Option<CancelToken> = None;
+            let mut save_task = IoTask::default();
+            let mut autosave : Option<ArchiverAutosave> = None;
+            let mut autosave_generation : u64 = 0;
+
+            // Whether the current save was started by the autosave.
+            let mut autosaving = false;
+
+            // Number of opens and saves in flight (see on_io_busy).
+            let mut io_busy : usize = 0;
+
+            // When the last open and save were requested, to measure their latency.
+            let mut open_started : Option<Instant> = None;
+            let mut save_started : Option<Instant> = None;
             curr_file.reset();
 
             // let mut ix = 0;
@@ -247,30 +608,30 @@ impl SingleArchiver {
 
                         // User requested to create a new file, but the current file has unsaved changes.
                         if !force && !curr_file.last_saved.is_some() {
-                            file_state = FileState::New;
+                            set_file_state(&file_state, FileState::New, &on_state_changed);
                             on_close_confirm.call(curr_file.path_or_untitled());
 
                         // User requested to create a new file by clicking the "discard" at the toast
                         // (or there isn't a currently opened path).
                         } else {
+                            move_opened(&recent_store, store_id, curr_file.path.as_deref(), None);
                             curr_file.reset();
                             on_new.call(());
                         }
                     },
                     SingleArchiverAction::SaveRequest(opt_path) => {
+                        let opt_path = opt_path.map(|path| normalize_file_name(expand_tilde(&path), save_options.borrow().normalization) );
                         if let Some(path) = opt_path {
-                            let content = on_buffer_read_request.call_with_values(()).remove(0);
-                            if let Some(handle) = file_save_handle.take() {
-                                handle.join().unwrap();
-                            }
-                            file_save_handle = Some(spawn_save_file(path, content, send.clone()));
+                            let content = reader.read();
+                            start_save(&mut save_task, path, content, curr_file.text_format(), &save_options.borrow(), io_backend.get(), &send);
+                            start_io(&mut io_busy, &on_io_busy);
+                            save_started = Some(Instant::now());
                         } else {
                             if let Some(path) = curr_file.path.clone() {
-                                let content = on_buffer_read_request.call_with_values(()).remove(0);
-                                if let Some(handle) = file_save_handle.take() {
-                                    handle.join().unwrap();
-                                }
-                                file_save_handle = Some(spawn_save_file(path, content, send.clone()));
+                                let content = reader.read();
+                                start_save(&mut save_task, path, content, curr_file.text_format(), &save_options.borrow(), io_backend.get(), &send);
+                                start_io(&mut io_busy, &on_io_busy);
+                                save_started = Some(Instant::now());
                             } else {
                                 on_save_unknown_path.call(String::new());
                             }
@@ -283,6 +644,8 @@ impl SingleArchiver {
                     // just_opened is false.
                     SingleArchiverAction::FileChanged => {
 
+                        // A save still reading the buffer would write a mix of the old and new content.
+                        reader.abort();
 
                         // Use this decision branch to inhibit buffer changes
                         // when a new file is opened.
@@ -297,69 +660,237 @@ impl SingleArchiver {
 
                     },
                     SingleArchiverAction::SaveSuccess(path) => {
+                        finish_io(&mut io_busy, &on_io_busy);
+                        {
+                            let mut metrics = metrics.borrow_mut();
+                            metrics.saves += 1;
+                            metrics.record_since(save_started.take());
+                        }
+                        if let Some(autosave) = &autosave {
+                            autosave.discard(&curr_file.recovery_source());
+                        }
+                        if autosaving {
+                            autosaving = false;
+                            on_autosaved.call(AutosaveRecord {
+                                source : path.clone(),
+                                target : path.clone(),
+                                recovery : false,
+                                dt : SystemTime::now()
+                            });
+                        }
+                        move_opened(&recent_store, store_id, curr_file.path.as_deref(), Some(&path));
                         curr_file.path = Some(path.clone());
                         curr_file.last_saved = Some(SystemTime::now());
                         on_save.call(path.clone());
+                        if let Some(suffix) = sidecar.borrow().as_ref() {
+                            if let Some(value) = on_sidecar_request.call_with_values(()).into_iter().flatten().next() {
+                                spawn_save_sidecar(sidecar_path(&path, suffix), value, send.clone());
+                            }
+                        }
+                        if let Some(pointer) = last_document.borrow().as_ref() {
+                            spawn_save_last_document(pointer.clone(), path);
+                        }
+                    },
+                    SingleArchiverAction::BackupCreated(path, backup) => {
+                        on_backup_created.call((path, backup));
+                    },
+                    SingleArchiverAction::SidecarLoaded(path, value) => {
+
+                        // Another file might have been opened while the sidecar was loading.
+                        if curr_file.path.as_ref() == Some(&path) {
+                            on_sidecar_loaded.call((path, value));
+                        }
                     },
                     SingleArchiverAction::SaveError(msg) => {
+                        finish_io(&mut io_busy, &on_io_busy);
+                        {
+                            let mut metrics = metrics.borrow_mut();
+                            metrics.save_errors += 1;
+                            metrics.record_since(save_started.take());
+                        }
+                        autosaving = false;
                         on_error.call(msg.clone());
                     },
+                    SingleArchiverAction::SetAutosave(opt_autosave) => {
+                        autosave = opt_autosave;
+                        autosave_generation += 1;
+                        if let Some(autosave) = &autosave {
+                            schedule_autosave(autosave, &send, autosave_generation);
+                        }
+                    },
+                    SingleArchiverAction::AutosaveTimeout(generation) => {
+                        if generation != autosave_generation {
+                            return glib::ControlFlow::Continue;
+                        }
+                        let Some(autosave) = &autosave else {
+                            return glib::ControlFlow::Continue;
+                        };
+                        if curr_file.last_saved.is_none() {
+                            if autosave.recovery_dir().is_some() {
+                                let content = on_buffer_read_request.call_with_values(()).remove(0);
+                                spawn_write_recovery(
+                                    autosave,
+                                    curr_file.recovery_source(),
+                                    content,
+                                    send.clone(),
+                                    SingleArchiverAction::RecoveryWritten
+                                );
+                            } else if let Some(path) = curr_file.path.clone() {
+                                let content = reader.read();
+                                autosaving = true;
+                                start_save(&mut save_task, path, content, curr_file.text_format(), &save_options.borrow(), io_backend.get(), &send);
+                                start_io(&mut io_busy, &on_io_busy);
+                                save_started = Some(Instant::now());
+                            }
+                        }
+                        schedule_autosave(autosave, &send, generation);
+                    },
+                    SingleArchiverAction::RecoveryWritten(res) => {
+                        match res {
+                            Ok(record) => on_autosaved.call(record),
+                            Err(msg) => on_error.call(ArchiverError::Io(msg))
+                        }
+                    },
+                    SingleArchiverAction::SaveTimeout(path, generation) => {
+                        if save_task.check_stalled(generation) {
+                            on_io_stalled.call(path);
+                        }
+                    },
+                    SingleArchiverAction::IoProgress(path, done, total) => {
+                        on_io_progress.call((path, done, total));
+                    },
                     SingleArchiverAction::RequestShowOpen => {
                         if curr_file.last_saved.is_some() {
                             on_show_open.call(());
                         } else {
-                            file_state = FileState::Open;
+                            set_file_state(&file_state, FileState::Open, &on_state_changed);
                             on_close_confirm.call(curr_file.path_or_untitled());
                         }
                     },
+                    SingleArchiverAction::OpenLastOrNewRequest => {
+                        match last_document.borrow().as_ref() {
+                            Some(pointer) => spawn_load_last_document(pointer.clone(), send.clone()),
+                            None => {
+                                send.send(SingleArchiverAction::LastDocumentResolved(None))
+                                    .unwrap_or_else(super::log_err);
+                            }
+                        }
+                    },
+                    SingleArchiverAction::LastDocumentResolved(opt_path) => {
+                        match opt_path {
+                            Some(path) => {
+                                send.send(SingleArchiverAction::OpenRequest(path.clone()))
+                                    .unwrap_or_else(super::log_err);
+                                on_startup.call(StartupChoice::Reopened(path));
+                            },
+                            None => {
+                                send.send(SingleArchiverAction::NewRequest(false))
+                                    .unwrap_or_else(super::log_err);
+                                on_startup.call(StartupChoice::New);
+                            }
+                        }
+                    },
                     SingleArchiverAction::OpenRequest(path) => {
+                        let path = expand_tilde(&path);
 
                         // User tried to open an already-opened file. Ignore the request in this case.
                         if let Some(curr_path) = &curr_file.path {
-                            if &curr_path[..] == path {
+                            if curr_path == &path {
                                 return glib::ControlFlow::Continue;
                             }
                         }
     
-                        if let Some(handle) = file_open_handle.take() {
-                            handle.join().unwrap();
+                        // A superseded open that could not be cancelled finished already, and its result
+                        // is replaced by the result of this one.
+                        if let Some(cancel) = open_cancel.take() {
+                            if cancel.cancel() {
+                                finish_io(&mut io_busy, &on_io_busy);
+                            }
                         }
-                        file_open_handle = Some(spawn_open_file(path, send.clone()));
+                        let cancel = CancelToken::default();
+                        let key = path.clone();
+                        match io_backend.get() {
+                            IoBackend::Threads => submit_io(&key, open_job(path, send.clone(), cancel.clone())),
+                            IoBackend::Gio => submit_local(&key, gio_open_job(path, send.clone(), cancel.clone()))
+                        };
+                        open_cancel = Some(cancel);
+                        start_io(&mut io_busy, &on_io_busy);
+                        open_started = Some(Instant::now());
 
                         // Just opened should be set here (before the confirmation of the open thread)
                         // because the on_open
                         // curr_file.just_opened = true;
                     },
-                    SingleArchiverAction::OpenSuccess(path, content) => {
+                    SingleArchiverAction::OpenSuccess(path, content, bom, encoding) => {
+                        finish_io(&mut io_busy, &on_io_busy);
+                        {
+                            let mut metrics = metrics.borrow_mut();
+                            metrics.opens += 1;
+                            metrics.record_since(open_started.take());
+                        }
 
                         // It is critical that just_opened is set to true before calling the on_open,
                         // because we must ignore the change to the sourceview buffer.
                         curr_file.just_opened = true;
+                        move_opened(&recent_store, store_id, curr_file.path.as_deref(), Some(&path));
                         curr_file.path = Some(path.clone());
                         curr_file.last_saved = Some(SystemTime::now());
+                        curr_file.bom = bom;
+                        curr_file.encoding = encoding.clone();
 
                         on_open.call((path.clone(), content.clone()));
+                        if let Some(encoding) = encoding {
+                            on_encoding_detected.call((path.clone(), encoding));
+                        }
+                        if let Some(suffix) = sidecar.borrow().as_ref() {
+                            spawn_load_sidecar(path.clone(), sidecar_path(&path, suffix), send.clone());
+                        }
+                        if let Some(pointer) = last_document.borrow().as_ref() {
+                            spawn_save_last_document(pointer.clone(), path);
+                        }
 
                     },
 
                     SingleArchiverAction::OpenError(e) => {
+                        finish_io(&mut io_busy, &on_io_busy);
+                        {
+                            let mut metrics = metrics.borrow_mut();
+                            metrics.open_errors += 1;
+                            metrics.record_since(open_started.take());
+                        }
                         on_error.call(e.clone());
                     },
+                    SingleArchiverAction::SidecarError(e) => {
+                        on_error.call(e);
+                    },
 
                     // Triggered when the user choses to close an unsaved file at the toast.
                     SingleArchiverAction::FileCloseRequest => {
+                        if let Some(autosave) = &autosave {
+                            autosave.discard(&curr_file.recovery_source());
+                        }
+                        move_opened(&recent_store, store_id, curr_file.path.as_deref(), None);
                         curr_file.reset();
-                        match file_state {
+                        match file_state.get() {
                             FileState::New => {
+                                set_file_state(&file_state, FileState::Editing, &on_state_changed);
                                 on_new.call(());
                                 curr_file.just_opened = true;
                             },
                             FileState::Open => {
+                                set_file_state(&file_state, FileState::Editing, &on_state_changed);
                                 on_show_open.call(());
                                 curr_file.just_opened = true;
                             },
                             FileState::CloseWindow => {
-                                on_window_close.call(());
+                                if !super::close_vetoed(&on_window_close_veto) {
+                                    on_window_close.call(());
+                                    if let Some(store) = &recent_store {
+                                        store.unregister(store_id);
+                                    }
+                                } else {
+                                    set_file_state(&file_state, FileState::Editing, &on_state_changed);
+                                }
                             },
                             FileState::Editing => {
 
@@ -368,10 +899,13 @@ impl SingleArchiver {
                     },
                     SingleArchiverAction::WindowCloseRequest => {
                         if !curr_file.last_saved.is_some() {
-                            file_state = FileState::CloseWindow;
+                            set_file_state(&file_state, FileState::CloseWindow, &on_state_changed);
                             on_close_confirm.call(curr_file.path_or_untitled());
-                        } else {
+                        } else if !super::close_vetoed(&on_window_close_veto) {
                             on_window_close.call(());
+                            if let Some(store) = &recent_store {
+                                store.unregister(store_id);
+                            }
                         }
                     }
                 }
@@ -383,14 +917,33 @@ impl SingleArchiver {
             send,
             on_save_unknown_path,
             on_buffer_read_request,
+            on_buffer_slice_request,
+            incremental_read,
             on_close_confirm,
             on_window_close,
+            on_window_close_veto,
             on_new,
             on_save,
             on_file_changed,
             on_open_request,
             on_show_open,
-            on_error
+            on_error,
+            on_io_stalled,
+            on_autosaved,
+            on_io_busy,
+            on_io_progress,
+            on_backup_created,
+            on_encoding_detected,
+            on_state_changed,
+            file_state,
+            on_sidecar_loaded,
+            on_sidecar_request,
+            on_startup,
+            save_options,
+            io_backend,
+            sidecar,
+            last_document,
+            metrics
         }
     }
 
@@ -398,35 +951,55 @@ impl SingleArchiver {
 
 /// Spawns thread to open a filesystem file. The result of the operation will
 /// be sent back to the main thread via the send glib channel.
-pub fn spawn_open_file(path : String, send : glib::Sender<SingleArchiverAction>) -> JoinHandle<bool> {
-    thread::spawn(move || {
+pub fn spawn_open_file(path : PathBuf, send : glib::Sender<SingleArchiverAction>) -> JoinHandle<bool> {
+    thread::spawn(open_job(path, send, CancelToken::default()))
+}
+
+// Nothing is sent back if the open is cancelled before the file is read.
+fn open_job(path : PathBuf, send : glib::Sender<SingleArchiverAction>, cancel : CancelToken) -> impl FnOnce() -> bool + Send + 'static {
+    let on_panic = {
+        let send = send.clone();
+        let cancel = cancel.clone();
+        move |msg| {
+            if cancel.commit() {
+                send.send(SingleArchiverAction::OpenError(ArchiverError::WorkerPanic(msg)))
+                    .unwrap_or_else(super::log_err);
+            }
+        }
+    };
+    worker(on_panic, move || {
+
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("open", path = %path.display()).entered();
     
-        if !Path::new(&path[..]).is_absolute() {
-            send.send(SingleArchiverAction::SaveError(String::from("Using non-absolute path")))
-                .unwrap_or_else(super::log_err);
+        if !path.is_absolute() {
+            if cancel.commit() {
+                send.send(SingleArchiverAction::OpenError(ArchiverError::NonAbsolutePath(path.clone())))
+                    .unwrap_or_else(super::log_err);
+            }
             return false;
         }
         
-        match File::open(&path) {
-            Ok(mut f) => {
-                let mut content = String::new();
-                match f.read_to_string(&mut content) {
-                    Ok(_) => {
-                        if let Err(e) = send.send(SingleArchiverAction::OpenSuccess(path.to_string(), content)) {
-                            eprintln!("{}", e);
-                        }
-                        true
-                    },
-                    Err(e) => {
-                        if let Err(e) = send.send(SingleArchiverAction::OpenError(format!("{}", e ))) {
-                            eprintln!("{}", e);
-                        }
-                        false
-                    }
+        let read = File::open(&path).and_then(|mut f| {
+            let mut content = String::new();
+            let progress = |done, total| {
+                send.send(SingleArchiverAction::IoProgress(path.clone(), done, total))
+                    .unwrap_or_else(super::log_err);
+            };
+            read_content(&mut f, &path, &mut content, &progress).map(|format| (content, format) )
+        });
+        if !cancel.commit() {
+            return false;
+        }
+        match read {
+            Ok((content, format)) => {
+                if let Err(e) = send.send(SingleArchiverAction::OpenSuccess(path, content, format.bom, format.encoding)) {
+                    eprintln!("{}", e);
                 }
+                true
             },
             Err(e) => {
-                if let Err(e) = send.send(SingleArchiverAction::OpenError(format!("{}", e ))) {
+                if let Err(e) = send.send(SingleArchiverAction::OpenError(ArchiverError::from(e))) {
                     eprintln!("{}", e);
                 }
                 false
@@ -435,42 +1008,333 @@ pub fn spawn_open_file(path : String, send : glib::Sender<SingleArchiverAction>)
     })
 }
 
+// Reads the file like open_job, with the gio backend.
+async fn gio_open_job(path : PathBuf, send : glib::Sender<SingleArchiverAction>, cancel : CancelToken) {
+    if !path.is_absolute() {
+        if cancel.commit() {
+            send.send(SingleArchiverAction::OpenError(ArchiverError::NonAbsolutePath(path.clone())))
+                .unwrap_or_else(super::log_err);
+        }
+        return;
+    }
+    let read = gio_read(&gio::File::for_path(&path)).await;
+    if !cancel.commit() {
+        return;
+    }
+    let action = match read {
+        Ok(read) => SingleArchiverAction::OpenSuccess(path, read.content, read.format.bom, read.format.encoding),
+        Err(e) => SingleArchiverAction::OpenError(ArchiverError::from(e))
+    };
+    send.send(action).unwrap_or_else(super::log_err);
+}
+
+// A missing sidecar is not an error (the document might never have been saved
+// with sidecars enabled).
+fn spawn_load_sidecar(path : PathBuf, sidecar_path : PathBuf, send : glib::Sender<SingleArchiverAction>) {
+    thread::spawn(move || {
+        let f = match File::open(&sidecar_path) {
+            Ok(f) => f,
+            Err(_) => return
+        };
+        match serde_json::from_reader::<_, serde_json::Value>(f) {
+            Ok(value) => {
+                send.send(SingleArchiverAction::SidecarLoaded(path, value))
+                    .unwrap_or_else(super::log_err);
+            },
+            Err(e) => {
+                send.send(SingleArchiverAction::SidecarError(ArchiverError::Io(format!("Could not load {}: {}", sidecar_path.display(), e))))
+                    .unwrap_or_else(super::log_err);
+            }
+        }
+    });
+}
+
+// The sidecar of a document is the document path followed by the suffix.
+fn sidecar_path(path : &Path, suffix : &str) -> PathBuf {
+    let mut sidecar = path.as_os_str().to_os_string();
+    sidecar.push(suffix);
+    PathBuf::from(sidecar)
+}
+
+fn spawn_save_sidecar(sidecar_path : PathBuf, value : serde_json::Value, send : glib::Sender<SingleArchiverAction>) {
+    thread::spawn(move || {
+        let res = File::create(&sidecar_path)
+            .map_err(|e| e.to_string() )
+            .and_then(|f| serde_json::to_writer_pretty(f, &value).map_err(|e| e.to_string() ) );
+        if let Err(e) = res {
+            send.send(SingleArchiverAction::SidecarError(ArchiverError::Io(format!("Could not save {}: {}", sidecar_path.display(), e))))
+                .unwrap_or_else(super::log_err);
+        }
+    });
+}
+
+// A missing or unreadable pointer file, or a pointer to a document that does not
+// exist anymore, resolves to None.
+fn spawn_load_last_document(pointer : PathBuf, send : glib::Sender<SingleArchiverAction>) {
+    thread::spawn(move || {
+        let opt_path = fs::read_to_string(&pointer).ok()
+            .map(|content| PathBuf::from(content.trim()) )
+            .filter(|path| path.is_absolute() && path.is_file() );
+        send.send(SingleArchiverAction::LastDocumentResolved(opt_path))
+            .unwrap_or_else(super::log_err);
+    });
+}
+
+// The pointer file is text, so a path that is not valid UTF-8 is written lossily (and
+// then resolves to None, unless the lossy path also exists).
+fn spawn_save_last_document(pointer : PathBuf, path : PathBuf) {
+    thread::spawn(move || {
+        if let Err(e) = fs::write(&pointer, path.to_string_lossy().as_bytes()) {
+            eprintln!("Could not save last document path: {}", e);
+        }
+    });
+}
+
+// Updates the path the archiver holds open at the recent store, pushing the new
+// path to the recent list.
+fn move_opened(recent_store : &Option<RecentStore>, id : usize, old : Option<&Path>, new : Option<&Path>) {
+    let Some(store) = recent_store else {
+        return;
+    };
+    if let Some(old) = old {
+        if Some(old) != new {
+            store.set_opened(id, old, false);
+        }
+    }
+    if let Some(new) = new {
+        store.set_opened(id, new, true);
+        if let Ok(file) = OpenedFile::for_path(new) {
+            store.push(id, &file);
+        }
+    }
+}
+
+fn set_file_state(file_state : &Cell<FileState>, state : FileState, on_state_changed : &Callbacks<FileState>) {
+    if file_state.get() != state {
+        file_state.set(state);
+        on_state_changed.call(state);
+    }
+}
+
+fn start_io(io_busy : &mut usize, on_io_busy : &Callbacks<bool>) {
+    *io_busy += 1;
+    if *io_busy == 1 {
+        on_io_busy.call(true);
+    }
+}
+
+fn finish_io(io_busy : &mut usize, on_io_busy : &Callbacks<bool>) {
+    if *io_busy > 0 {
+        *io_busy -= 1;
+        if *io_busy == 0 {
+            on_io_busy.call(false);
+        }
+    }
+}
+
+fn schedule_autosave(autosave : &ArchiverAutosave, send : &glib::Sender<SingleArchiverAction>, generation : u64) {
+    let send = send.clone();
+    autosave.schedule(move || {
+        send.send(SingleArchiverAction::AutosaveTimeout(generation))
+            .unwrap_or_else(super::log_err);
+    });
+}
+
+// A piece of the buffer, streamed to the save thread by an incremental read.
+enum BufferSlice {
+    Text(String),
+    End,
+
+    // The buffer changed (or another save started) before it was fully read.
+    Aborted
+}
+
+// The content given to the save thread.
+enum SaveContent {
+    Full(String),
+    Slices(mpsc::Receiver<BufferSlice>)
+}
+
+impl SaveContent {
+
+    // Waits for all the slices of an incremental read.
+    fn collect(self) -> Result<String, String> {
+        let rx = match self {
+            SaveContent::Full(content) => return Ok(content),
+            SaveContent::Slices(rx) => rx
+        };
+        let mut content = String::new();
+        loop {
+            match rx.recv() {
+                Ok(BufferSlice::Text(slice)) => content += &slice,
+                Ok(BufferSlice::End) => return Ok(content),
+                Ok(BufferSlice::Aborted) => return Err(String::from("The document changed while it was being saved")),
+                Err(_) => return Err(String::from("The document could not be read"))
+            }
+        }
+    }
+
+}
+
+// Reads the buffer content for a save, at once or in slices (see set_incremental_read).
+#[derive(Clone)]
+struct BufferReader {
+    on_buffer_read_request : ValuedCallbacks<(), String>,
+    on_buffer_slice_request : ValuedCallbacks<(usize, usize), String>,
+    incremental_read : Rc<Cell<Option<usize>>>,
+
+    // The gio backend writes from the main thread, which cannot wait for the slices.
+    io_backend : Rc<Cell<IoBackend>>,
+
+    // Incremented at every incremental read, so that the idle callbacks of a previous read stop.
+    generation : Rc<Cell<u64>>,
+
+    // Sender of the incremental read in progress.
+    stream : Rc<RefCell<Option<mpsc::Sender<BufferSlice>>>>
+}
+
+impl BufferReader {
+
+    fn read(&self) -> SaveContent {
+        let slice_len = match self.incremental_read.get() {
+            Some(len) if self.on_buffer_slice_request.count_bounded() > 0 && self.io_backend.get() == IoBackend::Threads => len,
+            _ => return SaveContent::Full(self.on_buffer_read_request.call_with_values(()).remove(0))
+        };
+
+        // The previous save thread must not wait for slices anymore, since start_save joins it.
+        self.abort();
+        let (tx, rx) = mpsc::channel();
+        *self.stream.borrow_mut() = Some(tx);
+        let generation = self.generation.get() + 1;
+        self.generation.set(generation);
+        let reader = self.clone();
+        let mut offset = 0;
+        glib::idle_add_local(move || {
+            if reader.generation.get() != generation {
+                return glib::ControlFlow::Break;
+            }
+            let mut stream = reader.stream.borrow_mut();
+            let Some(tx) = stream.as_ref() else {
+                return glib::ControlFlow::Break;
+            };
+            let slice = reader.on_buffer_slice_request.call_with_values((offset, slice_len)).remove(0);
+            if slice.is_empty() {
+                tx.send(BufferSlice::End).ok();
+                *stream = None;
+                glib::ControlFlow::Break
+            } else {
+                offset += slice_len;
+                if tx.send(BufferSlice::Text(slice)).is_ok() {
+                    glib::ControlFlow::Continue
+                } else {
+                    glib::ControlFlow::Break
+                }
+            }
+        });
+        SaveContent::Slices(rx)
+    }
+
+    // Aborts the incremental read in progress, if any.
+    fn abort(&self) {
+        if let Some(tx) = self.stream.borrow_mut().take() {
+            tx.send(BufferSlice::Aborted).ok();
+        }
+    }
+
+}
+
+// Submits the save, scheduling the stall check if a save timeout is set.
+fn start_save(
+    save_task : &mut IoTask,
+    path : PathBuf,
+    content : SaveContent,
+    format : TextFormat,
+    opts : &SaveOptions,
+    backend : IoBackend,
+    send : &glib::Sender<SingleArchiverAction>
+) {
+    let job = match backend {
+        IoBackend::Threads => submit_io(&path, save_job(path.clone(), content, format, opts.clone(), send.clone())),
+        IoBackend::Gio => submit_local(&path, gio_save_job(path.clone(), content, format, opts.clone(), send.clone()))
+    };
+    let generation = save_task.start(job);
+    if let Some(timeout) = opts.timeout {
+        let send = send.clone();
+        glib::timeout_add_local_once(timeout, move || {
+            send.send(SingleArchiverAction::SaveTimeout(path, generation))
+                .unwrap_or_else(super::log_err);
+        });
+    }
+}
+
+/// Spawns thread to save a file. bom tells whether the file had a byte order
+/// mark when it was opened (see BomPolicy). The file is written as UTF-8.
 pub fn spawn_save_file(
-    path : String,
+    path : PathBuf,
     content : String,
+    bom : bool,
+    opts : SaveOptions,
     send : glib::Sender<SingleArchiverAction>
 ) -> JoinHandle<bool> {
-    thread::spawn(move || {
+    thread::spawn(save_job(path, SaveContent::Full(content), TextFormat { bom, encoding : None }, opts, send))
+}
+
+fn save_job(
+    path : PathBuf,
+    content : SaveContent,
+    format : TextFormat,
+    opts : SaveOptions,
+    send : glib::Sender<SingleArchiverAction>
+) -> impl FnOnce() -> bool + Send + 'static {
+    let on_panic = {
+        let send = send.clone();
+        move |msg| {
+            send.send(SingleArchiverAction::SaveError(ArchiverError::WorkerPanic(msg)))
+                .unwrap_or_else(super::log_err);
+        }
+    };
+    worker(on_panic, move || {
 
-        if !Path::new(&path[..]).is_absolute() {
-            send.send(SingleArchiverAction::SaveError(String::from("Using non-absolute path")))
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("save", path = %path.display()).entered();
+
+        if !path.is_absolute() {
+            send.send(SingleArchiverAction::SaveError(ArchiverError::NonAbsolutePath(path.clone())))
                 .unwrap_or_else(super::log_err);
             return false;
         }
         
-        if Path::new(&path[..]).is_dir() {
-            send.send(SingleArchiverAction::SaveError(String::from("Tried to save file to directory path")))
+        if path.is_dir() {
+            send.send(SingleArchiverAction::SaveError(ArchiverError::IsDirectory(path.clone())))
                 .unwrap_or_else(super::log_err);
             return false;
         }
 
-        match File::create(&path) {
-            Ok(mut f) => {
-                match f.write_all(content.as_bytes()) {
-                    Ok(_) => {
-                        send.send(SingleArchiverAction::SaveSuccess(path))
-                            .unwrap_or_else(super::log_err);
-                        true
-                    },
-                    Err(e) => {
-                        send.send(SingleArchiverAction::SaveError(format!("{}",e )))
-                            .unwrap_or_else(super::log_err);
-                        false
-                    }
-                }
+        let content = match content.collect() {
+            Ok(content) => content,
+            Err(msg) => {
+                send.send(SingleArchiverAction::SaveError(ArchiverError::Io(msg)))
+                    .unwrap_or_else(super::log_err);
+                return false;
             }
+        };
+
+        let progress = |done, total| {
+            send.send(SingleArchiverAction::IoProgress(path.clone(), done, total))
+                .unwrap_or_else(super::log_err);
+        };
+        match write_content(&path, &content, &format, &opts, &progress) {
+            Ok(backup) => {
+                if let Some(backup) = backup {
+                    send.send(SingleArchiverAction::BackupCreated(path.clone(), backup))
+                        .unwrap_or_else(super::log_err);
+                }
+                send.send(SingleArchiverAction::SaveSuccess(path))
+                    .unwrap_or_else(super::log_err);
+                true
+            },
             Err(e) => {
-                send.send(SingleArchiverAction::SaveError(format!("{}",e )))
+                send.send(SingleArchiverAction::SaveError(ArchiverError::from(e)))
                     .unwrap_or_else(super::log_err);
                 false
             }
@@ -478,13 +1342,68 @@ pub fn spawn_save_file(
     })
 }
 
+// Writes the file like save_job, with the gio backend.
+async fn gio_save_job(
+    path : PathBuf,
+    content : SaveContent,
+    format : TextFormat,
+    opts : SaveOptions,
+    send : glib::Sender<SingleArchiverAction>
+) {
+    if !path.is_absolute() {
+        send.send(SingleArchiverAction::SaveError(ArchiverError::NonAbsolutePath(path.clone())))
+            .unwrap_or_else(super::log_err);
+        return;
+    }
+
+    let file = gio::File::for_path(&path);
+    match gio_metadata(&file).await {
+        Ok(Some(metadata)) if metadata.is_dir => {
+            send.send(SingleArchiverAction::SaveError(ArchiverError::IsDirectory(path.clone())))
+                .unwrap_or_else(super::log_err);
+            return;
+        },
+        Err(e) => {
+            send.send(SingleArchiverAction::SaveError(ArchiverError::from(e)))
+                .unwrap_or_else(super::log_err);
+            return;
+        },
+        _ => { }
+    }
+
+    // Does not wait, since the buffer is read at once for the gio backend (see BufferReader::read).
+    let content = match content.collect() {
+        Ok(content) => content,
+        Err(msg) => {
+            send.send(SingleArchiverAction::SaveError(ArchiverError::Io(msg)))
+                .unwrap_or_else(super::log_err);
+            return;
+        }
+    };
+
+    match gio_write(&file, &content, &format, &opts).await {
+        Ok(backup) => {
+            if let Some(backup) = backup {
+                send.send(SingleArchiverAction::BackupCreated(path.clone(), backup))
+                    .unwrap_or_else(super::log_err);
+            }
+            send.send(SingleArchiverAction::SaveSuccess(path))
+                .unwrap_or_else(super::log_err);
+        },
+        Err(e) => {
+            send.send(SingleArchiverAction::SaveError(ArchiverError::from(e)))
+                .unwrap_or_else(super::log_err);
+        }
+    }
+}
+
 pub fn connect_manager_with_open_dialog(send : &glib::Sender<SingleArchiverAction>, dialog : &OpenDialog) {
     let send = send.clone();
     dialog.dialog.connect_response(move |dialog, resp| {
         match resp {
             ResponseType::Accept => {
                 if let Some(path) = dialog.file().and_then(|f| f.path() ) {
-                    send.send(SingleArchiverAction::OpenRequest(path.to_str().unwrap().to_string())).unwrap();
+                    send.send(SingleArchiverAction::OpenRequest(path)).unwrap();
                 }
             },
             _ => { }
@@ -498,7 +1417,7 @@ pub fn connect_manager_with_save_dialog(send : &glib::Sender<SingleArchiverActio
         match resp {
             ResponseType::Accept => {
                 if let Some(path) = dialog.file().and_then(|f| f.path() ) {
-                    send.send(SingleArchiverAction::SaveRequest(Some(path.to_str().unwrap().to_string()))).unwrap();
+                    send.send(SingleArchiverAction::SaveRequest(Some(path))).unwrap();
                 }
             },
             _ => { }
@@ -506,11 +1425,15 @@ pub fn connect_manager_with_save_dialog(send : &glib::Sender<SingleArchiverActio
     });
 }
 
-pub fn connect_manager_with_editor(
+// Works with any TextView (including a sourceview5::View).
+pub fn connect_manager_with_editor<V>(
     send : &glib::Sender<SingleArchiverAction>,
-    view : &sourceview5::View,
+    view : &V,
     ignore_file_save_action : &gio::SimpleAction
-) -> SignalHandlerId {
+) -> SignalHandlerId
+where
+    V : IsA<TextView>
+{
     ignore_file_save_action.connect_activate({
         let send = send.clone();
         move |_action, _param| {
@@ -534,6 +1457,81 @@ pub fn connect_manager_responds_window(send : &glib::Sender<SingleArchiverAction
     });
 }
 
+/// Which part of the path is shown at the window title.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TitleName {
+
+    #[default]
+    FullPath,
+
+    // Only the last path component (e.g. document.tex).
+    Basename
+
+}
+
+/// How files with unsaved changes are marked at the window title.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DirtyMarker {
+
+    None,
+
+    // Shown before the file name (e.g. "• document.tex").
+    Prefix(String),
+
+    // Shown after the file name (e.g. "document.tex*").
+    Suffix(String)
+
+}
+
+impl Default for DirtyMarker {
+
+    fn default() -> Self {
+        DirtyMarker::Suffix(String::from("*"))
+    }
+
+}
+
+/// Builds the window title set by connect_manager_with_window_title. The default
+/// shows the full path followed by an asterisk when there are unsaved changes.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct TitleFormat {
+
+    pub name : TitleName,
+
+    pub dirty_marker : DirtyMarker,
+
+    // If set, appended to the file name (e.g. "document.tex — My Editor").
+    pub app_name : Option<String>
+
+}
+
+impl TitleFormat {
+
+    /// The title for the file at the given path (or the untitled file with the
+    /// given extension, if there is no path).
+    pub fn format(&self, path : Option<&Path>, extension : &str, dirty : bool) -> String {
+        let name = match path {
+            Some(path) => match self.name {
+                TitleName::FullPath => path.display().to_string(),
+                TitleName::Basename => path.file_name()
+                    .map(|n| n.to_string_lossy().to_string() )
+                    .unwrap_or_else(|| path.display().to_string() )
+            },
+            None => format!("Untitled.{}", extension)
+        };
+        let name = match (&self.dirty_marker, dirty) {
+            (DirtyMarker::Prefix(marker), true) => format!("{}{}", marker, name),
+            (DirtyMarker::Suffix(marker), true) => format!("{}{}", name, marker),
+            _ => name
+        };
+        match &self.app_name {
+            Some(app_name) => format!("{} — {}", name, app_name),
+            None => name
+        }
+    }
+
+}
+
 // This is a reaction of the window to changes in the manager
 pub fn connect_manager_with_app_window_and_actions<A>(
     manager : &A,
@@ -551,11 +1549,9 @@ where
     manager.connect_opened({
         let action_save = actions.save.clone();
         let action_save_as = actions.save_as.clone();
-        let window = window.clone();
-        move |(path, _)| {
+        move |_| {
             action_save.set_enabled(true);
             action_save_as.set_enabled(true);
-            window.set_title(Some(&path));
         }
     });
     manager.connect_open_request({
@@ -564,21 +1560,96 @@ where
             open_action.activate(None);
         }
     });
+    connect_manager_with_window_title(manager, window, extension, TitleFormat::default());
+}
+
+// Keeps the window title in sync with the current file, which is updated when the
+// file is opened, saved, changed or created.
+pub fn connect_manager_with_window_title<A>(
+    manager : &A,
+    window : &ApplicationWindow,
+    extension : &'static str,
+    format : TitleFormat
+)
+where
+    A : AsRef<SingleArchiver> + SingleArchiverImpl
+{
+    let format = Rc::new(format);
+    manager.connect_opened({
+        let window = window.clone();
+        let format = format.clone();
+        move |(path, _)| {
+            window.set_title(Some(&format.format(Some(&path), extension, false)));
+        }
+    });
     manager.connect_save({
         let window = window.clone();
+        let format = format.clone();
         move |path| {
-            window.set_title(Some(&path));
+            window.set_title(Some(&format.format(Some(&path), extension, false)));
         }
     });
     manager.connect_file_changed({
         let window = window.clone();
+        let format = format.clone();
         move |opt_path| {
-            if let Some(path) = opt_path {
-                window.set_title(Some(&format!("{}*", path)));
-            } else {
-                window.set_title(Some(&format!("Untitled.{}*", extension)));
-            }
+            window.set_title(Some(&format.format(opt_path.as_deref(), extension, true)));
+        }
+    });
+    manager.connect_new({
+        let window = window.clone();
+        let format = format.clone();
+        move |_| {
+            window.set_title(Some(&format.format(None, extension, false)));
+        }
+    });
+}
+
+// Shows the file name as the title and its directory as the subtitle of an
+// adw::WindowTitle (e.g. at the header bar), and spins the spinner while the
+// document is opened or saved.
+#[cfg(feature = "libadwaita")]
+pub fn connect_manager_with_window_title_widget<A>(
+    manager : &A,
+    title : &libadwaita::WindowTitle,
+    spinner : &Spinner,
+    extension : &'static str
+)
+where
+    A : AsRef<SingleArchiver> + SingleArchiverImpl
+{
+    let format = Rc::new(TitleFormat { name : TitleName::Basename, ..Default::default() });
+    let update = {
+        let title = title.clone();
+        let format = format.clone();
+        move |opt_path : Option<&Path>, dirty : bool| {
+            title.set_title(&format.format(opt_path, extension, dirty));
+            let dir = opt_path.and_then(|p| p.parent() )
+                .map(super::contract_tilde)
+                .unwrap_or_default();
+            title.set_subtitle(&dir);
         }
+    };
+    let update = Rc::new(update);
+    manager.connect_opened({
+        let update = update.clone();
+        move |(path, _)| update(Some(&path), false)
+    });
+    manager.connect_save({
+        let update = update.clone();
+        move |path| update(Some(&path), false)
+    });
+    manager.connect_file_changed({
+        let update = update.clone();
+        move |opt_path| update(opt_path.as_deref(), true)
+    });
+    manager.connect_new({
+        let update = update.clone();
+        move |_| update(None, false)
+    });
+    manager.connect_io_busy({
+        let spinner = spinner.clone();
+        move |busy| spinner.set_spinning(busy)
     });
 }
 
@@ -611,6 +1682,58 @@ pub fn connect_manager_with_file_actions(
     });
 }
 
+/// The content of an editor widget, as seen by the archiver: the opened content is
+/// written to it, and its content is read back when the file is saved. Implemented for
+/// TextView and sourceview5::View; applications can implement it for custom editors
+/// (e.g. a webview-based editor) and connect them with connect_manager_to_editor_buffer.
+pub trait EditorBuffer : Clone + 'static {
+
+    // Replaces the content of the editor by the content of an opened file.
+    fn set_content(&self, content : &str);
+
+    // The current content of the editor.
+    fn content(&self) -> String;
+
+    // The slice of the content with len characters starting at the given character offset
+    // (empty past the end), read when the incremental read is set (see set_incremental_read).
+    fn content_slice(&self, offset : usize, len : usize) -> String {
+        self.content().chars().skip(offset).take(len).collect()
+    }
+
+}
+
+impl EditorBuffer for TextView {
+
+    fn set_content(&self, content : &str) {
+        self.buffer().set_text(content);
+    }
+
+    fn content(&self) -> String {
+        buffer_text(&self.buffer())
+    }
+
+    fn content_slice(&self, offset : usize, len : usize) -> String {
+        buffer_slice(&self.buffer(), offset, len)
+    }
+
+}
+
+impl EditorBuffer for sourceview5::View {
+
+    fn set_content(&self, content : &str) {
+        self.buffer().set_text(content);
+    }
+
+    fn content(&self) -> String {
+        buffer_text(&self.buffer())
+    }
+
+    fn content_slice(&self, offset : usize, len : usize) -> String {
+        buffer_slice(&self.buffer(), offset, len)
+    }
+
+}
+
 pub fn connect_manager_to_editor<A>(
     manager : &A,
     view : &sourceview5::View,
@@ -618,6 +1741,68 @@ pub fn connect_manager_to_editor<A>(
 )
 where
     A : AsRef<SingleArchiver> + SingleArchiverImpl
+{
+    connect_opened_blocking(manager, view, buf_change_handler);
+    connect_buffer_read_request(manager, view);
+}
+
+// Like connect_manager_to_editor, for a plain TextView.
+pub fn connect_manager_to_textview<A>(
+    manager : &A,
+    view : &TextView,
+    buf_change_handler : &Rc<RefCell<Option<SignalHandlerId>>>
+)
+where
+    A : AsRef<SingleArchiver> + SingleArchiverImpl
+{
+    connect_opened_blocking(manager, view, buf_change_handler);
+    connect_buffer_read_request(manager, view);
+}
+
+// Connects a custom editor. The editor is responsible for not reporting the content set
+// by set_content as a change (i.e. not sending FileChanged for it).
+pub fn connect_manager_to_editor_buffer<A, E>(manager : &A, editor : &E)
+where
+    A : AsRef<SingleArchiver> + SingleArchiverImpl,
+    E : EditorBuffer
+{
+    manager.connect_opened({
+        let editor = editor.clone();
+        move |(_path, content)| {
+            editor.set_content(&content);
+        }
+    });
+    connect_buffer_read_request(manager, editor);
+}
+
+// Like connect_manager_to_editor, but blocks all the given changed-signal handlers of the
+// buffer while the opened content is set (e.g. the handler returned by connect_manager_with_editor
+// and the application's own handlers, such as a linter), so that none of them sees the open
+// as an edit. Handlers can be added to the list after this call.
+pub fn connect_manager_to_editor_with_handlers<A>(
+    manager : &A,
+    view : &sourceview5::View,
+    buf_change_handlers : &Rc<RefCell<Vec<SignalHandlerId>>>
+)
+where
+    A : AsRef<SingleArchiver> + SingleArchiverImpl
+{
+    manager.connect_opened({
+        let view = view.clone();
+        let change_handlers = buf_change_handlers.clone();
+        move |(_path, content)| {
+            let change_handlers = change_handlers.borrow();
+            let change_handlers : Vec<&SignalHandlerId> = change_handlers.iter().collect();
+            set_text_blocking(&view.buffer(), &content, &change_handlers);
+        }
+    });
+    connect_buffer_read_request(manager, view);
+}
+
+fn connect_opened_blocking<A, V>(manager : &A, view : &V, buf_change_handler : &Rc<RefCell<Option<SignalHandlerId>>>)
+where
+    A : AsRef<SingleArchiver> + SingleArchiverImpl,
+    V : IsA<TextView> + Clone + 'static
 {
     manager.connect_opened({
         let view = view.clone();
@@ -625,21 +1810,58 @@ where
         move |(_path, content)| {
             let handler_guard = change_handler.borrow();
             let change_handler = handler_guard.as_ref().unwrap();
-            view.buffer().block_signal(&change_handler);
-            view.buffer().set_text(&content);
-            view.buffer().unblock_signal(&change_handler);
+            set_text_blocking(&view.buffer(), &content, &[change_handler]);
         }
     });
+}
+
+fn set_text_blocking(buffer : &TextBuffer, content : &str, handlers : &[&SignalHandlerId]) {
+    for handler in handlers {
+        buffer.block_signal(handler);
+    }
+    buffer.set_text(content);
+    for handler in handlers {
+        buffer.unblock_signal(handler);
+    }
+}
+
+fn connect_buffer_read_request<A, E>(manager : &A, editor : &E)
+where
+    A : AsRef<SingleArchiver> + SingleArchiverImpl,
+    E : EditorBuffer
+{
     manager.connect_buffer_read_request({
-        let view = view.clone();
+        let editor = editor.clone();
         move |_| -> String {
-            let buffer = view.buffer();
-            buffer.text(
-                &buffer.start_iter(),
-                &buffer.end_iter(),
-                true
-            ).to_string()
+            editor.content()
         }
     });
+    manager.connect_buffer_slice_request({
+        let editor = editor.clone();
+        move |(offset, len)| -> String {
+            editor.content_slice(offset, len)
+        }
+    });
+}
+
+fn buffer_text(buffer : &TextBuffer) -> String {
+    buffer.text(
+        &buffer.start_iter(),
+        &buffer.end_iter(),
+        true
+    ).to_string()
+}
+
+fn buffer_slice(buffer : &TextBuffer, offset : usize, len : usize) -> String {
+    let count = buffer.char_count().max(0) as usize;
+    if offset >= count {
+        return String::new();
+    }
+    let end = offset.saturating_add(len).min(count);
+    buffer.text(
+        &buffer.iter_at_offset(offset as i32),
+        &buffer.iter_at_offset(end as i32),
+        true
+    ).to_string()
 }
 
@@ -0,0 +1,142 @@
+/*Copyright (c) 2022 Diego da Silva Lima. All rights reserved.
+
+This work is licensed under the terms of the MIT license.  
+For a copy, see <https://opensource.org/licenses/MIT>.*/
+
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::thread;
+use gtk4::glib;
+use serde::de::DeserializeOwned;
+use stateful::Callbacks;
+use super::{get_datadir, load_shared_serializable, WindowState, OpenedFile, FinalState, MultiArchiverImpl, MultiArchiverAction};
+
+/// Where Startup::restore loads each part of the state from: the same paths the application
+/// writes them to. Relative paths are taken from the data directory (see get_datadir), and
+/// parts without a path are not loaded.
+#[derive(Debug, Clone, Default)]
+pub struct StartupPaths {
+
+    // Written with save_shared_serializable.
+    pub config : Option<PathBuf>,
+
+    // Written with save_shared_serializable.
+    pub window : Option<PathBuf>,
+
+    // A JSON list, as written by RecentStore::persistent (or RecentManager, at RECENT_FILE).
+    pub recent : Option<PathBuf>,
+
+    // The snapshot written by the archiver (see MultiArchiverAction::SetSnapshot).
+    pub session : Option<PathBuf>
+
+}
+
+/*
+Loads everything an application restores at startup (see StartupPaths), in a single worker
+thread, so that the main thread can show the window meanwhile. The configuration is loaded
+first, then the window state, the recent list and the last session, which is the order the
+application applies them in (see StartupState::restore_multi). Files that do not exist are left
+as None (or empty), and files that cannot be parsed are logged and skipped, so a first run and
+a corrupt file both start from the defaults.
+
+The result is delivered to on_ready once. Listeners bound right after Startup::restore do not
+miss it, since it is delivered at the main context.
+*/
+pub struct Startup<C> {
+    on_ready : Callbacks<StartupState<C>>
+}
+
+/// Everything loaded by Startup::restore.
+#[derive(Debug, Clone)]
+pub struct StartupState<C> {
+
+    // The application data directory, if it could be found.
+    pub datadir : Option<PathBuf>,
+
+    pub config : Option<C>,
+
+    pub window : Option<WindowState>,
+
+    pub recent : Vec<OpenedFile>,
+
+    pub session : Option<FinalState>
+
+}
+
+impl<C> Startup<C>
+where
+    C : DeserializeOwned + Clone + Send + 'static
+{
+
+    /// Starts loading the state of the application with the given id.
+    pub fn restore(app_id : &str, paths : StartupPaths) -> Self {
+        let (send, recv) = glib::MainContext::channel::<StartupState<C>>(glib::source::Priority::DEFAULT);
+        let on_ready : Callbacks<StartupState<C>> = Default::default();
+        recv.attach(None, {
+            let on_ready = on_ready.clone();
+            move |state| {
+                on_ready.call(state);
+                glib::ControlFlow::Break
+            }
+        });
+        let app_id = app_id.to_string();
+        thread::spawn(move || {
+            let datadir = get_datadir(&app_id);
+            let resolve = |path : &Option<PathBuf>| -> Option<PathBuf> {
+                let path = path.as_ref()?;
+                let path = if path.is_absolute() {
+                    path.clone()
+                } else {
+                    datadir.as_ref()?.join(path)
+                };
+                Some(path).filter(|path| path.exists() )
+            };
+            let state = StartupState {
+                config : resolve(&paths.config).and_then(|path| load_shared(&path) ),
+                window : resolve(&paths.window).and_then(|path| load_shared(&path) ),
+                recent : resolve(&paths.recent).and_then(|path| load_shared(&path) ).unwrap_or_default(),
+                session : resolve(&paths.session).and_then(FinalState::load),
+                datadir
+            };
+            send.send(state).unwrap_or_else(super::log_err);
+        });
+        Self { on_ready }
+    }
+
+    pub fn connect_ready<F>(&self, f : F)
+    where
+        F : Fn(StartupState<C>) + 'static
+    {
+        self.on_ready.bind(f);
+    }
+
+}
+
+impl<C> StartupState<C> {
+
+    /// Adds the recent files to the archiver, then restores the session (see
+    /// MultiArchiverAction::RestoreSession) and checks which recent files still exist
+    /// (see MultiArchiverAction::ValidateRecent; missing files are not removed). The
+    /// recent files are added with the recent list of the session, so that each file
+    /// is added once.
+    pub fn restore_multi<A : MultiArchiverImpl>(&self, archiver : &A) {
+        if let Some(session) = &self.session {
+            let mut session = session.clone();
+            session.recent.extend(self.recent.iter().cloned());
+            archiver.restore_session(&session);
+        } else {
+            archiver.sender().send(MultiArchiverAction::AddMany(self.recent.clone()))
+                .unwrap_or_else(super::log_err);
+        }
+        archiver.validate_recent(false);
+    }
+
+}
+
+// The value is never shared, since it does not leave the worker thread.
+fn load_shared<T : DeserializeOwned>(path : &Path) -> Option<T> {
+    load_shared_serializable::<T, _>(path)
+        .and_then(|state| Rc::try_unwrap(state).ok() )
+        .map(RefCell::into_inner)
+}
@@ -0,0 +1,138 @@
+use std::fs;
+use std::path::PathBuf;
+use std::time::{SystemTime, Duration};
+use filecase::{is_within_prefix, canonicalize_path, sanitize_file_name, infer_extension, frecency_score, recency_score, OpenedFile};
+
+// Creates an empty directory under the temporary directory, unique to the test.
+fn scratch_dir(name : &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("filecase-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("project")).unwrap();
+    fs::create_dir_all(dir.join("project2")).unwrap();
+    dir
+}
+
+#[test]
+fn prefix_accepts_inner_paths() {
+    let dir = scratch_dir("inner");
+    let prefix = dir.join("project").display().to_string();
+    assert!(is_within_prefix(&prefix, &prefix, false));
+    assert!(is_within_prefix(&dir.join("project/a.sql").display().to_string(), &prefix, false));
+    assert!(is_within_prefix(&dir.join("project/new/b.sql").display().to_string(), &prefix, false));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn prefix_rejects_siblings_with_same_start() {
+    let dir = scratch_dir("sibling");
+    let prefix = dir.join("project").display().to_string();
+    assert!(!is_within_prefix(&dir.join("project2/a.sql").display().to_string(), &prefix, false));
+    assert!(!is_within_prefix(&dir.join("project2").display().to_string(), &prefix, false));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn prefix_rejects_traversal() {
+    let dir = scratch_dir("traversal");
+    let prefix = dir.join("project").display().to_string();
+    assert!(!is_within_prefix(&dir.join("project/../project2/a.sql").display().to_string(), &prefix, false));
+    assert!(!is_within_prefix(&dir.join("project/new/../../a.sql").display().to_string(), &prefix, false));
+    assert!(is_within_prefix(&dir.join("project/new/../a.sql").display().to_string(), &prefix, false));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn prefix_ignores_trailing_separators() {
+    let dir = scratch_dir("trailing");
+    let prefix = dir.join("project").display().to_string();
+    let file = dir.join("project/a.sql").display().to_string();
+    assert!(is_within_prefix(&file, &format!("{}/", prefix), false));
+    assert!(is_within_prefix(&format!("{}/", prefix), &prefix, false));
+    assert!(!is_within_prefix(&dir.join("project2/a.sql").display().to_string(), &format!("{}/", prefix), false));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn prefix_compares_case_when_asked() {
+    let dir = scratch_dir("case");
+    let prefix = dir.join("project").display().to_string();
+    let file = dir.join("PROJECT/a.sql").display().to_string();
+    assert!(!is_within_prefix(&file, &prefix, false));
+    assert!(is_within_prefix(&file, &prefix, true));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn prefix_resolves_symlinks() {
+    let dir = scratch_dir("symlink");
+    let prefix = dir.join("project").display().to_string();
+
+    // A link inside the prefix pointing outside of it.
+    std::os::unix::fs::symlink(dir.join("project2"), dir.join("project/escape")).unwrap();
+    assert!(!is_within_prefix(&dir.join("project/escape/a.sql").display().to_string(), &prefix, false));
+
+    // A link outside the prefix pointing inside of it.
+    std::os::unix::fs::symlink(dir.join("project"), dir.join("alias")).unwrap();
+    assert!(is_within_prefix(&dir.join("alias/a.sql").display().to_string(), &prefix, false));
+    assert!(is_within_prefix(&dir.join("project/a.sql").display().to_string(), &dir.join("alias").display().to_string(), false));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn canonical_paths_match_other_spellings() {
+    let dir = scratch_dir("canonical");
+    fs::write(dir.join("project/a.sql"), "").unwrap();
+    std::os::unix::fs::symlink(dir.join("project/a.sql"), dir.join("link.sql")).unwrap();
+    let canon = canonicalize_path(dir.join("project/a.sql"));
+    assert_eq!(canonicalize_path(dir.join("project/./a.sql")), canon);
+    assert_eq!(canonicalize_path(dir.join("project2/../project/a.sql")), canon);
+    assert_eq!(canonicalize_path(dir.join("link.sql")), canon);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn sanitize_replaces_invalid_characters() {
+    assert_eq!(sanitize_file_name("a/b\\c:d*.sql"), "a_b_c_d_.sql");
+    assert_eq!(sanitize_file_name("report\0.sql"), "report_.sql");
+    assert_eq!(sanitize_file_name("  notes.sql. "), "notes.sql");
+    assert_eq!(sanitize_file_name(".."), "_");
+    assert_eq!(sanitize_file_name("con.txt"), "_con.txt");
+    assert_eq!(sanitize_file_name("Untitled 1.sql"), "Untitled 1.sql");
+}
+
+#[test]
+fn sanitize_shortens_long_names() {
+    let name = sanitize_file_name(&format!("{}.sql", "é".repeat(200)));
+    assert!(name.len() <= 255);
+    assert!(name.ends_with("é.sql"));
+    assert_eq!(sanitize_file_name(&"a".repeat(300)).len(), 255);
+}
+
+#[test]
+fn frecency_prefers_frequent_files() {
+    let dir = scratch_dir("frecency");
+    let mut often = OpenedFile::for_path(dir.join("project/often.sql")).unwrap();
+    let mut once = OpenedFile::for_path(dir.join("project/once.sql")).unwrap();
+    often.dt = Some(SystemTime::now() - Duration::from_secs(2 * 86_400));
+    once.dt = Some(SystemTime::now());
+    assert!(frecency_score(&often, 2) > frecency_score(&once, 1));
+    assert!(recency_score(&often, 2) < recency_score(&once, 1));
+
+    // Old accesses weigh less than recent ones.
+    once.dt = Some(SystemTime::now() - Duration::from_secs(100 * 86_400));
+    assert!(frecency_score(&once, 5) < frecency_score(&often, 1));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn extension_inferred_for_extensionless_files() {
+    assert_eq!(infer_extension("/src/Makefile", "all:\n").as_deref(), Some("mk"));
+    assert_eq!(infer_extension("/bin/run", "#!/usr/bin/env python3\nprint()").as_deref(), Some("py"));
+    assert_eq!(infer_extension("/bin/run", "#!/bin/bash -e\n").as_deref(), Some("sh"));
+    assert_eq!(infer_extension("/bin/run", "#!/usr/bin/env -S deno run\n").as_deref(), Some("ts"));
+    assert_eq!(infer_extension("/data/feed", "<?xml version=\"1.0\"?>").as_deref(), Some("xml"));
+    assert_eq!(infer_extension("/bin/run.py", "#!/bin/sh\n"), None);
+    assert_eq!(infer_extension("/notes/todo", "buy milk"), None);
+}